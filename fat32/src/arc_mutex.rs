@@ -1,7 +1,5 @@
-use std::rc::Rc;
-use std::sync::Mutex;
+use std::sync::{self, LockResult, Mutex, PoisonError};
 use std::ops::DerefMut;
-use std::rc;
 
 /// A smart pointer to an instance of type `T`.
 ///
@@ -9,33 +7,48 @@ use std::rc;
 /// `.lock()`. The implementation guarantees the usual reference
 /// guarantees.
 #[derive(Debug)]
-pub struct ArcMutex<T>(Rc<Mutex<T>>);
+pub struct ArcMutex<T>(Arc<Mutex<T>>);
 
 impl<T> ArcMutex<T> {
 
     /// Wraps `val` into a `ArcMutex<T>` and returns it.
     pub fn new(val: T) -> ArcMutex<T> {
-        ArcMutex(Rc::new(Mutex::new(val)))
+        ArcMutex(Arc::new(Mutex::new(val)))
     }
 
-    pub fn from_rc(val: Rc<Mutex<T>>) -> ArcMutex<T> {
+    pub fn from_arc(val: Arc<Mutex<T>>) -> ArcMutex<T> {
         ArcMutex(val)
     }
 
     pub fn downgrade(val: &ArcMutex<T>) -> Weak<Mutex<T>> {
-        Rc::downgrade(&val.0)
+        Arc::downgrade(&val.0)
+    }
+
+    /// Returns `true` if both pointers point to the same value.
+    pub fn ptr_eq(a: &ArcMutex<T>, b: &ArcMutex<T>) -> bool {
+        Arc::ptr_eq(&a.0, &b.0)
     }
 
     /// Returns an immutable borrow to the inner value.
     ///
     /// If the inner value is presently mutably borrowed, this function blocks
-    /// until that borrow is returned.
+    /// until that borrow is returned. A panic of another thread while it held
+    /// the borrow doesn't make this fail, see `ignore_poison`.
     pub fn lock<'a>(&'a self) -> impl DerefMut<Target = T> + 'a {
-        self.0.lock().expect("Mutex::lock() failed")
+        ignore_poison(self.0.lock())
+    }
+
+    /// Returns the inner value if this is the only pointer to it, or the
+    /// pointer otherwise.
+    pub fn try_unwrap(val: ArcMutex<T>) -> Result<T, ArcMutex<T>> {
+        match Arc::try_unwrap(val.0) {
+            Ok(mutex) => Ok(ignore_poison(mutex.into_inner())),
+            Err(arc) => Err(ArcMutex(arc)),
+        }
     }
 
     pub fn unwrap(self) -> T {
-        Rc::try_unwrap(self.0).map_err(|_|()).unwrap().into_inner().unwrap()
+        ignore_poison(Arc::try_unwrap(self.0).map_err(|_|()).unwrap().into_inner())
     }
 }
 
@@ -49,12 +62,16 @@ impl<T> Clone for ArcMutex<T> {
     }
 }
 
-unsafe impl<T> Send for ArcMutex<T> {
-    // It's not Send.
-}
-unsafe impl<T> Sync for ArcMutex<T> {
-    // It's not Sync.
-}
+pub type Arc<T> = sync::Arc<T>;
+pub type Weak<T> = sync::Weak<T>;
 
-pub type Arc<T> = Rc<T>;
-pub type Weak<T> = rc::Weak<T>;
+/// Returns the guard of a lock even if it is poisoned.
+///
+/// A thread that panics while holding a lock poisons it. Failing every later
+/// operation on the file system because of that would turn one failed
+/// operation into an unusable volume, and the state behind our locks (lock
+/// counts, caches, handles of the device) stays usable, so the poison is
+/// ignored.
+pub(crate) fn ignore_poison<G>(result: LockResult<G>) -> G {
+    result.unwrap_or_else(PoisonError::into_inner)
+}
@@ -1,87 +1,479 @@
-use traits::BlockDevice;
+use traits::{BlockDevice, check_range};
+use error::Error;
 use std::io;
-use std::collections::HashMap;
-use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use arc_mutex::ignore_poison;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::ops::Range;
 
+/// The capacity of the cache `VFatFileSystem` puts in front of the device it
+/// mounts.
+pub const DEFAULT_CACHE_BYTES: usize = 512 * 1024;
+
+/// The number of sectors `VFatFileSystem` reads ahead on sequential misses
+/// unless `MountOptions::read_ahead_sectors` says otherwise.
+pub const DEFAULT_READ_AHEAD_SECTORS: u64 = 32;
+
+/// A snapshot of the activity and contents of a `CachedDevice`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Sector lookups served from the cache.
+    pub hits: usize,
+    /// Sector lookups that had to read the source.
+    pub misses: usize,
+    /// Sectors dropped to make room for others.
+    pub evictions: usize,
+    /// Dirty sectors written to the source.
+    pub write_backs: usize,
+    pub dirty_sectors: usize,
+    pub resident_sectors: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+    write_backs: AtomicUsize,
+}
+
+impl Counters {
+    fn count(counter: &AtomicUsize, n: usize) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+}
 
 #[derive(Debug)]
 struct CacheEntry {
     data: Vec<u8>,
-    is_dirty: bool
+    is_dirty: bool,
+    last_used: u64,
 }
 
-struct Cache(HashMap<u64, CacheEntry>);
+/// The cached sectors, with their order of use for LRU eviction.
+struct Cache {
+    entries: HashMap<u64, CacheEntry>,
+    /// Maps the `last_used` stamp of every entry to its sector.
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    /// The maximum number of cached sectors, unbounded if `None`.
+    max_sectors: Option<usize>,
+    /// The number of sectors to read past a miss that continues the
+    /// previous one.
+    read_ahead: u64,
+    /// The last sector read from the source on a miss.
+    last_miss: Option<u64>,
+}
 
 impl Cache {
-    fn cache_entry<T: BlockDevice>(&mut self, sector: u64, device: &T) -> io::Result<&mut CacheEntry> {
-        if !self.0.contains_key(&sector) {
-            let mut cache_entry = CacheEntry {
-                data: Vec::new(),
-                is_dirty: false,
-            };
-            cache_entry.data.resize(device.sector_size() as usize, 0);
-            device.read_sector(sector, &mut cache_entry.data)?;
-            self.0.insert(sector, cache_entry);
-        }
-        Ok(self.0.get_mut(&sector).unwrap())
+    fn new(max_sectors: Option<usize>, read_ahead: u64) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            max_sectors,
+            read_ahead,
+            last_miss: None,
+        }
+    }
+
+    /// Returns the entry for `sector`, marking it as the most recently used.
+    fn get(&mut self, sector: u64) -> Option<&mut CacheEntry> {
+        let clock = self.clock;
+        match self.entries.get_mut(&sector) {
+            Some(entry) => {
+                self.clock += 1;
+                self.lru.remove(&entry.last_used);
+                self.lru.insert(clock, sector);
+                entry.last_used = clock;
+                Some(entry)
+            }
+            None => None,
+        }
+    }
+
+    /// Caches `data` as the contents of `sector`, evicting the least
+    /// recently used entries to stay within the capacity.
+    fn insert<T: BlockDevice>(&mut self, sector: u64, data: Vec<u8>, is_dirty: bool, source: &mut T, counters: &Counters) -> io::Result<&mut CacheEntry> {
+        if let Some(old) = self.entries.remove(&sector) {
+            self.lru.remove(&old.last_used);
+        }
+        if let Some(max_sectors) = self.max_sectors {
+            while self.entries.len() >= max_sectors {
+                self.evict_one(source, counters)?;
+            }
+        }
+        let clock = self.clock;
+        self.clock += 1;
+        self.lru.insert(clock, sector);
+        self.entries.insert(sector, CacheEntry { data, is_dirty, last_used: clock });
+        Ok(self.entries.get_mut(&sector).unwrap())
     }
+
+    /// Drops the least recently used entry, writing it back first if it's
+    /// dirty. The entry stays cached if the write fails.
+    fn evict_one<T: BlockDevice>(&mut self, source: &mut T, counters: &Counters) -> io::Result<()> {
+        let (last_used, sector) = match self.lru.iter().next() {
+            Some((&last_used, &sector)) => (last_used, sector),
+            None => return Ok(()),
+        };
+        self.write_back(sector, source, counters)?;
+        self.lru.remove(&last_used);
+        self.entries.remove(&sector);
+        Counters::count(&counters.evictions, 1);
+        Ok(())
+    }
+
+    /// Writes `sector` to the source if it is cached and dirty.
+    fn write_back<T: BlockDevice>(&mut self, sector: u64, source: &mut T, counters: &Counters) -> io::Result<()> {
+        if let Some(entry) = self.entries.get_mut(&sector) {
+            if entry.is_dirty {
+                source.write_sector(sector, &entry.data)?;
+                entry.is_dirty = false;
+                Counters::count(&counters.write_backs, 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes back the dirty sectors in `sectors` in ascending order.
+    fn write_back_range<T: BlockDevice>(&mut self, sectors: Range<u64>, source: &mut T, counters: &Counters) -> io::Result<()> {
+        let mut dirty: Vec<u64> = self.entries.iter()
+            .filter(|&(sector, entry)| entry.is_dirty && sectors.start <= *sector && *sector < sectors.end)
+            .map(|(&sector, _)| sector)
+            .collect();
+        dirty.sort();
+        for sector in dirty {
+            self.write_back(sector, source, counters)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, sector: u64) {
+        if let Some(entry) = self.entries.remove(&sector) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+
+    fn cache_entry<T: BlockDevice>(&mut self, sector: u64, source: &mut T, counters: &Counters) -> io::Result<&mut CacheEntry> {
+        if !self.entries.contains_key(&sector) {
+            let mut data = vec![0; source.sector_size() as usize];
+            self.fetch(sector, &mut data, source, counters)?;
+            return Ok(self.get(sector).unwrap());
+        }
+        Counters::count(&counters.hits, 1);
+        Ok(self.get(sector).unwrap())
+    }
+
+    /// Reads the uncached sectors starting at `first` into `buf` and caches
+    /// them. If the miss continues the previous one, up to `read_ahead` more
+    /// uncached sectors are read along with them, as far as they fit into the
+    /// cache and the device.
+    fn fetch<T: BlockDevice>(&mut self, first: u64, buf: &mut [u8], source: &mut T, counters: &Counters) -> io::Result<()> {
+        let sector_size = source.sector_size() as usize;
+        let count = (buf.len() / sector_size) as u64;
+        Counters::count(&counters.misses, count as usize);
+
+        let sequential = first > 0 && self.last_miss == Some(first - 1);
+        let mut ahead = 0;
+        if sequential {
+            let room = self.max_sectors.map_or(::std::u64::MAX, |max| (max as u64).saturating_sub(count));
+            let limit = ::std::cmp::min(self.read_ahead, room);
+            let end = source.num_sectors();
+            while ahead < limit && first + count + ahead < end && !self.entries.contains_key(&(first + count + ahead)) {
+                ahead += 1;
+            }
+        }
+
+        if ahead == 0 {
+            if count == 1 {
+                source.read_sector(first, buf)?;
+            } else {
+                source.read_sectors(first, buf)?;
+            }
+            for (i, data) in buf.chunks(sector_size).enumerate() {
+                self.insert(first + i as u64, data.to_vec(), false, source, counters)?;
+            }
+        } else {
+            let mut data = vec![0; ((count + ahead) as usize) * sector_size];
+            source.read_sectors(first, &mut data)?;
+            buf.copy_from_slice(&data[..buf.len()]);
+            for (i, data) in data.chunks(sector_size).enumerate() {
+                self.insert(first + i as u64, data.to_vec(), false, source, counters)?;
+            }
+        }
+        self.last_miss = Some(first + count + ahead - 1);
+        Ok(())
+    }
+}
+
+struct State<T: BlockDevice> {
+    /// Only `None` once `into_inner` has taken it.
+    source: Option<T>,
+    cache: Cache,
 }
 
+impl<T: BlockDevice> State<T> {
+    fn source(&self) -> &T {
+        self.source.as_ref().expect("source taken by into_inner")
+    }
+
+    fn parts(&mut self) -> (&mut T, &mut Cache) {
+        (self.source.as_mut().expect("source taken by into_inner"), &mut self.cache)
+    }
+}
+
+/// A write-back sector cache in front of `source`. Dirty sectors reach the
+/// source when they are evicted or on `sync`.
 pub struct CachedDevice<T: BlockDevice> {
-    source: T,
-    cache: RefCell<Cache>,
+    sector_size: u64,
+    counters: Counters,
+    // The source is used from `&self` methods too, to write back evicted
+    // sectors, so it lives behind the same lock as the cache.
+    state: Mutex<State<T>>,
 }
 
+/// Dirty sectors that can't be written back on drop are lost; use `sync` or
+/// `into_inner` to see the errors.
 impl<T: BlockDevice> Drop for CachedDevice<T> {
     fn drop(&mut self) {
-        self.sync().unwrap();
+        if ignore_poison(self.state.get_mut()).source.is_some() {
+            let _ = self.sync();
+        }
     }
 }
 
 impl<T: BlockDevice> CachedDevice<T> {
+    /// Returns a cache without a size limit.
     pub fn new(source: T) -> Self {
+        CachedDevice::with_cache(source, Cache::new(None, 0))
+    }
+
+    /// Returns a cache holding at most `max_bytes` bytes of sectors, but at
+    /// least one sector. The least recently used sectors are evicted first.
+    pub fn with_capacity(source: T, max_bytes: usize) -> Self {
+        CachedDevice::with_read_ahead(source, max_bytes, 0)
+    }
+
+    /// Like `with_capacity`, but when a read misses right after the sector
+    /// of the previous miss, up to `read_ahead_sectors` following sectors
+    /// are read from the source in the same request.
+    pub fn with_read_ahead(source: T, max_bytes: usize, read_ahead_sectors: u64) -> Self {
+        let max_sectors = ::std::cmp::max(1, max_bytes / source.sector_size() as usize);
+        CachedDevice::with_cache(source, Cache::new(Some(max_sectors), read_ahead_sectors))
+    }
+
+    fn with_cache(source: T, cache: Cache) -> Self {
         CachedDevice {
-            source,
-            cache: RefCell::new(Cache(HashMap::new())),
+            sector_size: source.sector_size(),
+            counters: Counters::default(),
+            state: Mutex::new(State { source: Some(source), cache }),
         }
     }
+
+    /// The number of sectors currently cached.
+    pub fn cached_sectors(&self) -> usize {
+        ignore_poison(self.state.lock()).cache.entries.len()
+    }
+
+    /// Returns the counters and the current contents of the cache.
+    pub fn stats(&self) -> CacheStats {
+        let state = ignore_poison(self.state.lock());
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            write_backs: self.counters.write_backs.load(Ordering::Relaxed),
+            dirty_sectors: state.cache.entries.values().filter(|entry| entry.is_dirty).count(),
+            resident_sectors: state.cache.entries.len(),
+        }
+    }
+
+    /// Writes sector `n` to the source now if it is cached and dirty.
+    pub fn flush_sector(&self, n: u64) -> io::Result<()> {
+        let mut guard = ignore_poison(self.state.lock());
+        let (source, cache) = guard.parts();
+        cache.write_back(n, source, &self.counters)
+    }
+
+    /// Writes the dirty sectors in `sectors` to the source in ascending order,
+    /// leaving the other dirty sectors in the cache. The source isn't synced.
+    pub fn flush_range(&mut self, sectors: Range<u64>) -> io::Result<()> {
+        let (source, cache) = ignore_poison(self.state.get_mut()).parts();
+        cache.write_back_range(sectors, source, &self.counters)
+    }
+
+    /// Drops sector `n` from the cache so that the next access reads it from
+    /// the source, e.g. after the source was written to directly. Unwritten
+    /// changes to the sector are lost.
+    pub fn invalidate(&self, n: u64) {
+        ignore_poison(self.state.lock()).cache.remove(n);
+    }
+
+    /// Copies `buf.len()` bytes at `offset` of sector `n` straight out of its
+    /// cache entry, fetching the sector first if it isn't cached.
+    fn copy_from_sector(&self, n: u64, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        let mut guard = ignore_poison(self.state.lock());
+        let (source, cache) = guard.parts();
+        let cache_entry = cache.cache_entry(n, source, &self.counters)?;
+        buf.copy_from_slice(&cache_entry.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    /// Writes back the dirty sectors and returns the source device.
+    pub fn into_inner(mut self) -> io::Result<T> {
+        self.sync()?;
+        let source = ignore_poison(self.state.get_mut()).source.take();
+        Ok(source.expect("source taken by into_inner"))
+    }
 }
 
 impl<T: BlockDevice> BlockDevice for CachedDevice<T> {
     fn sector_size(&self) -> u64 {
-        self.source.sector_size()
+        self.sector_size
+    }
+
+    fn num_sectors(&self) -> u64 {
+        ignore_poison(self.state.lock()).source().num_sectors()
     }
 
     fn read_sector(&self, n: u64, buf: &mut [u8]) -> Result<(), io::Error> {
-        if buf.len() as u64 != self.sector_size() {
-            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        let size = self.sector_size() as usize;
+        if buf.len() < size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
         }
-        let mut cache = self.cache.borrow_mut();
-        let cache_entry = cache.cache_entry(n, &self.source)?;
-        buf.copy_from_slice(&cache_entry.data);
+        let mut guard = ignore_poison(self.state.lock());
+        let (source, cache) = guard.parts();
+        let cache_entry = cache.cache_entry(n, source, &self.counters)?;
+        buf[..size].copy_from_slice(&cache_entry.data);
         Ok(())
 
     }
 
     fn write_sector(&mut self, n: u64, buf: &[u8]) -> Result<(), io::Error> {
-        if buf.len() as u64 != self.sector_size() {
-            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        let size = self.sector_size() as usize;
+        if buf.len() < size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
         }
-        let mut cache = self.cache.borrow_mut();
-        let cache_entry = cache.cache_entry(n, &self.source)?;
-        cache_entry.data.copy_from_slice(&buf);
+        let mut guard = ignore_poison(self.state.lock());
+        let (source, cache) = guard.parts();
+        // Dirty sectors of a read-only source could never be written back.
+        if source.is_read_only() {
+            return Err(Error::ReadOnly.into());
+        }
+        let cache_entry = cache.cache_entry(n, source, &self.counters)?;
+        cache_entry.data.copy_from_slice(&buf[..size]);
         cache_entry.is_dirty = true;
         Ok(())
     }
 
-    fn sync(&mut self) -> io::Result<()> {
-        for (sector, entry) in &mut self.cache.borrow_mut().0 {
-            if entry.is_dirty {
-                self.source.write_sector(*sector, &entry.data)?;
-                entry.is_dirty = false;
+    /// Serves cached sectors from the cache and reads every run of uncached
+    /// sectors from the source at once, caching them.
+    fn read_sectors(&self, first: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        let sector_size = self.sector_size() as usize;
+        if buf.len() % sector_size != 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let count = buf.len() / sector_size;
+        let mut guard = ignore_poison(self.state.lock());
+        let (source, cache) = guard.parts();
+        let mut i = 0;
+        while i < count {
+            if let Some(entry) = cache.get(first + i as u64) {
+                Counters::count(&self.counters.hits, 1);
+                buf[i * sector_size..(i + 1) * sector_size].copy_from_slice(&entry.data);
+                i += 1;
+                continue;
+            }
+            let run_start = i;
+            while i < count && !cache.entries.contains_key(&(first + i as u64)) {
+                i += 1;
             }
+            let run = &mut buf[run_start * sector_size..i * sector_size];
+            cache.fetch(first + run_start as u64, run, source, &self.counters)?;
         }
-        self.source.sync()?;
         Ok(())
     }
+
+    /// Like the default implementation, but partially covered sectors are
+    /// copied out of the cache without a bounce buffer.
+    fn read_by_offset(&self, offset_bytes: u64, buf: &mut [u8]) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        check_range(self, offset_bytes, buf.len())?;
+        let sector_size = self.sector_size as usize;
+        let mut sector = offset_bytes / sector_size as u64;
+        let head_offset = (offset_bytes % sector_size as u64) as usize;
+        let mut done = 0;
+        if head_offset != 0 || buf.len() < sector_size {
+            done = ::std::cmp::min(buf.len(), sector_size - head_offset);
+            self.copy_from_sector(sector, head_offset, &mut buf[..done])?;
+            sector += 1;
+        }
+        let aligned = (buf.len() - done) / sector_size * sector_size;
+        if aligned > 0 {
+            self.read_sectors(sector, &mut buf[done..done + aligned])?;
+            done += aligned;
+            sector += (aligned / sector_size) as u64;
+        }
+        if done < buf.len() {
+            self.copy_from_sector(sector, 0, &mut buf[done..])?;
+        }
+        Ok(())
+    }
+
+    /// Stores the sectors in the cache without reading them first.
+    fn write_sectors(&mut self, first: u64, buf: &[u8]) -> Result<(), io::Error> {
+        let sector_size = self.sector_size() as usize;
+        if buf.len() % sector_size != 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let mut guard = ignore_poison(self.state.lock());
+        let (source, cache) = guard.parts();
+        if source.is_read_only() {
+            return Err(Error::ReadOnly.into());
+        }
+        for (i, data) in buf.chunks(sector_size).enumerate() {
+            cache.insert(first + i as u64, data.to_vec(), true, source, &self.counters)?;
+        }
+        Ok(())
+    }
+
+    /// Writes back all dirty sectors in ascending order and syncs the source.
+    fn sync(&mut self) -> io::Result<()> {
+        let (source, cache) = ignore_poison(self.state.get_mut()).parts();
+        cache.write_back_range(0..::std::u64::MAX, source, &self.counters)?;
+        source.sync()?;
+        Ok(())
+    }
+
+    /// Writes back the dirty sectors in the range, leaving the others in the
+    /// cache, and passes the range on to the source.
+    fn sync_range(&mut self, first: u64, count: u64) -> io::Result<()> {
+        let (source, cache) = ignore_poison(self.state.get_mut()).parts();
+        cache.write_back_range(first..first.saturating_add(count), source, &self.counters)?;
+        source.sync_range(first, count)
+    }
+
+    /// Writes back the sectors dirtied so far, so that they reach the source
+    /// before any sector written afterwards, and passes the barrier on.
+    fn barrier(&mut self) -> io::Result<()> {
+        let (source, cache) = ignore_poison(self.state.get_mut()).parts();
+        cache.write_back_range(0..::std::u64::MAX, source, &self.counters)?;
+        source.barrier()
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        let (source, cache) = ignore_poison(self.state.get_mut()).parts();
+        for sector in n..n + count {
+            cache.remove(sector);
+        }
+        source.discard(n, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        ignore_poison(self.state.lock()).source().is_read_only()
+    }
 }
@@ -0,0 +1,157 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use vfat;
+
+/// The errors of file system operations.
+///
+/// Methods that keep an `io::Result` signature, e.g. those of the `traits`,
+/// return these wrapped in an `io::Error` of a matching kind; `Error::of`
+/// gets them back.
+#[derive(Debug)]
+pub enum Error {
+    /// The device failed.
+    Io(io::Error),
+    /// There is no entry with the given name.
+    NotFound,
+    /// An entry with the given name already exists.
+    AlreadyExists,
+    /// A directory was expected but the entry is a file.
+    NotADirectory,
+    /// A file was expected but the entry is a directory.
+    IsADirectory,
+    /// Only empty directories can be removed.
+    DirectoryNotEmpty,
+    /// The entry is in use, e.g. a file that is open can't be removed.
+    /// Retrying after it was released may succeed.
+    Busy,
+    /// The entry at `path` is in use, so the tree containing it can't be
+    /// removed. Nothing was removed.
+    BusyEntry { path: String },
+    /// The volume has no free clusters left.
+    NoSpace,
+    /// There is no run of consecutive free clusters large enough.
+    NoContiguousSpace,
+    /// The name can't be stored in a directory entry.
+    NameInvalid { reason: &'static str },
+    /// The component `component` of a path can't be looked up, e.g. `..` or
+    /// a name that isn't valid UTF-8.
+    PathInvalid { component: String, reason: &'static str },
+    /// An argument is out of range or otherwise unusable.
+    InvalidInput { what: &'static str },
+    /// The on-disk structure `what` is inconsistent.
+    Corrupted { what: &'static str },
+    /// The file system or the device is read-only.
+    ReadOnly,
+    /// The file was opened for reading only.
+    NotOpenForWriting,
+    /// The file would grow past the 4 GiB limit of FAT32.
+    FileTooLarge,
+    /// The volume isn't a FAT32 volume. FAT12 and FAT16 volumes can be
+    /// mounted, but not written.
+    UnsupportedFs,
+}
+
+impl Error {
+    /// Returns the `Error` wrapped in `error`, if there is one.
+    pub fn of(error: &io::Error) -> Option<&Error> {
+        error.get_ref().and_then(|e| e.downcast_ref::<Error>())
+    }
+
+    /// The kind of the `io::Error` this error is converted to.
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::Io(ref e) => e.kind(),
+            Error::NotFound => io::ErrorKind::NotFound,
+            Error::AlreadyExists => io::ErrorKind::AlreadyExists,
+            Error::NameInvalid { .. } | Error::PathInvalid { .. } |
+            Error::InvalidInput { .. } => io::ErrorKind::InvalidInput,
+            Error::Corrupted { .. } | Error::UnsupportedFs => io::ErrorKind::InvalidData,
+            Error::Busy | Error::BusyEntry { .. } => io::ErrorKind::WouldBlock,
+            Error::DirectoryNotEmpty | Error::ReadOnly |
+            Error::NotOpenForWriting => io::ErrorKind::PermissionDenied,
+            Error::NotADirectory | Error::IsADirectory | Error::NoSpace |
+            Error::NoContiguousSpace | Error::FileTooLarge => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::NameInvalid { reason } => write!(f, "invalid name: {}", reason),
+            Error::PathInvalid { ref component, reason } => write!(f, "invalid path component {:?}: {}", component, reason),
+            Error::InvalidInput { what } => write!(f, "invalid input: {}", what),
+            Error::Corrupted { what } => write!(f, "file system is corrupted: {}", what),
+            Error::BusyEntry { ref path } => write!(f, "{} is in use", path),
+            _ => write!(f, "{}", error::Error::description(self)),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "I/O error",
+            Error::NotFound => "no such file or directory",
+            Error::AlreadyExists => "file or directory already exists",
+            Error::NotADirectory => "not a directory",
+            Error::IsADirectory => "is a directory",
+            Error::DirectoryNotEmpty => "directory is not empty",
+            Error::Busy | Error::BusyEntry { .. } => "file or directory is in use",
+            Error::NoSpace => "no free clusters",
+            Error::NoContiguousSpace => "no run of free clusters large enough",
+            Error::NameInvalid { .. } => "invalid name",
+            Error::PathInvalid { .. } => "invalid path",
+            Error::InvalidInput { .. } => "invalid input",
+            Error::Corrupted { .. } => "file system is corrupted",
+            Error::ReadOnly => "file system is read-only",
+            Error::NotOpenForWriting => "file is opened for reading only",
+            Error::FileTooLarge => "file is too large for FAT32",
+            Error::UnsupportedFs => "not a FAT32 file system",
+        }
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Unwraps errors that were converted to an `io::Error`.
+    fn from(error: io::Error) -> Error {
+        if Error::of(&error).is_some() {
+            let inner = error.into_inner().unwrap();
+            *inner.downcast::<Error>().unwrap()
+        } else {
+            Error::Io(error)
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        match error {
+            Error::Io(e) => e,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
+
+impl From<vfat::Error> for Error {
+    fn from(error: vfat::Error) -> Error {
+        match error {
+            vfat::Error::Io(e) => Error::from(e),
+            vfat::Error::NotFat32 | vfat::Error::UnsupportedFilesystem(_) => Error::UnsupportedFs,
+            vfat::Error::NotFound => Error::NotFound,
+            vfat::Error::BadSignature => Error::Corrupted { what: "boot sector signature" },
+            vfat::Error::InvalidGeometry { .. } => Error::Corrupted { what: "BIOS parameter block" },
+            vfat::Error::Mbr(_) => Error::Corrupted { what: "master boot record" },
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use traits::BlockDevice;
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.write_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_write(buf, offset)
+}
+
+/// A block device backed by a disk image file. Uses positional I/O, so
+/// reads don't need to seek a shared file cursor.
+///
+/// The length of the file is read when the device is created and kept up to
+/// date by `set_len`; the file mustn't be resized behind the device's back.
+#[derive(Debug)]
+pub struct FileBlockDevice {
+    file: File,
+    sector_size: u64,
+    len: u64,
+}
+
+impl FileBlockDevice {
+    /// Opens the image at `path` for reading and writing with 512-byte
+    /// sectors.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileBlockDevice> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        FileBlockDevice::new(file, 512)
+    }
+
+    /// Returns a device reading and writing `file` in sectors of
+    /// `sector_size` bytes. Fails if the length of `file` can't be read.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector_size` isn't a non-zero multiple of 512.
+    pub fn new(file: File, sector_size: u64) -> io::Result<FileBlockDevice> {
+        assert!(sector_size >= 512 && sector_size % 512 == 0, "sector size must be a multiple of 512");
+        let len = file.metadata()?.len();
+        Ok(FileBlockDevice { file, sector_size, len })
+    }
+
+    /// The length of the image file in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Truncates or extends the image file to `len` bytes. Sectors past the
+    /// old end read as zeros.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    /// Returns the underlying file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Returns the offset of `sector` if the `len` bytes from there are
+    /// within the whole sectors of the file.
+    fn range_offset(&self, sector: u64, len: usize) -> io::Result<u64> {
+        let num_sectors = self.num_sectors();
+        let end = sector.checked_mul(self.sector_size).and_then(|start| start.checked_add(len as u64));
+        match end {
+            Some(end) if sector < num_sectors && end <= num_sectors * self.sector_size => Ok(sector * self.sector_size),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "sector out of range")),
+        }
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            match read_at(&self.file, &mut buf[done..], offset + done as u64) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => done += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            match write_at(&self.file, &buf[done..], offset + done as u64) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => done += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for FileBlockDevice {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// The number of whole sectors in the image file.
+    fn num_sectors(&self) -> u64 {
+        self.len / self.sector_size
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        let len = self.sector_size as usize;
+        if buf.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
+        }
+        let offset = self.range_offset(sector, len)?;
+        self.read_exact_at(&mut buf[..len], offset)
+    }
+
+    fn write_sector(&mut self, sector: u64, buf: &[u8]) -> io::Result<()> {
+        let len = self.sector_size as usize;
+        if buf.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
+        }
+        let offset = self.range_offset(sector, len)?;
+        self.write_all_at(&buf[..len], offset)
+    }
+
+    fn read_sectors(&self, first: u64, buf: &mut [u8]) -> io::Result<()> {
+        let offset = self.range_offset(first, buf.len())?;
+        self.read_exact_at(buf, offset)
+    }
+
+    fn write_sectors(&mut self, first: u64, buf: &[u8]) -> io::Result<()> {
+        if buf.len() as u64 % self.sector_size != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is not a whole number of sectors"));
+        }
+        let offset = self.range_offset(first, buf.len())?;
+        self.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
@@ -0,0 +1,559 @@
+//! Mounting a FAT32 file system on the host through FUSE.
+//!
+//! `FuseFs` adapts an `ArcMutex<VFatFileSystem>` to the callbacks of the
+//! `fuser` crate. Permissions and ownership aren't stored by FAT32; every
+//! entry is reported as owned by the user making the request, with mode 0755
+//! for directories and 0644 (0444 if read-only) for files.
+//!
+//! ```no_run
+//! # extern crate fat32;
+//! # fn main() {
+//! use fat32::vfat::VFatFileSystem;
+//! use fat32::FileBlockDevice;
+//! use fat32::fuse::FuseFs;
+//!
+//! let device = FileBlockDevice::open("sd.img").unwrap();
+//! let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+//! fat32::fuse::mount(FuseFs::new(vfat), "/mnt/sd").unwrap();
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{self, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData};
+use fuser::{ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow};
+use libc::{self, c_int};
+
+use arc_mutex::ArcMutex;
+use error::Error;
+use traits::{Dir, Entry, EntriesExt, File, FileOpenMode, FileSystem, Metadata, DateTime};
+use vfat::{LockKey, VFatEntry, VFatFile, VFatFileSystem, fat_epoch};
+
+/// How long the kernel may cache attributes and lookups.
+const TTL: Duration = Duration::from_secs(1);
+
+/// A FAT32 file system that can be mounted with `mount` or `spawn_mount`.
+///
+/// Inode numbers are assigned to entries as the kernel looks them up, keyed
+/// by the slot of their regular directory entry. The first cluster can't be
+/// used as the key: it is 0 for every empty file and changes once a file gets
+/// its first cluster. Every spelling of a name that finds an entry, such as
+/// its short name in any case, thus gets the same inode. An entry keeps its
+/// inode until the kernel forgets every lookup of it.
+pub struct FuseFs {
+    vfat: ArcMutex<VFatFileSystem>,
+    paths: HashMap<u64, PathBuf>,
+    inodes: HashMap<LockKey, u64>,
+    lookups: HashMap<u64, u64>,
+    next_inode: u64,
+    files: HashMap<u64, (u64, VFatFile)>,
+    next_handle: u64,
+}
+
+/// Mounts `fs` at `mountpoint` and serves requests until it is unmounted.
+pub fn mount<P: AsRef<Path>>(fs: FuseFs, mountpoint: P) -> io::Result<()> {
+    fuser::mount2(fs, mountpoint, &mount_options())
+}
+
+/// Mounts `fs` at `mountpoint` and serves requests on a background thread.
+/// The file system is unmounted when the returned session is dropped.
+pub fn spawn_mount<P: AsRef<Path>>(fs: FuseFs, mountpoint: P) -> io::Result<fuser::BackgroundSession> {
+    fuser::spawn_mount2(fs, mountpoint, &mount_options())
+}
+
+fn mount_options() -> Vec<MountOption> {
+    vec![MountOption::FSName("fat32".to_string()), MountOption::DefaultPermissions]
+}
+
+/// Translates an error returned by the file system to an errno value.
+pub fn errno(error: io::Error) -> c_int {
+    match Error::from(error) {
+        Error::Io(e) => e.raw_os_error().unwrap_or_else(|| match e.kind() {
+            io::ErrorKind::NotFound => libc::ENOENT,
+            io::ErrorKind::AlreadyExists => libc::EEXIST,
+            io::ErrorKind::PermissionDenied => libc::EACCES,
+            io::ErrorKind::InvalidInput => libc::EINVAL,
+            _ => libc::EIO,
+        }),
+        Error::NotFound => libc::ENOENT,
+        Error::AlreadyExists => libc::EEXIST,
+        Error::NotADirectory => libc::ENOTDIR,
+        Error::IsADirectory => libc::EISDIR,
+        Error::DirectoryNotEmpty => libc::ENOTEMPTY,
+        Error::Busy | Error::BusyEntry { .. } => libc::EBUSY,
+        Error::NoSpace | Error::NoContiguousSpace => libc::ENOSPC,
+        Error::NameInvalid { .. } | Error::PathInvalid { .. } |
+        Error::InvalidInput { .. } => libc::EINVAL,
+        Error::Corrupted { .. } | Error::UnsupportedFs => libc::EIO,
+        Error::ReadOnly => libc::EROFS,
+        Error::NotOpenForWriting => libc::EBADF,
+        Error::FileTooLarge => libc::EFBIG,
+    }
+}
+
+fn system_time(time: DateTime) -> SystemTime {
+    let seconds = time.timestamp();
+    if seconds > 0 {
+        UNIX_EPOCH + Duration::from_secs(seconds as u64)
+    } else {
+        UNIX_EPOCH
+    }
+}
+
+impl FuseFs {
+    pub fn new(vfat: ArcMutex<VFatFileSystem>) -> FuseFs {
+        let mut fs = FuseFs {
+            vfat,
+            paths: HashMap::new(),
+            inodes: HashMap::new(),
+            lookups: HashMap::new(),
+            next_inode: fuser::FUSE_ROOT_ID + 1,
+            files: HashMap::new(),
+            next_handle: 1,
+        };
+        fs.paths.insert(fuser::FUSE_ROOT_ID, PathBuf::from("/"));
+        fs
+    }
+
+    pub(crate) fn path(&self, inode: u64) -> io::Result<PathBuf> {
+        self.paths.get(&inode).cloned().ok_or_else(|| Error::NotFound.into())
+    }
+
+    fn child_path(&self, parent: u64, name: &OsStr) -> io::Result<PathBuf> {
+        Ok(self.path(parent)?.join(name))
+    }
+
+    /// Returns the inode of `entry`, found at `path`, assigning one if it has
+    /// none. Only for entries that were just looked up, see `resolve`.
+    fn inode(&mut self, path: &Path, entry: &VFatEntry) -> u64 {
+        let key = entry.slot_key();
+        if let Some(&inode) = self.inodes.get(&key) {
+            return inode;
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.paths.insert(inode, path.to_path_buf());
+        self.inodes.insert(key, inode);
+        inode
+    }
+
+    /// Changes the paths of `from` and everything below it to `to`.
+    fn rename_paths(&mut self, from: &Path, to: &Path) {
+        for path in self.paths.values_mut() {
+            if path.starts_with(from) {
+                *path = to.join(path.strip_prefix(from).unwrap());
+            }
+        }
+    }
+
+    /// Drops the inode of the entry in slot `key`, which was removed.
+    fn forget_key(&mut self, key: LockKey) {
+        if let Some(inode) = self.inodes.remove(&key) {
+            self.paths.remove(&inode);
+            self.lookups.remove(&inode);
+        }
+    }
+
+    /// Looks up the entry of `path` and only then returns its inode, so that
+    /// paths that don't exist never get one. The entry is `None` for the
+    /// root directory.
+    pub(crate) fn resolve(&mut self, path: &Path) -> io::Result<(u64, Option<VFatEntry>)> {
+        if path == Path::new("/") {
+            return Ok((fuser::FUSE_ROOT_ID, None));
+        }
+        let entry = self.vfat.get_entry(path)?;
+        Ok((self.inode(path, &entry), Some(entry)))
+    }
+
+    /// Records that the kernel was handed `inode` once more, as it does for
+    /// every entry returned by `lookup`, `mkdir` and `create`.
+    pub(crate) fn count_lookup(&mut self, inode: u64) {
+        *self.lookups.entry(inode).or_insert(0) += 1;
+    }
+
+    /// Drops `count` lookups of `inode` and, once none are left, its path.
+    /// The root directory is never forgotten.
+    pub(crate) fn forget_inode(&mut self, inode: u64, count: u64) {
+        if inode == fuser::FUSE_ROOT_ID {
+            return;
+        }
+        let remaining = match self.lookups.get_mut(&inode) {
+            Some(lookups) => {
+                *lookups = lookups.saturating_sub(count);
+                *lookups
+            }
+            None => return,
+        };
+        if remaining == 0 {
+            self.lookups.remove(&inode);
+            self.paths.remove(&inode);
+            self.inodes.retain(|_, other| *other != inode);
+        }
+    }
+
+    fn root_attr(&self, req: &Request) -> FileAttr {
+        let epoch = system_time(fat_epoch());
+        FileAttr {
+            ino: fuser::FUSE_ROOT_ID,
+            size: 0,
+            blocks: 0,
+            atime: epoch,
+            mtime: epoch,
+            ctime: epoch,
+            crtime: epoch,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: self.vfat.lock().cluster_size_bytes(),
+            flags: 0,
+        }
+    }
+
+    fn entry_attr(&self, req: &Request, inode: u64, entry: &VFatEntry) -> FileAttr {
+        let metadata = entry.metadata();
+        // An open file may have grown past the size in its directory entry.
+        let size = self.files.values()
+            .filter(|&&(file_inode, _)| file_inode == inode)
+            .map(|&(_, ref file)| file.size())
+            .max()
+            .unwrap_or(metadata.size as u64);
+        let (kind, perm) = if entry.is_dir() {
+            (FileType::Directory, 0o755)
+        } else if metadata.is_read_only() {
+            (FileType::RegularFile, 0o444)
+        } else {
+            (FileType::RegularFile, 0o644)
+        };
+        FileAttr {
+            ino: inode,
+            size,
+            blocks: (size + 511) / 512,
+            // FAT only records the access date.
+            atime: system_time(metadata.accessed().and_hms(0, 0, 0)),
+            mtime: system_time(metadata.modified()),
+            ctime: system_time(metadata.modified()),
+            crtime: system_time(metadata.created()),
+            kind,
+            perm,
+            nlink: if entry.is_dir() { 2 } else { 1 },
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: self.vfat.lock().cluster_size_bytes(),
+            flags: 0,
+        }
+    }
+
+    fn attr(&mut self, req: &Request, path: &Path) -> io::Result<FileAttr> {
+        match self.resolve(path)? {
+            (inode, Some(entry)) => Ok(self.entry_attr(req, inode, &entry)),
+            (_, None) => Ok(self.root_attr(req)),
+        }
+    }
+
+    /// Like `attr`, for replies that hand the entry to the kernel.
+    fn lookup_attr(&mut self, req: &Request, path: &Path) -> io::Result<FileAttr> {
+        let attr = self.attr(req, path)?;
+        self.count_lookup(attr.ino);
+        Ok(attr)
+    }
+
+    fn open_handle(&mut self, inode: u64, file: VFatFile) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(handle, (inode, file));
+        handle
+    }
+
+    fn file(&mut self, handle: u64) -> io::Result<&mut VFatFile> {
+        match self.files.get_mut(&handle) {
+            Some(&mut (_, ref mut file)) => Ok(file),
+            None => Err(io::Error::from_raw_os_error(libc::EBADF)),
+        }
+    }
+
+    fn set_size(&mut self, inode: u64, handle: Option<u64>, size: u64) -> io::Result<()> {
+        if let Some(handle) = handle {
+            return self.file(handle)?.set_len(size);
+        }
+        let path = self.path(inode)?;
+        let mut file = self.vfat.open_file(path, FileOpenMode::Write)?;
+        file.set_len(size)?;
+        file.flush()
+    }
+
+    fn read_at(&mut self, handle: u64, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let file = self.file(handle)?;
+        let offset = offset.min(file.size());
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; size];
+        let mut read = 0;
+        while read < size {
+            match file.read(&mut data[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        data.truncate(read);
+        Ok(data)
+    }
+
+    fn write_at(&mut self, handle: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let file = self.file(handle)?;
+        if offset > file.size() {
+            file.set_len(offset)?;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn read_dir(&mut self, inode: u64, offset: i64, reply: &mut ReplyDirectory) -> io::Result<()> {
+        let path = self.path(inode)?;
+        let parent = match path.parent() {
+            Some(parent) => self.resolve(parent)?.0,
+            None => inode,
+        };
+        let mut listing = vec![
+            (inode, FileType::Directory, ".".to_string()),
+            (parent, FileType::Directory, "..".to_string()),
+        ];
+        for entry in self.vfat.open_dir(&path)?.entries()?.std_iter() {
+            let entry = entry?;
+            let kind = if entry.is_dir() { FileType::Directory } else { FileType::RegularFile };
+            let child = self.inode(&path.join(entry.name()), &entry);
+            listing.push((child, kind, entry.name().to_string()));
+        }
+        for (i, (inode, kind, name)) in listing.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(inode, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, parent: u64, name: &OsStr, dir: bool) -> io::Result<()> {
+        let path = self.child_path(parent, name)?;
+        let entry = self.vfat.get_entry(&path)?;
+        if dir && !entry.is_dir() {
+            return Err(Error::NotADirectory.into());
+        } else if !dir && entry.is_dir() {
+            return Err(Error::IsADirectory.into());
+        }
+        let key = entry.slot_key();
+        self.vfat.remove_entry(entry)?;
+        self.forget_key(key);
+        Ok(())
+    }
+
+    pub(crate) fn rename_entry(&mut self, parent: u64, name: &OsStr, new_parent: u64,
+                               new_name: &OsStr) -> io::Result<()> {
+        let from = self.child_path(parent, name)?;
+        let to = self.child_path(new_parent, new_name)?;
+        let (key, is_dir) = {
+            let entry = self.vfat.get_entry(&from)?;
+            (entry.slot_key(), entry.is_dir())
+        };
+        // rename(2) replaces an existing file.
+        match self.vfat.get_entry(&to) {
+            // `to` is another spelling of `from`.
+            Ok(ref target) if target.slot_key() == key => return Ok(()),
+            Ok(target) => {
+                if target.is_dir() || is_dir {
+                    return Err(Error::AlreadyExists.into());
+                }
+                let target_key = target.slot_key();
+                self.vfat.remove_entry(target)?;
+                self.forget_key(target_key);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.vfat.rename(&from, &to)?;
+        // The entry moved to another slot; the entries below a directory
+        // stay where they are.
+        let inode = self.inodes.remove(&key);
+        let old_path = inode.and_then(|inode| self.paths.get(&inode).cloned()).unwrap_or(from);
+        if let Some(inode) = inode {
+            let new_key = self.vfat.get_entry(&to)?.slot_key();
+            self.inodes.insert(new_key, inode);
+        }
+        self.rename_paths(&old_path, &to);
+        Ok(())
+    }
+}
+
+impl Filesystem for FuseFs {
+    fn destroy(&mut self) {
+        self.files.clear();
+        let _ = self.vfat.sync();
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.child_path(parent, name).and_then(|path| self.lookup_attr(req, &path)) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.forget_inode(ino, nlookup);
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.path(ino).and_then(|path| self.attr(req, &path)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn setattr(&mut self, req: &Request, ino: u64, _mode: Option<u32>, _uid: Option<u32>,
+               _gid: Option<u32>, size: Option<u64>, _atime: Option<TimeOrNow>,
+               _mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>, fh: Option<u64>,
+               _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>,
+               _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
+        let result = match size {
+            Some(size) => self.set_size(ino, fh, size),
+            None => Ok(()),
+        };
+        match result.and_then(|_| self.path(ino)).and_then(|path| self.attr(req, &path)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, _mode: u32, _umask: u32,
+             reply: ReplyEntry) {
+        let result = self.child_path(parent, name).and_then(|path| {
+            drop(self.vfat.create_dir(&path)?);
+            self.lookup_attr(req, &path)
+        });
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove(parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove(parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn rename(&mut self, _req: &Request, parent: u64, name: &OsStr, newparent: u64,
+              newname: &OsStr, _flags: u32, reply: ReplyEmpty) {
+        match self.rename_entry(parent, name, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        let mode = if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            FileOpenMode::Read
+        } else {
+            FileOpenMode::Write
+        };
+        let result = self.path(ino).and_then(|path| {
+            let mut file = self.vfat.open_file(path, mode)?;
+            if flags & libc::O_TRUNC != 0 && mode == FileOpenMode::Write {
+                file.set_len(0)?;
+            }
+            Ok(self.open_handle(ino, file))
+        });
+        match result {
+            Ok(handle) => reply.opened(handle, 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, _mode: u32, _umask: u32,
+              _flags: i32, reply: ReplyCreate) {
+        let result = self.child_path(parent, name).and_then(|path| {
+            let file = self.vfat.create_file(&path)?;
+            let attr = self.lookup_attr(req, &path)?;
+            let handle = self.open_handle(attr.ino, file);
+            Ok((attr, handle))
+        });
+        match result {
+            Ok((attr, handle)) => reply.created(&TTL, &attr, 0, handle, 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn read(&mut self, _req: &Request, _ino: u64, fh: u64, offset: i64, size: u32, _flags: i32,
+            _lock_owner: Option<u64>, reply: ReplyData) {
+        match self.read_at(fh, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn write(&mut self, _req: &Request, _ino: u64, fh: u64, offset: i64, data: &[u8],
+             _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
+        match self.write_at(fh, offset as u64, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        match self.file(fh).and_then(|file| file.flush()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, _ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.file(fh).and_then(|file| file.flush()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn release(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32,
+               _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        match self.files.remove(&fh) {
+            Some((_, mut file)) => match file.flush() {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(errno(e)),
+            },
+            None => reply.error(libc::EBADF),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64,
+               mut reply: ReplyDirectory) {
+        match self.read_dir(ino, offset, &mut reply) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let cluster_size = self.vfat.lock().cluster_size_bytes();
+        let bitmap = self.vfat.lock().usage_bitmap();
+        match bitmap {
+            Ok(bitmap) => {
+                let total = bitmap.cluster_count() as u64;
+                let free = total - bitmap.allocated_count() as u64;
+                reply.statfs(total, free, free, 0, 0, cluster_size, 255, cluster_size)
+            }
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+}
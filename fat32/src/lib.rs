@@ -1,6 +1,5 @@
 #![feature(decl_macro, conservative_impl_trait)]
 #![feature(range_contains)]
-#![allow(safe_packed_borrows)]
 #![feature(use_nested_groups)]
 #![feature(dotdoteq_in_patterns)]
 #![feature(inclusive_range_syntax)]
@@ -8,22 +7,37 @@
 
 #![deny(unused_must_use)]
 
-#[cfg(not(target_endian="little"))]
-compile_error!("only little endian platforms supported");
-
 #[cfg(test)]
 mod tests;
+pub mod error;
 pub mod mbr;
 mod partition;
 pub mod cache;
+pub mod memory;
+pub mod file_device;
+pub mod read_only;
+pub mod scan;
 
 pub mod vfat;
 pub mod traits;
+#[cfg(feature = "fuse")]
+pub mod fuse;
 
 pub use mbr::*;
+pub use error::Error;
+pub use partition::Partition;
+pub use memory::MemoryBlockDevice;
+pub use file_device::FileBlockDevice;
+pub use read_only::ReadOnlyDevice;
+pub use scan::{scan_device, MountablePartition, SkippedPartition, Scan};
 
+#[cfg(feature = "chrono")]
 pub extern crate chrono;
 pub extern crate fallible_iterator;
 extern crate byteorder;
+#[cfg(feature = "fuse")]
+extern crate fuser;
+#[cfg(feature = "fuse")]
+extern crate libc;
 
 pub mod arc_mutex;
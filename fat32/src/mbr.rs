@@ -1,18 +1,62 @@
-use std::{fmt, io};
+use std::{error, fmt, io};
+use std::collections::HashSet;
 
+use byteorder::{LittleEndian, ByteOrder};
+
+use arc_mutex::ArcMutex;
 use traits::BlockDevice;
 use partition::Partition;
 
-#[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 pub struct CHS {
-    c: u8,
     h: u8,
+    /// The sector in bits 0-5 and bits 8-9 of the cylinder in bits 6-7.
     s: u8,
+    /// Bits 0-7 of the cylinder.
+    c: u8,
+}
+
+impl CHS {
+    /// The conventional address of partitions beyond the reach of CHS
+    /// addressing.
+    pub const MAX: CHS = CHS { h: 0xFE, s: 0xFF, c: 0xFF };
+
+    /// The size of the on-disk representation in bytes.
+    pub const SIZE: usize = 3;
+
+    /// Parses the address from the first `SIZE` bytes of `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than `SIZE`.
+    pub fn from_bytes(buf: &[u8]) -> CHS {
+        CHS { h: buf[0], s: buf[1], c: buf[2] }
+    }
+
+    /// Returns the on-disk representation of the address.
+    pub fn to_bytes(&self) -> [u8; CHS::SIZE] {
+        [self.h, self.s, self.c]
+    }
+
+    /// Returns the CHS address of sector `lba` on a disk with the usual
+    /// translated geometry of 255 heads and 63 sectors per track, or
+    /// `CHS::MAX` if the sector is beyond cylinder 1023.
+    pub fn from_lba(lba: u32) -> CHS {
+        let sector = lba % 63 + 1;
+        let head = lba / 63 % 255;
+        let cylinder = lba / 63 / 255;
+        if cylinder > 1023 {
+            return CHS::MAX;
+        }
+        CHS {
+            h: head as u8,
+            s: sector as u8 | ((cylinder >> 8) as u8) << 6,
+            c: cylinder as u8,
+        }
+    }
 }
 
-#[repr(C, packed)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct PartitionEntry {
     pub boot_indicator: u8,
     pub start_chs: CHS,
@@ -22,10 +66,83 @@ pub struct PartitionEntry {
     pub size: u32,
 }
 
+impl PartitionEntry {
+    /// The size of the on-disk representation in bytes.
+    pub const SIZE: usize = 16;
+
+    /// Parses the entry from the first `SIZE` bytes of `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than `SIZE`.
+    pub fn from_bytes(buf: &[u8]) -> PartitionEntry {
+        PartitionEntry {
+            boot_indicator: buf[0],
+            start_chs: CHS::from_bytes(&buf[1..4]),
+            entry_type: buf[4],
+            end_chs: CHS::from_bytes(&buf[5..8]),
+            start_lba: LittleEndian::read_u32(&buf[8..12]),
+            size: LittleEndian::read_u32(&buf[12..16]),
+        }
+    }
+
+    /// Returns the on-disk representation of the entry.
+    pub fn to_bytes(&self) -> [u8; PartitionEntry::SIZE] {
+        let mut buf = [0; PartitionEntry::SIZE];
+        buf[0] = self.boot_indicator;
+        buf[1..4].copy_from_slice(&self.start_chs.to_bytes());
+        buf[4] = self.entry_type;
+        buf[5..8].copy_from_slice(&self.end_chs.to_bytes());
+        LittleEndian::write_u32(&mut buf[8..12], self.start_lba);
+        LittleEndian::write_u32(&mut buf[12..16], self.size);
+        buf
+    }
+}
+
+/// A used entry of the partition table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// The 0-based index of the entry in the partition table.
+    pub index: usize,
+    /// The partition type byte.
+    pub kind: u8,
+    pub start_lba: u32,
+    pub sectors: u32,
+    pub bootable: bool,
+}
+
+impl PartitionInfo {
+    /// Returns `true` if the partition type is one of the FAT32 types.
+    pub fn is_fat32(&self) -> bool {
+        self.kind == 0x0B || self.kind == 0x0C
+    }
+
+    /// Returns `true` if the partition is an extended partition holding a
+    /// chain of extended boot records.
+    pub fn is_extended(&self) -> bool {
+        self.kind == 0x05 || self.kind == 0x0F || self.kind == 0x85
+    }
+
+    /// The first sector past the end of the partition.
+    pub fn end_lba(&self) -> u64 {
+        self.start_lba as u64 + self.sectors as u64
+    }
+
+    /// Returns `true` if the two partitions share a sector.
+    pub fn overlaps(&self, other: &PartitionInfo) -> bool {
+        (self.start_lba as u64) < other.end_lba() && (other.start_lba as u64) < self.end_lba()
+    }
+}
+
+/// Logical partitions beyond this many are ignored, which also stops walks
+/// of looping EBR chains.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
 /// The master boot record (MBR).
-#[repr(C, packed)]
 pub struct MasterBootRecord {
-    _data: [u8; 446],
+    bootstrap: [u8; 440],
+    disk_signature: u32,
+    copy_protect: u16,
     pub entries: [PartitionEntry; 4],
     signature: u16,
 }
@@ -38,9 +155,126 @@ pub enum Error {
     UnknownBootIndicator(u8),
     /// The MBR magic signature was invalid.
     BadSignature,
+    /// Partitions `.0` and `.1` (0-indexed) overlap.
+    OverlappingPartitions(u8, u8),
+    /// Partition `.0` (0-indexed) extends past the end of the device.
+    PartitionOutOfRange(u8),
+}
+
+impl Error {
+    /// The I/O error, if this is an `Io` error.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// The 0-based index of the partition the error is about, if any. For
+    /// overlapping partitions this is the first of the two.
+    pub fn partition(&self) -> Option<u8> {
+        match *self {
+            Error::UnknownBootIndicator(index) |
+            Error::OverlappingPartitions(index, _) |
+            Error::PartitionOutOfRange(index) => Some(index),
+            Error::Io(_) | Error::BadSignature => None,
+        }
+    }
+
+    /// Returns `true` if the boot record lacks the `0xAA55` signature.
+    pub fn is_bad_signature(&self) -> bool {
+        match *self {
+            Error::BadSignature => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error while accessing the MBR: {}", e),
+            Error::UnknownBootIndicator(index) => write!(f, "partition {} has an unknown boot indicator", index),
+            Error::BadSignature => write!(f, "the MBR signature is invalid"),
+            Error::OverlappingPartitions(a, b) => write!(f, "partitions {} and {} overlap", a, b),
+            Error::PartitionOutOfRange(index) => write!(f, "partition {} extends past the end of the device", index),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "I/O error while accessing the MBR",
+            Error::UnknownBootIndicator(_) => "unknown boot indicator",
+            Error::BadSignature => "invalid MBR signature",
+            Error::OverlappingPartitions(..) => "overlapping partitions",
+            Error::PartitionOutOfRange(_) => "partition extends past the end of the device",
+        }
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
 }
 
 impl MasterBootRecord {
+    /// The size of the on-disk representation in bytes.
+    pub const SIZE: usize = 512;
+
+    /// Returns an MBR with an empty partition table and no bootstrap code.
+    pub fn new() -> MasterBootRecord {
+        let mut buf = [0u8; MasterBootRecord::SIZE];
+        buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+        MasterBootRecord::from_bytes(&buf)
+    }
+
+    /// Parses a boot record from the first `SIZE` bytes of `buf` without
+    /// checking the signature or the partition table.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than `SIZE`.
+    pub fn from_bytes(buf: &[u8]) -> MasterBootRecord {
+        let mut bootstrap = [0; 440];
+        bootstrap.copy_from_slice(&buf[..440]);
+        let mut entries = [PartitionEntry::from_bytes(&[0; PartitionEntry::SIZE]); 4];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            let offset = 446 + i * PartitionEntry::SIZE;
+            *entry = PartitionEntry::from_bytes(&buf[offset..offset + PartitionEntry::SIZE]);
+        }
+        MasterBootRecord {
+            bootstrap,
+            disk_signature: LittleEndian::read_u32(&buf[440..444]),
+            copy_protect: LittleEndian::read_u16(&buf[444..446]),
+            entries,
+            signature: LittleEndian::read_u16(&buf[510..512]),
+        }
+    }
+
+    /// Returns the on-disk representation of the boot record.
+    pub fn to_bytes(&self) -> [u8; MasterBootRecord::SIZE] {
+        let mut buf = [0; MasterBootRecord::SIZE];
+        buf[..440].copy_from_slice(&self.bootstrap);
+        LittleEndian::write_u32(&mut buf[440..444], self.disk_signature);
+        LittleEndian::write_u16(&mut buf[444..446], self.copy_protect);
+        for (i, entry) in self.entries.iter().enumerate() {
+            let offset = 446 + i * PartitionEntry::SIZE;
+            buf[offset..offset + PartitionEntry::SIZE].copy_from_slice(&entry.to_bytes());
+        }
+        LittleEndian::write_u16(&mut buf[510..512], self.signature);
+        buf
+    }
+
     /// Reads and returns the master boot record (MBR) from `device`.
     ///
     /// # Errors
@@ -50,9 +284,15 @@ impl MasterBootRecord {
     /// boot indicator. Returns `Io(err)` if the I/O error `err` occured while
     /// reading the MBR.
     pub fn read_from<T: BlockDevice>(device: &T) -> Result<MasterBootRecord, Error> {
-        let mut buf = [0; 512];
-        device.read_sector(0, &mut buf).map_err(|e| Error::Io(e))?;
-        let mbr: MasterBootRecord = unsafe { ::std::mem::transmute(buf) };
+        MasterBootRecord::read_at(device, 0)
+    }
+
+    /// Reads a boot record, the MBR or an extended boot record, from sector
+    /// `sector` of `device`.
+    fn read_at<T: BlockDevice>(device: &T, sector: u64) -> Result<MasterBootRecord, Error> {
+        let mut buf = [0; MasterBootRecord::SIZE];
+        device.read_by_offset(sector * device.sector_size(), &mut buf).map_err(|e| Error::Io(e))?;
+        let mbr = MasterBootRecord::from_bytes(&buf);
         if mbr.signature != 0xAA55 {
             return Err(Error::BadSignature)
         }
@@ -63,27 +303,260 @@ impl MasterBootRecord {
         }
         Ok(mbr)
     }
+
+    /// Returns an iterator over the used entries of the partition table.
+    pub fn partitions<'a>(&'a self) -> impl Iterator<Item = PartitionInfo> + 'a {
+        self.entries.iter().enumerate()
+            .filter(|&(_, entry)| entry.entry_type != 0)
+            .map(|(index, entry)| PartitionInfo {
+                index,
+                kind: entry.entry_type,
+                start_lba: entry.start_lba,
+                sectors: entry.size,
+                bootable: entry.boot_indicator == 0x80,
+            })
+    }
+
+    /// The boot code in the first 440 bytes of the MBR.
+    pub fn bootstrap_code(&self) -> &[u8] {
+        &self.bootstrap[..]
+    }
+
+    /// Returns `true` if the MBR contains boot code, i.e. the boot code area
+    /// isn't all zeros.
+    pub fn has_bootstrap_code(&self) -> bool {
+        self.bootstrap.iter().any(|&byte| byte != 0)
+    }
+
+    /// The 32-bit disk signature that identifies the disk.
+    pub fn disk_signature(&self) -> u32 {
+        self.disk_signature
+    }
+
+    pub fn set_disk_signature(&mut self, disk_signature: u32) {
+        self.disk_signature = disk_signature;
+    }
+
+    /// Returns `true` if the disk is marked as copy-protected.
+    pub fn is_copy_protected(&self) -> bool {
+        self.copy_protect == 0x5A5A
+    }
+
+    /// Sets partition table entry `index` to a partition of `sectors` sectors
+    /// starting at `start_lba` with the partition type `kind`. The CHS fields
+    /// are derived from the LBA fields.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OverlappingPartitions` if the partition would overlap another
+    /// entry; the table is left unchanged.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not below 4.
+    pub fn set_partition(&mut self, index: usize, kind: u8, start_lba: u32, sectors: u32, bootable: bool) -> Result<(), Error> {
+        assert!(index < 4, "partition index out of range");
+        let new = PartitionInfo { index, kind, start_lba, sectors, bootable };
+        if let Some(other) = self.partitions().find(|other| other.index != index && other.overlaps(&new)) {
+            return Err(Error::OverlappingPartitions(other.index as u8, index as u8));
+        }
+        let last_lba = if sectors == 0 { start_lba } else { (new.end_lba() - 1) as u32 };
+        self.entries[index] = PartitionEntry {
+            boot_indicator: if bootable { 0x80 } else { 0x00 },
+            start_chs: CHS::from_lba(start_lba),
+            entry_type: kind,
+            end_chs: CHS::from_lba(last_lba),
+            start_lba,
+            size: sectors,
+        };
+        Ok(())
+    }
+
+    /// Clears partition table entry `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not below 4.
+    pub fn clear_partition(&mut self, index: usize) {
+        assert!(index < 4, "partition index out of range");
+        self.entries[index] = PartitionEntry::from_bytes(&[0; PartitionEntry::SIZE]);
+    }
+
+    /// Writes the MBR to the first sector of `device`. The bootstrap code of
+    /// an MBR read with `read_from` is written back unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OverlappingPartitions` if two entries overlap and
+    /// `PartitionOutOfRange(n)` if partition `n` extends past the end of
+    /// `device`; nothing is written in either case. Returns `Io(err)` if the
+    /// I/O error `err` occured while writing the MBR.
+    pub fn write_to<T: BlockDevice>(&self, device: &mut T) -> Result<(), Error> {
+        let partitions: Vec<PartitionInfo> = self.partitions().collect();
+        for (i, partition) in partitions.iter().enumerate() {
+            if partition.end_lba() > device.num_sectors() {
+                return Err(Error::PartitionOutOfRange(partition.index as u8));
+            }
+            if let Some(other) = partitions[i + 1..].iter().find(|other| partition.overlaps(other)) {
+                return Err(Error::OverlappingPartitions(partition.index as u8, other.index as u8));
+            }
+        }
+        let mut buf = self.to_bytes();
+        buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+        device.write_by_offset(0, &buf).map_err(|e| Error::Io(e))
+    }
+
+    /// Returns the first partition with a FAT32 partition type.
+    pub fn find_fat32(&self) -> Option<PartitionInfo> {
+        self.partitions().find(|partition| partition.is_fat32())
+    }
+
+    /// Returns the primary partitions followed by the logical partitions in
+    /// the EBR chain of the extended partition, if there is one. Logical
+    /// partitions are numbered from 4 in chain order and their `start_lba`
+    /// is absolute.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `read_from` for every EBR in the chain, and
+    /// `Io` with an error of `InvalidData` if the chain leaves the extended
+    /// partition.
+    pub fn all_partitions<T: BlockDevice>(&self, device: &T) -> Result<Vec<PartitionInfo>, Error> {
+        let mut partitions: Vec<PartitionInfo> = self.partitions().collect();
+        let extended = match partitions.iter().find(|partition| partition.is_extended()) {
+            Some(extended) => *extended,
+            None => return Ok(partitions),
+        };
+        let extended_start = extended.start_lba as u64;
+        let extended_end = extended_start + extended.sectors as u64;
+        let invalid_chain = |what| Error::Io(io::Error::new(io::ErrorKind::InvalidData, what));
+
+        let mut visited = HashSet::new();
+        let mut ebr_sector = extended_start;
+        for index in 4..4 + MAX_LOGICAL_PARTITIONS {
+            if !visited.insert(ebr_sector) {
+                return Err(invalid_chain("the EBR chain loops"));
+            }
+            let ebr = MasterBootRecord::read_at(device, ebr_sector)?;
+            let logical = &ebr.entries[0];
+            if logical.entry_type != 0 {
+                let start_lba = ebr_sector + logical.start_lba as u64;
+                if start_lba + logical.size as u64 > extended_end {
+                    return Err(invalid_chain("EBR outside of the extended partition"));
+                }
+                if start_lba > ::std::u32::MAX as u64 {
+                    return Err(invalid_chain("logical partition starts beyond the reach of an LBA"));
+                }
+                partitions.push(PartitionInfo {
+                    index,
+                    kind: logical.entry_type,
+                    start_lba: start_lba as u32,
+                    sectors: logical.size,
+                    bootable: logical.boot_indicator == 0x80,
+                });
+            }
+            let next = &ebr.entries[1];
+            if next.entry_type == 0 || next.start_lba == 0 {
+                break;
+            }
+            ebr_sector = extended_start + next.start_lba as u64;
+            if ebr_sector >= extended_end {
+                return Err(invalid_chain("EBR outside of the extended partition"));
+            }
+        }
+        Ok(partitions)
+    }
+}
+
+/// Returns partition `partition_number` of `device`: 0 to 3 for the primary
+/// partitions, 4 and up for the logical partitions in the extended
+/// partition. To use several partitions of one device, see `Disk`.
+pub fn get_partition<T: BlockDevice>(device: T, partition_number: usize) -> io::Result<Partition<T>> {
+    let partitions = read_partitions(&device)?;
+    let info = partitions.iter().find(|partition| partition.index == partition_number)
+        .ok_or(::error::Error::NotFound)?;
+    Ok(partition_from_info(device, info))
 }
 
-pub fn get_partition<T: BlockDevice>(mut device: T, partition_number: usize) -> io::Result<Partition<T>> {
-    if partition_number >= 4 {
-        return Err(io::ErrorKind::InvalidInput.into());
+/// Returns the first partition of `device` with a FAT32 partition type,
+/// primary partitions first.
+pub fn get_fat32_partition<T: BlockDevice>(device: T) -> io::Result<Partition<T>> {
+    let partitions = read_partitions(&device)?;
+    let info = partitions.iter().find(|partition| partition.is_fat32())
+        .ok_or(::error::Error::NotFound)?;
+    Ok(partition_from_info(device, info))
+}
+
+/// A partitioned device shared by the partitions handed out by `partition`,
+/// so that several of them can be mounted at the same time. Each access of a
+/// partition locks the device for the duration of the access.
+pub struct Disk<T: BlockDevice> {
+    device: ArcMutex<T>,
+    partitions: Vec<PartitionInfo>,
+}
+
+impl<T: BlockDevice> Disk<T> {
+    /// Reads the partition table of `device`, including the logical
+    /// partitions.
+    pub fn new(device: T) -> io::Result<Disk<T>> {
+        Disk::from_shared(ArcMutex::new(device))
     }
-    let mbr = MasterBootRecord::read_from(&mut device).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
-    let entry = &mbr.entries[partition_number];
-    if entry.entry_type == 0 {
-        return Err(io::ErrorKind::NotFound.into());
+
+    /// Like `new`, for a device that is already shared.
+    pub fn from_shared(device: ArcMutex<T>) -> io::Result<Disk<T>> {
+        let partitions = read_partitions(&device)?;
+        Ok(Disk { device, partitions })
+    }
+
+    /// The partitions as read by `new`, numbered as in `get_partition`.
+    pub fn partitions(&self) -> &[PartitionInfo] {
+        &self.partitions
+    }
+
+    /// Returns partition `partition_number` of the device. A partition may
+    /// be handed out more than once; the handles must not be mounted at the
+    /// same time then.
+    pub fn partition(&self, partition_number: usize) -> io::Result<Partition<ArcMutex<T>>> {
+        let info = self.partitions.iter().find(|partition| partition.index == partition_number)
+            .ok_or(::error::Error::NotFound)?;
+        Ok(partition_from_info(self.device.clone(), info))
     }
-    let sector_start = entry.start_lba as u64;
-    let sector_end = sector_start + entry.size as u64;
-    Ok(Partition::new(device, sector_start..sector_end))
+
+    /// Returns the first partition with a FAT32 partition type, primary
+    /// partitions first.
+    pub fn fat32_partition(&self) -> io::Result<Partition<ArcMutex<T>>> {
+        let info = self.partitions.iter().find(|partition| partition.is_fat32())
+            .ok_or(::error::Error::NotFound)?;
+        Ok(partition_from_info(self.device.clone(), info))
+    }
+
+    /// The shared device, e.g. to sync it once all partitions are done.
+    pub fn device(&self) -> &ArcMutex<T> {
+        &self.device
+    }
+}
+
+fn read_partitions<T: BlockDevice>(device: &T) -> io::Result<Vec<PartitionInfo>> {
+    MasterBootRecord::read_from(device)
+        .and_then(|mbr| mbr.all_partitions(device))
+        .map_err(|error| match error {
+            Error::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        })
+}
+
+pub(crate) fn partition_from_info<T: BlockDevice>(device: T, info: &PartitionInfo) -> Partition<T> {
+    let sector_start = info.start_lba as u64;
+    let sector_end = sector_start + info.sectors as u64;
+    Partition::new(device, sector_start..sector_end, info.kind)
 }
 
 impl fmt::Debug for MasterBootRecord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("FatEntry")
-            .field("entries", &self.entries)
-            .field("signature", &self.signature)
+        f.debug_struct("MasterBootRecord")
+            .field("disk_signature", &format_args!("{:#010x}", self.disk_signature()))
+            .field("partitions", &self.partitions().collect::<Vec<_>>())
+            .field("signature", &{ self.signature })
             .finish()
     }
 }
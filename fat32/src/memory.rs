@@ -0,0 +1,151 @@
+use std::io;
+
+use traits::BlockDevice;
+
+/// A block device backed by memory, e.g. for RAM disks or for building disk
+/// images before writing them out.
+#[derive(Debug, Clone)]
+pub struct MemoryBlockDevice {
+    data: Vec<u8>,
+    sector_size: u64,
+}
+
+impl MemoryBlockDevice {
+    /// Returns a device holding `data` with sectors of `sector_size` bytes.
+    /// Trailing bytes that don't fill a whole sector are kept but can't be
+    /// accessed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector_size` isn't a non-zero multiple of 512.
+    pub fn from_vec(data: Vec<u8>, sector_size: u64) -> MemoryBlockDevice {
+        assert!(sector_size >= 512 && sector_size % 512 == 0, "sector size must be a multiple of 512");
+        MemoryBlockDevice { data, sector_size }
+    }
+
+    /// Returns a zero-filled device of `sector_count` sectors of
+    /// `sector_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector_size` isn't a non-zero multiple of 512.
+    pub fn with_capacity_sectors(sector_count: u64, sector_size: u64) -> MemoryBlockDevice {
+        MemoryBlockDevice::from_vec(vec![0; (sector_count * sector_size) as usize], sector_size)
+    }
+
+    /// Returns the contents of the device.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn sector_start(&self, sector: u64) -> io::Result<usize> {
+        self.range_start(sector, 1)
+    }
+
+    /// Returns the offset of `sector` if the `len` bytes from there are
+    /// within the whole sectors of the device.
+    fn range_start(&self, sector: u64, len: usize) -> io::Result<usize> {
+        let end = sector.checked_mul(self.sector_size).and_then(|start| start.checked_add(len as u64));
+        match end {
+            Some(end) if sector < self.num_sectors() && end <= self.num_sectors() * self.sector_size => {
+                Ok((sector * self.sector_size) as usize)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "sector out of range")),
+        }
+    }
+}
+
+impl BlockDevice for MemoryBlockDevice {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// The number of whole sectors on the device.
+    fn num_sectors(&self) -> u64 {
+        self.data.len() as u64 / self.sector_size
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = self.sector_start(sector)?;
+        let len = self.sector_size as usize;
+        if buf.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
+        }
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, sector: u64, buf: &[u8]) -> io::Result<()> {
+        let start = self.sector_start(sector)?;
+        let len = self.sector_size as usize;
+        if buf.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
+        }
+        self.data[start..start + len].copy_from_slice(&buf[..len]);
+        Ok(())
+    }
+
+    fn read_sectors(&self, first: u64, buf: &mut [u8]) -> io::Result<()> {
+        if buf.len() as u64 % self.sector_size != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is not a whole number of sectors"));
+        }
+        let start = self.range_start(first, buf.len())?;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, first: u64, buf: &[u8]) -> io::Result<()> {
+        if buf.len() as u64 % self.sector_size != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is not a whole number of sectors"));
+        }
+        let start = self.range_start(first, buf.len())?;
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_memory_block_device() {
+    let mut device = MemoryBlockDevice::with_capacity_sectors(4, 1024);
+    assert_eq!(device.num_sectors(), 4);
+
+    device.write_sector(3, &[0xAB; 1024]).unwrap();
+    let mut buf = [0; 1024];
+    device.read_sector(3, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0xAB));
+
+    // buffers must hold a whole sector
+    let mut short = [0; 16];
+    assert_eq!(device.read_sector(3, &mut short).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(device.write_sector(2, &[1; 512]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    assert_eq!(device.read_sector(4, &mut buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(device.write_sector(4, &buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    let data = device.into_inner();
+    assert_eq!(data.len(), 4096);
+    assert!(data[..3072].iter().all(|&b| b == 0));
+    assert!(data[3072..].iter().all(|&b| b == 0xAB));
+
+    let mut device = MemoryBlockDevice::with_capacity_sectors(4, 512);
+    device.write_sectors(1, &[7; 1024]).unwrap();
+    let mut buf = [0; 1536];
+    device.read_sectors(1, &mut buf).unwrap();
+    assert_eq!(&buf[..1024], &[7; 1024][..]);
+    assert_eq!(&buf[1024..], &[0; 512][..]);
+    assert!(device.read_sectors(2, &mut buf).is_err());
+    assert!(device.write_sectors(3, &[7; 1024]).is_err());
+    assert!(device.write_sectors(0, &[7; 700]).is_err());
+    let mut partial = [0; 700];
+    assert_eq!(device.read_sectors(0, &mut partial).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    let mut buf = [0; 1024];
+
+    // a trailing partial sector isn't addressable
+    let device = MemoryBlockDevice::from_vec(vec![0; 1000], 512);
+    assert_eq!(device.num_sectors(), 1);
+    assert!(device.read_sector(1, &mut buf).is_err());
+}
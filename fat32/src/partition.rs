@@ -5,24 +5,54 @@ use std::io;
 pub type SectorRange = Range<u64>;
 
 
+/// The sectors `sector_range` of a source device, addressed from 0.
 pub struct Partition<T: BlockDevice> {
     source: T,
     sector_range: SectorRange,
+    partition_type: u8,
 }
 
 impl<T: BlockDevice> Partition<T> {
-    pub fn new(source: T, sector_range: SectorRange) -> Self {
+    /// Returns the partition spanning `sector_range` of `source`.
+    /// `partition_type` is the type byte of its MBR entry, or 0 if it has
+    /// none.
+    pub fn new(source: T, sector_range: SectorRange, partition_type: u8) -> Self {
         Partition {
-            source, sector_range
+            source, sector_range, partition_type
+        }
+    }
+
+    /// The first sector of the partition on the source device.
+    pub fn start_lba(&self) -> u64 {
+        self.sector_range.start
+    }
+
+    /// The number of sectors in the partition's range, whether or not the
+    /// source device actually has all of them.
+    pub fn len_sectors(&self) -> u64 {
+        self.sector_range.end.saturating_sub(self.sector_range.start)
+    }
+
+    /// The type byte of the partition's MBR entry.
+    pub fn partition_type(&self) -> u8 {
+        self.partition_type
+    }
+
+    /// Translates the run of sectors starting at `n` that holds `len` bytes.
+    fn to_source_sectors(&self, n: u64, len: usize) -> Result<u64, io::Error> {
+        let m = self.to_source_sector(n)?;
+        let count = (len as u64 + self.sector_size() - 1) / self.sector_size();
+        match n.checked_add(count) {
+            Some(end) if end <= self.len_sectors() => Ok(m),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
         }
     }
 
     fn to_source_sector(&self, n: u64) -> Result<u64, io::Error> {
-        let source_sector = n + self.sector_range.start;
-        if !self.sector_range.contains(source_sector) {
+        if n >= self.len_sectors() {
             return Err(io::Error::from(io::ErrorKind::InvalidInput));
         }
-        Ok(source_sector)
+        Ok(self.sector_range.start + n)
     }
 }
 
@@ -31,6 +61,13 @@ impl<T: BlockDevice> BlockDevice for Partition<T> {
         self.source.sector_size()
     }
 
+    /// The size of the partition's range, cut short if the source device
+    /// ends before it.
+    fn num_sectors(&self) -> u64 {
+        let end = ::std::cmp::min(self.sector_range.end, self.source.num_sectors());
+        end.saturating_sub(self.sector_range.start)
+    }
+
     fn read_sector(&self, n: u64, buf: &mut [u8]) -> Result<(), io::Error> {
         let m = self.to_source_sector(n)?;
         self.source.read_sector(m, buf)
@@ -41,7 +78,41 @@ impl<T: BlockDevice> BlockDevice for Partition<T> {
         self.source.write_sector(m, buf)
     }
 
+    fn read_sectors(&self, n: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        let m = self.to_source_sectors(n, buf.len())?;
+        self.source.read_sectors(m, buf)
+    }
+
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> Result<(), io::Error> {
+        let m = self.to_source_sectors(n, buf.len())?;
+        self.source.write_sectors(m, buf)
+    }
+
     fn sync(&mut self) -> io::Result<()> {
         self.source.sync()
     }
+
+    fn sync_range(&mut self, n: u64, count: u64) -> io::Result<()> {
+        let m = self.to_source_sector(n)?;
+        if m + count > self.sector_range.end {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.source.sync_range(m, count)
+    }
+
+    fn barrier(&mut self) -> io::Result<()> {
+        self.source.barrier()
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        let m = self.to_source_sector(n)?;
+        if m + count > self.sector_range.end {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.source.discard(m, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.source.is_read_only()
+    }
 }
@@ -0,0 +1,59 @@
+use std::io;
+
+use traits::BlockDevice;
+use error::Error;
+
+/// Wraps a block device and rejects every write to it with an error of
+/// `PermissionDenied`, whatever the code on top of it does.
+///
+/// File systems mounted on a `ReadOnlyDevice` are read-only.
+#[derive(Debug)]
+pub struct ReadOnlyDevice<T: BlockDevice> {
+    inner: T,
+}
+
+impl<T: BlockDevice> ReadOnlyDevice<T> {
+    pub fn new(inner: T) -> ReadOnlyDevice<T> {
+        ReadOnlyDevice { inner }
+    }
+
+    /// Returns the wrapped device.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for ReadOnlyDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.inner.sector_size()
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.inner.num_sectors()
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_sector(sector, buf)
+    }
+
+    fn write_sector(&mut self, _sector: u64, _buf: &[u8]) -> io::Result<()> {
+        Err(Error::ReadOnly.into())
+    }
+
+    /// Nothing is ever written, so there is nothing to sync.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_range(&mut self, _first: u64, _count: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn discard(&mut self, _sector: u64, _count: u64) -> io::Result<()> {
+        Err(Error::ReadOnly.into())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,80 @@
+use std::io;
+
+use arc_mutex::ArcMutex;
+use mbr::{Disk, PartitionInfo, partition_from_info};
+use partition::Partition;
+use traits::BlockDevice;
+use vfat::{BiosParameterBlock, Error, MountOptions, VFatFileSystem};
+
+/// A partition holding a valid FAT32 volume, found by `Disk::scan`.
+pub struct MountablePartition<T: BlockDevice> {
+    info: PartitionInfo,
+    device: ArcMutex<T>,
+}
+
+impl<T: BlockDevice> MountablePartition<T> {
+    /// The partition table entry of the partition.
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+
+    /// Returns the partition, sharing the device like `Disk::partition`.
+    pub fn partition(&self) -> Partition<ArcMutex<T>> {
+        partition_from_info(self.device.clone(), &self.info)
+    }
+}
+
+impl<T: BlockDevice + 'static> MountablePartition<T> {
+    /// Mounts the volume. The volumes of several partitions of a device can
+    /// be mounted at the same time.
+    pub fn mount(&self) -> Result<ArcMutex<VFatFileSystem>, Error> {
+        self.mount_with_options(MountOptions::default())
+    }
+
+    /// Like `mount`, with `options`.
+    pub fn mount_with_options(&self, options: MountOptions) -> Result<ArcMutex<VFatFileSystem>, Error> {
+        VFatFileSystem::from_with_options(Box::new(self.partition()), options)
+    }
+}
+
+/// A partition that `Disk::scan` found no FAT32 volume on.
+#[derive(Debug)]
+pub struct SkippedPartition {
+    pub info: PartitionInfo,
+    /// Why the volume can't be mounted, as mounting it would fail.
+    pub reason: Error,
+}
+
+/// The partitions of a device, as sorted by `Disk::scan`.
+pub struct Scan<T: BlockDevice> {
+    pub mountable: Vec<MountablePartition<T>>,
+    pub skipped: Vec<SkippedPartition>,
+}
+
+impl<T: BlockDevice> Disk<T> {
+    /// Probes every partition for a FAT volume, whatever its type byte
+    /// says, as many tools get that wrong. Extended partitions only hold
+    /// other partitions and are left out. Nothing is written to the device.
+    pub fn scan(&self) -> Scan<T> {
+        let mut scan = Scan { mountable: Vec::new(), skipped: Vec::new() };
+        for info in self.partitions().iter().filter(|info| !info.is_extended()) {
+            let partition = partition_from_info(self.device().clone(), info);
+            match BiosParameterBlock::read_valid_from(&partition) {
+                Ok(_) => scan.mountable.push(MountablePartition { info: *info, device: self.device().clone() }),
+                Err(reason) => scan.skipped.push(SkippedPartition { info: *info, reason }),
+            }
+        }
+        scan
+    }
+}
+
+/// Reads the partition table of `device` and returns the partitions holding
+/// a FAT32 volume, primary partitions first. Only MBR partition tables are
+/// supported.
+///
+/// # Errors
+///
+/// Returns an error of `InvalidData` if the partition table can't be read.
+pub fn scan_device<T: BlockDevice>(device: T) -> io::Result<Vec<MountablePartition<T>>> {
+    Ok(Disk::new(device)?.scan().mountable)
+}
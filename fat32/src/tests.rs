@@ -5,10 +5,9 @@ use std::io::Cursor;
 use std::path::Path;
 
 use vfat::{VFatFileSystem, BiosParameterBlock};
-use mbr::{MasterBootRecord, CHS, PartitionEntry, get_partition};
+use mbr::{MasterBootRecord, CHS, PartitionEntry, get_partition, get_fat32_partition};
 use traits::*;
 use fallible_iterator::FallibleIterator;
-use chrono::{Datelike, Timelike};
 use std::io::SeekFrom;
 use std::cell::RefCell;
 use vfat::lock_manager::LockMode;
@@ -20,10 +19,18 @@ use arc_mutex::ArcMutex;
 mod mock {
     use std::io::{Read, Write, Seek, Result, SeekFrom};
     use std::cell::RefCell;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use traits::BlockDevice;
 
     pub trait MockBlockDevice : Read + Write + Seek + Send {    }
 
     impl<T: MockBlockDevice> ::traits::BlockDevice for RefCell<T> {
+        fn num_sectors(&self) -> u64 {
+            let sector_size = self.sector_size();
+            self.borrow_mut().seek(SeekFrom::End(0)).map(|len| len / sector_size).unwrap_or(0)
+        }
+
         fn read_sector(&self, n: u64, buf: &mut [u8]) -> Result<()> {
             let mut self1 = self.borrow_mut();
             let sector_size = self.sector_size();
@@ -40,20 +47,356 @@ mod mock {
             Ok(())
         }
 
+        fn read_sectors(&self, first: u64, buf: &mut [u8]) -> Result<()> {
+            self.read_sector(first, buf)
+        }
+
+        fn write_sectors(&mut self, first: u64, buf: &[u8]) -> Result<()> {
+            self.write_sector(first, buf)
+        }
+
         fn sync(&mut self) -> Result<()> {
             self.borrow_mut().flush()
         }
     }
 
+    /// Wraps a block device and records the sectors that are read, written
+    /// and discarded through it, the `(first, count)` runs passed to
+    /// `read_sectors` and `write_sectors` and where barriers fall between the
+    /// writes.
+    pub struct InstrumentedDevice<T: BlockDevice> {
+        pub inner: T,
+        pub reads: Arc<Mutex<Vec<u64>>>,
+        pub multi_reads: Arc<Mutex<Vec<(u64, u64)>>>,
+        pub writes: Arc<Mutex<Vec<u64>>>,
+        pub multi_writes: Arc<Mutex<Vec<(u64, u64)>>>,
+        pub discards: Arc<Mutex<Vec<(u64, u64)>>>,
+        /// The number of `writes` before each barrier.
+        pub barriers: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl<T: BlockDevice> InstrumentedDevice<T> {
+        pub fn new(inner: T) -> Self {
+            InstrumentedDevice {
+                inner,
+                reads: Arc::new(Mutex::new(Vec::new())),
+                multi_reads: Arc::new(Mutex::new(Vec::new())),
+                writes: Arc::new(Mutex::new(Vec::new())),
+                multi_writes: Arc::new(Mutex::new(Vec::new())),
+                discards: Arc::new(Mutex::new(Vec::new())),
+                barriers: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    impl<T: BlockDevice> BlockDevice for InstrumentedDevice<T> {
+        fn sector_size(&self) -> u64 {
+            self.inner.sector_size()
+        }
+
+        fn num_sectors(&self) -> u64 {
+            self.inner.num_sectors()
+        }
+
+        fn read_sector(&self, n: u64, buf: &mut [u8]) -> Result<()> {
+            self.reads.lock().unwrap().push(n);
+            self.inner.read_sector(n, buf)
+        }
+
+        fn read_sectors(&self, first: u64, buf: &mut [u8]) -> Result<()> {
+            let count = (buf.len() as u64 + self.sector_size() - 1) / self.sector_size();
+            self.reads.lock().unwrap().extend(first..first + count);
+            self.multi_reads.lock().unwrap().push((first, count));
+            self.inner.read_sectors(first, buf)
+        }
+
+        fn write_sector(&mut self, n: u64, buf: &[u8]) -> Result<()> {
+            self.writes.lock().unwrap().push(n);
+            self.inner.write_sector(n, buf)
+        }
+
+        fn write_sectors(&mut self, first: u64, buf: &[u8]) -> Result<()> {
+            let count = (buf.len() as u64 + self.sector_size() - 1) / self.sector_size();
+            self.writes.lock().unwrap().extend(first..first + count);
+            self.multi_writes.lock().unwrap().push((first, count));
+            self.inner.write_sectors(first, buf)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.inner.sync()
+        }
+
+        fn sync_range(&mut self, first: u64, count: u64) -> Result<()> {
+            self.inner.sync_range(first, count)
+        }
+
+        fn barrier(&mut self) -> Result<()> {
+            self.barriers.lock().unwrap().push(self.writes.lock().unwrap().len());
+            self.inner.barrier()
+        }
+
+        fn discard(&mut self, sector: u64, count: u64) -> Result<()> {
+            self.discards.lock().unwrap().push((sector, count));
+            self.inner.discard(sector, count)
+        }
+    }
+
+    /// Panics on the first read after `armed` is set, like a driver with a
+    /// bug would.
+    pub struct PanickingReadsDevice<T: BlockDevice> {
+        pub inner: T,
+        pub armed: Arc<AtomicBool>,
+    }
+
+    impl<T: BlockDevice> PanickingReadsDevice<T> {
+        pub fn new(inner: T) -> Self {
+            PanickingReadsDevice {
+                inner,
+                armed: Arc::new(AtomicBool::new(false)),
+            }
+        }
+
+        fn check(&self) {
+            if self.armed.swap(false, Ordering::SeqCst) {
+                panic!("read from a panicking device");
+            }
+        }
+    }
+
+    impl<T: BlockDevice> BlockDevice for PanickingReadsDevice<T> {
+        fn sector_size(&self) -> u64 {
+            self.inner.sector_size()
+        }
+
+        fn num_sectors(&self) -> u64 {
+            self.inner.num_sectors()
+        }
+
+        fn read_sector(&self, n: u64, buf: &mut [u8]) -> Result<()> {
+            self.check();
+            self.inner.read_sector(n, buf)
+        }
+
+        fn read_sectors(&self, first: u64, buf: &mut [u8]) -> Result<()> {
+            self.check();
+            self.inner.read_sectors(first, buf)
+        }
+
+        fn write_sector(&mut self, n: u64, buf: &[u8]) -> Result<()> {
+            self.inner.write_sector(n, buf)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.inner.sync()
+        }
+    }
+
+    /// Fails every write to the sector stored in `failing_sector`.
+    pub struct FailingWritesDevice<T: BlockDevice> {
+        pub inner: T,
+        pub failing_sector: Arc<Mutex<Option<u64>>>,
+    }
+
+    impl<T: BlockDevice> FailingWritesDevice<T> {
+        pub fn new(inner: T) -> Self {
+            FailingWritesDevice {
+                inner,
+                failing_sector: Arc::new(Mutex::new(None)),
+            }
+        }
+    }
+
+    impl<T: BlockDevice> BlockDevice for FailingWritesDevice<T> {
+        fn sector_size(&self) -> u64 {
+            self.inner.sector_size()
+        }
+
+        fn num_sectors(&self) -> u64 {
+            self.inner.num_sectors()
+        }
+
+        fn read_sector(&self, n: u64, buf: &mut [u8]) -> Result<()> {
+            self.inner.read_sector(n, buf)
+        }
+
+        fn write_sector(&mut self, n: u64, buf: &[u8]) -> Result<()> {
+            if *self.failing_sector.lock().unwrap() == Some(n) {
+                return Err(::std::io::Error::new(::std::io::ErrorKind::Other, "write failed"));
+            }
+            self.inner.write_sector(n, buf)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.inner.sync()
+        }
+    }
+
+    /// The faults injected by a `FaultyDevice` and the operations it has
+    /// performed so far.
+    #[derive(Default, Debug)]
+    pub struct Faults {
+        /// Fail the write with this index, counting from 0.
+        pub fail_nth_write: Option<u64>,
+        /// Also fail every write after the failed one, as if the device was
+        /// removed.
+        pub fail_later_writes: bool,
+        /// Silently drop the write with this index, counting from 0, and all
+        /// later ones, as if power was cut.
+        pub lose_writes_from: Option<u64>,
+        /// Fail every read of these sectors.
+        pub failing_reads: ::std::collections::HashSet<u64>,
+        /// Only fill the first half of the buffer when reading these sectors.
+        pub short_reads: ::std::collections::HashSet<u64>,
+        /// Return the inverted contents when reading these sectors.
+        pub garbled_reads: ::std::collections::HashSet<u64>,
+        pub reads: u64,
+        pub writes: u64,
+        pub failed_writes: u64,
+    }
+
+    /// Wraps a block device and fails or corrupts operations as configured
+    /// through `faults`, which can be changed while the device is in use.
+    pub struct FaultyDevice<T: BlockDevice> {
+        pub inner: T,
+        pub faults: Arc<Mutex<Faults>>,
+    }
+
+    impl<T: BlockDevice> FaultyDevice<T> {
+        pub fn new(inner: T) -> Self {
+            FaultyDevice {
+                inner,
+                faults: Arc::new(Mutex::new(Faults::default())),
+            }
+        }
+    }
+
+    impl<T: BlockDevice> BlockDevice for FaultyDevice<T> {
+        fn sector_size(&self) -> u64 {
+            self.inner.sector_size()
+        }
+
+        fn num_sectors(&self) -> u64 {
+            self.inner.num_sectors()
+        }
+
+        fn read_sector(&self, n: u64, buf: &mut [u8]) -> Result<()> {
+            let mut faults = self.faults.lock().unwrap();
+            faults.reads += 1;
+            if faults.failing_reads.contains(&n) {
+                return Err(::std::io::Error::new(::std::io::ErrorKind::Other, "read failed"));
+            }
+            self.inner.read_sector(n, buf)?;
+            if faults.short_reads.contains(&n) {
+                let half = buf.len() / 2;
+                for byte in &mut buf[half..] {
+                    *byte = 0;
+                }
+            }
+            if faults.garbled_reads.contains(&n) {
+                for byte in buf.iter_mut() {
+                    *byte = !*byte;
+                }
+            }
+            Ok(())
+        }
+
+        fn write_sector(&mut self, n: u64, buf: &[u8]) -> Result<()> {
+            let mut faults = self.faults.lock().unwrap();
+            let index = faults.writes;
+            faults.writes += 1;
+            let fail = match faults.fail_nth_write {
+                Some(nth) => index == nth || (faults.fail_later_writes && index > nth),
+                None => false,
+            };
+            if fail {
+                faults.failed_writes += 1;
+                return Err(::std::io::Error::new(::std::io::ErrorKind::Other, "write failed"));
+            }
+            if faults.lose_writes_from.map_or(false, |from| index >= from) {
+                return Ok(());
+            }
+            self.inner.write_sector(n, buf)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.inner.sync()
+        }
+    }
+
+    /// A large device of 512-byte sectors that only stores the sectors that
+    /// have been written; all others read as zeros.
+    pub struct SparseDevice {
+        pub sectors: ::std::collections::HashMap<u64, Vec<u8>>,
+        pub num_sectors: u64,
+    }
+
+    impl SparseDevice {
+        pub fn new(num_sectors: u64) -> Self {
+            SparseDevice {
+                sectors: ::std::collections::HashMap::new(),
+                num_sectors,
+            }
+        }
+    }
+
+    impl BlockDevice for SparseDevice {
+        fn num_sectors(&self) -> u64 {
+            self.num_sectors
+        }
+
+        fn read_sector(&self, n: u64, buf: &mut [u8]) -> Result<()> {
+            if n >= self.num_sectors {
+                return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "sector out of range"));
+            }
+            if buf.len() < 512 {
+                return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
+            }
+            match self.sectors.get(&n) {
+                Some(sector) => buf[..512].copy_from_slice(sector),
+                None => for byte in &mut buf[..512] {
+                    *byte = 0;
+                },
+            }
+            Ok(())
+        }
+
+        fn write_sector(&mut self, n: u64, buf: &[u8]) -> Result<()> {
+            if n >= self.num_sectors {
+                return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "sector out of range"));
+            }
+            if buf.len() < 512 {
+                return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
+            }
+            self.sectors.insert(n, buf[..512].to_vec());
+            Ok(())
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
     impl<'a> MockBlockDevice for ::std::io::Cursor<&'a mut [u8]> { }
     impl MockBlockDevice for ::std::io::Cursor<Vec<u8>> { }
     impl MockBlockDevice for ::std::io::Cursor<Box<[u8]>> { }
     impl MockBlockDevice for ::std::fs::File { }
 }
 
-macro assert_size_eq($T:ty, $size:expr) {
-    assert_eq!(::std::mem::size_of::<$T>(), $size,
-        "'{}' does not have the expected size of {}", stringify!($T), $size);
+macro assert_serialized_len($value:expr, $size:expr) {
+    assert_eq!($value.to_bytes().len(), $size,
+        "'{}' does not serialize to the expected {} bytes", stringify!($value), $size);
+}
+
+/// Asserts that parsing and serializing random bytes with `$T` gives back the
+/// same bytes.
+macro assert_round_trip($T:ty, $size:expr) {
+    use tests::rand::Rng;
+    let mut rng = rand::thread_rng();
+    for _ in 0..16 {
+        let mut buf = [0u8; $size];
+        rng.fill_bytes(&mut buf);
+        assert_eq!(&<$T>::from_bytes(&buf).to_bytes()[..], &buf[..], "'{}' round trip", stringify!($T));
+    }
 }
 
 macro assert_matches($e:expr, $variant:pat $(if $($cond:tt)*)*) {
@@ -63,8 +406,20 @@ macro assert_matches($e:expr, $variant:pat $(if $($cond:tt)*)*) {
     }
 }
 
+/// The crate error of the failed `result`.
+fn fs_error<T>(result: ::std::io::Result<T>) -> ::error::Error {
+    match result {
+        Ok(_) => panic!("expected an error"),
+        Err(e) => ::error::Error::from(e),
+    }
+}
+
+fn resource_path(name: &str) -> String {
+    format!("{}/../files/resources/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
 fn load_disk_image_part(name: &str) -> ::std::io::Cursor<Vec<u8>> {
-    let path = format!("{}/../files/resources/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let path = resource_path(name);
     let mut file = match ::std::fs::File::open(path) {
         Ok(file) => file,
         Err(e) => {
@@ -79,7 +434,7 @@ fn load_disk_image_part(name: &str) -> ::std::io::Cursor<Vec<u8>> {
 }
 
 fn load_partition(name: &str) -> impl BlockDevice {
-    get_partition(RefCell::from(load_disk_image_part(name)), 0).expect("get_partition failed")
+    get_fat32_partition(RefCell::from(load_disk_image_part(name))).expect("get_fat32_partition failed")
 }
 
 
@@ -110,15 +465,45 @@ fn vfat_from_resource(name: &str) -> ArcMutex<VFatFileSystem> {
     VFatFileSystem::from(Box::new(load_partition(name))).expect("failed to initialize VFAT from image")
 }
 
+fn new_metadata(vfat: &ArcMutex<VFatFileSystem>, attributes: ::vfat::metadata::Attributes) -> ::vfat::metadata::VFatMetadata {
+    let time = Date::from_ymd(2018, 1, 1).and_hms(0, 0, 0);
+    ::vfat::metadata::VFatMetadata {
+        attributes,
+        created: Some(time),
+        accessed: Some(time.date()),
+        modified: Some(time),
+        first_cluster: vfat.lock().fat().new_chain().unwrap(),
+        size: 0,
+    }
+}
+
 //fn vfat_from_block_device<T: BlockDevice + 'static>(block_device: T) -> ArcMutex<VFat> {
 //    VFat::from(get_partition(block_device, 0).expect("get_partition failed")).expect("failed to initialize VFAT from image")
 //}
 
 #[test]
 fn check_mbr_size() {
-    assert_size_eq!(MasterBootRecord, 512);
-    assert_size_eq!(PartitionEntry, 16);
-    assert_size_eq!(CHS, 3);
+    assert_serialized_len!(MasterBootRecord::new(), 512);
+    assert_serialized_len!(PartitionEntry::from_bytes(&[0; 16]), 16);
+    assert_serialized_len!(CHS::MAX, 3);
+}
+
+#[test]
+fn mbr_round_trip() {
+    assert_round_trip!(MasterBootRecord, 512);
+    assert_round_trip!(PartitionEntry, 16);
+    assert_round_trip!(CHS, 3);
+
+    let entry = PartitionEntry::from_bytes(&[0x80, 0x01, 0x02, 0x03, 0x0C, 0xFE, 0xFF, 0xFF,
+        0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00]);
+    assert_eq!(entry.boot_indicator, 0x80);
+    assert_eq!(entry.entry_type, 0x0C);
+    assert_eq!(entry.start_lba, 0x800);
+    assert_eq!(entry.size, 0x100000);
+
+    let mut data = [0u8; 512];
+    load_disk_image_part("mbr.img").read_exact(&mut data).expect("read resource data");
+    assert_eq!(&MasterBootRecord::from_bytes(&data).to_bytes()[..], &data[..]);
 }
 
 #[test]
@@ -162,9 +547,421 @@ fn test_mbr() {
     assert_eq!(entry.size, 393215);
 }
 
+#[test]
+fn test_mbr_partitions() {
+    use mbr::PartitionInfo;
+
+    let mut mbr = load_disk_image_part("mbr.img");
+    let mut data = [0u8; 512];
+    mbr.read_exact(&mut data).expect("read resource data");
+    let mbr = MasterBootRecord::read_from(&RefCell::from(Cursor::new(&mut data[..]))).expect("valid MBR");
+    let partitions: Vec<_> = mbr.partitions().collect();
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].index, 0);
+    assert_eq!(partitions[0].kind, 0x0b);
+    assert_eq!(partitions[0].start_lba, 1);
+    assert_eq!(partitions[0].sectors, 393215);
+    assert_eq!(mbr.find_fat32(), Some(partitions[0]));
+    assert!(format!("{:?}", mbr).contains("393215"));
+
+    let info = PartitionInfo { index: 2, kind: 0x83, start_lba: 2048, sectors: 4096, bootable: false };
+    assert!(!info.is_fat32());
+}
+
+#[test]
+fn test_mbr_find_fat32() {
+    let mut data = [0u8; 1024];
+    data[510..512].copy_from_slice(&[0x55, 0xAA]);
+    // a Linux partition first, then a bootable FAT32 (LBA) partition
+    data[446 + 4] = 0x83;
+    data[446 + 8] = 1;
+    data[446 + 12] = 1;
+    data[462] = 0x80;
+    data[462 + 4] = 0x0C;
+    data[462 + 8] = 1;
+    data[462 + 12] = 1;
+    let mbr = MasterBootRecord::read_from(&RefCell::from(Cursor::new(&mut data[..]))).unwrap();
+    assert_eq!(mbr.partitions().count(), 2);
+    let fat32 = mbr.find_fat32().unwrap();
+    assert_eq!((fat32.index, fat32.kind, fat32.bootable), (1, 0x0C, true));
+
+    let partition = get_fat32_partition(RefCell::from(Cursor::new(&mut data[..]))).unwrap();
+    assert_eq!(partition.partition_type(), 0x0C);
+    let mut data = [0u8; 512];
+    data[510..512].copy_from_slice(&[0x55, 0xAA]);
+    let error = get_fat32_partition(RefCell::from(Cursor::new(&mut data[..]))).err().unwrap();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_partition_bounds() {
+    let image = load_disk_image_part("mock1.fat32.img").into_inner();
+    let partition = get_partition(RefCell::from(load_disk_image_part("mock1.fat32.img")), 0).unwrap();
+    assert_eq!(partition.start_lba(), 1);
+    assert!(partition.partition_type() == 0x0B || partition.partition_type() == 0x0C);
+    let len = partition.len_sectors();
+    assert_eq!(partition.num_sectors(), len);
+
+    let mut buf = [0; 512];
+    partition.read_sector(0, &mut buf).unwrap();
+    assert_eq!(&buf[..], &image[512..1024]);
+    partition.read_sector(len - 1, &mut buf).unwrap();
+    let last = (len as usize) * 512;
+    assert_eq!(&buf[..], &image[last..last + 512]);
+
+    let error = partition.read_sector(len, &mut buf).unwrap_err();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::InvalidInput);
+    assert!(partition.read_sector(::std::u64::MAX, &mut buf).is_err());
+    let mut two = [0; 1024];
+    assert!(partition.read_sectors(len - 1, &mut two).is_err());
+}
+
+/// Writes an MBR or EBR partition entry into the boot record `record`.
+fn write_partition_entry(record: &mut [u8], index: usize, kind: u8, start_lba: u32, sectors: u32) {
+    use byteorder::{LittleEndian, ByteOrder};
+
+    let entry = &mut record[446 + index * 16..446 + (index + 1) * 16];
+    entry[4] = kind;
+    LittleEndian::write_u32(&mut entry[8..12], start_lba);
+    LittleEndian::write_u32(&mut entry[12..16], sectors);
+    record[510..512].copy_from_slice(&[0x55, 0xAA]);
+}
+
+/// Writes an empty FAT32 volume of `sectors` sectors starting at `start`.
+fn format_fat32<T: BlockDevice>(device: &mut T, start: u64, sectors: u32) {
+    use vfat::BiosParameterBlockBuilder;
+    use byteorder::{LittleEndian, ByteOrder};
+
+    let bpb = BiosParameterBlockBuilder::new(sectors).build().unwrap();
+    bpb.write_to(device, start).unwrap();
+    bpb.write_to(device, start + BiosParameterBlock::BACKUP_SECTOR).unwrap();
+    let mut fat = [0u8; 512];
+    LittleEndian::write_u32(&mut fat[0..4], 0x0FFFFFF8);
+    LittleEndian::write_u32(&mut fat[4..8], 0x0FFFFFFF);
+    LittleEndian::write_u32(&mut fat[8..12], 0x0FFFFFFF);
+    for i in 0..bpb.number_of_fats as u64 {
+        let fat_start = start + { bpb.reserved_logical_sectors } as u64 + i * { bpb.logical_sectors_per_fat } as u64;
+        device.write_sector(fat_start, &fat).unwrap();
+    }
+}
+
+#[test]
+fn test_mbr_logical_partitions() {
+    use tests::mock::SparseDevice;
+
+    // one primary partition and an extended partition with two logical ones
+    let mut device = SparseDevice::new(240000);
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0B, 2048, 70000);
+    write_partition_entry(&mut record, 1, 0x0F, 80000, 150000);
+    device.write_sector(0, &record).unwrap();
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0C, 2048, 70000);
+    write_partition_entry(&mut record, 1, 0x05, 75000, 75000);
+    device.write_sector(80000, &record).unwrap();
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0B, 2048, 70000);
+    device.write_sector(155000, &record).unwrap();
+    for &start in &[2048, 82048, 157048] {
+        format_fat32(&mut device, start, 70000);
+    }
+    let device = ArcMutex::new(device);
+
+    let mbr = MasterBootRecord::read_from(&device).unwrap();
+    let partitions = mbr.all_partitions(&device).unwrap();
+    let summary: Vec<_> = partitions.iter().map(|p| (p.index, p.kind, p.start_lba, p.sectors)).collect();
+    assert_eq!(summary, vec![
+        (0, 0x0B, 2048, 70000),
+        (1, 0x0F, 80000, 150000),
+        (4, 0x0C, 82048, 70000),
+        (5, 0x0B, 157048, 70000),
+    ]);
+    assert!(partitions[1].is_extended());
+    assert_eq!(get_fat32_partition(device.clone()).unwrap().start_lba(), 2048);
+    assert_eq!(get_partition(device.clone(), 6).err().unwrap().kind(), ::std::io::ErrorKind::NotFound);
+
+    for &index in &[4, 5] {
+        let name = format!("/logical{}.txt", index);
+        let partition = get_partition(device.clone(), index).unwrap();
+        let vfat = VFatFileSystem::from(Box::new(partition)).unwrap();
+        vfat.create_file(&name).unwrap().write_all(name.as_bytes()).unwrap();
+    }
+    for &index in &[4, 5] {
+        let partition = get_partition(device.clone(), index).unwrap();
+        let vfat = VFatFileSystem::from(Box::new(partition)).unwrap();
+        let mut names = Vec::new();
+        let mut entries = vfat.open_dir("/").unwrap().entries().unwrap();
+        while let Some(entry) = entries.next().unwrap() {
+            names.push(entry.name().to_string());
+        }
+        assert_eq!(names, vec![format!("logical{}.txt", index)]);
+    }
+
+    // an EBR pointing outside of the extended partition is rejected
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0C, 2048, 70000);
+    write_partition_entry(&mut record, 1, 0x05, 200000, 75000);
+    device.clone().write_sector(80000, &record).unwrap();
+    assert!(mbr.all_partitions(&device).is_err());
+
+    // an EBR chain that loops back is rejected
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0C, 2048, 70000);
+    write_partition_entry(&mut record, 1, 0x05, 75000, 75000);
+    device.clone().write_sector(80000, &record).unwrap();
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0B, 2048, 70000);
+    write_partition_entry(&mut record, 1, 0x05, 75000, 75000);
+    device.clone().write_sector(155000, &record).unwrap();
+    let e = mbr.all_partitions(&device).unwrap_err();
+    assert_eq!(e.io_error().unwrap().kind(), ::std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_mbr_logical_partition_beyond_lba_range() {
+    use tests::mock::SparseDevice;
+
+    // the logical partition starts at 2^32, which an LBA can't address
+    let mut device = SparseDevice::new(0x1_0002_0000);
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0F, 0xFFFF_0000, 0x20000);
+    device.write_sector(0, &record).unwrap();
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0C, 0x10000, 2048);
+    device.write_sector(0xFFFF_0000, &record).unwrap();
+
+    let mbr = MasterBootRecord::read_from(&device).unwrap();
+    let e = mbr.all_partitions(&device).unwrap_err();
+    assert_eq!(e.io_error().unwrap().kind(), ::std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_mbr_read_error_keeps_its_kind() {
+    use tests::mock::{FaultyDevice, SparseDevice};
+
+    let mut device = SparseDevice::new(240000);
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0B, 2048, 70000);
+    write_partition_entry(&mut record, 1, 0x0F, 80000, 150000);
+    device.write_sector(0, &record).unwrap();
+    let device = FaultyDevice::new(device);
+    device.faults.lock().unwrap().failing_reads.insert(80000);
+
+    let e = get_partition(device, 0).err().unwrap();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+}
+
+#[test]
+fn test_disk_mounts_partitions_concurrently() {
+    use mbr::Disk;
+    use tests::mock::SparseDevice;
+
+    // a boot and a data partition
+    let mut device = SparseDevice::new(150000);
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0C, 2048, 70000);
+    write_partition_entry(&mut record, 1, 0x0B, 74048, 70000);
+    device.write_sector(0, &record).unwrap();
+    for &start in &[2048, 74048] {
+        format_fat32(&mut device, start, 70000);
+    }
+
+    // the cause of an invalid partition table is kept
+    let error = Disk::new(SparseDevice::new(100)).err().unwrap();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::InvalidData);
+    let cause = error.get_ref().and_then(|cause| cause.downcast_ref::<::mbr::Error>());
+    assert_matches!(cause, Some(&::mbr::Error::BadSignature));
+
+    let disk = Disk::new(device).unwrap();
+    assert_eq!(disk.partitions().len(), 2);
+    assert_eq!(disk.fat32_partition().unwrap().start_lba(), 2048);
+    assert_eq!(disk.partition(2).err().unwrap().kind(), ::std::io::ErrorKind::NotFound);
+    let boot = VFatFileSystem::from(Box::new(disk.partition(0).unwrap())).unwrap();
+    let data = VFatFileSystem::from(Box::new(disk.partition(1).unwrap())).unwrap();
+
+    let mut boot_file = boot.create_file("/config.txt").unwrap();
+    let mut data_file = data.create_file("/log.bin").unwrap();
+    for i in 0..20u8 {
+        boot_file.write_all(&[i; 300]).unwrap();
+        data_file.write_all(&[0xFF - i; 700]).unwrap();
+        if i % 5 == 0 {
+            data.create_dir(format!("/dir{}", i)).unwrap();
+            boot.sync().unwrap();
+        }
+    }
+    drop(boot_file);
+    drop(data_file);
+    assert_eq!(boot.open_dir("/").unwrap().entries().unwrap().count().unwrap(), 1);
+    assert_eq!(data.open_dir("/").unwrap().entries().unwrap().count().unwrap(), 5);
+    boot.sync().unwrap();
+    data.sync().unwrap();
+    drop(boot);
+    drop(data);
+
+    // both partitions are intact when mounted again
+    let disk = Disk::from_shared(disk.device().clone()).unwrap();
+    let boot = VFatFileSystem::from(Box::new(disk.partition(0).unwrap())).unwrap();
+    let data = VFatFileSystem::from(Box::new(disk.partition(1).unwrap())).unwrap();
+    let mut contents = Vec::new();
+    boot.open_file("/config.txt", FileOpenMode::Read).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents.len(), 20 * 300);
+    assert!(contents.chunks(300).enumerate().all(|(i, chunk)| chunk.iter().all(|&b| b == i as u8)));
+    contents.clear();
+    data.open_file("/log.bin", FileOpenMode::Read).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents.len(), 20 * 700);
+    assert!(contents.chunks(700).enumerate().all(|(i, chunk)| chunk.iter().all(|&b| b == 0xFF - i as u8)));
+    assert_eq!(boot.check().unwrap().findings, vec![]);
+    assert_eq!(data.check().unwrap().findings, vec![]);
+}
+
+#[test]
+fn test_scan_device() {
+    use mbr::Disk;
+    use scan::scan_device;
+    use tests::mock::SparseDevice;
+
+    // a FAT32 partition, one with a Linux type byte but a FAT32 volume and
+    // an unformatted one
+    let mut device = SparseDevice::new(160000);
+    let mut record = [0u8; 512];
+    write_partition_entry(&mut record, 0, 0x0C, 2048, 70000);
+    write_partition_entry(&mut record, 1, 0x83, 74048, 70000);
+    write_partition_entry(&mut record, 2, 0x0B, 146048, 10000);
+    device.write_sector(0, &record).unwrap();
+    for &start in &[2048, 74048] {
+        format_fat32(&mut device, start, 70000);
+    }
+
+    let device = ArcMutex::new(device);
+    let sectors_before = device.lock().sectors.clone();
+    let scan = Disk::from_shared(device.clone()).unwrap().scan();
+    assert!(device.lock().sectors == sectors_before);
+    let indices: Vec<usize> = scan.mountable.iter().map(|partition| partition.info().index).collect();
+    assert_eq!(indices, vec![0, 1]);
+    assert_eq!(scan.skipped.len(), 1);
+    assert_eq!(scan.skipped[0].info.index, 2);
+    assert_matches!(&scan.skipped[0].reason, &::vfat::Error::BadSignature);
+
+    let volumes: Vec<_> = scan.mountable.iter().map(|partition| partition.mount().unwrap()).collect();
+    for (i, vfat) in volumes.iter().enumerate() {
+        vfat.create_file(format!("/volume{}.txt", i)).unwrap().write_all(&[i as u8; 100]).unwrap();
+    }
+    for vfat in &volumes {
+        assert_eq!(vfat.open_dir("/").unwrap().entries().unwrap().count().unwrap(), 1);
+        vfat.sync().unwrap();
+    }
+    drop(volumes);
+
+    let partitions = scan_device(device).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[1].info().kind, 0x83);
+    let vfat = partitions[1].mount().unwrap();
+    let mut contents = Vec::new();
+    vfat.open_file("/volume1.txt", FileOpenMode::Read).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, vec![1; 100]);
+}
+
+#[test]
+fn test_mbr_write() {
+    use MemoryBlockDevice;
+    use mbr::Error;
+
+    let mut mbr = MasterBootRecord::new();
+    assert_eq!(mbr.partitions().count(), 0);
+    mbr.set_partition(0, 0x0C, 2048, 70000, true).unwrap();
+    assert_matches!(mbr.set_partition(1, 0x83, 72000, 100, false), Err(Error::OverlappingPartitions(0, 1)));
+    assert_eq!(mbr.partitions().count(), 1);
+
+    let mut device = ArcMutex::new(MemoryBlockDevice::with_capacity_sectors(80000, 512));
+    mbr.write_to(&mut device).unwrap();
+    let mut entry = [0; 16];
+    device.read_by_offset(446, &mut entry).unwrap();
+    // bootable, CHS 0/32/33, FAT32 (LBA), CHS 4/123/39 (as H, S, C), LBA 2048, 70000 sectors
+    assert_eq!(entry, [0x80, 32, 33, 0, 0x0C, 123, 39, 4, 0x00, 0x08, 0, 0, 0x70, 0x11, 0x01, 0]);
+    format_fat32(&mut device, 2048, 70000);
+
+    let partition = get_fat32_partition(device.clone()).unwrap();
+    assert_eq!((partition.start_lba(), partition.len_sectors(), partition.partition_type()), (2048, 70000, 0x0C));
+    let vfat = VFatFileSystem::from(Box::new(partition)).unwrap();
+    vfat.create_file("/hello.txt").unwrap().write_all(b"hello").unwrap();
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    let mut contents = String::new();
+    vfat.open_file("/hello.txt", FileOpenMode::Read).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "hello");
+
+    // the partition table is checked against the device
+    mbr.set_partition(1, 0x83, 72048, 8000, false).unwrap();
+    assert_matches!(mbr.write_to(&mut device), Err(Error::PartitionOutOfRange(1)));
+    mbr.clear_partition(1);
+    assert_eq!(mbr.partitions().count(), 1);
+
+    // rewriting an existing MBR keeps its bootstrap code
+    let mut image = load_disk_image_part("mbr.img").into_inner();
+    image.truncate(512);
+    image[..446].copy_from_slice(&[0xFA; 446]);
+    let mut device = RefCell::from(Cursor::new(image));
+    let mut mbr = MasterBootRecord::read_from(&device).unwrap();
+    assert_matches!(mbr.write_to(&mut device), Err(Error::PartitionOutOfRange(0)));
+    mbr.clear_partition(0);
+    mbr.write_to(&mut device).unwrap();
+    let written = device.into_inner().into_inner();
+    assert!(written[..446].iter().all(|&byte| byte == 0xFA));
+    assert_eq!(&written[510..], &[0x55, 0xAA]);
+}
+
+#[test]
+fn test_mbr_disk_signature() {
+    use byteorder::{LittleEndian, ByteOrder};
+    use tests::mock::SparseDevice;
+
+    let mut image = load_disk_image_part("mbr.img").into_inner();
+    image.truncate(512);
+    let mut device = SparseDevice::new(1 << 32);
+    device.write_sector(0, &image).unwrap();
+
+    let mut mbr = MasterBootRecord::read_from(&device).unwrap();
+    assert_eq!(mbr.disk_signature(), LittleEndian::read_u32(&image[440..444]));
+    assert_eq!(mbr.bootstrap_code(), &image[..440]);
+    assert_eq!(mbr.is_copy_protected(), &image[444..446] == &[0x5A, 0x5A]);
+
+    // a read/modify/write cycle leaves everything before the table intact
+    mbr.entries[0].boot_indicator = 0x80;
+    mbr.write_to(&mut device).unwrap();
+    let mut written = [0; 512];
+    device.read_sector(0, &mut written).unwrap();
+    assert_eq!(&written[..446], &image[..446]);
+    assert_eq!(written[446], 0x80);
+
+    mbr.set_disk_signature(0xDEADBEEF);
+    mbr.write_to(&mut device).unwrap();
+    device.read_sector(0, &mut written).unwrap();
+    assert_eq!(&written[440..444], &[0xEF, 0xBE, 0xAD, 0xDE]);
+    assert_eq!(&written[..440], &image[..440]);
+    assert_eq!(MasterBootRecord::read_from(&device).unwrap().disk_signature(), 0xDEADBEEF);
+
+    let mbr = MasterBootRecord::new();
+    assert!(!mbr.has_bootstrap_code());
+    assert_eq!(mbr.disk_signature(), 0);
+}
+
 #[test]
 fn check_ebpb_size() {
-    assert_size_eq!(BiosParameterBlock, 512);
+    assert_serialized_len!(BiosParameterBlock::from_bytes(&[0; 512]), 512);
+}
+
+#[test]
+fn ebpb_round_trip() {
+    use byteorder::{LittleEndian, ByteOrder};
+
+    assert_round_trip!(BiosParameterBlock, 512);
+
+    let mut data = [0u8; 512];
+    load_disk_image_part("ebpb1.img").read_exact(&mut data).expect("read resource data");
+    let ebpb = BiosParameterBlock::from_bytes(&data);
+    assert_eq!(&ebpb.to_bytes()[..], &data[..]);
+    assert_eq!({ ebpb.bytes_per_logical_sector }, LittleEndian::read_u16(&data[11..13]));
+    assert_eq!({ ebpb.root_directory_cluster }, LittleEndian::read_u32(&data[44..48]));
+    assert_eq!(&ebpb.fs_type, &data[82..90]);
 }
 
 #[test]
@@ -191,60 +988,444 @@ fn test_ebpb() {
     BiosParameterBlock::read_from(&RefCell::from(Cursor::new(&mut data[512..]))).expect("valid EBPB");
 }
 
-#[test]
-fn check_entry_sizes() {
-    assert_size_eq!(::vfat::dir::VFatRegularDirEntry, 32);
-    assert_size_eq!(::vfat::dir::VFatUnknownDirEntry, 32);
-    assert_size_eq!(::vfat::dir::VFatLfnDirEntry, 32);
-    assert_size_eq!(::vfat::dir::VFatDirEntry, 32);
+fn fat32_bpb_sector() -> Vec<u8> {
+    use byteorder::{LittleEndian, ByteOrder};
+
+    let mut data = vec![0u8; 512];
+    LittleEndian::write_u16(&mut data[11..13], 512);
+    data[13] = 1;
+    LittleEndian::write_u16(&mut data[14..16], 32);
+    data[16] = 2;
+    LittleEndian::write_u32(&mut data[32..36], 200000);
+    LittleEndian::write_u32(&mut data[36..40], 1563);
+    LittleEndian::write_u32(&mut data[44..48], 2);
+    data[510..512].copy_from_slice(&[0x55, 0xAA]);
+    data
 }
 
-#[test]
-fn test_vfat_init() {
-    vfat_from_resource("mock1.fat32.img");
-    vfat_from_resource("mock2.fat32.img");
-    vfat_from_resource("mock3.fat32.img");
-    vfat_from_resource("mock4.fat32.img");
+fn validate_bpb(data: &[u8]) -> Result<(), ::vfat::Error> {
+    let mut data = data.to_vec();
+    BiosParameterBlock::read_from(&RefCell::from(Cursor::new(&mut data[..])))?.validate()
 }
 
-fn hash_entry<T: Entry>(hash: &mut String, entry: &T) -> ::std::fmt::Result {
-    use std::fmt::Write;
-
-    fn write_bool(to: &mut String, b: bool, c: char) -> ::std::fmt::Result {
-        if b { write!(to, "{}", c) } else { write!(to, "-") }
+#[test]
+fn check_ebpb_validation() {
+    use byteorder::{LittleEndian, ByteOrder};
+
+    let valid = fat32_bpb_sector();
+    validate_bpb(&valid).expect("valid FAT32 EBPB");
+
+    let mut data = valid.clone();
+    LittleEndian::write_u16(&mut data[11..13], 0);
+    assert_matches!(validate_bpb(&data), Err(::vfat::Error::InvalidGeometry { field: "bytes_per_logical_sector" }));
+    LittleEndian::write_u16(&mut data[11..13], 8192);
+    assert_matches!(validate_bpb(&data), Err(::vfat::Error::InvalidGeometry { field: "bytes_per_logical_sector" }));
+
+    for &sectors_per_cluster in &[0u8, 3, 255] {
+        let mut data = valid.clone();
+        data[13] = sectors_per_cluster;
+        assert_matches!(validate_bpb(&data), Err(::vfat::Error::InvalidGeometry { field: "logical_sectors_per_cluster" }));
     }
 
-    fn write_timestamp(to: &mut String, ts: DateTime) -> ::std::fmt::Result {
-        write!(to, "{:02}/{:02}/{} {:02}:{:02}:{:02} ",
-               ts.month(), ts.day(), ts.year(), ts.hour(), ts.minute(), ts.second())
-    }
+    let mut data = valid.clone();
+    data[16] = 0;
+    assert_matches!(validate_bpb(&data), Err(::vfat::Error::InvalidGeometry { field: "number_of_fats" }));
 
-    write_bool(hash, entry.is_dir(), 'd')?;
-    write_bool(hash, entry.is_file(), 'f')?;
-    write_bool(hash, entry.metadata().is_read_only(), 'r')?;
-    write_bool(hash, entry.metadata().is_hidden(), 'h')?;
-    write!(hash, "\t")?;
+    let mut data = valid.clone();
+    LittleEndian::write_u32(&mut data[44..48], 1);
+    assert_matches!(validate_bpb(&data), Err(::vfat::Error::InvalidGeometry { field: "root_directory_cluster" }));
 
-    write_timestamp(hash, entry.metadata().created())?;
-    write_timestamp(hash, entry.metadata().modified())?;
-    write_timestamp(hash, entry.metadata().accessed())?;
-    write!(hash, "\t")?;
+    let mut data = valid.clone();
+    LittleEndian::write_u32(&mut data[32..36], 1000);
+    assert_matches!(validate_bpb(&data), Err(::vfat::Error::InvalidGeometry { field: "total_logical_sectors" }));
 
-    write!(hash, "{}", entry.name())?;
-    Ok(())
+    // too few clusters for FAT32
+    let mut data = valid.clone();
+    LittleEndian::write_u32(&mut data[32..36], 60000);
+    assert_matches!(validate_bpb(&data), Err(::vfat::Error::NotFat32));
+
+    // truncated: not even a whole sector
+    let mut data = valid[..300].to_vec();
+    let e = BiosParameterBlock::read_from(&RefCell::from(Cursor::new(&mut data[..]))).unwrap_err();
+    assert_matches!(e, ::vfat::Error::Io(_));
 }
 
-fn hash_dir<T: Dir>(
-    hash: &mut String, dir: T
-) -> Result<Vec<T::Entry>, ::std::fmt::Error> {
-    let entries_iter = dir.entries()
-        .expect("entries interator");
-    let mut entries = entries_iter.collect::<Vec<_>>().unwrap();
+#[test]
+fn check_ebpb_round_trip() {
+    use vfat::BiosParameterBlockBuilder;
+
+    let bpb = BiosParameterBlockBuilder::new(524288)
+        .volume_serial_number(0x1234ABCD)
+        .volume_label("RPI")
+        .build()
+        .unwrap();
+    let mut device = RefCell::from(Cursor::new(vec![0u8; 1024]));
+    bpb.write_to(&mut device, 1).unwrap();
+    let mut written = device.borrow().get_ref()[512..].to_vec();
+    assert_eq!(&written[510..], &[0x55, 0xAA]);
+
+    let read = BiosParameterBlock::read_from(&RefCell::from(Cursor::new(&mut written[..]))).unwrap();
+    read.validate().unwrap();
+    assert_eq!({ read.large_total_logical_sectors }, 524288);
+    assert_eq!({ read.volume_serial_number }, 0x1234ABCD);
+    assert_eq!(&read.volume_label, b"RPI        ");
+
+    let mut device = RefCell::from(Cursor::new(vec![0u8; 512]));
+    read.write_to(&mut device, 0).unwrap();
+    assert_eq!(device.borrow().get_ref()[..], written[..]);
+}
 
-    entries.sort_by(|a, b| a.name().cmp(b.name()));
-    for (i, entry) in entries.iter().enumerate() {
+#[test]
+fn check_ebpb_builder() {
+    use vfat::BiosParameterBlockBuilder;
+
+    // (total sectors, sectors per cluster, sectors per FAT) of images made by
+    // `mkfs.vfat -F 32`
+    for &(total_sectors, sectors_per_cluster, sectors_per_fat) in &[
+        (524288u32, 1u8, 4033u32),
+        (2097152, 8, 2044),
+    ] {
+        let bpb = BiosParameterBlockBuilder::new(total_sectors).build().unwrap();
+        assert_eq!(bpb.logical_sectors_per_cluster, sectors_per_cluster);
+        assert_eq!({ bpb.logical_sectors_per_fat }, sectors_per_fat);
+        assert_eq!({ bpb.reserved_logical_sectors }, 32);
+        assert_eq!({ bpb.fs_information_sector_location }, 1);
+        assert_eq!({ bpb.backup_sector_location }, 6);
+        assert!(bpb.data_cluster_count() + 2 <= sectors_per_fat as u64 * 512 / 4);
+    }
+
+    let bpb = BiosParameterBlockBuilder::new(2097152 / 8).bytes_per_sector(4096).build().unwrap();
+    assert_eq!(bpb.logical_sectors_per_cluster, 1);
+
+    assert_matches!(BiosParameterBlockBuilder::new(65536).build(), Err(::vfat::Error::NotFat32));
+
+    // the reserved sectors have to hold the FSInfo and backup boot sectors
+    assert_eq!({ BiosParameterBlockBuilder::new(524288).reserved_sectors(7).build().unwrap().reserved_logical_sectors }, 7);
+    assert_matches!(BiosParameterBlockBuilder::new(524288).reserved_sectors(6).build(),
+                    Err(::vfat::Error::InvalidGeometry { field: "reserved_logical_sectors" }));
+    assert_matches!(BiosParameterBlockBuilder::new(524288).reserved_sectors(1).build(),
+                    Err(::vfat::Error::InvalidGeometry { .. }));
+}
+
+#[test]
+fn check_fat16_bpb() {
+    use byteorder::{LittleEndian, ByteOrder};
+    use vfat::FatKind;
+
+    let mut data = fat32_bpb_sector();
+    LittleEndian::write_u16(&mut data[14..16], 1);
+    LittleEndian::write_u16(&mut data[17..19], 512);
+    LittleEndian::write_u16(&mut data[19..21], 40000);
+    LittleEndian::write_u16(&mut data[22..24], 157);
+    LittleEndian::write_u32(&mut data[32..36], 0);
+    for byte in &mut data[36..90] {
+        *byte = 0;
+    }
+    assert_matches!(validate_bpb(&data), Err(::vfat::Error::NotFat32));
+    let bpb = BiosParameterBlock::read_from(&RefCell::from(Cursor::new(&mut data[..]))).unwrap();
+    assert_matches!(bpb.validate_any(), Ok(FatKind::Fat16));
+    assert_eq!(bpb.data_start_sector(), 1 + 2 * 157 + 32);
+
+    // a FAT16 layout with a FAT32 cluster count
+    let mut large = data.clone();
+    LittleEndian::write_u16(&mut large[19..21], 0);
+    LittleEndian::write_u32(&mut large[32..36], 200000);
+    let bpb = BiosParameterBlock::read_from(&RefCell::from(Cursor::new(&mut large[..]))).unwrap();
+    assert_matches!(bpb.validate_any(), Err(::vfat::Error::InvalidGeometry { field: "total_logical_sectors" }));
+
+    data.resize(40000 * 512, 0);
+    let device: Box<BlockDevice> = Box::new(RefCell::from(Cursor::new(data)));
+    let vfat = VFatFileSystem::from(device).expect("mount FAT16 volume");
+    assert_eq!(vfat.lock().fat_kind(), FatKind::Fat16);
+}
+
+#[test]
+fn check_foreign_fs_detected() {
+    use byteorder::{LittleEndian, ByteOrder};
+    use vfat::ForeignFs;
+
+    // exFAT: no BPB, the volume geometry follows from offset 0x40 on
+    let mut exfat = vec![0u8; 512];
+    exfat[..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+    exfat[3..11].copy_from_slice(b"EXFAT   ");
+    LittleEndian::write_u64(&mut exfat[0x48..0x50], 65536);
+    LittleEndian::write_u32(&mut exfat[0x50..0x54], 128);
+    LittleEndian::write_u32(&mut exfat[0x54..0x58], 64);
+    exfat[0x6c] = 9;
+    exfat[0x6d] = 3;
+    exfat[0x6e] = 1;
+    exfat[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    // NTFS: a BPB with zero reserved sectors and FATs
+    let mut ntfs = vec![0u8; 512];
+    ntfs[..3].copy_from_slice(&[0xEB, 0x52, 0x90]);
+    ntfs[3..11].copy_from_slice(b"NTFS    ");
+    LittleEndian::write_u16(&mut ntfs[11..13], 512);
+    ntfs[13] = 8;
+    ntfs[21] = 0xF8;
+    LittleEndian::write_u64(&mut ntfs[0x28..0x30], 65535);
+    LittleEndian::write_u64(&mut ntfs[0x30..0x38], 4);
+    ntfs[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    for &(ref sector, kind, message) in &[
+        (exfat, ForeignFs::ExFat, "the volume is formatted as exFAT, not FAT"),
+        (ntfs, ForeignFs::Ntfs, "the volume is formatted as NTFS, not FAT"),
+    ] {
+        let mut data = sector.clone();
+        let error = BiosParameterBlock::read_from(&RefCell::from(Cursor::new(&mut data[..]))).err().unwrap();
+        assert_matches!(error, ::vfat::Error::UnsupportedFilesystem(k) if k == kind);
+        assert_eq!(error.to_string(), message);
+        assert_matches!(::error::Error::from(error), ::error::Error::UnsupportedFs);
+
+        data.resize(65536 * 512, 0);
+        let device: Box<BlockDevice> = Box::new(RefCell::from(Cursor::new(data)));
+        assert_matches!(VFatFileSystem::from(device).err(), Some(::vfat::Error::UnsupportedFilesystem(k)) if k == kind);
+    }
+
+    // a FAT boot sector that only claims to be exFAT keeps its BPB
+    let mut data = fat32_bpb_sector();
+    data[3..11].copy_from_slice(b"EXFAT   ");
+    validate_bpb(&data).expect("valid FAT32 EBPB");
+}
+
+/// Builds a FAT12 or FAT16 image with one 512-byte sector per cluster and a
+/// 64-entry root directory holding `HELLO.TXT`, the fragmented `BIG.BIN`
+/// and the directory `DIR` with `INNER.TXT`. Returns the image and the path
+/// and contents of each file, sorted by path.
+fn legacy_fat_image(kind: ::vfat::FatKind) -> (Vec<u8>, Vec<(&'static str, Vec<u8>)>) {
+    use byteorder::{LittleEndian, ByteOrder};
+    use vfat::FatKind;
+
+    fn set_entry(fat: &mut [u8], kind: FatKind, cluster: usize, value: u16) {
+        if kind == FatKind::Fat16 {
+            LittleEndian::write_u16(&mut fat[cluster * 2..], value);
+        } else if cluster % 2 == 0 {
+            let offset = cluster * 3 / 2;
+            fat[offset] = value as u8;
+            fat[offset + 1] = (fat[offset + 1] & 0xF0) | ((value >> 8) as u8 & 0x0F);
+        } else {
+            let offset = cluster * 3 / 2;
+            fat[offset] = (fat[offset] & 0x0F) | ((value as u8 & 0x0F) << 4);
+            fat[offset + 1] = (value >> 4) as u8;
+        }
+    }
+
+    fn dir_entry(name: &[u8; 11], attributes: u8, cluster: u16, size: u32) -> [u8; 32] {
+        let mut entry = [0; 32];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        LittleEndian::write_u16(&mut entry[26..28], cluster);
+        LittleEndian::write_u32(&mut entry[28..32], size);
+        entry
+    }
+
+    let (total_sectors, fat_sectors, eoc, fs_type) = match kind {
+        FatKind::Fat12 => (2048u16, 6usize, 0xFFFu16, b"FAT12   "),
+        _ => (8192, 33, 0xFFFF, b"FAT16   "),
+    };
+    let fat_start = 512;
+    let root_start = fat_start + 2 * fat_sectors * 512;
+    let data_start = root_start + 64 * 32;
+    let mut image = vec![0u8; total_sectors as usize * 512];
+
+    {
+        let boot = &mut image[..512];
+        boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        boot[3..11].copy_from_slice(b"MSWIN4.1");
+        LittleEndian::write_u16(&mut boot[11..13], 512);
+        boot[13] = 1;
+        LittleEndian::write_u16(&mut boot[14..16], 1);
+        boot[16] = 2;
+        LittleEndian::write_u16(&mut boot[17..19], 64);
+        LittleEndian::write_u16(&mut boot[19..21], total_sectors);
+        boot[21] = 0xF8;
+        LittleEndian::write_u16(&mut boot[22..24], fat_sectors as u16);
+        boot[36] = 0x80;
+        boot[38] = 0x29;
+        LittleEndian::write_u32(&mut boot[39..43], 0x1234ABCD);
+        boot[43..54].copy_from_slice(b"NO NAME    ");
+        boot[54..62].copy_from_slice(fs_type);
+        boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+    }
+
+    let hello = b"hello, fat\n".to_vec();
+    let big: Vec<u8> = (0..3000).map(|i| (i * 7 % 251) as u8).collect();
+    let inner: Vec<u8> = (0..700).map(|i| b'a' + (i % 13) as u8).collect();
+
+    let chains: [(&[usize], &[u8]); 4] = [
+        (&[2][..], &hello[..]),
+        (&[3, 4, 6, 7, 8, 9][..], &big[..]),
+        (&[5][..], &[][..]),
+        (&[10, 11][..], &inner[..]),
+    ];
+    {
+        let (fat, mirror) = image[fat_start..root_start].split_at_mut(fat_sectors * 512);
+        set_entry(fat, kind, 0, 0xFFF8 & eoc);
+        set_entry(fat, kind, 1, eoc);
+        for &(clusters, _) in &chains {
+            for pair in clusters.windows(2) {
+                set_entry(fat, kind, pair[0], pair[1] as u16);
+            }
+            set_entry(fat, kind, clusters[clusters.len() - 1], eoc);
+        }
+        mirror.copy_from_slice(fat);
+    }
+    for &(clusters, data) in &chains {
+        for (&cluster, chunk) in clusters.iter().zip(data.chunks(512)) {
+            let offset = data_start + (cluster - 2) * 512;
+            image[offset..offset + chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    let root = [
+        dir_entry(b"FATTEST    ", 0x08, 0, 0),
+        dir_entry(b"HELLO   TXT", 0x20, 2, hello.len() as u32),
+        dir_entry(b"BIG     BIN", 0x20, 3, big.len() as u32),
+        dir_entry(b"DIR        ", 0x10, 5, 0),
+    ];
+    let dir = [
+        dir_entry(b".          ", 0x10, 5, 0),
+        dir_entry(b"..         ", 0x10, 0, 0),
+        dir_entry(b"INNER   TXT", 0x20, 10, inner.len() as u32),
+    ];
+    for (index, entry) in root.iter().enumerate() {
+        image[root_start + index * 32..root_start + (index + 1) * 32].copy_from_slice(entry);
+    }
+    for (index, entry) in dir.iter().enumerate() {
+        let offset = data_start + 3 * 512 + index * 32;
+        image[offset..offset + 32].copy_from_slice(entry);
+    }
+
+    (image, vec![("/BIG.BIN", big), ("/DIR/INNER.TXT", inner), ("/HELLO.TXT", hello)])
+}
+
+fn check_legacy_fat_volume(kind: ::vfat::FatKind) {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    let (image, files) = legacy_fat_image(kind);
+    let vfat = VFatFileSystem::from(Box::new(RefCell::from(Cursor::new(image)))).expect("mount image");
+    assert_eq!(vfat.lock().fat_kind(), kind);
+    assert!(vfat.lock().is_read_only());
+    assert_eq!(vfat.lock().layout().root_dir_cluster, 0);
+
+    let mut expected = String::new();
+    for &(path, ref data) in &files {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(data);
+        expected.push_str(&format!("{}: {}\n", path, hasher.finish()));
+    }
+    let hash = hash_files_recursive_from(vfat.clone(), "/");
+    assert_hash_eq(&format!("{:?} image", kind), &hash, &expected);
+
+    let mut data = Vec::new();
+    vfat.open_file("/DIR/INNER.TXT", FileOpenMode::Read).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data, files[1].1);
+    assert_eq!(vfat.open_dir("/DIR/..").unwrap().entries().unwrap().count().unwrap(), 3);
+    assert!(vfat.get_entry("/DIR/INNER.TXT").unwrap().is_file());
+
+    assert_matches!(fs_error(vfat.create_file("/NEW.TXT")), ::error::Error::UnsupportedFs);
+    assert_matches!(fs_error(vfat.create_dir("/NEWDIR")), ::error::Error::UnsupportedFs);
+    assert_matches!(fs_error(vfat.open_file("/HELLO.TXT", FileOpenMode::Write)), ::error::Error::UnsupportedFs);
+    assert_matches!(fs_error(vfat.rename("/HELLO.TXT", "/BYE.TXT")), ::error::Error::UnsupportedFs);
+    assert_matches!(fs_error(vfat.remove("/HELLO.TXT")), ::error::Error::UnsupportedFs);
+    let token = vfat.raw_write_token();
+    assert_matches!(fs_error(vfat.write_cluster_raw(2, &[0; 512], &token)), ::error::Error::UnsupportedFs);
+    vfat.sync().unwrap();
+
+    let hash = hash_files_recursive_from(vfat.clone(), "/");
+    assert_hash_eq(&format!("{:?} image after writes", kind), &hash, &expected);
+}
+
+#[test]
+fn fat12_volume_read_only() {
+    check_legacy_fat_volume(::vfat::FatKind::Fat12);
+}
+
+#[test]
+fn fat16_volume_read_only() {
+    check_legacy_fat_volume(::vfat::FatKind::Fat16);
+}
+
+#[test]
+fn check_entry_sizes() {
+    use vfat::dir::{VFatRegularDirEntry, VFatLfnDirEntry};
+
+    assert_serialized_len!(VFatRegularDirEntry::from_bytes(&[0; 32]), 32);
+    assert_serialized_len!(VFatLfnDirEntry::from_bytes(&[0; 32]), 32);
+    assert_serialized_len!(VFatDirEntry::new_free(), 32);
+}
+
+#[test]
+fn dir_entry_round_trip() {
+    use vfat::dir::{VFatRegularDirEntry, VFatLfnDirEntry};
+
+    assert_round_trip!(VFatRegularDirEntry, 32);
+    assert_round_trip!(VFatLfnDirEntry, 32);
+    assert_round_trip!(VFatDirEntry, 32);
+
+    // "a.txt" as an LFN entry, whose name is stored as little endian UTF-16
+    let mut buf = [0xFFu8; 32];
+    buf[..11].copy_from_slice(&[0x41, b'a', 0, b'.', 0, b't', 0, b'x', 0, b't', 0]);
+    buf[11..14].copy_from_slice(&[0x0F, 0x00, 0x9A]);
+    buf[14..16].copy_from_slice(&[0, 0]);
+    buf[26..28].copy_from_slice(&[0, 0]);
+    let entry = VFatDirEntry::from_bytes(&buf);
+    assert!(entry.is_lfn());
+    assert_eq!(&VFatDirEntry::from(entry.long_filename()).to_bytes()[..], &buf[..]);
+    assert_eq!(&VFatDirEntry::from(entry.regular()).to_bytes()[..], &buf[..]);
+}
+
+#[test]
+fn test_vfat_init() {
+    vfat_from_resource("mock1.fat32.img");
+    vfat_from_resource("mock2.fat32.img");
+    vfat_from_resource("mock3.fat32.img");
+    vfat_from_resource("mock4.fat32.img");
+}
+
+fn hash_entry<T: Entry>(hash: &mut String, entry: &T, sizes: bool) -> ::std::fmt::Result {
+    use std::fmt::Write;
+
+    fn write_bool(to: &mut String, b: bool, c: char) -> ::std::fmt::Result {
+        if b { write!(to, "{}", c) } else { write!(to, "-") }
+    }
+
+    fn write_timestamp(to: &mut String, ts: DateTime) -> ::std::fmt::Result {
+        write!(to, "{:02}/{:02}/{} {:02}:{:02}:{:02} ",
+               ts.month(), ts.day(), ts.year(), ts.hour(), ts.minute(), ts.second())
+    }
+
+    write_bool(hash, entry.is_dir(), 'd')?;
+    write_bool(hash, entry.is_file(), 'f')?;
+    write_bool(hash, entry.metadata().is_read_only(), 'r')?;
+    write_bool(hash, entry.metadata().is_hidden(), 'h')?;
+    write!(hash, "\t")?;
+
+    write_timestamp(hash, entry.metadata().created())?;
+    write_timestamp(hash, entry.metadata().modified())?;
+    // The expected hashes print the access date with a time of 00:00:00.
+    write_timestamp(hash, entry.metadata().accessed().and_hms(0, 0, 0))?;
+    write!(hash, "\t")?;
+
+    if sizes {
+        write!(hash, "{:>10}\t", entry.metadata().size())?;
+    }
+    write!(hash, "{}", entry.name())?;
+    Ok(())
+}
+
+fn hash_dir<T: Dir>(
+    hash: &mut String, dir: T, sizes: bool
+) -> Result<Vec<T::Entry>, ::std::fmt::Error> {
+    let entries_iter = dir.entries()
+        .expect("entries interator");
+    let mut entries = entries_iter.collect::<Vec<_>>().unwrap();
+
+    entries.sort_by(|a, b| a.name().cmp(b.name()));
+    for (i, entry) in entries.iter().enumerate() {
         if i != 0 { hash.push('\n'); }
-        hash_entry(hash, entry)?;
+        hash_entry(hash, entry, sizes)?;
     }
 
     Ok(entries)
@@ -252,7 +1433,14 @@ fn hash_dir<T: Dir>(
 
 fn hash_dir_from<P: AsRef<Path>>(vfat: ArcMutex<VFatFileSystem>, path: P) -> String {
     let mut hash = String::new();
-    hash_dir(&mut hash, vfat.open_dir(path).expect("directory exists")).unwrap();
+    hash_dir(&mut hash, vfat.open_dir(path).expect("directory exists"), false).unwrap();
+    hash
+}
+
+/// Like `hash_dir_from`, with the size of every entry in front of its name.
+fn hash_dir_sizes_from<P: AsRef<Path>>(vfat: ArcMutex<VFatFileSystem>, path: P) -> String {
+    let mut hash = String::new();
+    hash_dir(&mut hash, vfat.open_dir(path).expect("directory exists"), true).unwrap();
     hash
 }
 
@@ -271,6 +1459,20 @@ fn test_root_entries() {
     assert_hash_eq("mock 4 root directory", &hash, &hash_for("root-entries-4"));
 }
 
+#[test]
+fn test_entry_sizes() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let hash = hash_dir_sizes_from(vfat.clone(), "/rpi3-docs");
+    let entries = vfat.open_dir("/rpi3-docs").unwrap().collect_entries().unwrap();
+    assert!(entries.iter().any(|entry| entry.is_file()));
+    for entry in &entries {
+        let size = if entry.is_dir() { 0 } else { entry.open_file(FileOpenMode::Read).unwrap().size() };
+        assert_eq!(entry.metadata().size(), size);
+        assert!(hash.contains(&format!("{:>10}\t{}", size, entry.name())));
+    }
+    assert_ne!(vfat.get_entry("/rpi3-docs").unwrap().metadata().first_cluster(), 0);
+}
+
 fn hash_dir_recursive<P: AsRef<Path>>(
     hash: &mut String,
     vfat: ArcMutex<VFatFileSystem>,
@@ -282,7 +1484,7 @@ fn hash_dir_recursive<P: AsRef<Path>>(
     let dir = vfat.open_dir(path).expect("directory");
 
     write!(hash, "{}\n", path.display())?;
-    let entries = hash_dir(hash, dir)?;
+    let entries = hash_dir(hash, dir, false)?;
     hash.push_str("\n\n");
 
     for entry in entries {
@@ -301,6 +1503,31 @@ fn hash_dir_recursive_from<P: AsRef<Path>>(vfat: ArcMutex<VFatFileSystem>, path:
     hash
 }
 
+#[test]
+fn vfat_backup_boot_sector() {
+    let intact = hash_dir_recursive_from(vfat_from_resource("mock1.fat32.img"), "/");
+
+    let mut partition = load_partition("mock1.fat32.img");
+    partition.write_sector(0, &[0xA5; 512]).unwrap();
+    let vfat = VFatFileSystem::from(Box::new(partition)).expect("mount from the backup boot sector");
+    assert!(vfat.lock().boot_sector_damaged());
+    let hash = hash_dir_recursive_from(vfat.clone(), "/");
+    assert_hash_eq("mock 1 mounted from backup", &hash, &intact);
+
+    vfat.lock().repair_boot_sector().unwrap();
+    assert!(!vfat.lock().boot_sector_damaged());
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    assert!(!vfat.lock().boot_sector_damaged());
+    let hash = hash_dir_recursive_from(vfat, "/");
+    assert_hash_eq("mock 1 after boot sector repair", &hash, &intact);
+
+    // with both copies gone, the error for the primary is reported
+    let mut partition = load_partition("mock1.fat32.img");
+    partition.write_sector(0, &[0; 512]).unwrap();
+    partition.write_sector(6, &[0; 512]).unwrap();
+    assert_matches!(VFatFileSystem::from(Box::new(partition)).err(), Some(::vfat::Error::BadSignature));
+}
+
 #[test]
 fn test_all_dir_entries() {
     let hash = hash_dir_recursive_from(vfat_from_resource("mock1.fat32.img"), "/");
@@ -316,28 +1543,58 @@ fn test_all_dir_entries() {
     assert_hash_eq("mock 4 all dir entries", &hash, &hash_for("all-entries-4"));
 }
 
-fn hash_file<T: File>(hash: &mut String, mut file: T) -> ::std::fmt::Result {
+#[test]
+fn test_file_block_device() {
+    use FileBlockDevice;
+
+    for &(image, expected) in &[("mock1.fat32.img", "all-entries-1"), ("mock2.fat32.img", "all-entries-2")] {
+        let file = ::std::fs::File::open(resource_path(image)).expect("open resource");
+        let device = FileBlockDevice::new(file, 512).unwrap();
+        assert_eq!(device.num_sectors() * 512, device.len());
+        let mut buf = [0; 512];
+        assert!(device.read_sector(device.num_sectors(), &mut buf).is_err());
+
+        let partition = get_partition(device, 0).expect("get_partition failed");
+        let vfat = VFatFileSystem::from(Box::new(partition)).expect("mount image file");
+        let hash = hash_dir_recursive_from(vfat, "/");
+        assert_hash_eq(image, &hash, &hash_for(expected));
+    }
+}
+
+#[test]
+fn test_file_block_device_set_len() {
+    use std::fs::{self, OpenOptions};
+    use FileBlockDevice;
+
+    let path = ::std::env::temp_dir().join(format!("fat32-file-device-{}.img", ::std::process::id()));
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    let mut device = FileBlockDevice::new(file, 512).unwrap();
+    assert_eq!(device.num_sectors(), 0);
+
+    device.set_len(4 * 512 + 100).unwrap();
+    assert_eq!((device.len(), device.num_sectors()), (4 * 512 + 100, 4));
+    device.write_sector(3, &[0xAB; 512]).unwrap();
+    assert!(device.write_sector(4, &[0xAB; 512]).is_err());
+    let mut short = [0; 16];
+    assert_eq!(device.read_sector(3, &mut short).unwrap_err().kind(), ::std::io::ErrorKind::InvalidInput);
+    assert_eq!(device.write_sector(3, &short).unwrap_err().kind(), ::std::io::ErrorKind::InvalidInput);
+    let mut buf = [0; 512];
+    device.read_sector(3, &mut buf).unwrap();
+    assert_eq!(&buf[..], &[0xAB; 512][..]);
+
+    device.set_len(2 * 512).unwrap();
+    assert_eq!(device.num_sectors(), 2);
+    assert!(device.read_sector(3, &mut buf).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+fn hash_file(hash: &mut String, mut file: ::vfat::VFatFile) -> ::std::fmt::Result {
     use std::fmt::Write;
     use std::collections::hash_map::DefaultHasher;
     use std::hash::Hasher;
-    use tests::rand::distributions::{Sample, Range};
 
-    let mut rng = rand::thread_rng();
-    let mut range = Range::new(128, 8192);
     let mut hasher = DefaultHasher::new();
-
-    let mut bytes_read = 0;
-    loop {
-        let mut buffer = vec![0; range.sample(&mut rng)];
-        match file.read(&mut buffer) {
-            Ok(0) => break,
-            Ok(n) => {
-                hasher.write(&buffer[..n]);
-                bytes_read += n as u64;
-            }
-            Err(e) => panic!("failed to read file: {:?}", e)
-        }
-    }
+    let bytes_read = file.hash_contents(&mut hasher).expect("failed to read file");
 
     assert_eq!(bytes_read, file.size(),
         "expected to read {} bytes (file size) but read {}", file.size(), bytes_read);
@@ -382,6 +1639,42 @@ fn hash_files_recursive_from<P: AsRef<Path>>(vfat: ArcMutex<VFatFileSystem>, pat
     hash
 }
 
+#[test]
+fn vfat_file_read_chunks() {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let data: Vec<u8> = (0..40 * cluster_size + 123).map(|i| (i * 7 % 251) as u8).collect();
+    // A file in between makes the chain of the second one non-contiguous.
+    let mut file = vfat.create_file("/chunks.bin").unwrap();
+    file.write_all(&data[..3 * cluster_size]).unwrap();
+    vfat.create_file("/between.bin").unwrap().write_all(&[1; 10]).unwrap();
+    file.write_all(&data[3 * cluster_size..]).unwrap();
+    drop(file);
+
+    let mut file = vfat.open_file("/chunks.bin", FileOpenMode::Read).unwrap();
+    file.seek(SeekFrom::Start(1000)).unwrap();
+    let mut chunks = Vec::new();
+    let mut contents = Vec::new();
+    let size = file.read_chunks(|chunk| {
+        chunks.push(chunk.len());
+        contents.extend_from_slice(chunk);
+        Ok(())
+    }).unwrap();
+    assert_eq!(size, data.len() as u64);
+    assert!(contents == data);
+    assert!(chunks[..chunks.len() - 1].iter().all(|&len| len % cluster_size == 0 && len == chunks[0]), "{:?}", chunks);
+    assert!(file.at_end());
+
+    let mut expected = DefaultHasher::new();
+    expected.write(&data);
+    let mut hasher = DefaultHasher::new();
+    assert_eq!(file.hash_contents(&mut hasher).unwrap(), data.len() as u64);
+    assert_eq!(hasher.finish(), expected.finish());
+}
+
 #[test]
 fn test_mock1_files_recursive() {
     let hash = hash_files_recursive_from(vfat_from_resource("mock1.fat32.img"), "/");
@@ -412,6 +1705,100 @@ fn shared_fs_is_sync_send_static() {
     f::<ArcMutex<VFatFileSystem>>();
 }
 
+#[test]
+fn vfat_concurrent_reads() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let contents: Vec<Vec<u8>> = (0..4u32)
+        .map(|i| (0..20000 + i * 1000).map(|j| (i * 31 + j * 7) as u8).collect())
+        .collect();
+    for (i, data) in contents.iter().enumerate() {
+        vfat.create_file(format!("/thread{}.bin", i)).unwrap().write_all(data).unwrap();
+    }
+
+    let threads: Vec<_> = contents.into_iter().enumerate().map(|(i, data)| {
+        let vfat = vfat.clone();
+        ::std::thread::spawn(move || {
+            for _ in 0..20 {
+                let mut read_back = Vec::new();
+                let mut file = vfat.open_file(format!("/thread{}.bin", i), FileOpenMode::Read).unwrap();
+                file.read_to_end(&mut read_back).unwrap();
+                assert!(read_back == data, "contents of file {} differ", i);
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn vfat_file_io_while_fs_locked() {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let data: Vec<u8> = (0..20000u32).map(|i| (i * 13) as u8).collect();
+    vfat.create_file("/stream.bin").unwrap().write_all(&data).unwrap();
+    let mut reader = vfat.open_file("/stream.bin", FileOpenMode::Read).unwrap();
+    let mut writer = vfat.create_file("/copy.bin").unwrap();
+
+    // cluster I/O only locks the device, not the file system; it is still
+    // serialized with the I/O of other files at the device
+    let guard = vfat.lock();
+    let (sender, receiver) = mpsc::channel();
+    let thread = ::std::thread::spawn(move || {
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        writer.write_all(&contents).unwrap();
+        sender.send(contents).unwrap();
+        // closing the files writes back the size, which does lock it
+        (reader, writer)
+    });
+    let contents = receiver.recv_timeout(Duration::from_secs(10))
+        .expect("file I/O waited for the file system lock");
+    drop(guard);
+    drop(thread.join().unwrap());
+    assert!(contents == data);
+
+    let mut copy = Vec::new();
+    vfat.open_file("/copy.bin", FileOpenMode::Read).unwrap().read_to_end(&mut copy).unwrap();
+    assert!(copy == data);
+}
+
+#[test]
+fn vfat_dir_ops_while_fs_locked() {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let root = vfat.root().unwrap();
+    let metadata = new_metadata(&vfat, ::vfat::metadata::Attributes::new(false));
+
+    // A directory is locked before the file system, so that holding the file
+    // system while locking a directory can't deadlock with a thread that
+    // holds the directory. Directory operations never lock the file system.
+    let guard = vfat.lock();
+    let (sender, receiver) = mpsc::channel();
+    let thread = {
+        let root = root.clone();
+        ::std::thread::spawn(move || {
+            let names = root.names().unwrap();
+            let entry = root.find("rpi3-docs").unwrap();
+            let created = root.create_entry("created.txt", &metadata).unwrap();
+            let clone = created.clone();
+            sender.send(names).unwrap();
+            (entry, created, clone)
+        })
+    };
+    let names = receiver.recv_timeout(Duration::from_secs(10))
+        .expect("directory operations waited for the file system lock");
+    drop(root.0.lock());
+    drop(guard);
+    drop(thread.join().unwrap());
+    assert!(names.iter().any(|name| name == "rpi3-docs"));
+    vfat.get_entry("/created.txt").unwrap();
+}
+
 #[test]
 fn mbr_get_partition() {
     let device = load_partition("mock1.fat32.img");
@@ -443,18 +1830,227 @@ fn block_device_read_by_offset() {
     assert_eq!(buffer, bytes);
 }
 
+#[test]
+fn vfat_cluster_io_bounds() {
+    use byteorder::{LittleEndian, ByteOrder};
+    use tests::mock::SparseDevice;
+    use vfat::BiosParameterBlockBuilder;
+
+    fn assert_invalid(result: ::std::io::Result<()>) {
+        assert_matches!(fs_error(result), ::error::Error::InvalidInput { .. });
+    }
+
+    // 256 KiB clusters of 64 sectors of 4 KiB
+    let clusters = 66000;
+    let bpb = BiosParameterBlockBuilder::new(clusters * 64 + 1000).bytes_per_sector(4096)
+        .sectors_per_cluster(64).build().unwrap();
+    let mut device = SparseDevice::new((clusters as u64 * 64 + 1000) * 8);
+    bpb.write_to(&mut device, 0).unwrap();
+    let mut fat = [0u8; 12];
+    LittleEndian::write_u32(&mut fat[0..4], 0x0FFFFFF8);
+    LittleEndian::write_u32(&mut fat[4..8], 0x0FFFFFFF);
+    LittleEndian::write_u32(&mut fat[8..12], 0x0FFFFFFF);
+    for i in 0..2u64 {
+        let fat_start = { bpb.reserved_logical_sectors } as u64 + i * { bpb.logical_sectors_per_fat } as u64;
+        device.write_by_offset(fat_start * 4096, &fat).unwrap();
+    }
+
+    let options = ::vfat::MountOptions { disable_cache: true, ..::vfat::MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+    let mut vfat = vfat.lock();
+    let cluster_size = vfat.cluster_size_bytes();
+    assert_eq!(cluster_size, 256 * 1024);
+    let last_cluster = vfat.layout().cluster_count + 1;
+    let mut buf = vec![0; cluster_size as usize + 1];
+
+    vfat.write_cluster(last_cluster, cluster_size - 1, &[0xA5]).unwrap();
+    vfat.read_cluster(last_cluster, cluster_size - 1, &mut buf[..1]).unwrap();
+    assert_eq!(buf[0], 0xA5);
+    vfat.read_cluster(last_cluster, cluster_size, &mut buf[..0]).unwrap();
+    vfat.read_cluster(last_cluster, 0, &mut buf[..cluster_size as usize]).unwrap();
+    assert_invalid(vfat.read_cluster(last_cluster, cluster_size, &mut buf[..1]));
+    assert_invalid(vfat.read_cluster(last_cluster, 0, &mut buf));
+    assert_invalid(vfat.write_cluster(last_cluster, 1, &buf[..cluster_size as usize]));
+    // offsets that used to wrap around
+    assert_invalid(vfat.read_cluster(last_cluster, ::std::u32::MAX, &mut buf[..1]));
+    assert_invalid(vfat.write_cluster(last_cluster, ::std::u32::MAX - 1, &buf[..2]));
+    assert_invalid(vfat.read_cluster(2, ::std::u32::MAX, &mut buf[..0]));
+
+    assert_invalid(vfat.read_cluster(1, 0, &mut buf[..1]));
+    assert_matches!(fs_error(vfat.read_cluster(last_cluster + 1, 0, &mut buf[..1])),
+                    ::error::Error::Corrupted { .. });
+    assert_matches!(fs_error(vfat.write_cluster(::std::u32::MAX, 0, &buf[..1])),
+                    ::error::Error::Corrupted { .. });
+}
+
+#[test]
+fn vfat_layout() {
+    use tests::mock::SparseDevice;
+    use vfat::VolumeLayout;
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let layout = vfat.lock().layout();
+    assert_eq!(layout, VolumeLayout {
+        bytes_per_sector: 512,
+        sectors_per_cluster: 1,
+        reserved_sectors: 32,
+        fat_count: 2,
+        fat_start_sectors: vec![32, 571],
+        fat_sectors: 539,
+        data_start_sector: 1110,
+        cluster_count: 68890,
+        root_dir_cluster: 2,
+    });
+    assert_eq!(layout.cluster_size_bytes(), 512);
+    {
+        let vfat = vfat.lock();
+        let params = vfat.params();
+        assert_eq!(params.total_sectors(), 70000);
+        assert_eq!(params.data_start_sector(), layout.data_start_sector);
+        assert_eq!(params.data_cluster_count(), layout.cluster_count as u64);
+        assert_eq!(params.fs_information_sector_location, 1);
+        assert_eq!(params.backup_sector_location, 6);
+    }
+    assert_eq!(vfat.lock().cluster_to_device_offset(2), 1110 * 512);
+    assert_eq!(vfat.lock().cluster_to_device_offset(10), 1118 * 512);
+    let summary = layout.to_string();
+    assert!(summary.contains("FAT 1:         sectors 571..1110"));
+    assert!(summary.contains("data:          sectors 1110..70000 (68890 clusters)"));
+    assert!(summary.contains("cluster 2:     byte 0x8ac00"));
+}
+
+#[test]
+fn vfat_extreme_geometry() {
+    use vfat::VolumeLayout;
+    use vfat::fat::{fat_entry_count, FatEntry, Status};
+
+    // The largest FAT32 volume: 4K sectors, 32 KiB clusters and as many
+    // clusters as FAT32 allows, about 8 TiB.
+    let clusters = BiosParameterBlock::MAX_CLUSTER_COUNT;
+    let mut ebpb = BiosParameterBlock::from_bytes(&fat32_bpb_sector());
+    ebpb.bytes_per_logical_sector = 4096;
+    ebpb.logical_sectors_per_cluster = 8;
+    ebpb.logical_sectors_per_fat = (((clusters + 2) * 4 + 4095) / 4096) as u32;
+    ebpb.large_total_logical_sectors = (ebpb.data_start_sector() + clusters * 8) as u32;
+    ebpb.validate().unwrap();
+    assert!(ebpb.total_sectors() * 4096 > 8 << 40);
+    assert_eq!(ebpb.data_cluster_count(), clusters);
+    assert_eq!(fat_entry_count(&ebpb) as u64, clusters + 2);
+
+    let last_cluster = (clusters + 1) as u32;
+    assert_eq!(FatEntry(last_cluster).status(), Status::Data(last_cluster));
+    assert_eq!(FatEntry(last_cluster + 1).status(), Status::Reserved);
+
+    let layout = VolumeLayout {
+        bytes_per_sector: 4096,
+        sectors_per_cluster: 8,
+        reserved_sectors: 32,
+        fat_count: 2,
+        fat_start_sectors: vec![32, 32 + ebpb.logical_sectors_per_fat as u64],
+        fat_sectors: ebpb.logical_sectors_per_fat,
+        data_start_sector: ebpb.data_start_sector(),
+        cluster_count: clusters as u32,
+        root_dir_cluster: 2,
+    };
+    assert_eq!(layout.cluster_size_bytes(), 32 * 1024);
+    let end = ebpb.total_sectors() * 4096;
+    assert_eq!(layout.cluster_offset(last_cluster) + layout.cluster_size_bytes(), end);
+    assert_eq!(layout.cluster_offset(1 << 20), (ebpb.data_start_sector() + ((1 << 20) - 2) * 8) * 4096);
+
+    // 256 KiB clusters with a FAT far larger than cluster numbers can
+    // address: the entries past the last cluster number don't count.
+    ebpb.logical_sectors_per_cluster = 64;
+    ebpb.logical_sectors_per_fat = ::std::u32::MAX;
+    assert_eq!(fat_entry_count(&ebpb) as u64, clusters + 2);
+}
+
+#[test]
+fn vfat_64k_clusters() {
+    use tests::mock::SparseDevice;
+    use vfat::BiosParameterBlockBuilder;
+    use byteorder::{LittleEndian, ByteOrder};
+
+    let sectors = 70000 * 128;
+    let mut device = SparseDevice::new(sectors as u64);
+    let bpb = BiosParameterBlockBuilder::new(sectors).sectors_per_cluster(128).build().unwrap();
+    bpb.write_to(&mut device, 0).unwrap();
+    let mut fat = [0u8; 512];
+    LittleEndian::write_u32(&mut fat[0..4], 0x0FFFFFF8);
+    LittleEndian::write_u32(&mut fat[4..8], 0x0FFFFFFF);
+    LittleEndian::write_u32(&mut fat[8..12], 0x0FFFFFFF);
+    for i in 0..bpb.number_of_fats as u64 {
+        device.write_sector(bpb.reserved_logical_sectors as u64 + i * bpb.logical_sectors_per_fat as u64, &fat).unwrap();
+    }
+
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    assert_eq!(vfat.lock().layout().cluster_size_bytes(), 64 * 1024);
+    let data: Vec<u8> = (0..200 * 1024u32).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/big.bin").unwrap().write_all(&data).unwrap();
+    assert_eq!(read_file(&vfat, "/big.bin"), data);
+    let first_cluster = vfat.get_entry("/big.bin").unwrap().metadata().first_cluster();
+    assert_eq!(vfat.lock().fat().chain_length(first_cluster).unwrap(), 4);
+}
+
+#[test]
+fn vfat_raw_clusters() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+
+    // The root directory starts with the volume label.
+    let mut cluster = vec![0; 512];
+    vfat.read_cluster_raw(2, &mut cluster).unwrap();
+    assert_eq!(&cluster[..11], b"CS140E     ");
+    assert_eq!(cluster[11], 0x28);
+    let partition = load_partition("mock1.fat32.img");
+    let mut expected = vec![0; 512];
+    partition.read_by_offset(vfat.lock().layout().cluster_offset(2), &mut expected).unwrap();
+    assert_eq!(cluster, expected);
+
+    let cluster_count = vfat.lock().layout().cluster_count;
+    assert_matches!(fs_error(vfat.read_cluster_raw(1, &mut cluster)), ::error::Error::InvalidInput { .. });
+    assert_matches!(fs_error(vfat.read_cluster_raw(cluster_count + 2, &mut cluster)), ::error::Error::InvalidInput { .. });
+    assert!(vfat.read_cluster_raw(2, &mut vec![0; 513]).is_err());
+
+    // Patch the first cluster of a file and of the root directory.
+    vfat.create_file("/raw.txt").unwrap().write_all(&[b'a'; 600]).unwrap();
+    assert_eq!(vfat.open_dir("/").unwrap().find("raw.txt").unwrap().name(), "raw.txt");
+    let first_cluster = vfat.get_entry("/raw.txt").unwrap().metadata().first_cluster();
+    let token = vfat.raw_write_token();
+    vfat.write_cluster_raw(first_cluster, b"patched", &token).unwrap();
+    let mut data = read_file(&vfat, "/raw.txt");
+    assert_eq!(&data[..7], b"patched");
+    data[..7].copy_from_slice(b"aaaaaaa");
+    assert_eq!(data, vec![b'a'; 600]);
+
+    let root = vfat.open_dir("/").unwrap();
+    root.entries().unwrap().count().unwrap();
+    vfat.write_cluster_raw(2, &[0; 512], &token).unwrap();
+    assert_eq!(root.entries().unwrap().count().unwrap(), 0);
+}
+
 #[test]
 fn vfat_fields() {
     let vfat = vfat_from_resource("mock1.fat32.img");
     {
         let mut vfat = vfat.lock();
         assert_eq!(vfat.device.sector_size(), 512);
-        assert_eq!(vfat.bytes_per_sector, 512);
-        assert_eq!(vfat.sectors_per_cluster, 1);
-        assert_eq!(vfat.data_start_sector, 6082);
-        assert_eq!(vfat.root_dir_cluster, 2);
+        assert_eq!(vfat.params().bytes_per_logical_sector, 512);
+        assert_eq!(vfat.params().logical_sectors_per_cluster, 1);
+        assert_eq!(vfat.params().data_start_sector(), 6082);
+        assert_eq!(vfat.params().root_directory_cluster, 2);
         assert_eq!(vfat.cluster_size_bytes(), 512);
 
+        let layout = vfat.layout();
+        assert_eq!(layout.bytes_per_sector, 512);
+        assert_eq!(layout.sectors_per_cluster, 1);
+        assert_eq!(layout.data_start_sector, 6082);
+        assert_eq!(layout.root_dir_cluster, 2);
+        assert_eq!(layout.fat_start_sectors[0], layout.reserved_sectors as u64);
+        assert_eq!(layout.data_start_sector,
+                   layout.reserved_sectors as u64 + layout.fat_count as u64 * layout.fat_sectors as u64);
+        assert_eq!(vfat.cluster_to_device_offset(2), 6082 * 512);
+
         let mut buffer = [0; 16];
         vfat.read_cluster(2, 0, &mut buffer).unwrap();
         let first16 = [0x43, 0x53, 0x31, 0x34, 0x30, 0x45, 0x20, 0x20, 0x20, 0x20, 0x20, 0x28, 0x00, 0x00, 0x00, 0x00];
@@ -473,10 +2069,17 @@ fn vfat_fields() {
     assert_eq!(entry, Some(6));
 }
 
+fn open_chain(vfat: ArcMutex<VFatFileSystem>, first_cluster: u32, mode: LockMode) -> ClusterChain {
+    use vfat::LockKey;
+    use vfat::lock_manager::LockWait;
+
+    ClusterChain::open_with(vfat, first_cluster, LockKey::Cluster(first_cluster), mode, LockWait::NoWait).unwrap()
+}
+
 #[test]
 fn vfat_cluster_chain0() {
     let vfat = vfat_from_resource("mock1.fat32.img");
-    let mut chain = ClusterChain::open(vfat, 2, LockMode::Read).unwrap();
+    let mut chain = open_chain(vfat, 2, LockMode::Read);
 
     let mut buffer = [0; 4];
     let bytes = [0x43, 0x53, 0x31, 0x34, 0x30, 0x45, 0x20, 0x20, 0x20, 0x20, 0x20, 0x28, 0x00, 0x00, 0x00, 0x00];
@@ -493,7 +2096,7 @@ fn vfat_cluster_chain0() {
 #[test]
 fn vfat_cluster_chain1() {
     let vfat = vfat_from_resource("mock1.fat32.img");
-    let mut chain = ::vfat::cluster_chain::ClusterChain::open(vfat, 2, LockMode::Read).unwrap();
+    let mut chain = open_chain(vfat, 2, LockMode::Read);
 
     let mut buffer = [0; 512];
     chain.read_exact(&mut buffer).unwrap();
@@ -506,7 +2109,7 @@ fn vfat_cluster_chain1() {
 #[test]
 fn vfat_cluster_chain2() {
     let vfat = vfat_from_resource("mock1.fat32.img");
-    let mut chain = ::vfat::cluster_chain::ClusterChain::open(vfat, 2, LockMode::Read).unwrap();
+    let mut chain = open_chain(vfat, 2, LockMode::Read);
 
     let mut buffer = [0; 256];
     chain.read_exact(&mut buffer).unwrap();
@@ -523,7 +2126,7 @@ fn vfat_cluster_chain2() {
 #[test]
 fn vfat_cluster_chain3() {
     let vfat = vfat_from_resource("mock1.fat32.img");
-    let mut chain = ::vfat::cluster_chain::ClusterChain::open(vfat, 2, LockMode::Read).unwrap();
+    let mut chain = open_chain(vfat, 2, LockMode::Read);
 
     let mut buffer = [0; 500];
     chain.read_exact(&mut buffer).unwrap();
@@ -540,7 +2143,7 @@ fn vfat_cluster_chain3() {
 #[test]
 fn vfat_cluster_chain4() {
     let vfat = vfat_from_resource("mock1.fat32.img");
-    let mut chain = ::vfat::cluster_chain::ClusterChain::open(vfat, 2, LockMode::Read).unwrap();
+    let mut chain = open_chain(vfat, 2, LockMode::Read);
 
     let mut buffer = [0; 500];
     chain.read_exact(&mut buffer).unwrap();
@@ -552,7 +2155,7 @@ fn vfat_cluster_chain4() {
 #[test]
 fn vfat_cluster_chain5() {
     let vfat = vfat_from_resource("mock1.fat32.img");
-    let mut chain = ::vfat::cluster_chain::ClusterChain::open(vfat, 5, LockMode::Read).unwrap();
+    let mut chain = open_chain(vfat, 5, LockMode::Read);
 
     let mut buffer = [0; 600];
     chain.read_exact(&mut buffer).unwrap();
@@ -564,12 +2167,110 @@ fn vfat_cluster_chain5() {
     assert_eq!(buffer[512..512+16], bytes);
 }
 
+#[test]
+fn vfat_cluster_chain_write_empty() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let fat = vfat.lock().fat();
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let free = fat.free_cluster_count().unwrap();
+
+    // cluster by cluster
+    let mut chain = open_chain(vfat.clone(), 0, LockMode::Write);
+    assert!(chain.at_end());
+    chain.write_all(&[1; 100]).unwrap();
+    assert_ne!(chain.first_cluster, 0);
+    assert_eq!(fat.chain_length(chain.first_cluster).unwrap(), 1);
+    chain.write_all(&vec![2; cluster_size]).unwrap();
+    assert_eq!(fat.chain_length(chain.first_cluster).unwrap(), 2);
+    let mut data = vec![0; 100 + cluster_size];
+    chain.seek(SeekFrom::Start(0)).unwrap();
+    chain.read_exact(&mut data).unwrap();
+    assert_eq!(&data[..100], &[1; 100][..]);
+    assert_eq!(&data[100..], &vec![2; cluster_size][..]);
+    drop(chain);
+
+    // in runs of whole clusters
+    let data: Vec<u8> = (0..3 * cluster_size + 10).map(|i| (i % 251) as u8).collect();
+    let mut chain = open_chain(vfat.clone(), 0, LockMode::Write);
+    chain.write_all(&data).unwrap();
+    assert_eq!(fat.chain_length(chain.first_cluster).unwrap(), 4);
+    let mut read_back = Vec::new();
+    chain.seek(SeekFrom::Start(0)).unwrap();
+    chain.read_to_end(&mut read_back).unwrap();
+    assert_eq!(&read_back[..data.len()], &data[..]);
+    assert_eq!(fat.free_cluster_count().unwrap(), free - 6);
+}
+
+#[test]
+fn vfat_file_without_clusters() {
+    let vfat = deferred_delete_vfat();
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    for path in &["/empty.txt", "/removed.txt"] {
+        vfat.create_file(path).unwrap();
+        let entry = vfat.get_entry(path).unwrap();
+        vfat.lock().fat().free_chain(entry.metadata.first_cluster).unwrap();
+        edit_raw_entry(&entry.dir, entry.dir_entry_index_range.end, |bytes| {
+            bytes[20..22].copy_from_slice(&[0, 0]);
+            bytes[26..28].copy_from_slice(&[0, 0]);
+        });
+    }
+    let free = vfat.lock().fs_info().free_count;
+    assert_eq!(vfat.get_entry("/empty.txt").unwrap().metadata.first_cluster, 0);
+    assert_eq!(read_file(&vfat, "/empty.txt"), b"");
+
+    // The first write gives the file a chain, which the entry points to once
+    // the file is flushed.
+    let mut file = vfat.open_file("/empty.txt", FileOpenMode::Write).unwrap();
+    file.write_all(b"hello").unwrap();
+    drop(file);
+    assert_ne!(vfat.get_entry("/empty.txt").unwrap().metadata.first_cluster, 0);
+    assert_eq!(read_file(&vfat, "/empty.txt"), b"hello");
+    assert_eq!(vfat.lock().fs_info().free_count, free - 1);
+
+    // The chain of a file removed while open is freed when it is closed.
+    let mut file = vfat.open_file("/removed.txt", FileOpenMode::Write).unwrap();
+    vfat.remove("/removed.txt").unwrap();
+    file.write_all(&vec![3; 3 * cluster_size]).unwrap();
+    drop(file);
+    assert_eq!(vfat.lock().fs_info().free_count, free - 1);
+    assert!(vfat.check().unwrap().is_clean());
+}
+
+#[test]
+fn vfat_file_without_clusters_stays_locked() {
+    use tests::mock::SparseDevice;
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    vfat.create_file("/empty.txt").unwrap();
+    let entry = vfat.get_entry("/empty.txt").unwrap();
+    vfat.lock().fat().free_chain(entry.metadata.first_cluster).unwrap();
+    edit_raw_entry(&entry.dir, entry.dir_entry_index_range.end, |bytes| {
+        bytes[20..22].copy_from_slice(&[0, 0]);
+        bytes[26..28].copy_from_slice(&[0, 0]);
+    });
+    drop(entry);
+
+    // Once the entry points to the chain of the first write, listings key
+    // the file by its first cluster, which the open file has to lock too.
+    let mut file = vfat.open_file("/empty.txt", FileOpenMode::Write).unwrap();
+    file.write_all(b"hello").unwrap();
+    file.flush().unwrap();
+    assert_ne!(vfat.get_entry("/empty.txt").unwrap().metadata.first_cluster, 0);
+    assert_matches!(fs_error(vfat.open_file("/empty.txt", FileOpenMode::Write)), ::error::Error::Busy);
+    assert_matches!(fs_error(vfat.open_file("/empty.txt", FileOpenMode::Read)), ::error::Error::Busy);
+    drop(file);
+    assert_eq!(read_file(&vfat, "/empty.txt"), b"hello");
+    assert!(vfat.lock().lock_manager().snapshot().is_empty());
+}
+
 #[test]
 fn vfat_file_write_read_only() {
     let file_path = "/rpi3-docs/RPi3-Schematics.pdf";
     let vfat = vfat_from_resource("mock1.fat32.img");
     let mut file = vfat.open_file(file_path, FileOpenMode::Read).unwrap();
-    file.write_all(&[1, 2, 3]).unwrap_err();
+    assert_matches!(fs_error(file.write_all(&[1, 2, 3])), ::error::Error::NotOpenForWriting);
 }
 
 #[test]
@@ -581,7 +2282,7 @@ fn vfat_file_write1() {
         //file.seek(SeekFrom::End(0)).unwrap();
         file.write_all(&[1, 2, 3]).unwrap();
     }
-    let partition = vfat.into_block_device();
+    let partition = vfat.try_unmount().unwrap();
     let vfat = VFatFileSystem::from(partition).unwrap();
     let mut file = vfat.open_file(file_path, FileOpenMode::Read).unwrap();
 
@@ -603,7 +2304,7 @@ fn vfat_file_write2() {
         file.write_all(&[1, 2, 3]).unwrap();
         assert_eq!(file.size(), 76738);
     }
-    let partition = vfat.into_block_device();
+    let partition = vfat.try_unmount().unwrap();
     let vfat = VFatFileSystem::from(partition).unwrap();
     let mut file = vfat.open_file(file_path, FileOpenMode::Read).unwrap();
     assert_eq!(file.size(), 76738);
@@ -623,7 +2324,7 @@ fn vfat_remove_file() {
 
     vfat.remove(file_path).unwrap();
 
-    assert!(vfat.open_file(file_path, FileOpenMode::Read).is_err());
+    assert_matches!(fs_error(vfat.open_file(file_path, FileOpenMode::Read)), ::error::Error::NotFound);
 }
 
 #[test]
@@ -634,7 +2335,7 @@ fn vfat_remove_file2() {
     vfat.remove(file_path).unwrap();
 
     // Remount
-    let partition = vfat.into_block_device();
+    let partition = vfat.try_unmount().unwrap();
     let vfat = VFatFileSystem::from(partition).unwrap();
 
     assert!(vfat.open_file(file_path, FileOpenMode::Read).is_err());
@@ -646,7 +2347,43 @@ fn vfat_remove_file_fail() {
     let vfat = vfat_from_resource("mock1.fat32.img");
     let _file = vfat.open_file(file_path, FileOpenMode::Read).unwrap();
 
-    assert!(vfat.remove(file_path).is_err());
+    assert_matches!(fs_error(vfat.remove(file_path)), ::error::Error::Busy);
+}
+
+#[test]
+fn vfat_remove_dir_reused_cluster() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    vfat.create_dir("/old").unwrap();
+    vfat.create_file("/old/inner.txt").unwrap().write_all(b"old").unwrap();
+    let old_cluster = vfat.get_entry("/old").unwrap().metadata.first_cluster;
+    assert_eq!(vfat.open_dir("/old").unwrap().names().unwrap(), vec!["inner.txt"]);
+    assert!(vfat.lock().is_dir_cached(old_cluster));
+    vfat.remove("/old/inner.txt").unwrap();
+    vfat.remove("/old").unwrap();
+    assert!(!vfat.lock().is_dir_cached(old_cluster));
+
+    // Directories are created until one gets the cluster of the removed one.
+    let mut new_dir = None;
+    for i in 0..10 {
+        let path = format!("/new{}", i);
+        vfat.create_dir(&path).unwrap();
+        if vfat.get_entry(&path).unwrap().metadata.first_cluster == old_cluster {
+            new_dir = Some(path);
+            break;
+        }
+    }
+    let new_dir = new_dir.expect("the cluster of the removed directory wasn't reused");
+    vfat.create_file(format!("{}/new.txt", new_dir)).unwrap();
+    assert_eq!(vfat.open_dir(&new_dir).unwrap().names().unwrap(), vec!["new.txt"]);
+    assert!(vfat.get_entry("/old").is_err());
+
+    // A directory that is moved keeps its cluster.
+    vfat.remove(format!("{}/new.txt", new_dir)).unwrap();
+    vfat.rename(&new_dir, "/moved").unwrap();
+    assert!(!vfat.lock().is_dir_cached(old_cluster));
+    let moved = vfat.open_dir("/moved").unwrap();
+    assert!(moved.names().unwrap().is_empty());
+    assert_eq!(moved.entry().unwrap().name(), "moved");
 }
 
 #[test]
@@ -654,7 +2391,7 @@ fn vfat_remove_dir_fail() {
     let dir_path = "/rpi3-docs";
     let vfat = vfat_from_resource("mock1.fat32.img");
 
-    assert!(vfat.remove(dir_path).is_err());
+    assert_matches!(fs_error(vfat.remove(dir_path)), ::error::Error::DirectoryNotEmpty);
 }
 
 #[test]
@@ -667,6 +2404,139 @@ fn vfat_remove_dir() {
     vfat.remove_dir_recursively(dir).unwrap();
 }
 
+#[test]
+fn vfat_remove_created_tree() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let free = vfat.lock().fs_info().free_count;
+    vfat.create_dir_all("/tree/a/b/c").unwrap();
+    vfat.create_dir_all("/tree/a/d").unwrap();
+    vfat.create_dir("/tree/e").unwrap();
+    for path in &["/tree/file.txt", "/tree/a/file.txt", "/tree/a/b/c/file.txt", "/tree/a/d/a long file name.txt"] {
+        vfat.create_file(path).unwrap().write_all(&[1; 5000]).unwrap();
+    }
+    // The dot entries are there to be skipped.
+    let dir = vfat.open_dir("/tree/a/b").unwrap();
+    let raw_names: Vec<String> = RawDirIterator { dir: &mut dir.0.lock(), raw_index: 0 }
+        .filter(|&(_, ref entry)| entry.is_regular())
+        .and_then(|(_, entry)| entry.regular().short_name())
+        .collect().unwrap();
+    assert_eq!(&raw_names[..2], &[".", ".."]);
+    drop(dir);
+
+    vfat.remove_dir_recursively(vfat.open_dir("/tree").unwrap()).unwrap();
+    assert!(vfat.get_entry("/tree").is_err());
+    assert_eq!(vfat.lock().fs_info().free_count, free);
+    assert!(vfat.check().unwrap().is_clean());
+}
+
+#[test]
+fn vfat_remove_tree_with_open_file() {
+    use vfat::error::is_busy;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let free = vfat.lock().fs_info().free_count;
+    vfat.create_dir_all("/tree/a/b/c").unwrap();
+    vfat.create_dir("/tree/d").unwrap();
+    let files = ["/tree/file.txt", "/tree/a/file.txt", "/tree/a/b/c/deep.txt", "/tree/d/file.txt"];
+    for path in &files {
+        vfat.create_file(path).unwrap().write_all(&[1; 5000]).unwrap();
+    }
+
+    let open = vfat.open_file("/tree/a/b/c/deep.txt", FileOpenMode::Read).unwrap();
+    let error = vfat.remove_dir_recursively(vfat.open_dir("/tree").unwrap()).unwrap_err();
+    assert!(is_busy(&error));
+    assert_matches!(::error::Error::from(error), ::error::Error::BusyEntry { ref path } if path == "/tree/a/b/c/deep.txt");
+    // nothing was removed, and the tree is usable again
+    for path in &files {
+        assert_eq!(vfat.get_entry(path).unwrap().metadata().size(), 5000);
+    }
+    assert!(vfat.open_dir("/tree/d").unwrap().entries().unwrap().count().unwrap() > 0);
+    assert!(vfat.check().unwrap().is_clean());
+
+    // a directory that is open elsewhere is in use as well
+    let open_dir = vfat.open_dir("/tree/d").unwrap();
+    drop(open);
+    let error = vfat.remove_dir_recursively(vfat.open_dir("/tree").unwrap()).unwrap_err();
+    assert_matches!(::error::Error::from(error), ::error::Error::BusyEntry { ref path } if path == "/tree/d");
+    assert!(vfat.get_entry("/tree/a/b/c/deep.txt").is_ok());
+    drop(open_dir);
+
+    vfat.remove_dir_recursively(vfat.open_dir("/tree").unwrap()).unwrap();
+    assert!(vfat.get_entry("/tree").is_err());
+    assert_eq!(vfat.lock().fs_info().free_count, free);
+    assert!(vfat.check().unwrap().is_clean());
+}
+
+fn device_contains(device: Box<BlockDevice>, pattern: &[u8]) -> bool {
+    let mut bytes = vec![0; (device.num_sectors() * device.sector_size()) as usize];
+    for (sector, buf) in bytes.chunks_mut(device.sector_size() as usize).enumerate() {
+        device.read_sector(sector as u64, buf).unwrap();
+    }
+    bytes.windows(pattern.len()).any(|window| window == pattern)
+}
+
+#[test]
+fn vfat_remove_secure() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let secret = b"hunter2-not-for-your-eyes";
+    let data: Vec<u8> = secret.iter().cloned().cycle().take(3 * cluster_size).collect();
+    vfat.create_file("/aws credentials.txt").unwrap().write_all(&data).unwrap();
+    let entry = vfat.get_entry("/aws credentials.txt").unwrap();
+    let (dir, range) = (entry.dir.clone(), entry.dir_entry_index_range.clone());
+    drop(entry);
+
+    vfat.remove_secure("/aws credentials.txt").unwrap();
+    assert_matches!(fs_error(vfat.remove_secure("/aws credentials.txt")), ::error::Error::NotFound);
+    for index in range {
+        let raw = dir.0.lock().get_raw_entry(index).unwrap().unwrap();
+        assert_eq!(&raw.to_bytes()[..], &VFatDirEntry::new_free().to_bytes()[..]);
+    }
+    drop(dir);
+
+    let device = vfat.try_unmount().unwrap();
+    let vfat = VFatFileSystem::from(device).unwrap();
+    assert!(vfat.check().unwrap().is_clean());
+    let device = vfat.try_unmount().unwrap();
+    assert!(!device_contains(device, secret));
+}
+
+#[test]
+fn vfat_remove_plain_leaves_data() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let plain = b"nothing-to-hide-here";
+    vfat.create_file("/plain.txt").unwrap().write_all(plain).unwrap();
+    vfat.remove("/plain.txt").unwrap();
+    assert!(device_contains(vfat.try_unmount().unwrap(), plain));
+}
+
+#[test]
+fn vfat_remove_secure_bad_cluster() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let mut data = Vec::new();
+    for pattern in &[b"first-cluster-secret", b"bad-cluster-contents"] {
+        data.extend(pattern.iter().cloned().cycle().take(cluster_size));
+    }
+    vfat.create_file("/secret.bin").unwrap().write_all(&data).unwrap();
+    vfat.create_file("/empty.bin").unwrap();
+
+    // the second cluster went bad after it was written
+    let mut fat = vfat.lock().fat();
+    let first = vfat.get_entry("/secret.bin").unwrap().metadata.first_cluster;
+    let second = fat.get_next_in_chain(first).unwrap().unwrap();
+    fat.mark_bad(second).unwrap();
+    let bad_clusters = fat.bad_cluster_count().unwrap();
+    drop(fat);
+
+    vfat.remove_secure("/secret.bin").unwrap();
+    vfat.remove_secure("/empty.bin").unwrap();
+    assert_eq!(vfat.lock().fat().bad_cluster_count().unwrap(), bad_clusters);
+    assert!(vfat.get_entry("/empty.bin").is_err());
+    let device = vfat.try_unmount().unwrap();
+    assert!(!device_contains(device, b"first-cluster-secret"));
+}
+
 #[test]
 fn vfat_create_file() {
     let file_path = "/rpi3-docs/test.txt";
@@ -679,7 +2549,7 @@ fn vfat_create_file() {
     drop(file);
 
     // Remount
-    let partition = vfat.into_block_device();
+    let partition = vfat.try_unmount().unwrap();
     let vfat = VFatFileSystem::from(partition).unwrap();
 
     let mut file = vfat.open_file(file_path, FileOpenMode::Read).unwrap();
@@ -696,7 +2566,7 @@ fn vfat_create_last_entry() {
 
     assert_eq!(RawDirIterator { dir: &mut dir.0.lock(), raw_index: 0}.count().unwrap(), 10);
 
-    let garbage: VFatDirEntry = unsafe { ::std::mem::transmute([0x42u8; VFatDirEntry::SIZE]) };
+    let garbage = VFatDirEntry::from_bytes(&[0x42u8; VFatDirEntry::SIZE]);
     for i in 11..16 {
         dir.0.lock().set_raw_entry(i, &garbage).unwrap();
     }
@@ -725,7 +2595,7 @@ fn vfat_create_dir_and_file() {
     drop(file);
 
     // Remount
-    let partition = vfat.into_block_device();
+    let partition = vfat.try_unmount().unwrap();
     let vfat = VFatFileSystem::from(partition).unwrap();
 
     let mut file = vfat.open_file(file_path, FileOpenMode::Read).unwrap();
@@ -734,6 +2604,100 @@ fn vfat_create_dir_and_file() {
     assert_eq!(buf, bytes);
 }
 
+#[test]
+fn vfat_entry_refresh() {
+    use vfat::MountOptions;
+
+    let options = MountOptions { deferred_delete: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(load_partition("mock1.fat32.img")), options).unwrap();
+    vfat.create_file("/notes.txt").unwrap();
+    let mut entry = vfat.get_entry("/notes.txt").unwrap();
+    assert_eq!(entry.metadata().size, 0);
+    assert!(!entry.has_changed().unwrap());
+
+    let mut file = vfat.open_file("/notes.txt", FileOpenMode::Write).unwrap();
+    file.write_all(&[7; 1500]).unwrap();
+    file.flush().unwrap();
+    drop(file);
+    assert_eq!(entry.metadata().size, 0);
+    assert!(entry.has_changed().unwrap());
+    entry.refresh().unwrap();
+    assert_eq!(entry.metadata().size, 1500);
+    assert_ne!(entry.metadata().first_cluster, 0);
+    assert!(!entry.has_changed().unwrap());
+    let mut contents = Vec::new();
+    entry.open_file(FileOpenMode::Read).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, vec![7; 1500]);
+
+    // the slot is free once the file is removed
+    vfat.remove("/notes.txt").unwrap();
+    assert_matches!(fs_error(entry.refresh()), ::error::Error::NotFound);
+    assert_matches!(fs_error(entry.has_changed()), ::error::Error::NotFound);
+    assert_eq!(entry.metadata().size, 1500);
+}
+
+#[test]
+fn vfat_weird_paths() {
+    use std::ffi::OsString;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let mut paths: Vec<OsString> = ["", "/", "//", "/a/..", "/a/.", "/..", "/./", "/a/", "a", "../a",
+                                    "/hello.txt/", "/hello.txt/..", "/nul\0name", "/rpi3-docs//", "///x"]
+        .iter().map(OsString::from).collect();
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        paths.push(OsString::from_vec(b"/bad\xFFname".to_vec()));
+        paths.push(OsString::from_vec(b"/rpi3-docs/\xC0\x80".to_vec()));
+    }
+    // none of these may panic, whatever they return
+    for path in &paths {
+        let _ = vfat.get_entry(path);
+        let _ = vfat.open_file(path, FileOpenMode::Read);
+        let _ = vfat.open_dir(path);
+        let _ = vfat.create_file(path);
+        let _ = vfat.create_dir(path);
+        let _ = vfat.create_dir_all(path);
+        let _ = vfat.rename(path, "/renamed");
+        let _ = vfat.rename("/renamed", path);
+        let _ = vfat.remove(path);
+    }
+
+    let invalid_component = |result: ::std::io::Result<()>| match fs_error(result) {
+        ::error::Error::PathInvalid { component, .. } => component,
+        e => panic!("unexpected error {:?}", e),
+    };
+    assert_eq!(invalid_component(vfat.create_file("/a/..").map(|_| ())), "..");
+    assert_eq!(invalid_component(vfat.create_dir("/rpi3-docs/..").map(|_| ())), "..");
+    assert_eq!(vfat.get_entry("/rpi3-docs/../hello.txt").unwrap().name(), vfat.get_entry("/hello.txt").unwrap().name());
+    assert_eq!(invalid_component(vfat.create_file("/nul\0name").map(|_| ())), "nul\0name");
+    assert_eq!(invalid_component(vfat.rename("/hello.txt", "/..")), "..");
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(::std::ffi::OsStr::from_bytes(b"/rpi3-docs/bad\xFFname"));
+        let error = vfat.open_file(path, FileOpenMode::Read).err().unwrap();
+        assert_eq!(error.to_string(), "invalid path component \"bad\u{FFFD}name\": not valid UTF-8");
+    }
+    assert_matches!(fs_error(vfat.get_entry("/")), ::error::Error::InvalidInput { .. });
+    assert_matches!(fs_error(vfat.remove("//")), ::error::Error::InvalidInput { .. });
+    assert_matches!(fs_error(vfat.create_dir("/")), ::error::Error::AlreadyExists);
+    assert_matches!(fs_error(vfat.create_file("relative")), ::error::Error::InvalidInput { .. });
+
+    // a trailing separator refers to a directory, as with std::fs
+    vfat.create_dir("/newdir/").unwrap();
+    assert!(vfat.get_entry("/newdir/").unwrap().is_dir());
+    vfat.rename("/newdir/", "/moved/").unwrap();
+    vfat.create_file("/moved//inner.txt").unwrap();
+    assert!(vfat.open_dir("/moved/").is_ok());
+    assert_matches!(fs_error(vfat.create_file("/newfile/")), ::error::Error::IsADirectory);
+    assert_matches!(fs_error(vfat.get_entry("/newfile")), ::error::Error::NotFound);
+    assert_matches!(fs_error(vfat.open_file("/hello.txt/", FileOpenMode::Read)), ::error::Error::NotADirectory);
+    assert_matches!(fs_error(vfat.rename("/hello.txt", "/moved.txt/")), ::error::Error::NotADirectory);
+    vfat.open_file("/moved/inner.txt", FileOpenMode::Read).unwrap();
+    assert_eq!(vfat.check().unwrap().findings, vec![]);
+}
+
 #[test]
 fn vfat_rename_file() {
     let file_path = "/rpi3-docs/RPi3-Schematics.pdf";
@@ -744,7 +2708,7 @@ fn vfat_rename_file() {
     vfat.rename(file_path, new_file_path).unwrap();
 
     // Remount
-    let partition = vfat.into_block_device();
+    let partition = vfat.try_unmount().unwrap();
     let vfat = VFatFileSystem::from(partition).unwrap();
 
     assert!(vfat.open_file(file_path, FileOpenMode::Read).is_err());
@@ -758,11 +2722,65 @@ fn vfat_rename_file() {
 }
 
 #[test]
-fn vfat_create_file_twice() {
-    let file_path = "/rpi3-docs/RPi3-Schematics.pdf";
+fn vfat_rename_populated_dir() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    vfat.create_dir_all("/from/tree/sub").unwrap();
+    vfat.create_dir("/to").unwrap();
+    vfat.create_file("/from/tree/file.txt").unwrap().write_all(b"moved along").unwrap();
+    vfat.create_file("/from/tree/sub/inner.txt").unwrap();
+
+    vfat.rename("/from/tree", "/to/tree").unwrap();
+    assert_matches!(fs_error(vfat.get_entry("/from/tree")), ::error::Error::NotFound);
+    assert_eq!(vfat.open_dir("/to/tree").unwrap().names().unwrap(), vec!["sub", "file.txt"]);
+    assert_eq!(vfat.open_dir("/to/tree/sub").unwrap().names().unwrap(), vec!["inner.txt"]);
+    let mut contents = String::new();
+    vfat.open_file("/to/tree/file.txt", FileOpenMode::Read).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "moved along");
+
+    // ".." of the moved directory points to its new parent.
+    let to_cluster = vfat.get_entry("/to").unwrap().metadata.first_cluster;
+    assert_eq!(vfat.get_entry("/to/tree/..").unwrap().metadata.first_cluster, to_cluster);
+    let tree = vfat.open_dir("/to/tree").unwrap();
+    let dotdot = tree.0.lock().get_raw_entry(1).unwrap().unwrap().regular();
+    assert_eq!(dotdot.first_cluster(), to_cluster);
+    drop(tree);
+
+    // Moving to the root points ".." to cluster 0.
+    vfat.rename("/to/tree", "/tree").unwrap();
+    let tree = vfat.open_dir("/tree").unwrap();
+    assert_eq!(tree.0.lock().get_raw_entry(1).unwrap().unwrap().regular().first_cluster(), 0);
+    drop(tree);
+    assert_eq!(vfat.check().unwrap().findings, vec![]);
+
+    // A populated directory in use can't be moved.
+    let file = vfat.open_file("/tree/file.txt", FileOpenMode::Read).unwrap();
+    assert_matches!(fs_error(vfat.rename("/tree", "/to/tree")), ::error::Error::Busy);
+    drop(file);
+}
+
+#[test]
+fn vfat_rename_dir_into_own_subtree() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    vfat.create_dir_all("/a/b/c").unwrap();
+
+    assert_matches!(fs_error(vfat.rename("/a", "/a/b/c/a")), ::error::Error::InvalidInput { .. });
+    assert_matches!(fs_error(vfat.rename("/a", "/a/a")), ::error::Error::InvalidInput { .. });
+    assert_matches!(fs_error(vfat.rename("/a/b", "/a/b/c/b")), ::error::Error::InvalidInput { .. });
+    assert!(vfat.get_entry("/a/b/c").unwrap().is_dir());
+    assert_eq!(vfat.open_dir("/a/b").unwrap().names().unwrap(), vec!["c"]);
+
+    // Moving up out of the source is fine.
+    vfat.rename("/a/b/c", "/c").unwrap();
+    assert!(vfat.open_dir("/a/b").unwrap().names().unwrap().is_empty());
+    assert_eq!(vfat.check().unwrap().findings, vec![]);
+}
+
+#[test]
+fn vfat_create_file_twice() {
+    let file_path = "/rpi3-docs/RPi3-Schematics.pdf";
     let vfat = vfat_from_resource("mock1.fat32.img");
 
-    assert!(vfat.create_file(file_path).is_err());
+    assert_matches!(fs_error(vfat.create_file(file_path)), ::error::Error::AlreadyExists);
 }
 
 #[test]
@@ -775,3 +2793,3473 @@ fn test_root_entries_after_create() {
     let hash = hash_dir_from(vfat, "/");
     assert_hash_eq("mock 1 root directory", &hash, &hash_for("root-entries-1"));
 }
+
+#[test]
+fn vfat_dir_options() {
+    use vfat::DirOptions;
+    use vfat::metadata::Attributes;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let root = vfat.root().unwrap();
+    let count_before = root.entries().unwrap().count().unwrap();
+
+    root.create_entry("hidden.txt", &new_metadata(&vfat, Attributes(0x02))).unwrap();
+
+    let entries = root.entries().unwrap().collect::<Vec<_>>().unwrap();
+    assert_eq!(entries.len(), count_before + 1);
+    let visible = entries.iter().filter(|e| !e.metadata().is_hidden()).count();
+    assert!(visible < entries.len());
+
+    let skip_hidden = DirOptions { skip_hidden: true, ..DirOptions::default() };
+    assert_eq!(root.entries_with(skip_hidden).unwrap().count().unwrap(), visible);
+
+    let with_volume_id = DirOptions { include_volume_id: true, ..DirOptions::default() };
+    assert_eq!(root.entries_with(with_volume_id).unwrap().count().unwrap(), entries.len() + 1);
+
+    let dir = vfat.create_dir("/dots").unwrap();
+    assert_eq!(dir.entries().unwrap().count().unwrap(), 0);
+    let with_dots = DirOptions { include_dot_entries: true, ..DirOptions::default() };
+    let names = dir.entries_with(with_dots).unwrap().map(|e| e.name().to_string()).collect::<Vec<_>>().unwrap();
+    assert_eq!(names, vec![".", ".."]);
+}
+
+#[test]
+fn vfat_dir_dot_entries() {
+    use vfat::metadata::Attributes;
+    use vfat::dir::empty_dir_cluster;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let raw_entry = |dir: &::vfat::dir::SharedVFatDir, index| dir.0.lock().get_raw_entry(index).unwrap().unwrap().to_bytes();
+    let cluster = |bytes: &[u8; 32]| (bytes[20] as u32) << 16 | (bytes[21] as u32) << 24 | bytes[26] as u32 | (bytes[27] as u32) << 8;
+
+    let top = vfat.create_dir("/top").unwrap();
+    let top_cluster = vfat.get_entry("/top").unwrap().metadata.first_cluster;
+    let dot = raw_entry(&top, 0);
+    let dotdot = raw_entry(&top, 1);
+    assert_eq!(&dot[..11], b".          ");
+    assert_eq!(&dotdot[..11], b"..         ");
+    assert_eq!((dot[11], dotdot[11]), (0x10, 0x10));
+    assert_eq!(cluster(&dot), top_cluster);
+    // ".." of a directory in the root directory points to cluster 0
+    assert_eq!(cluster(&dotdot), 0);
+    assert_eq!((&dot[28..], &dotdot[28..]), (&[0; 4][..], &[0; 4][..]));
+
+    let sub = vfat.create_dir("/top/sub").unwrap();
+    assert_eq!(cluster(&raw_entry(&sub, 1)), top_cluster);
+
+    // The dot entries get the attributes of the directory.
+    let root = vfat.root().unwrap();
+    let metadata = new_metadata(&vfat, Attributes(0x12));
+    let cluster_size = vfat.lock().cluster_size_bytes();
+    vfat.lock().data_region().write_cluster(metadata.first_cluster, 0, &empty_dir_cluster(cluster_size, &metadata, 0)).unwrap();
+    let hidden = root.create_entry("hidden", &metadata).unwrap().open_dir().unwrap();
+    assert_eq!((raw_entry(&hidden, 0)[11], raw_entry(&hidden, 1)[11]), (0x12, 0x12));
+    drop((top, sub, hidden));
+    assert!(vfat.check().unwrap().is_clean());
+}
+
+#[test]
+fn vfat_attributes() {
+    use vfat::Attributes;
+
+    let attributes = Attributes::default().with_read_only().with_hidden().with_system().with_archive();
+    assert_eq!(u8::from(attributes), 0x27);
+    assert_eq!(attributes.to_string(), "RHSA");
+    assert!(attributes.is_read_only() && attributes.is_hidden() && attributes.is_system() && attributes.is_archive());
+    assert!(!attributes.is_dir() && !attributes.is_volume_id());
+    let attributes = attributes.without_read_only().without_system().with_dir();
+    assert_eq!(attributes, Attributes::HIDDEN.with_dir().with_archive());
+    assert_eq!(attributes.to_string(), "HDA");
+    assert!(Attributes::from(0x08).with_dir().without_dir().is_volume_id());
+    assert_eq!(Attributes::default().to_string(), "");
+
+    // Every combination but those marking LFN entries can be stored.
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let dir = vfat.create_dir("/attributes").unwrap();
+    let all = [Attributes::READ_ONLY, Attributes::HIDDEN, Attributes::SYSTEM, Attributes::VOLUME_ID,
+               Attributes::DIRECTORY, Attributes::ARCHIVE];
+    for raw in (0..0x40u8).filter(|raw| raw & 0x0F != 0x0F) {
+        let attributes = Attributes::from(raw);
+        assert_eq!(all.iter().filter(|&&bit| attributes.contains(bit)).count(), raw.count_ones() as usize);
+        let entry = dir.create_entry(&format!("entry{}", raw), &new_metadata(&vfat, attributes)).unwrap();
+        assert_eq!(entry.metadata().attributes(), attributes);
+        let raw_entry = dir.0.lock().get_raw_entry(entry.dir_entry_index_range.end).unwrap().unwrap();
+        assert_eq!(raw_entry.to_bytes()[11], raw);
+        if !attributes.is_volume_id() {
+            assert_eq!(dir.find(format!("entry{}", raw)).unwrap().metadata().attributes(), attributes);
+        }
+    }
+}
+
+#[test]
+fn date_time_timestamps() {
+    use traits::{Date, Time};
+
+    assert_eq!(Date::from_ymd(1970, 1, 1).and_hms(0, 0, 0).timestamp(), 0);
+    assert_eq!(Date::from_ymd(1980, 1, 1).and_hms(0, 0, 0).timestamp(), 315_532_800);
+    assert_eq!(Date::from_ymd(2000, 2, 29).and_hms(12, 30, 15).timestamp(), 951_827_415);
+    assert_eq!(DateTime::from_timestamp(-1), Date::from_ymd(1969, 12, 31).and_hms(23, 59, 59));
+    for &seconds in &[0, 1, 86_399, 951_827_415, 4_354_819_199, -86_401, -11_644_473_600] {
+        assert_eq!(DateTime::from_timestamp(seconds).timestamp(), seconds);
+    }
+
+    assert_eq!(Date::from_ymd_opt(2000, 2, 29).map(|date| date.day()), Some(29));
+    assert_eq!(Date::from_ymd_opt(2100, 2, 29), None);
+    assert_eq!(Date::from_ymd_opt(2018, 13, 1), None);
+    assert_eq!(Date::from_ymd_opt(2018, 4, 31), None);
+    assert_eq!(Time::from_hms_opt(24, 0, 0), None);
+    assert_eq!(Time::from_hms_opt(23, 59, 60), None);
+    assert_eq!(Date::from_ymd(2018, 6, 1).and_hms(2, 4, 6).to_string(), "2018-06-01 02:04:06");
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn date_time_chrono_conversions() {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use traits::Date;
+
+    let time = Date::from_ymd(2107, 12, 31).and_hms(23, 59, 58);
+    let naive = NaiveDate::from_ymd(2107, 12, 31).and_hms(23, 59, 58);
+    assert_eq!(NaiveDateTime::from(time), naive);
+    assert_eq!(DateTime::from(naive), time);
+    assert_eq!(DateTime::from(naive).timestamp(), naive.timestamp());
+    let leap_second = NaiveDate::from_ymd(2016, 12, 31).and_hms_milli(23, 59, 59, 1500);
+    assert_eq!(DateTime::from(leap_second), Date::from_ymd(2016, 12, 31).and_hms(23, 59, 59));
+}
+
+#[test]
+fn vfat_timestamp_decoding() {
+    use vfat::dir::{decode_date, decode_time};
+    use traits::{Date, Time};
+
+    assert_eq!(decode_time((23 << 11) | (59 << 5) | 29), Some(Time::from_hms(23, 59, 58)));
+    assert_eq!(decode_date((127 << 9) | (12 << 5) | 31), Some(Date::from_ymd(2107, 12, 31)));
+    assert_eq!(decode_time(0), Some(Time::from_hms(0, 0, 0)));
+    assert_eq!(decode_date(0), None);
+    assert_eq!(decode_date((1 << 5) | 1), Some(Date::from_ymd(1980, 1, 1)));
+    let days_in_month = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    for raw in 0..=::std::u16::MAX {
+        let (year, month, day) = (1980 + (raw >> 9) as i32, ((raw >> 5) & 0xF) as u32, (raw & 0x1F) as u32);
+        let leap_day = (month == 2 && year % 4 == 0 && year != 2100) as u32;
+        let valid = month >= 1 && month <= 12 && day >= 1 && day <= days_in_month[month as usize - 1] + leap_day;
+        assert_eq!(decode_date(raw).is_some(), valid);
+        if let Some(date) = decode_date(raw) {
+            assert_eq!((date.year(), date.month(), date.day()), (year, month, day));
+        }
+        let (hour, minute, second) = ((raw >> 11) as u32, ((raw >> 5) & 0x3F) as u32, 2 * (raw & 0x1F) as u32);
+        assert_eq!(decode_time(raw).is_some(), hour < 24 && minute < 60 && second < 60);
+        if let Some(time) = decode_time(raw) {
+            assert_eq!((time.hour(), time.minute(), time.second()), (hour, minute, second));
+        }
+    }
+
+    // Unknown and invalid timestamps don't make the entry unreadable.
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    vfat.create_file("/stamps.txt").unwrap();
+    let dir = vfat.open_dir("/").unwrap();
+    let entry = dir.find("stamps.txt").unwrap();
+    edit_raw_entry(&dir, entry.dir_entry_index_range.end, |bytes| {
+        bytes[14..16].copy_from_slice(&[0xFF, 0xFF]);
+        bytes[18..20].copy_from_slice(&[0, 0]);
+        bytes[22..24].copy_from_slice(&[0x7D, 0xBF]);
+        bytes[24..26].copy_from_slice(&[0x9F, 0xFF]);
+    });
+    let metadata = dir.find("stamps.txt").unwrap().metadata().clone();
+    assert_eq!(metadata.created_opt(), None);
+    assert_eq!(metadata.created(), ::vfat::fat_epoch());
+    assert_eq!(metadata.accessed_opt(), None);
+    assert_eq!(metadata.accessed(), ::vfat::fat_epoch().date());
+    assert_eq!(metadata.modified_opt(), Some(Date::from_ymd(2107, 12, 31).and_hms(23, 59, 58)));
+}
+
+#[test]
+fn vfat_set_times() {
+    use traits::Date;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    vfat.create_file("/times.txt").unwrap();
+    let accessed = Date::from_ymd(2019, 2, 28);
+    let modified = Date::from_ymd(2018, 6, 1).and_hms(12, 34, 56);
+    vfat.set_times("/times.txt", accessed, modified).unwrap();
+    let entry = vfat.get_entry("/times.txt").unwrap();
+    assert_eq!(entry.metadata().accessed(), accessed);
+    assert_eq!(entry.metadata().accessed_opt(), Some(accessed));
+    assert_eq!(entry.metadata().modified(), modified);
+}
+
+#[test]
+fn vfat_dot_components() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+
+    // a directory of the mock image, which has no dot entries
+    let docs = vfat.open_dir("/rpi3-docs").unwrap();
+    assert_eq!(docs.find(".").unwrap().name(), "rpi3-docs");
+    assert_matches!(fs_error(docs.find("..")), ::error::Error::InvalidInput { .. });
+    assert_eq!(vfat.get_entry("/rpi3-docs/.").unwrap().name(), "rpi3-docs");
+    assert_eq!(vfat.get_entry("/./rpi3-docs/../rpi3-docs/RPi3-Schematics.pdf").unwrap().name(), "RPi3-Schematics.pdf");
+    assert_matches!(fs_error(vfat.get_entry("/rpi3-docs/..")), ::error::Error::InvalidInput { .. });
+    assert!(vfat.open_dir("/rpi3-docs/..").unwrap().entry().is_none());
+    assert!(vfat.open_dir("/..").unwrap().entry().is_none());
+    assert_matches!(fs_error(vfat.get_entry("/rpi3-docs/RPi3-Schematics.pdf/.")), ::error::Error::NotADirectory);
+    assert_matches!(fs_error(vfat.get_entry("/rpi3-docs/RPi3-Schematics.pdf/..")), ::error::Error::NotADirectory);
+
+    // directories created with dot entries
+    vfat.create_dir_all("/a/b").unwrap();
+    vfat.create_file("/a/b/file.txt").unwrap().write_all(b"data").unwrap();
+    let b = vfat.open_dir("/a/b").unwrap();
+    assert_eq!(b.find(".").unwrap().name(), "b");
+    assert_eq!(b.find("..").unwrap().name(), "a");
+    assert_eq!(b.entries().unwrap().count().unwrap(), 1);
+    assert_eq!(vfat.get_entry("/a/./b/../b").unwrap().name(), "b");
+    assert_eq!(vfat.get_entry("/a/b/../../a/b/./file.txt").unwrap().name(), "file.txt");
+    assert_eq!(vfat.open_dir("/a/b/..").unwrap().names().unwrap(), vec!["b"]);
+    assert_eq!(read_file(&vfat, "/a/../a/b/./file.txt"), b"data");
+}
+
+#[test]
+fn vfat_dir_resume_iteration() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let dir = vfat.open_dir("/rpi3-docs").unwrap();
+    let all = dir.entries().unwrap().map(|e| e.name().to_string()).collect::<Vec<_>>().unwrap();
+    assert!(all.len() >= 2);
+
+    let half = all.len() / 2;
+    let mut first = Vec::new();
+    let position = {
+        let mut iterator = dir.entries().unwrap();
+        for _ in 0..half {
+            first.push(iterator.next().unwrap().unwrap().name().to_string());
+        }
+        iterator.position()
+    };
+    let second = dir.entries_from(position).unwrap().map(|e| e.name().to_string()).collect::<Vec<_>>().unwrap();
+    first.extend(second);
+    assert_eq!(first, all);
+}
+
+#[test]
+fn vfat_dir_resume_inside_lfn_run() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let dir = vfat.open_dir("/rpi3-docs").unwrap();
+    let entry = dir.entries().unwrap()
+        .find(|e| e.dir_entry_index_range.start != e.dir_entry_index_range.end).unwrap()
+        .expect("an entry with a long file name");
+
+    for index in entry.dir_entry_index_range.clone() {
+        let resumed = dir.entries_from(index).unwrap().next().unwrap().unwrap();
+        assert_eq!(resumed.name(), entry.name());
+    }
+}
+
+#[test]
+fn vfat_dir_is_empty() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let dir = vfat.create_dir("/empty").unwrap();
+    assert!(dir.is_empty().unwrap());
+    assert_eq!(dir.entry_count().unwrap(), 0);
+
+    vfat.create_file("/empty/a.txt").unwrap();
+    vfat.create_file("/empty/a long file name.txt").unwrap();
+    assert!(!dir.is_empty().unwrap());
+    assert_eq!(dir.entry_count().unwrap(), 2);
+
+    vfat.remove("/empty/a.txt").unwrap();
+    vfat.remove("/empty/a long file name.txt").unwrap();
+    assert!(dir.is_empty().unwrap());
+    assert_eq!(dir.entry_count().unwrap(), 0);
+
+    drop(dir);
+    vfat.remove("/empty").unwrap();
+}
+
+#[test]
+fn vfat_dir_entry_count() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let root = vfat.root().unwrap();
+    let expected = hash_for("root-entries-1").trim().lines().count();
+    assert_eq!(root.entry_count().unwrap(), expected);
+    assert_eq!(root.entry_count().unwrap(), root.entries().unwrap().count().unwrap());
+    assert!(!root.is_empty().unwrap());
+}
+
+#[test]
+fn vfat_find_takes_single_ref_lock() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let dir = vfat.open_dir("/rpi3-docs").unwrap();
+    let (first_key, last_name, last_key) = {
+        let entries = dir.entries().unwrap().collect::<Vec<_>>().unwrap();
+        assert!(entries.len() >= 2);
+        let last = entries.last().unwrap();
+        (entries[0].lock_key, last.name().to_string(), last.lock_key)
+    };
+    let lock_manager = vfat.lock().lock_manager();
+    assert_eq!(lock_manager.ref_lock_count(last_key), 0);
+
+    // A Delete lock on an earlier entry would block if `find` took a Ref lock
+    // on every entry it passed over.
+    let _delete_lock = lock_manager.try_lock(first_key, LockMode::Delete).unwrap();
+    let entry = dir.find(&last_name).unwrap();
+    assert_eq!(entry.name(), last_name);
+    assert_eq!(lock_manager.ref_lock_count(last_key), 1);
+
+    drop(entry);
+    assert_eq!(lock_manager.ref_lock_count(last_key), 0);
+}
+
+#[test]
+fn vfat_dir_listing_reads_clusters_once() {
+    use std::collections::HashMap;
+    use tests::mock::InstrumentedDevice;
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let reads = device.reads.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+
+    let dir = vfat.create_dir("/many").unwrap();
+    for i in 0..200 {
+        vfat.create_file(format!("/many/file{}", i)).unwrap();
+    }
+
+    let mut dir_sectors = Vec::new();
+    {
+        let vfat = vfat.lock();
+        let fat = vfat.fat();
+        let mut cluster = Some(dir.0.lock().chain.first_cluster);
+        while let Some(c) = cluster {
+            let first_sector = vfat.params().data_start_sector() + (c as u64 - 2) * vfat.params().logical_sectors_per_cluster as u64;
+            dir_sectors.extend(first_sector..first_sector + vfat.params().logical_sectors_per_cluster as u64);
+            cluster = fat.get_next_in_chain(c).unwrap();
+        }
+    }
+
+    reads.lock().unwrap().clear();
+    assert_eq!(dir.entries().unwrap().count().unwrap(), 200);
+
+    let mut counts = HashMap::new();
+    for sector in reads.lock().unwrap().iter() {
+        *counts.entry(*sector).or_insert(0) += 1;
+    }
+    for sector in dir_sectors {
+        assert!(counts.get(&sector).cloned().unwrap_or(0) <= 1,
+                "directory sector {} was read more than once", sector);
+    }
+}
+
+#[test]
+fn vfat_create_entry_with_short_name() {
+    use vfat::ShortName;
+    use vfat::metadata::Attributes;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let root = vfat.root().unwrap();
+    let short_name = ShortName::parse("KERNEL8.IMG").unwrap();
+    let entry = root.create_entry_with_short_name("kernel8.img", &short_name, &new_metadata(&vfat, Attributes::new(false))).unwrap();
+    let regular_index = entry.dir_entry_index_range.end;
+    drop(entry);
+
+    let raw_entry = root.0.lock().get_raw_entry(regular_index).unwrap().unwrap();
+    let bytes = raw_entry.to_bytes();
+    assert_eq!(&bytes[..11], b"KERNEL8 IMG");
+
+    let mut file = vfat.open_file("/kernel8.img", FileOpenMode::Write).unwrap();
+    file.write_all(b"kernel").unwrap();
+    drop(file);
+
+    let duplicate = root.create_entry_with_short_name("other.img", &short_name, &new_metadata(&vfat, Attributes::new(false)));
+    assert_matches!(fs_error(duplicate), ::error::Error::AlreadyExists);
+
+    // A long name identical to the short name is stored without LFN entries.
+    let plain = ShortName::parse("CONFIG.TXT").unwrap();
+    let entry = root.create_entry_with_short_name("CONFIG.TXT", &plain, &new_metadata(&vfat, Attributes::new(false))).unwrap();
+    assert_eq!(entry.dir_entry_index_range.start, entry.dir_entry_index_range.end);
+}
+
+#[test]
+fn vfat_entry_name_source() {
+    use std::collections::HashMap;
+    use vfat::{NameSource, ShortName};
+    use vfat::metadata::Attributes;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let dir = vfat.create_dir("/names").unwrap();
+    vfat.create_file("/names/Long name.txt").unwrap().write_all(b"long").unwrap();
+    let plain = ShortName::parse("PLAIN.TXT").unwrap();
+    dir.create_entry_with_short_name("PLAIN.TXT", &plain, &new_metadata(&vfat, Attributes::new(false))).unwrap();
+
+    let sources: HashMap<String, NameSource> = dir.entries().unwrap()
+        .map(|entry| (entry.name().to_string(), entry.name_source()))
+        .collect().unwrap();
+    assert_eq!(sources["Long name.txt"], NameSource::Long);
+    assert_eq!(sources["PLAIN.TXT"], NameSource::Short);
+
+    // Renaming keeps a short-only entry without LFN entries.
+    vfat.rename("/names/PLAIN.TXT", "/names/RENAMED.TXT").unwrap();
+    let renamed = vfat.get_entry("/names/RENAMED.TXT").unwrap();
+    assert_eq!(renamed.name_source(), NameSource::Short);
+    assert_eq!(renamed.dir_entry_index_range.start, renamed.dir_entry_index_range.end);
+    let bytes = dir.0.lock().get_raw_entry(renamed.dir_entry_index_range.end).unwrap().unwrap().to_bytes();
+    assert_eq!(&bytes[..11], b"RENAMED TXT");
+    drop(renamed);
+
+    vfat.rename("/names/Long name.txt", "/names/Other name.txt").unwrap();
+    let other = vfat.get_entry("/names/Other name.txt").unwrap();
+    assert_eq!(other.name_source(), NameSource::Long);
+    assert!(dir.0.lock().get_raw_entry(other.dir_entry_index_range.start).unwrap().unwrap().is_lfn());
+
+    // A name that isn't a valid short name needs a long name.
+    vfat.rename("/names/RENAMED.TXT", "/names/renamed.txt").unwrap();
+    assert_eq!(vfat.get_entry("/names/renamed.txt").unwrap().name_source(), NameSource::Long);
+
+    // A long name whose checksum doesn't match falls back to the short name.
+    let short_name = other.short_name().to_string();
+    edit_raw_entry(&dir, other.dir_entry_index_range.end, |bytes| bytes[0] = b'A');
+    drop(other);
+    let fallback = dir.entries().unwrap()
+        .find(|entry| entry.name() != "renamed.txt")
+        .unwrap().unwrap();
+    assert_eq!(fallback.name_source(), NameSource::Short);
+    assert_eq!(fallback.name(), fallback.short_name());
+    assert_eq!(&fallback.short_name()[1..], &short_name[1..]);
+}
+
+#[test]
+fn vfat_entry_short_names() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let entry = vfat.get_entry("/rpi3-docs").unwrap();
+    assert_eq!(entry.short_name(), "RPI3-D~1");
+
+    let by_short_name = vfat.get_entry("/RPI3-D~1").unwrap();
+    assert_eq!(by_short_name.name(), "rpi3-docs");
+
+    let entries = vfat.open_dir("/rpi3-docs").unwrap().entries().unwrap().collect::<Vec<_>>().unwrap();
+    for entry in entries {
+        let short_name = entry.short_name().to_string();
+        assert!(short_name.len() > 0 && short_name.len() <= 12);
+        assert_eq!(short_name, short_name.to_uppercase());
+        let path = format!("/rpi3-docs/{}", short_name);
+        assert_eq!(vfat.get_entry(&path).unwrap().name(), entry.name());
+    }
+}
+
+#[test]
+fn vfat_fs_info_tracks_allocations() {
+    fn count_free_clusters(vfat: &ArcMutex<VFatFileSystem>, cluster_count: u32) -> u32 {
+        let fat = vfat.lock().fat();
+        (2..cluster_count + 2).filter(|&c| fat.get(c).unwrap().status() == ::vfat::fat::Status::Free).count() as u32
+    }
+
+    let mut device: Box<BlockDevice> = Box::new(load_partition("mock1.fat32.img"));
+    let ebpb = BiosParameterBlock::read_from(&mut device).unwrap();
+    let total_sectors = if ebpb.total_logical_sectors != 0 {
+        ebpb.total_logical_sectors as u32
+    } else {
+        ebpb.large_total_logical_sectors
+    };
+    let data_start_sector = ebpb.reserved_logical_sectors as u32 + ebpb.number_of_fats as u32 * ebpb.logical_sectors_per_fat;
+    let cluster_count = (total_sectors - data_start_sector) / ebpb.logical_sectors_per_cluster as u32;
+
+    let vfat = VFatFileSystem::from(device).unwrap();
+    let initial = vfat.lock().fat().fs_info();
+    assert_eq!(initial.free_count, count_free_clusters(&vfat, cluster_count));
+
+    let mut fat = vfat.lock().fat();
+    let first = fat.new_chain().unwrap();
+    let mut last = first;
+    for _ in 0..4 {
+        last = fat.alloc_for_chain(last).unwrap();
+    }
+    let kept = fat.new_chain().unwrap();
+    fat.free_chain(first).unwrap();
+    drop(fat);
+    assert_eq!(vfat.lock().fat().fs_info().free_count, initial.free_count - 1);
+
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    let fs_info = vfat.lock().fat().fs_info();
+    assert_eq!(fs_info.free_count, initial.free_count - 1);
+    assert_eq!(fs_info.free_count, count_free_clusters(&vfat, cluster_count));
+    assert!(first < kept);
+    assert_eq!(fs_info.next_free, first);
+}
+
+#[test]
+fn vfat_alloc_reads_stay_constant() {
+    use tests::mock::InstrumentedDevice;
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let reads = device.reads.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let mut fat = vfat.lock().fat();
+
+    let mut reads_per_alloc = Vec::new();
+    for _ in 0..1000 {
+        reads.lock().unwrap().clear();
+        fat.new_chain().unwrap();
+        reads_per_alloc.push(reads.lock().unwrap().len());
+    }
+    let first = reads_per_alloc[..10].iter().max().unwrap();
+    let last = reads_per_alloc[990..].iter().max().unwrap();
+    assert!(*last <= *first + 1, "reads per allocation grew from {} to {}", first, last);
+}
+
+#[test]
+fn vfat_file_preallocate_contiguous() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes() as u64;
+    let free_before = vfat.lock().fs_info().free_count;
+
+    let mut file = vfat.create_file("/big.bin").unwrap();
+    file.preallocate(64 * cluster_size).unwrap();
+
+    let first_cluster = vfat.get_entry("/big.bin").unwrap().metadata.first_cluster;
+    let fat = vfat.lock().fat();
+    let mut clusters = vec![first_cluster];
+    while let Some(next) = fat.get_next_in_chain(*clusters.last().unwrap()).unwrap() {
+        clusters.push(next);
+    }
+    assert_eq!(clusters.len(), 64);
+    for pair in clusters.windows(2) {
+        assert_eq!(pair[1], pair[0] + 1);
+    }
+
+    // Only the clusters actually written survive closing the file.
+    file.write_all(&vec![0xAB; 3 * cluster_size as usize]).unwrap();
+    file.close();
+    let mut cluster = first_cluster;
+    let mut count = 1;
+    while let Some(next) = fat.get_next_in_chain(cluster).unwrap() {
+        cluster = next;
+        count += 1;
+    }
+    assert_eq!(count, 3);
+    assert_eq!(vfat.lock().fs_info().free_count, free_before - 3);
+
+    let mut data = Vec::new();
+    vfat.open_file("/big.bin", FileOpenMode::Read).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data, vec![0xAB; 3 * cluster_size as usize]);
+}
+
+#[test]
+fn vfat_fat_mirroring_disabled() {
+    use tests::mock::InstrumentedDevice;
+    use vfat::MountOptions;
+
+    let mut partition = load_partition("mock1.fat32.img");
+    let mut sector = [0u8; 512];
+    partition.read_sector(0, &mut sector).unwrap();
+    // mirroring disabled, FAT 1 active
+    sector[40] = 0x81;
+    sector[41] = 0x00;
+    partition.write_sector(0, &sector).unwrap();
+
+    let device = InstrumentedDevice::new(partition);
+    let writes = device.writes.clone();
+    // FAT writes have to reach the device right away
+    let options = MountOptions { disable_cache: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+    assert!(!vfat.lock().is_fat_mirrored());
+    assert_eq!(vfat.lock().active_fat(), 1);
+    let mut fat = vfat.lock().fat();
+
+    let mut partition = load_partition("mock1.fat32.img");
+    let ebpb = BiosParameterBlock::read_from(&mut partition).unwrap();
+    let fat_size = ebpb.logical_sectors_per_fat as u64;
+    let second_fat = ebpb.reserved_logical_sectors as u64 + fat_size;
+
+    writes.lock().unwrap().clear();
+    let first = fat.new_chain().unwrap();
+    fat.alloc_for_chain(first).unwrap();
+    let writes = writes.lock().unwrap();
+    assert!(writes.len() > 0);
+    for &sector in writes.iter() {
+        assert!(sector >= second_fat && sector < second_fat + fat_size, "write to sector {}", sector);
+    }
+}
+
+#[test]
+fn vfat_fat_mirror_repair() {
+    use vfat::MountOptions;
+
+    let mut partition = load_partition("mock1.fat32.img");
+    let ebpb = BiosParameterBlock::read_from(&mut partition).unwrap();
+    let second_fat = ebpb.reserved_logical_sectors as u64 + ebpb.logical_sectors_per_fat as u64;
+
+    // Corrupt the entries for clusters 5 and 130 in the second FAT.
+    let mut sector = [0u8; 512];
+    partition.read_sector(second_fat, &mut sector).unwrap();
+    sector[5 * 4] ^= 0xFF;
+    partition.write_sector(second_fat, &sector).unwrap();
+    partition.read_sector(second_fat + 1, &mut sector).unwrap();
+    sector[(130 - 128) * 4 + 1] ^= 0x01;
+    partition.write_sector(second_fat + 1, &sector).unwrap();
+
+    let options = MountOptions { verify_fat_mirrors: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(partition), options).unwrap();
+    assert_eq!(vfat.lock().fat_mirror_mismatches(), 2);
+
+    let fat = vfat.lock().fat();
+    assert_eq!(fat.verify_mirrors().unwrap(), vec![5, 130]);
+    vfat.repair_fat_mirrors(0).unwrap();
+    assert_eq!(fat.verify_mirrors().unwrap(), vec![]);
+    assert_eq!(vfat.lock().fat_mirror_mismatches(), 0);
+    assert!(vfat.repair_fat_mirrors(2).is_err());
+}
+
+#[test]
+fn vfat_repair_fat_mirrors() {
+    use tests::mock::SparseDevice;
+    use vfat::MountOptions;
+    use ReadOnlyDevice;
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let (second_fat, fat_size) = {
+        let ebpb = BiosParameterBlock::read_from(&mut device).unwrap();
+        let fat_size = ebpb.logical_sectors_per_fat as u64;
+        (ebpb.reserved_logical_sectors as u64 + fat_size, fat_size)
+    };
+    let mut sector = [0u8; 512];
+    device.read_sector(second_fat, &mut sector).unwrap();
+    sector[2 * 4] ^= 0xFF;
+    device.write_sector(second_fat, &sector).unwrap();
+
+    let options = MountOptions { verify_fat_mirrors: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(ReadOnlyDevice::new(device)), options).unwrap();
+    assert_eq!(vfat.lock().fat_mirror_mismatches(), 1);
+    assert!(vfat.lock().is_fat_mirrored());
+    assert_eq!(vfat.lock().active_fat(), 0);
+    assert_eq!(vfat.lock().fat_entries(), (fat_size * 512 / 4) as u32);
+    let error = vfat.repair_fat_mirrors(0).err().unwrap();
+    assert_matches!(::error::Error::of(&error), Some(&::error::Error::ReadOnly));
+    assert_eq!(vfat.lock().fat_mirror_mismatches(), 1);
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    device.write_sector(second_fat, &sector).unwrap();
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+    vfat.repair_fat_mirrors(0).unwrap();
+    let device = vfat.try_unmount().unwrap();
+    let mut first = [0u8; 512];
+    device.read_sector(second_fat - fat_size, &mut first).unwrap();
+    device.read_sector(second_fat, &mut sector).unwrap();
+    assert_eq!(&first[..], &sector[..]);
+}
+
+#[test]
+fn vfat_mount_report() {
+    use vfat::{MountOptions, FsInfo};
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let report = vfat.lock().mount_report();
+    assert_eq!(report.counted_free, None);
+    assert!(!report.free_count_mismatch() && !report.dirty_bit_was_set && !report.backup_boot_used);
+    let free = report.fsinfo_free.unwrap();
+
+    let options = MountOptions { verify_free_count: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(load_partition("mock1.fat32.img")), options).unwrap();
+    let report = vfat.lock().mount_report();
+    assert_eq!(report.counted_free, Some(free));
+    assert!(!report.free_count_mismatch());
+
+    // Record a wrong free count.
+    let mut partition = load_partition("mock1.fat32.img");
+    let ebpb = BiosParameterBlock::read_from(&mut partition).unwrap();
+    let mut sector = [0u8; 512];
+    partition.read_sector(ebpb.fs_information_sector_location as u64, &mut sector).unwrap();
+    FsInfo { free_count: free + 10, ..FsInfo::parse(&sector) }.write_into(&mut sector);
+    partition.write_sector(ebpb.fs_information_sector_location as u64, &sector).unwrap();
+    let vfat = VFatFileSystem::from_with_options(Box::new(partition), options).unwrap();
+    let report = vfat.lock().mount_report();
+    assert_eq!((report.fsinfo_free, report.counted_free), (Some(free + 10), Some(free)));
+    assert!(report.free_count_mismatch());
+}
+
+#[test]
+fn vfat_fat_cache_flush_retry() {
+    use tests::mock::{FaultyDevice, SparseDevice};
+    use vfat::{MountOptions, FatCacheMode};
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let shared = ArcMutex::new(device);
+    let device = FaultyDevice::new(shared.clone());
+    let faults = device.faults.clone();
+    let options = MountOptions { fat_cache: FatCacheMode::Lazy, disable_cache: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+
+    // a chain spanning several FAT sectors
+    let mut fat = vfat.lock().fat();
+    let first_cluster = fat.new_chain().unwrap();
+    let mut last = first_cluster;
+    for _ in 0..299 {
+        last = fat.alloc_for_chain(last).unwrap();
+    }
+    {
+        let mut faults = faults.lock().unwrap();
+        faults.fail_nth_write = Some(faults.writes);
+    }
+    assert!(fat.sync().is_err());
+    // The sectors that weren't written are written by the next flush.
+    fat.sync().unwrap();
+    assert_eq!(faults.lock().unwrap().failed_writes, 1);
+    drop(fat);
+    drop(vfat);
+
+    let vfat = VFatFileSystem::from(Box::new(shared)).unwrap();
+    assert_eq!(vfat.lock().fat().chain_length(first_cluster).unwrap(), 300);
+}
+
+#[test]
+fn vfat_fat_cache() {
+    use tests::mock::InstrumentedDevice;
+    use vfat::{MountOptions, FatCacheMode};
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let reads = device.reads.clone();
+    let writes = device.writes.clone();
+    let options = MountOptions { fat_cache: FatCacheMode::Lazy, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+    let first_cluster = vfat.get_entry("/rpi3-docs/RPi3-Schematics.pdf").unwrap().metadata.first_cluster;
+
+    let mut fat = vfat.lock().fat();
+    let walk = |fat: &::vfat::fat::SharedFat| {
+        let mut clusters = vec![first_cluster];
+        while let Some(next) = fat.get_next_in_chain(*clusters.last().unwrap()).unwrap() {
+            clusters.push(next);
+        }
+        clusters
+    };
+    let clusters = walk(&fat);
+    assert!(clusters.len() > 1);
+    reads.lock().unwrap().clear();
+    assert_eq!(walk(&fat), clusters);
+    assert_eq!(reads.lock().unwrap().len(), 0);
+
+    // The first FAT update marks the volume dirty, which is written through.
+    let new_first = fat.new_chain().unwrap();
+    writes.lock().unwrap().clear();
+    let mut last = new_first;
+    for _ in 0..10 {
+        last = fat.alloc_for_chain(last).unwrap();
+    }
+    fat.free_chain(first_cluster).unwrap();
+    assert_eq!(writes.lock().unwrap().len(), 0);
+    drop(fat);
+
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    let fat = vfat.lock().fat();
+    let mut count = 1;
+    let mut cluster = new_first;
+    while let Some(next) = fat.get_next_in_chain(cluster).unwrap() {
+        cluster = next;
+        count += 1;
+    }
+    assert_eq!((count, cluster), (11, last));
+    assert_eq!(fat.get(first_cluster).unwrap().status(), ::vfat::fat::Status::Free);
+    assert!(fat.verify_mirrors().unwrap().is_empty());
+}
+
+#[test]
+fn vfat_bad_clusters() {
+    use tests::mock::FailingWritesDevice;
+    use vfat::fat::Status;
+    use vfat::MountOptions;
+
+    let device = FailingWritesDevice::new(load_partition("mock1.fat32.img"));
+    let failing_sector = device.failing_sector.clone();
+    // write errors have to show up at the write, not when the cache evicts
+    let options = MountOptions { disable_cache: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+    assert_eq!(vfat.lock().bad_cluster_count().unwrap(), 0);
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+
+    let mut file = vfat.create_file("/bad.bin").unwrap();
+    let bad_cluster = vfat.lock().fs_info().next_free;
+    {
+        let vfat = vfat.lock();
+        let sector = vfat.params().data_start_sector() + (bad_cluster as u64 - 2) * vfat.params().logical_sectors_per_cluster as u64;
+        *failing_sector.lock().unwrap() = Some(sector);
+    }
+    let data: Vec<u8> = (0..2 * cluster_size).map(|i| i as u8).collect();
+    file.write_all(&data).unwrap();
+    file.close();
+
+    let mut fat = vfat.lock().fat();
+    assert_eq!(fat.get(bad_cluster).unwrap().status(), Status::Bad);
+    assert_eq!(vfat.lock().bad_cluster_count().unwrap(), 1);
+
+    let mut read_back = Vec::new();
+    vfat.open_file("/bad.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read_back).unwrap();
+    assert_eq!(read_back, data);
+
+    for _ in 0..100 {
+        assert_ne!(fat.new_chain().unwrap(), bad_cluster);
+    }
+
+    // Freeing a chain that runs into a bad cluster frees everything before it.
+    let first = fat.new_chain().unwrap();
+    let second = fat.alloc_for_chain(first).unwrap();
+    fat.mark_bad(second).unwrap();
+    fat.free_chain(first).unwrap();
+    assert_eq!(fat.get(first).unwrap().status(), Status::Free);
+    assert_eq!(fat.get(second).unwrap().status(), Status::Bad);
+    assert_eq!(vfat.lock().bad_cluster_count().unwrap(), 2);
+}
+
+#[test]
+fn vfat_lost_clusters() {
+    use vfat::ReclaimMode;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    assert_eq!(vfat.find_lost_clusters().unwrap().lost_clusters, 0);
+
+    let mut fat = vfat.lock().fat();
+    let orphan = fat.new_chain().unwrap();
+    fat.alloc_for_chain(orphan).unwrap();
+    let report = vfat.find_lost_clusters().unwrap();
+    assert_eq!(report.chains, vec![orphan]);
+    assert_eq!(report.lost_clusters, 2);
+
+    let free_before = vfat.lock().fs_info().free_count;
+    vfat.reclaim_lost_clusters(&report, ReclaimMode::Free).unwrap();
+    assert_eq!(vfat.lock().fs_info().free_count, free_before + 2);
+    assert_eq!(vfat.find_lost_clusters().unwrap().lost_clusters, 0);
+
+    let orphan = fat.new_chain().unwrap();
+    let report = vfat.find_lost_clusters().unwrap();
+    assert_eq!(report.chains, vec![orphan]);
+    vfat.reclaim_lost_clusters(&report, ReclaimMode::SaveToFiles).unwrap();
+    let entry = vfat.get_entry("/FOUND.000/FILE0000.CHK").unwrap();
+    assert_eq!(entry.metadata.first_cluster, orphan);
+    assert_eq!(vfat.find_lost_clusters().unwrap().lost_clusters, 0);
+}
+
+#[test]
+fn vfat_cross_links() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    assert_eq!(vfat.find_cross_links().unwrap(), vec![]);
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+
+    let a_data: Vec<u8> = (0..3 * cluster_size).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/a.txt").unwrap().write_all(&a_data).unwrap();
+    vfat.create_file("/b.txt").unwrap().write_all(&vec![0xBB; 3 * cluster_size]).unwrap();
+
+    // Make the second cluster of b.txt point at the second cluster of a.txt.
+    let mut fat = vfat.lock().fat();
+    let a_first = vfat.get_entry("/a.txt").unwrap().metadata.first_cluster;
+    let b_first = vfat.get_entry("/b.txt").unwrap().metadata.first_cluster;
+    let a_second = fat.get_next_in_chain(a_first).unwrap().unwrap();
+    let b_second = fat.get_next_in_chain(b_first).unwrap().unwrap();
+    fat.set_raw(b_second, a_second).unwrap();
+
+    let cross_links = vfat.find_cross_links().unwrap();
+    assert_eq!(cross_links.len(), 1);
+    assert_eq!(cross_links[0].cluster, a_second);
+    assert_eq!(cross_links[0].first_owner, "/a.txt");
+    assert_eq!(cross_links[0].second_owner, "/b.txt");
+    assert_eq!(cross_links[0].previous_cluster, Some(b_second));
+
+    vfat.repair_cross_link(&cross_links[0]).unwrap();
+    assert_eq!(vfat.find_cross_links().unwrap(), vec![]);
+
+    let mut b_data = Vec::new();
+    vfat.open_file("/b.txt", FileOpenMode::Read).unwrap().read_to_end(&mut b_data).unwrap();
+    assert_eq!(&b_data[..2 * cluster_size], &vec![0xBB; 2 * cluster_size][..]);
+    assert_eq!(&b_data[2 * cluster_size..], &a_data[cluster_size..2 * cluster_size]);
+
+    let mut b = vfat.open_file("/b.txt", FileOpenMode::Write).unwrap();
+    b.seek(SeekFrom::Start(2 * cluster_size as u64)).unwrap();
+    b.write_all(&vec![0xCC; cluster_size]).unwrap();
+    drop(b);
+    let mut a_read = Vec::new();
+    vfat.open_file("/a.txt", FileOpenMode::Read).unwrap().read_to_end(&mut a_read).unwrap();
+    assert_eq!(a_read, a_data);
+}
+
+fn edit_raw_entry<F: FnOnce(&mut [u8; 32])>(dir: &::vfat::dir::SharedVFatDir, index: u64, edit: F) {
+    let mut dir = dir.0.lock();
+    let mut bytes = dir.get_raw_entry(index).unwrap().unwrap().to_bytes();
+    edit(&mut bytes);
+    dir.set_raw_entry(index, &VFatDirEntry::from_bytes(&bytes)).unwrap();
+}
+
+#[test]
+fn vfat_check_clean() {
+    use vfat::RepairPolicy;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    assert_eq!(vfat.check().unwrap().findings, vec![]);
+    vfat.create_file("/a long file name.txt").unwrap().write_all(b"data").unwrap();
+    vfat.create_dir("/new dir").unwrap();
+    vfat.create_file("/new dir/inner.bin").unwrap().write_all(&[1; 9000]).unwrap();
+    let report = vfat.check().unwrap();
+    assert!(report.is_clean());
+    assert_eq!(vfat.repair(&report, RepairPolicy::all()).unwrap(), 0);
+}
+
+#[test]
+fn vfat_check_chains() {
+    use vfat::{ChainProblem, Finding, Location, Problem, RepairPolicy, Severity};
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes();
+    vfat.create_file("/big.bin").unwrap().write_all(&vec![1; 2 * cluster_size as usize]).unwrap();
+    vfat.create_file("/gone.bin").unwrap().write_all(&vec![2; 3 * cluster_size as usize]).unwrap();
+
+    // big.bin claims more than its chain holds
+    let big = vfat.get_entry("/big.bin").unwrap();
+    let big_index = big.dir_entry_index_range.end;
+    big.dir.0.lock().set_file_size(big_index, 5 * cluster_size).unwrap();
+    drop(big);
+    // the first cluster of gone.bin is free, which orphans the rest
+    let gone = vfat.get_entry("/gone.bin").unwrap();
+    let gone_index = gone.dir_entry_index_range.end;
+    let gone_first = gone.metadata.first_cluster;
+    drop(gone);
+    let mut fat = vfat.lock().fat();
+    let gone_second = fat.get_next_in_chain(gone_first).unwrap().unwrap();
+    fat.free_cluster(gone_first).unwrap();
+
+    let root_cluster = vfat.lock().root_dir_cluster();
+    let root_entry = |index| Location::Entry { dir: "/".to_string(), dir_cluster: root_cluster, index };
+    let report = vfat.check().unwrap();
+    assert_eq!(report.findings, vec![
+        Finding {
+            severity: Severity::Error,
+            location: root_entry(big_index),
+            problem: Problem::BrokenChain(ChainProblem::TooShort { size: 5 * cluster_size, chain_bytes: 2 * cluster_size as u64 }),
+        },
+        Finding {
+            severity: Severity::Error,
+            location: root_entry(gone_index),
+            problem: Problem::BrokenChain(ChainProblem::Dangling { cluster: gone_first }),
+        },
+        Finding { severity: Severity::Warning, location: Location::Cluster(gone_second), problem: Problem::LostChain },
+    ]);
+    assert!(report.has_errors());
+
+    let free = vfat.lock().fs_info().free_count;
+    assert_eq!(vfat.repair(&report, RepairPolicy::all()).unwrap(), 2);
+    assert_eq!(vfat.get_entry("/big.bin").unwrap().metadata.size, 2 * cluster_size);
+    assert_eq!(vfat.lock().fs_info().free_count, free + 2);
+    // a dangling chain isn't safe to repair
+    let report = vfat.check().unwrap();
+    assert_eq!(report.findings.len(), 1);
+    assert_matches!(&report.findings[0].problem, &Problem::BrokenChain(ChainProblem::Dangling { .. }));
+}
+
+#[test]
+fn vfat_check_lfn_entries() {
+    use vfat::{Finding, Location, Problem, RepairPolicy, Severity};
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    for name in &["/checksum mismatch.txt", "/bad sequence.txt", "/orphan.txt"] {
+        vfat.create_file(name).unwrap().write_all(b"data").unwrap();
+    }
+    let range = |path| {
+        let entry = vfat.get_entry(path).unwrap();
+        (entry.dir_entry_index_range.start, entry.dir_entry_index_range.end)
+    };
+    let (checksum_start, checksum_end) = range("/checksum mismatch.txt");
+    let (sequence_start, _) = range("/bad sequence.txt");
+    let (orphan_start, orphan_end) = range("/orphan.txt");
+    let orphan_cluster = vfat.get_entry("/orphan.txt").unwrap().metadata.first_cluster;
+    let root = vfat.root().unwrap();
+    // a different short name no longer matches the LFN checksum
+    edit_raw_entry(&root, checksum_end, |bytes| bytes[0] = b'A');
+    // the first of two LFN entries claims there are three
+    edit_raw_entry(&root, sequence_start, |bytes| bytes[0] = 0x43);
+    edit_raw_entry(&root, orphan_end, |bytes| bytes[0] = 0xE5);
+
+    let root_cluster = vfat.lock().root_dir_cluster();
+    let root_entry = |index| Location::Entry { dir: "/".to_string(), dir_cluster: root_cluster, index };
+    let report = vfat.check().unwrap();
+    assert_eq!(report.findings, vec![
+        Finding {
+            severity: Severity::Warning,
+            location: root_entry(checksum_start),
+            problem: Problem::LfnChecksumMismatch { count: 2 },
+        },
+        Finding {
+            severity: Severity::Error,
+            location: root_entry(sequence_start),
+            problem: Problem::BadLfnSequence { count: 2 },
+        },
+        Finding {
+            severity: Severity::Warning,
+            location: root_entry(orphan_start),
+            problem: Problem::OrphanLfn { count: 1 },
+        },
+        Finding { severity: Severity::Warning, location: Location::Cluster(orphan_cluster), problem: Problem::LostChain },
+    ]);
+    assert!(root.names().is_err());
+
+    assert_eq!(vfat.repair(&report, RepairPolicy::all()).unwrap(), 4);
+    assert!(vfat.check().unwrap().is_clean());
+    let names = root.names().unwrap();
+    // the two entries with damaged LFNs remain under their short names
+    assert_eq!(names.len(), 4);
+    assert!(names.iter().all(|name| !name.contains("checksum") && !name.contains("sequence")), "{:?}", names);
+}
+
+#[test]
+fn vfat_repair_unresolvable_dir() {
+    use vfat::{ChainProblem, Location, Problem, RepairPolicy};
+
+    let vfat = deferred_delete_memory_vfat();
+    let cluster_size = vfat.lock().cluster_size_bytes();
+    vfat.create_dir("/a long dir").unwrap();
+    vfat.create_file("/a long dir/big.bin").unwrap().write_all(&vec![1; cluster_size as usize]).unwrap();
+    let big = vfat.get_entry("/a long dir/big.bin").unwrap();
+    let big_index = big.dir_entry_index_range.end;
+    big.dir.0.lock().set_file_size(big_index, 3 * cluster_size).unwrap();
+    drop(big);
+    let dir = vfat.get_entry("/a long dir").unwrap();
+    let (dir_end, dir_cluster) = (dir.dir_entry_index_range.end, dir.metadata.first_cluster);
+    drop(dir);
+    // the LFN checksum no longer matches, so the path can't be resolved
+    edit_raw_entry(&vfat.root().unwrap(), dir_end, |bytes| bytes[0] = b'B');
+    assert!(vfat.open_dir("/a long dir").is_err());
+
+    let report = vfat.check().unwrap();
+    let finding = report.findings.iter()
+        .find(|finding| match finding.problem { Problem::BrokenChain(_) => true, _ => false })
+        .unwrap();
+    assert_matches!(&finding.location, &Location::Entry { dir_cluster: c, index, .. } if c == dir_cluster && index == big_index);
+    assert_matches!(&finding.problem, &Problem::BrokenChain(ChainProblem::TooShort { .. }));
+
+    let policy = RepairPolicy { truncate_oversized_files: true, ..RepairPolicy::default() };
+    assert_eq!(vfat.repair(&report, policy).unwrap(), 1);
+    let report = vfat.check().unwrap();
+    assert!(!report.findings.iter().any(|finding| match finding.problem { Problem::BrokenChain(_) => true, _ => false }),
+            "{:#?}", report.findings);
+}
+
+#[test]
+fn vfat_repair_read_only() {
+    use tests::mock::SparseDevice;
+    use vfat::{CrossLink, LostClusterReport, ReclaimMode, RepairPolicy};
+    use ReadOnlyDevice;
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let vfat = VFatFileSystem::from(Box::new(ReadOnlyDevice::new(device))).unwrap();
+    let report = vfat.check().unwrap();
+    let read_only = |error: ::std::io::Error| match ::error::Error::of(&error) {
+        Some(&::error::Error::ReadOnly) => true,
+        _ => false,
+    };
+    assert!(read_only(vfat.repair(&report, RepairPolicy::all()).unwrap_err()));
+    let lost = LostClusterReport { chains: vec![], lost_clusters: 0 };
+    assert!(read_only(vfat.reclaim_lost_clusters(&lost, ReclaimMode::SaveToFiles).unwrap_err()));
+    let cross_link = CrossLink {
+        cluster: 4,
+        first_owner: "/a".to_string(),
+        second_owner: "/b".to_string(),
+        previous_cluster: Some(3),
+    };
+    assert!(read_only(vfat.repair_cross_link(&cross_link).unwrap_err()));
+}
+
+#[test]
+fn vfat_check_structure() {
+    use vfat::{CrossLink, Finding, Location, Problem, Severity};
+
+    // The entry for cluster 5 differs between the copies of the FAT.
+    let mut partition = load_partition("mock1.fat32.img");
+    let ebpb = BiosParameterBlock::read_from(&mut partition).unwrap();
+    let second_fat = ebpb.reserved_logical_sectors as u64 + ebpb.logical_sectors_per_fat as u64;
+    let mut sector = [0u8; 512];
+    partition.read_sector(second_fat, &mut sector).unwrap();
+    sector[5 * 4] ^= 0xFF;
+    partition.write_sector(second_fat, &sector).unwrap();
+    let vfat = VFatFileSystem::from(Box::new(partition)).unwrap();
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let root_cluster = vfat.lock().root_dir_cluster();
+
+    // The second cluster of b.txt links to the second cluster of a.txt.
+    vfat.create_file("/a.txt").unwrap().write_all(&vec![0xAA; 3 * cluster_size]).unwrap();
+    vfat.create_file("/b.txt").unwrap().write_all(&vec![0xBB; 3 * cluster_size]).unwrap();
+    let mut fat = vfat.lock().fat();
+    let a_first = vfat.get_entry("/a.txt").unwrap().metadata.first_cluster;
+    let b_first = vfat.get_entry("/b.txt").unwrap().metadata.first_cluster;
+    let a_second = fat.get_next_in_chain(a_first).unwrap().unwrap();
+    let b_second = fat.get_next_in_chain(b_first).unwrap().unwrap();
+    let b_third = fat.get_next_in_chain(b_second).unwrap().unwrap();
+    fat.set_raw(b_second, a_second).unwrap();
+
+    vfat.create_file("/c.txt").unwrap();
+    let c_entry = vfat.get_entry("/c.txt").unwrap();
+    let c_index = c_entry.dir_entry_index_range.end;
+    drop(c_entry);
+    edit_raw_entry(&vfat.root().unwrap(), c_index, |bytes| bytes[..8].copy_from_slice(b"C*      "));
+    // ".." of /rpi3-docs points elsewhere
+    let docs_cluster = vfat.get_entry("/rpi3-docs").unwrap().metadata.first_cluster;
+    edit_raw_entry(&vfat.open_dir("/rpi3-docs").unwrap(), 1, |bytes| {
+        bytes[20..22].copy_from_slice(&[0, 0]);
+        bytes[26..28].copy_from_slice(&[0x34, 0x12]);
+    });
+
+    let findings = vfat.check().unwrap().findings;
+    let expected = vec![
+        Finding { severity: Severity::Warning, location: Location::Cluster(5), problem: Problem::FatMirrorMismatch },
+        Finding {
+            severity: Severity::Warning,
+            location: Location::Entry { dir: "/".to_string(), dir_cluster: root_cluster, index: c_index - 1 },
+            problem: Problem::LfnChecksumMismatch { count: 1 },
+        },
+        Finding {
+            severity: Severity::Warning,
+            location: Location::Entry { dir: "/".to_string(), dir_cluster: root_cluster, index: c_index },
+            problem: Problem::InvalidShortName,
+        },
+        Finding {
+            severity: Severity::Error,
+            location: Location::Entry { dir: "/rpi3-docs".to_string(), dir_cluster: docs_cluster, index: 1 },
+            problem: Problem::BadDotEntry { expected: root_cluster, found: Some(0x1234) },
+        },
+        Finding {
+            severity: Severity::Error,
+            location: Location::Cluster(a_second),
+            problem: Problem::CrossLink(CrossLink {
+                cluster: a_second,
+                first_owner: "/a.txt".to_string(),
+                second_owner: "/b.txt".to_string(),
+                previous_cluster: Some(b_second),
+            }),
+        },
+        Finding { severity: Severity::Warning, location: Location::Cluster(b_third), problem: Problem::LostChain },
+    ];
+    assert_eq!(findings.len(), expected.len(), "{:#?}", findings);
+    for finding in &expected {
+        assert!(findings.contains(finding), "{:?} not in {:#?}", finding, findings);
+    }
+}
+
+#[test]
+fn vfat_defragment_file() {
+    use vfat::fat::Status;
+    use vfat::error::is_busy;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let mut data = Vec::new();
+    {
+        // writing both files a cluster at a time interleaves their clusters
+        let mut a = vfat.create_file("/a.bin").unwrap();
+        let mut b = vfat.create_file("/b.bin").unwrap();
+        for i in 0..4 {
+            let cluster = vec![i as u8 + 1; cluster_size];
+            a.write_all(&cluster).unwrap();
+            b.write_all(&vec![0xBB; cluster_size]).unwrap();
+            data.extend_from_slice(&cluster);
+        }
+    }
+    let mut fat = vfat.lock().fat();
+    let old_clusters = fat.chain_clusters(vfat.get_entry("/a.bin").unwrap().metadata.first_cluster).unwrap();
+    assert_eq!(old_clusters.len(), 4);
+    assert_eq!(vfat.fragmentation_of("/a.bin").unwrap(), 1.0);
+    assert_eq!(vfat.fragmentation_of("/hello.txt").unwrap(), 1.0);
+
+    // only the single clusters of b.bin are free
+    let filler = fat.alloc_contiguous(fat.free_cluster_count().unwrap()).unwrap();
+    vfat.remove("/b.bin").unwrap();
+    let free = vfat.lock().fs_info().free_count;
+    assert_eq!(free, 4);
+    assert_matches!(fs_error(vfat.defragment_file("/a.bin")), ::error::Error::NoContiguousSpace);
+    assert_eq!(vfat.lock().fs_info().free_count, free);
+    assert_eq!(vfat.fragmentation_of("/a.bin").unwrap(), 1.0);
+
+    fat.free_chain(filler).unwrap();
+    let free = vfat.lock().fs_info().free_count;
+    {
+        let _file = vfat.open_file("/a.bin", FileOpenMode::Read).unwrap();
+        assert!(is_busy(&vfat.defragment_file("/a.bin").unwrap_err()));
+    }
+    vfat.defragment_file("/a.bin").unwrap();
+    assert_eq!(vfat.fragmentation_of("/a.bin").unwrap(), 0.25);
+    assert_eq!(vfat.lock().fs_info().free_count, free);
+    for &cluster in &old_clusters {
+        assert_eq!(fat.get(cluster).unwrap().status(), Status::Free);
+    }
+    let mut read_back = Vec::new();
+    vfat.open_file("/a.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read_back).unwrap();
+    assert_eq!(read_back, data);
+    // contiguous files and directories are left alone
+    vfat.defragment_file("/a.bin").unwrap();
+    assert_matches!(fs_error(vfat.defragment_file("/rpi3-docs")), ::error::Error::IsADirectory);
+    drop(fat);
+
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    let mut read_back = Vec::new();
+    vfat.open_file("/a.bin", FileOpenMode::Write).unwrap().read_to_end(&mut read_back).unwrap();
+    assert_eq!(read_back, data);
+    assert!(vfat.check().unwrap().is_clean());
+}
+
+#[test]
+fn vfat_grow_volume() {
+    use tests::mock::SparseDevice;
+    use vfat::resize;
+
+    let mut device = SparseDevice::new(4 * 70000);
+    format_fat32(&mut device, 0, 70000);
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let old_clusters = vfat.lock().cluster_count;
+    let data: Vec<u8> = (0..100 * cluster_size).map(|i| (i % 251) as u8).collect();
+    vfat.create_dir("/dir").unwrap();
+    vfat.create_file("/dir/big.bin").unwrap().write_all(&data).unwrap();
+    vfat.create_file("/hello.txt").unwrap().write_all(b"Hello, world!").unwrap();
+    let old_free = vfat.lock().fs_info().free_count;
+    let mut device = vfat.try_unmount().unwrap();
+
+    assert_matches!(fs_error(resize::grow(&mut device, 60000)), ::error::Error::InvalidInput { .. });
+    assert_matches!(fs_error(resize::grow(&mut device, 4 * 70000 + 1)), ::error::Error::InvalidInput { .. });
+    let old_ebpb = BiosParameterBlock::read_from(&mut device).unwrap();
+    resize::grow(&mut device, 4 * 70000).unwrap();
+    let ebpb = BiosParameterBlock::read_from(&mut device).unwrap();
+    assert_eq!(ebpb.total_sectors(), 4 * 70000);
+    assert!(ebpb.logical_sectors_per_fat > old_ebpb.logical_sectors_per_fat);
+    assert_eq!(BiosParameterBlock::read_backup_from(&mut device).unwrap().to_bytes()[..], ebpb.to_bytes()[..]);
+
+    let vfat = VFatFileSystem::from(device).unwrap();
+    let new_clusters = vfat.lock().cluster_count;
+    assert!(new_clusters > 3 * old_clusters);
+    assert_eq!(vfat.lock().fs_info().free_count, old_free + (new_clusters - old_clusters));
+    let mut read_back = Vec::new();
+    vfat.open_file("/dir/big.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read_back).unwrap();
+    assert!(read_back == data);
+    let mut read_back = String::new();
+    vfat.open_file("/hello.txt", FileOpenMode::Read).unwrap().read_to_string(&mut read_back).unwrap();
+    assert_eq!(read_back, "Hello, world!");
+    assert!(vfat.check().unwrap().is_clean());
+
+    // all of the new clusters can be allocated and written
+    let mut fat = vfat.lock().fat();
+    let free = fat.free_cluster_count().unwrap();
+    assert_eq!(free, vfat.lock().fs_info().free_count);
+    let first = fat.alloc_contiguous(free).unwrap();
+    let clusters = fat.chain_clusters(first).unwrap();
+    let last = *clusters.last().unwrap();
+    assert_eq!(last, new_clusters + 1);
+    vfat.lock().write_cluster(last, 0, b"end of the volume").unwrap();
+    let mut buf = [0u8; 17];
+    vfat.lock().read_cluster(last, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"end of the volume");
+}
+
+#[test]
+fn vfat_grow_volume_refuses_dirty() {
+    use tests::mock::SparseDevice;
+    use vfat::resize;
+
+    let mut device = SparseDevice::new(2 * 70000);
+    format_fat32(&mut device, 0, 70000);
+    let ebpb = BiosParameterBlock::read_from(&mut device).unwrap();
+    let offset = ebpb.reserved_logical_sectors as u64 * 512 + 7;
+    device.write_by_offset(offset, &[0x07]).unwrap();
+    assert_matches!(fs_error(resize::grow(&mut device, 2 * 70000)), ::error::Error::Corrupted { .. });
+    assert_eq!(BiosParameterBlock::read_from(&mut device).unwrap().total_sectors(), 70000);
+}
+
+/// Returns the files below `dir` with their contents and the directories,
+/// with `None`, by their path relative to `dir`. Symbolic links are left out.
+fn host_tree(dir: &Path) -> Vec<(::std::path::PathBuf, Option<Vec<u8>>)> {
+    use std::fs;
+
+    let mut tree = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let file_type = fs::symlink_metadata(&path).unwrap().file_type();
+        let name = ::std::path::PathBuf::from(path.file_name().unwrap());
+        if file_type.is_dir() {
+            tree.push((name.clone(), None));
+            tree.extend(host_tree(&path).into_iter().map(|(child, contents)| (name.join(child), contents)));
+        } else if file_type.is_file() {
+            tree.push((name, Some(fs::read(&path).unwrap())));
+        }
+    }
+    tree.sort();
+    tree
+}
+
+#[test]
+fn vfat_populate_and_extract() {
+    use std::fs;
+    use vfat::{populate_from_dir, extract_to_dir, PopulateStats};
+
+    let base = ::std::env::temp_dir().join(format!("fat32-populate-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    let host = base.join("boot");
+    fs::create_dir_all(host.join("overlays/nested")).unwrap();
+    let firmware: Vec<u8> = (0..5000).map(|i| (i % 253) as u8).collect();
+    fs::write(host.join("config.txt"), b"arm_64bit=1\nenable_uart=1\n").unwrap();
+    fs::write(host.join("start.elf"), &firmware).unwrap();
+    fs::write(host.join("overlays/uart.dtbo"), b"overlay").unwrap();
+    fs::write(host.join("overlays/nested/empty.bin"), b"").unwrap();
+    #[cfg(unix)]
+    ::std::os::unix::fs::symlink("config.txt", host.join("link.txt")).unwrap();
+    let skipped_symlinks = if cfg!(unix) { 1 } else { 0 };
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let stats = populate_from_dir(&vfat, &host, Path::new("/boot/fw")).unwrap();
+    let expected = PopulateStats { files: 4, dirs: 2, bytes: 5000 + 26 + 7, skipped_symlinks };
+    assert_eq!(stats, expected);
+    let modified = fs::metadata(host.join("start.elf")).unwrap().modified().unwrap()
+        .duration_since(::std::time::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let entry = vfat.get_entry("/boot/fw/start.elf").unwrap();
+    assert_eq!(entry.metadata().modified().timestamp() / 2, modified / 2);
+    drop(entry);
+
+    // populating again overwrites the files
+    fs::write(host.join("config.txt"), b"arm_64bit=0\n").unwrap();
+    let stats = populate_from_dir(&vfat, &host, Path::new("/boot/fw")).unwrap();
+    assert_eq!(stats.bytes, 5000 + 12 + 7);
+    assert_eq!(vfat.get_entry("/boot/fw/config.txt").unwrap().metadata().size, 12);
+
+    let out = base.join("out");
+    let stats = extract_to_dir(&vfat, Path::new("/boot/fw"), &out).unwrap();
+    assert_eq!(stats, PopulateStats { files: 4, dirs: 2, bytes: 5000 + 12 + 7, skipped_symlinks: 0 });
+    assert_eq!(host_tree(&out), host_tree(&host));
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn vfat_populate_clamps_times_to_fat_epoch() {
+    use std::time::{Duration, UNIX_EPOCH};
+    use vfat::populate::date_time;
+    use vfat::fat_epoch;
+
+    // 1975-01-01 and a time before 1970 both predate FAT timestamps
+    assert_eq!(date_time(UNIX_EPOCH + Duration::from_secs(157_766_400)), fat_epoch());
+    assert_eq!(date_time(UNIX_EPOCH - Duration::from_secs(1)), fat_epoch());
+    let after = date_time(UNIX_EPOCH + Duration::from_secs(315_532_800 + 86_400));
+    assert_eq!(after, Date::from_ymd(1980, 1, 2).and_hms(0, 0, 0));
+}
+
+#[test]
+fn vfat_metrics() {
+    use vfat::{Metrics, MountOptions};
+
+    let options = MountOptions { disable_cache: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(load_partition("mock1.fat32.img")), options).unwrap();
+    vfat.lock().reset_metrics();
+    let mut file = vfat.create_file("/metrics.bin").unwrap();
+    let data: Vec<u8> = (0..10 * 512).map(|i| (i % 251) as u8).collect();
+    file.write_all(&data).unwrap();
+    // 10 data sectors plus the directory entry, which is written on
+    // creation and again when the first cluster and the size are set;
+    // the 9 clusters after the first are allocated as one run, and every
+    // FAT entry is written to both FATs
+    assert_eq!(vfat.lock().metrics(), Metrics {
+        sectors_read: 1,
+        sectors_written: 13,
+        fat_reads: 25,
+        fat_writes: 24,
+        clusters_allocated: 10,
+        clusters_freed: 0,
+        cache_hits: None,
+    });
+
+    file.seek(SeekFrom::Start(0)).unwrap();
+    vfat.lock().reset_metrics();
+    let mut buf = [0; 512];
+    for chunk in data.chunks(512) {
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], chunk);
+    }
+    assert_eq!(vfat.lock().metrics(), Metrics { sectors_read: 10, fat_reads: 10, ..Metrics::default() });
+
+    drop(file);
+    vfat.remove("/metrics.bin").unwrap();
+    assert_eq!(vfat.lock().metrics().clusters_freed, 10);
+    vfat.lock().reset_metrics();
+    assert_eq!(vfat.lock().metrics(), Metrics::default());
+
+    // cache hits are counted from the last reset
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    vfat.open_dir("/rpi3-docs").unwrap();
+    vfat.lock().reset_metrics();
+    assert_eq!(vfat.lock().metrics().cache_hits, Some(0));
+    vfat.open_dir("/rpi3-docs").unwrap();
+    assert_eq!(vfat.lock().metrics().cache_hits, Some(2));
+}
+
+#[cfg(feature = "trace")]
+#[test]
+fn vfat_trace_hook() {
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use vfat::Event;
+
+    static SECTORS_WRITTEN: AtomicUsize = ATOMIC_USIZE_INIT;
+    fn hook(event: Event) {
+        if let Event::SectorsWritten { count, .. } = event {
+            SECTORS_WRITTEN.fetch_add(count as usize, Ordering::SeqCst);
+        }
+    }
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    vfat.lock().set_trace_hook(Some(hook));
+    let mut file = vfat.create_file("/traced.bin").unwrap();
+    file.write_all(&[0x5A; 3 * 512]).unwrap();
+    drop(file);
+    let traced = vfat.lock().metrics().sectors_written;
+    assert!(traced > 3);
+    assert_eq!(SECTORS_WRITTEN.load(Ordering::SeqCst), traced);
+
+    vfat.lock().set_trace_hook(None);
+    vfat.create_file("/untraced.bin").unwrap();
+    assert!(vfat.lock().metrics().sectors_written > traced);
+    assert_eq!(SECTORS_WRITTEN.load(Ordering::SeqCst), traced);
+}
+
+#[test]
+fn vfat_reserved_fat_entries_not_freed() {
+    use tests::mock::SparseDevice;
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    vfat.create_file("/one.txt").unwrap();
+    // unmounting marks the volume clean, which makes FAT[1] look like the
+    // end of a chain
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    let fat1 = || {
+        let vfat = vfat.lock();
+        let ebpb = vfat.params();
+        let offset = ebpb.reserved_logical_sectors as u64 * ebpb.bytes_per_logical_sector as u64 + 4;
+        let mut buf = [0u8; 4];
+        vfat.device.read_by_offset(offset, &mut buf).unwrap();
+        buf
+    };
+    let expected = fat1();
+    assert!(vfat.lock().fat().get(1).is_err());
+    assert!(vfat.lock().fat().free_chain(1).is_err());
+    vfat.sync().unwrap();
+    assert_eq!(fat1(), expected);
+
+    // a corrupted entry refers to cluster 1, whose FAT entry holds the
+    // volume flags
+    let entry = vfat.get_entry("/one.txt").unwrap();
+    let index = entry.dir_entry_index_range.end;
+    edit_raw_entry(&entry.dir, index, |bytes| {
+        bytes[20..22].copy_from_slice(&[0, 0]);
+        bytes[26..28].copy_from_slice(&[1, 0]);
+    });
+    drop(entry);
+    assert_eq!(vfat.get_entry("/one.txt").unwrap().metadata.first_cluster, 1);
+    let error = vfat.remove("/one.txt").err().unwrap();
+    assert_matches!(::error::Error::of(&error), Some(&::error::Error::Corrupted { .. }));
+    vfat.sync().unwrap();
+    assert_eq!(fat1(), expected);
+}
+
+#[test]
+fn vfat_volume_dirty_flag() {
+    fn fat1_offset<T: BlockDevice>(device: &mut T) -> u64 {
+        let ebpb = BiosParameterBlock::read_from(device).unwrap();
+        ebpb.reserved_logical_sectors as u64 * ebpb.bytes_per_logical_sector as u64 + 4
+    }
+
+    fn clean_shutdown_bit<T: BlockDevice>(device: &mut T) -> bool {
+        let offset = fat1_offset(device);
+        let mut buf = [0u8; 4];
+        device.read_by_offset(offset, &mut buf).unwrap();
+        (buf[3] & 0x08) != 0
+    }
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    assert!(!vfat.lock().was_mounted_dirty());
+    let mut device = vfat.lock().device.clone();
+    assert!(clean_shutdown_bit(&mut device));
+
+    vfat.create_file("/dirty.txt").unwrap().write_all(b"dirty").unwrap();
+    assert!(!clean_shutdown_bit(&mut device));
+    drop(device);
+
+    let mut device = vfat.try_unmount().unwrap();
+    assert!(clean_shutdown_bit(&mut device));
+    let vfat = VFatFileSystem::from(device).unwrap();
+    assert!(!vfat.lock().was_mounted_dirty());
+
+    let mut partition = load_partition("mock1.fat32.img");
+    let offset = fat1_offset(&mut partition);
+    let mut buf = [0u8; 4];
+    partition.read_by_offset(offset, &mut buf).unwrap();
+    buf[3] &= !0x08;
+    partition.write_by_offset(offset, &buf).unwrap();
+    let vfat = VFatFileSystem::from(Box::new(partition)).unwrap();
+    assert!(vfat.lock().was_mounted_dirty());
+    let mut device = vfat.try_unmount().unwrap();
+    assert!(clean_shutdown_bit(&mut device));
+}
+
+#[test]
+fn vfat_free_chain_batches_writes() {
+    use tests::mock::InstrumentedDevice;
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let writes = device.writes.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let free_before = vfat.lock().fs_info().free_count;
+
+    let mut fat = vfat.lock().fat();
+    let first = fat.alloc_contiguous(1000).unwrap();
+    writes.lock().unwrap().clear();
+    fat.free_chain(first).unwrap();
+
+    // 1000 entries span at most 9 sectors of 128 entries in each of the 2 FATs.
+    let writes = writes.lock().unwrap();
+    assert!(writes.len() <= 2 * 9, "{} sector writes", writes.len());
+    assert_eq!(vfat.lock().fs_info().free_count, free_before);
+    assert_eq!(fat.get(first).unwrap().status(), ::vfat::fat::Status::Free);
+    assert_eq!(fat.get(first + 999).unwrap().status(), ::vfat::fat::Status::Free);
+}
+
+#[test]
+fn vfat_write_clusters_in_runs() {
+    use tests::mock::{InstrumentedDevice, SparseDevice};
+    use vfat::MountOptions;
+
+    let mut sparse = SparseDevice::new(70000);
+    format_fat32(&mut sparse, 0, 70000);
+    let device = InstrumentedDevice::new(sparse);
+    let writes = device.writes.clone();
+    let multi_writes = device.multi_writes.clone();
+    let options = MountOptions { disable_cache: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+    let data_start = vfat.lock().params().data_start_sector();
+    // the number of device write calls in the data region
+    let data_writes = || {
+        let multi_writes = multi_writes.lock().unwrap();
+        let runs: Vec<_> = multi_writes.iter().filter(|&&(first, _)| first >= data_start).collect();
+        let run_sectors: u64 = runs.iter().map(|&&(_, count)| count).sum();
+        let sectors = writes.lock().unwrap().iter().filter(|&&sector| sector >= data_start).count();
+        runs.len() + sectors - run_sectors as usize
+    };
+    let extents = |path: &str| {
+        let first_cluster = vfat.get_entry(path).unwrap().metadata.first_cluster;
+        let clusters = vfat.lock().fat().chain_clusters(first_cluster).unwrap();
+        (clusters.len(), 1 + clusters.windows(2).filter(|pair| pair[1] != pair[0] + 1).count())
+    };
+
+    let data: Vec<u8> = (0..8 << 20).map(|i| (i % 253) as u8).collect();
+    let mut file = vfat.create_file("/big.bin").unwrap();
+    writes.lock().unwrap().clear();
+    multi_writes.lock().unwrap().clear();
+    file.write_all(&data).unwrap();
+    let (clusters, runs) = extents("/big.bin");
+    assert_eq!(clusters, 16384);
+    assert_eq!(data_writes(), runs);
+    assert!(runs <= 2, "{} runs", runs);
+    drop(file);
+    let mut read = Vec::new();
+    vfat.open_file("/big.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read).unwrap();
+    assert!(read == data);
+
+    // Gathered buffers are written like a single one.
+    let parts = [&data[..100], &data[100..1000], &[][..], &data[1000..64 * 1024]];
+    let mut file = vfat.create_file("/vectored.bin").unwrap();
+    writes.lock().unwrap().clear();
+    multi_writes.lock().unwrap().clear();
+    assert_eq!(file.write_vectored(&parts).unwrap(), 64 * 1024);
+    assert_eq!(data_writes(), extents("/vectored.bin").1);
+    drop(file);
+    let mut read = Vec::new();
+    vfat.open_file("/vectored.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read).unwrap();
+    assert!(read[..] == data[..64 * 1024]);
+
+    // Whole clusters gathered at a cluster boundary go to the device in a
+    // single run.
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let mut file = vfat.create_file("/aligned.bin").unwrap();
+    file.write_all(&data[..cluster_size]).unwrap();
+    file.flush().unwrap();
+    let parts: Vec<&[u8]> = data[cluster_size..5 * cluster_size].chunks(cluster_size).collect();
+    writes.lock().unwrap().clear();
+    multi_writes.lock().unwrap().clear();
+    assert_eq!(file.write_vectored(&parts).unwrap(), 4 * cluster_size);
+    assert_eq!(extents("/aligned.bin"), (5, 1));
+    assert_eq!(data_writes(), 1);
+    drop(file);
+    let mut read = Vec::new();
+    vfat.open_file("/aligned.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read).unwrap();
+    assert!(read[..] == data[..5 * cluster_size]);
+}
+
+#[test]
+fn vfat_write_clusters_partial_failure() {
+    use tests::mock::{FailingWritesDevice, SparseDevice};
+    use vfat::MountOptions;
+
+    let mut sparse = SparseDevice::new(70000);
+    format_fat32(&mut sparse, 0, 70000);
+    let device = FailingWritesDevice::new(sparse);
+    let failing_sector = device.failing_sector.clone();
+    let options = MountOptions { disable_cache: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+    let data_start = vfat.lock().params().data_start_sector();
+    let chain = |path: &str| {
+        let first_cluster = vfat.get_entry(path).unwrap().metadata.first_cluster;
+        vfat.lock().fat().chain_clusters(first_cluster).unwrap()
+    };
+    let cluster_sector = |cluster: u32| data_start + cluster as u64 - 2;
+
+    // Nothing is written: the clusters allocated for the write are freed.
+    let mut file = vfat.create_file("/partial.bin").unwrap();
+    let free_before = vfat.lock().fat().free_cluster_count().unwrap();
+    *failing_sector.lock().unwrap() = Some(cluster_sector(chain("/partial.bin")[0]));
+    assert!(file.write(&[0xAA; 8 * 512]).is_err());
+    assert_eq!(file.size(), 0);
+    assert_eq!(chain("/partial.bin").len(), 1);
+    assert_eq!(vfat.lock().fat().free_cluster_count().unwrap(), free_before);
+
+    // A failed run of new clusters is written cluster by cluster instead.
+    let blocker = vfat.create_file("/blocker.bin").unwrap();
+    let failing_cluster = vfat.get_entry("/blocker.bin").unwrap().metadata.first_cluster + 3;
+    *failing_sector.lock().unwrap() = Some(cluster_sector(failing_cluster));
+    assert_eq!(file.write(&[0xAA; 8 * 512]).unwrap(), 8 * 512);
+    assert!(!chain("/partial.bin").contains(&failing_cluster));
+    drop(blocker);
+
+    // Overwriting stops at the cluster that fails, and says so.
+    let clusters = chain("/partial.bin");
+    *failing_sector.lock().unwrap() = Some(cluster_sector(clusters[4]));
+    file.seek(SeekFrom::Start(0)).unwrap();
+    assert_eq!(file.write(&[0xBB; 8 * 512]).unwrap(), 4 * 512);
+    *failing_sector.lock().unwrap() = None;
+    drop(file);
+    let mut read = Vec::new();
+    vfat.open_file("/partial.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read.len(), 8 * 512);
+    assert!(read[..4 * 512].iter().all(|&byte| byte == 0xBB));
+    assert!(read[4 * 512..].iter().all(|&byte| byte == 0xAA));
+    assert!(vfat.check().unwrap().is_clean());
+}
+
+#[test]
+fn vfat_usage_bitmap() {
+    fn naive_allocated_count(vfat: &ArcMutex<VFatFileSystem>) -> u32 {
+        let fat = vfat.lock().fat();
+        (2..fat.cluster_count() + 2).filter(|&c| fat.get(c).unwrap().status() != ::vfat::fat::Status::Free).count() as u32
+    }
+
+    fn check(vfat: &ArcMutex<VFatFileSystem>) {
+        let bitmap = vfat.lock().usage_bitmap().unwrap();
+        assert_eq!(bitmap.allocated_count(), naive_allocated_count(vfat));
+        let free: u32 = bitmap.iter_free_runs().map(|(_, len)| len).sum();
+        assert_eq!(free + bitmap.allocated_count(), bitmap.cluster_count());
+        for (start, len) in bitmap.iter_free_runs() {
+            assert!(!bitmap.is_allocated(start) && !bitmap.is_allocated(start + len - 1));
+            assert!(bitmap.is_allocated(start - 1) && bitmap.is_allocated(start + len));
+        }
+    }
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    check(&vfat);
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    for i in 0..5 {
+        vfat.create_file(format!("/bitmap{}.bin", i)).unwrap().write_all(&vec![1; (i + 1) * cluster_size]).unwrap();
+    }
+    check(&vfat);
+    let entry = vfat.get_entry("/bitmap2.bin").unwrap();
+    vfat.remove_entry(entry).unwrap();
+    check(&vfat);
+}
+
+#[test]
+fn vfat_discard_freed_clusters() {
+    use tests::mock::InstrumentedDevice;
+    use vfat::MountOptions;
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let discards = device.discards.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+
+    let mut file = vfat.create_file("/discard.bin").unwrap();
+    file.preallocate(4 * cluster_size as u64).unwrap();
+    file.write_all(&vec![7; 4 * cluster_size]).unwrap();
+    drop(file);
+    let entry = vfat.get_entry("/discard.bin").unwrap();
+    let first_cluster = entry.metadata.first_cluster;
+    let (data_start_sector, sectors_per_cluster) = {
+        let vfat = vfat.lock();
+        (vfat.params().data_start_sector(), vfat.params().logical_sectors_per_cluster as u64)
+    };
+
+    discards.lock().unwrap().clear();
+    vfat.remove_entry(entry).unwrap();
+    let first_sector = data_start_sector + (first_cluster as u64 - 2) * sectors_per_cluster;
+    assert_eq!(*discards.lock().unwrap(), vec![(first_sector, 4 * sectors_per_cluster)]);
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let discards = device.discards.clone();
+    let options = MountOptions { disable_discard: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+    let entry = vfat.get_entry("/hello.txt").unwrap();
+    vfat.remove_entry(entry).unwrap();
+    assert_eq!(discards.lock().unwrap().len(), 0);
+}
+
+#[test]
+fn vfat_write_storage_full() {
+    use vfat::is_storage_full;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let mut file = vfat.create_file("/full.bin").unwrap();
+
+    // Leave exactly 5 free clusters besides the first cluster of the file.
+    let mut fat = vfat.lock().fat();
+    let free = fat.free_cluster_count().unwrap();
+    fat.alloc_contiguous(free - 5).unwrap();
+    assert_eq!(fat.free_cluster_count().unwrap(), 5);
+
+    let data = vec![0x5A; 7 * cluster_size];
+    file.write_all(&data[..4 * cluster_size]).unwrap();
+    let error = file.write(&data[4 * cluster_size..]).unwrap_err();
+    // nothing is written if the data can't fit
+    assert!(is_storage_full(&error));
+    assert_matches!(fs_error::<()>(Err(error)), ::error::Error::NoSpace);
+    assert_eq!(file.size(), 4 * cluster_size as u64);
+
+    file.write_all(&data[4 * cluster_size..6 * cluster_size]).unwrap();
+    assert_eq!(fat.free_cluster_count().unwrap(), 0);
+    assert!(is_storage_full(&file.write(&[1]).unwrap_err()));
+    drop(file);
+    drop(fat);
+
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    let mut read_back = Vec::new();
+    vfat.open_file("/full.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read_back).unwrap();
+    assert_eq!(read_back, &data[..6 * cluster_size]);
+}
+
+#[test]
+fn vfat_alloc_contiguous_too_many() {
+    use tests::mock::SparseDevice;
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let mut fat = vfat.lock().fat();
+    // Scatter the free clusters, so that the chain is built cluster by
+    // cluster until they run out.
+    let first = fat.alloc_contiguous(100).unwrap();
+    for cluster in (first..first + 100).filter(|cluster| (cluster - first) % 2 == 0) {
+        fat.set_raw(cluster, 0).unwrap();
+    }
+    let free = fat.free_cluster_count().unwrap();
+    assert_matches!(fs_error(fat.alloc_contiguous(free + 1)), ::error::Error::NoSpace);
+    assert_eq!(fat.free_cluster_count().unwrap(), free);
+    assert_eq!(fat.get(first).unwrap().status(), ::vfat::fat::Status::Free);
+}
+
+#[test]
+fn vfat_chain_length() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let mut fat = vfat.lock().fat();
+    let first = fat.alloc_contiguous(5).unwrap();
+    assert_eq!(fat.chain_length(first).unwrap(), 5);
+    assert_eq!(fat.chain_clusters(first).unwrap(), (first..first + 5).collect::<Vec<_>>());
+    assert_eq!(fat.chain_length(first + 4).unwrap(), 1);
+
+    // truncated: the chain runs into a free cluster
+    fat.set_raw(first + 2, 0).unwrap();
+    assert_eq!(fat.chain_length(first).unwrap_err().kind(), ::std::io::ErrorKind::InvalidData);
+    assert_matches!(fs_error(fat.chain_length(first)), ::error::Error::Corrupted { what: "cluster chain is broken" });
+
+    // looped: the last cluster points back at the first
+    fat.set_raw(first + 2, first + 3).unwrap();
+    fat.set_raw(first + 4, first).unwrap();
+    assert_eq!(fat.chain_length(first).unwrap_err().kind(), ::std::io::ErrorKind::InvalidData);
+    assert_matches!(fs_error(fat.chain_length(first)), ::error::Error::Corrupted { what: "cluster chain has a cycle" });
+}
+
+#[test]
+fn vfat_open_file_larger_than_chain() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    vfat.create_file("/short.bin").unwrap().write_all(&vec![1; 2 * cluster_size]).unwrap();
+
+    let mut entry = vfat.get_entry("/short.bin").unwrap();
+    entry.set_file_size(3 * cluster_size as u32).unwrap();
+    let error = vfat.open_file("/short.bin", FileOpenMode::Read).err().unwrap();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn vfat_cluster_beyond_data_region() {
+    use vfat::is_storage_full;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let mut fat = vfat.lock().fat();
+    let end = fat.cluster_count() + 2;
+    assert!(fat.size() >= end);
+
+    assert_eq!(fat.get(end).unwrap_err().kind(), ::std::io::ErrorKind::InvalidData);
+    let error = vfat.lock().write_cluster(end, 0, &[0; 4]).unwrap_err();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::InvalidData);
+
+    // a chain that runs past the data region can't be opened
+    vfat.create_file("/past.bin").unwrap().write_all(&vec![1; 2 * cluster_size]).unwrap();
+    let first = vfat.get_entry("/past.bin").unwrap().metadata().first_cluster;
+    fat.set_raw(first, end).unwrap();
+    let error = vfat.open_file("/past.bin", FileOpenMode::Read).err().unwrap();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::InvalidData);
+
+    // the FAT slack is never handed out
+    let free = fat.free_cluster_count().unwrap();
+    fat.alloc_contiguous(free).unwrap();
+    assert!(is_storage_full(&vfat.create_file("/none.bin").err().unwrap()));
+}
+
+#[test]
+fn vfat_faulty_device_reads() {
+    use self::mock::FaultyDevice;
+
+    let device = FaultyDevice::new(load_partition("mock1.fat32.img"));
+    let faults = device.faults.clone();
+    faults.lock().unwrap().failing_reads.extend(&[0, 6]);
+    assert_matches!(VFatFileSystem::from(Box::new(device)).err(), Some(::vfat::Error::Io(_)));
+    assert!(faults.lock().unwrap().reads > 0);
+
+    let device = FaultyDevice::new(load_partition("mock1.fat32.img"));
+    device.faults.lock().unwrap().garbled_reads.insert(0);
+    assert!(VFatFileSystem::from(Box::new(device)).unwrap().lock().boot_sector_damaged());
+
+    // a garbled FAT sector must surface as an error, not a panic
+    let device = FaultyDevice::new(load_partition("mock1.fat32.img"));
+    let faults = device.faults.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let reserved = 32;
+    faults.lock().unwrap().garbled_reads.insert(reserved);
+    faults.lock().unwrap().short_reads.insert(reserved + 1);
+    if let Ok(mut entries) = vfat.root().and_then(|root| root.entries()) {
+        while let Ok(Some(entry)) = entries.next() {
+            if entry.is_file() {
+                let _ = entry.open_file(FileOpenMode::Read).and_then(|mut file| file.read_to_end(&mut Vec::new()));
+            }
+        }
+    }
+}
+
+#[test]
+fn vfat_read_recovery_zero_fills() {
+    use self::mock::FaultyDevice;
+    use vfat::{MountOptions, BadRange};
+
+    let shared = ArcMutex::new(load_partition("mock1.fat32.img"));
+    let vfat = VFatFileSystem::from(Box::new(shared.clone())).unwrap();
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let data: Vec<u8> = (0..5 * cluster_size - 100).map(|i| (i % 251) as u8 + 1).collect();
+    vfat.create_file("/dying.bin").unwrap().write_all(&data).unwrap();
+    let first_cluster = vfat.get_entry("/dying.bin").unwrap().metadata().first_cluster();
+    let bad_cluster = vfat.lock().fat().chain_clusters(first_cluster).unwrap()[2];
+    let bad_sector = vfat.lock().cluster_to_device_offset(bad_cluster) / 512;
+    vfat.try_unmount().unwrap();
+
+    let mount = |read_recovery| {
+        let device = FaultyDevice::new(shared.clone());
+        device.faults.lock().unwrap().failing_reads.insert(bad_sector);
+        let options = MountOptions { disable_cache: true, read_recovery, ..MountOptions::default() };
+        VFatFileSystem::from_with_options(Box::new(device), options).unwrap()
+    };
+
+    // by default the read fails
+    let vfat = mount(false);
+    let mut file = vfat.open_file("/dying.bin", FileOpenMode::Read).unwrap();
+    assert!(file.read_to_end(&mut Vec::new()).is_err());
+    assert!(file.read_errors().is_empty());
+    drop(file);
+    drop(vfat);
+
+    let vfat = mount(true);
+    let mut file = vfat.open_file("/dying.bin", FileOpenMode::Read).unwrap();
+    let mut read = Vec::new();
+    file.read_to_end(&mut read).unwrap();
+    let bad = 2 * cluster_size..3 * cluster_size;
+    assert_eq!(read.len(), data.len());
+    assert_eq!(&read[..bad.start], &data[..bad.start]);
+    assert!(read[bad.clone()].iter().all(|&byte| byte == 0));
+    assert_eq!(&read[bad.end..], &data[bad.end..]);
+    let expected = [BadRange { cluster: bad_cluster, offset: bad.start as u64, len: cluster_size as u64 }];
+    assert_eq!(file.read_errors(), &expected[..]);
+
+    // whole clusters are read in runs, which fall back to single clusters
+    let mut chunks = Vec::new();
+    file.read_chunks(|chunk| {
+        chunks.extend_from_slice(chunk);
+        Ok(())
+    }).unwrap();
+    assert_eq!(chunks, read);
+    assert_eq!(file.read_errors(), &expected[..]);
+}
+
+/// Runs `operation` on a copy of mock1 with the `fail_at`-th write after
+/// mounting and every later write failing, then checks that the volume left
+/// behind still mounts and can be listed.
+fn check_mountable_after_failed_write<F>(fail_at: u64, operation: F) -> bool
+    where F: FnOnce(&ArcMutex<VFatFileSystem>) -> ::std::io::Result<()>
+{
+    use self::mock::FaultyDevice;
+
+    let shared = ArcMutex::new(load_partition("mock1.fat32.img"));
+    let device = FaultyDevice::new(shared.clone());
+    let faults = device.faults.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    {
+        let mut faults = faults.lock().unwrap();
+        faults.fail_nth_write = Some(faults.writes + fail_at);
+        faults.fail_later_writes = true;
+    }
+    let result = operation(&vfat);
+    let unmounted = vfat.try_unmount();
+    let failed = faults.lock().unwrap().failed_writes > 0;
+    assert!(result.is_err() || unmounted.is_err() || !failed);
+    drop(unmounted);
+
+    let vfat = VFatFileSystem::from(Box::new(shared)).expect("volume unmountable after a failed write");
+    let root = vfat.root().unwrap();
+    let mut entries = root.entries().unwrap();
+    while let Some(_) = entries.next().unwrap() {}
+    failed
+}
+
+/// Runs `operation` on a copy of mock1 prepared by `setup`, losing every
+/// write from the `cut_at`-th one after mounting on, as if power was cut
+/// there. The FAT is cached so that its updates are written late. Then
+/// checks that no entry of the volume left behind refers to unallocated
+/// clusters. Returns `false` once nothing was lost.
+fn check_consistent_after_power_cut<S, F>(cut_at: u64, setup: S, operation: F) -> bool
+    where S: FnOnce(&ArcMutex<VFatFileSystem>) -> ::std::io::Result<()>,
+          F: FnOnce(&ArcMutex<VFatFileSystem>) -> ::std::io::Result<()>
+{
+    use self::mock::FaultyDevice;
+    use vfat::{MountOptions, FatCacheMode};
+
+    let shared = ArcMutex::new(load_partition("mock1.fat32.img"));
+    let device = FaultyDevice::new(shared.clone());
+    let faults = device.faults.clone();
+    let options = MountOptions { fat_cache: FatCacheMode::Lazy, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+    setup(&vfat).unwrap();
+    vfat.sync().unwrap();
+    {
+        let mut faults = faults.lock().unwrap();
+        faults.lose_writes_from = Some(faults.writes + cut_at);
+    }
+    operation(&vfat).unwrap();
+    vfat.try_unmount().unwrap();
+    let lost = {
+        let faults = faults.lock().unwrap();
+        faults.writes > faults.lose_writes_from.unwrap()
+    };
+
+    let vfat = VFatFileSystem::from(Box::new(shared)).expect("volume unmountable after a power cut");
+    assert_eq!(vfat.find_broken_chains().unwrap(), vec![], "power cut at write {}", cut_at);
+    lost
+}
+
+#[test]
+fn vfat_power_cut_during_create() {
+    let data = vec![0x5A; 5000];
+    let mut cut_at = 0;
+    while check_consistent_after_power_cut(cut_at, |_| Ok(()), |vfat| {
+        vfat.create_file("/new.bin")?.write_all(&data)?;
+        vfat.create_dir("/new-dir").map(|_| ())
+    }) {
+        cut_at += 1;
+    }
+    assert!(cut_at > 0);
+}
+
+#[test]
+fn vfat_power_cut_during_append() {
+    let data = vec![0xA5; 10000];
+    let mut cut_at = 0;
+    while check_consistent_after_power_cut(cut_at, |vfat| {
+        vfat.create_file("/log.txt")?.write_all(&data[..100])
+    }, |vfat| {
+        let mut file = vfat.open_file("/log.txt", FileOpenMode::Write)?;
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&data[100..6000])?;
+        file.flush()?;
+        file.write_all(&data[6000..])?;
+        file.set_len(3000)
+    }) {
+        cut_at += 1;
+    }
+    assert!(cut_at > 0);
+}
+
+#[test]
+fn vfat_power_cut_during_delete() {
+    let data = vec![0x3C; 10000];
+    let mut cut_at = 0;
+    while check_consistent_after_power_cut(cut_at, |vfat| {
+        vfat.create_file("/old.bin")?.write_all(&data)?;
+        vfat.create_file("/moved.bin")?.write_all(&data)
+    }, |vfat| {
+        vfat.remove("/old.bin")?;
+        vfat.rename("/moved.bin", "/rpi3-docs/moved.bin")?;
+        vfat.remove("/rpi3-docs/RPi3-Schematics.pdf")
+    }) {
+        cut_at += 1;
+    }
+    assert!(cut_at > 0);
+}
+
+#[test]
+fn vfat_failed_write_during_create_file() {
+    let data = vec![0x3C; 5000];
+    let mut fail_at = 0;
+    while check_mountable_after_failed_write(fail_at, |vfat| {
+        let mut file = vfat.create_file("/new.bin")?;
+        file.write_all(&data)?;
+        file.flush()
+    }) {
+        fail_at += 1;
+    }
+    assert!(fail_at > 0);
+}
+
+#[test]
+fn vfat_failed_write_during_flush() {
+    let data = vec![0x7E; 3000];
+    let mut fail_at = 0;
+    while check_mountable_after_failed_write(fail_at, |vfat| {
+        let mut file = vfat.create_file("/new.bin")?;
+        file.write_all(&data[..1000])?;
+        file.flush()?;
+        file.write_all(&data[1000..])?;
+        file.flush()?;
+        vfat.sync()
+    }) {
+        fail_at += 1;
+    }
+    assert!(fail_at > 0);
+}
+
+#[test]
+fn vfat_read_only_device() {
+    use self::mock::InstrumentedDevice;
+    use ReadOnlyDevice;
+
+    let expected = hash_dir_recursive_from(vfat_from_resource("mock1.fat32.img"), "/");
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let writes = device.writes.clone();
+    let vfat = VFatFileSystem::from(Box::new(ReadOnlyDevice::new(device))).unwrap();
+    assert!(vfat.lock().is_read_only());
+    let hash = hash_dir_recursive_from(vfat.clone(), "/");
+    assert_hash_eq("mock 1 read-only", &hash, &expected);
+
+    let error = vfat.create_file("/new.txt").err().unwrap();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::PermissionDenied);
+    assert_matches!(::error::Error::of(&error), Some(&::error::Error::ReadOnly));
+    let error = vfat.create_dir("/new").err().unwrap();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::PermissionDenied);
+    let root = vfat.root().unwrap();
+    let mut entries = root.entries().unwrap();
+    while let Some(entry) = entries.next().unwrap() {
+        if entry.is_file() {
+            let error = entry.open_file(FileOpenMode::Write).err().unwrap();
+            assert_eq!(error.kind(), ::std::io::ErrorKind::PermissionDenied);
+        }
+    }
+    drop(entries);
+    drop(root);
+
+    vfat.try_unmount().unwrap();
+    assert!(writes.lock().unwrap().is_empty());
+}
+
+#[test]
+fn vfat_cache_reduces_device_reads() {
+    use self::mock::InstrumentedDevice;
+    use vfat::MountOptions;
+
+    let mut source_reads = Vec::new();
+    for &disable_cache in &[true, false] {
+        let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+        let reads = device.reads.clone();
+        let options = MountOptions { disable_cache, ..MountOptions::default() };
+        let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+        let expected = hash_dir_recursive_from(vfat.clone(), "/");
+        reads.lock().unwrap().clear();
+        for _ in 0..3 {
+            assert_eq!(hash_dir_recursive_from(vfat.clone(), "/"), expected);
+        }
+        source_reads.push(reads.lock().unwrap().len());
+
+        // unmounting hands back the original device with its contents
+        let device = vfat.try_unmount().unwrap();
+        let vfat = VFatFileSystem::from(device).unwrap();
+        assert_eq!(hash_dir_recursive_from(vfat, "/"), expected);
+    }
+    assert!(source_reads[1] * 10 < source_reads[0], "reads without and with the cache: {:?}", source_reads);
+}
+
+#[test]
+fn cached_device_stats() {
+    use self::mock::InstrumentedDevice;
+    use MemoryBlockDevice;
+    use cache::{CachedDevice, CacheStats};
+
+    let source = InstrumentedDevice::new(MemoryBlockDevice::with_capacity_sectors(16, 512));
+    let reads = source.reads.clone();
+    let mut cached = CachedDevice::with_capacity(source, 2 * 512);
+    let mut buf = [0; 512];
+    cached.read_sector(0, &mut buf).unwrap();
+    cached.read_sector(0, &mut buf).unwrap();
+    cached.write_sector(1, &[1; 512]).unwrap();
+    cached.read_sector(1, &mut buf).unwrap();
+    cached.read_sector(2, &mut buf).unwrap();
+    assert_eq!(cached.stats(), CacheStats {
+        hits: 2,
+        misses: 3,
+        evictions: 1,
+        write_backs: 0,
+        dirty_sectors: 1,
+        resident_sectors: 2,
+    });
+
+    let mut two = [0; 1024];
+    cached.read_sectors(2, &mut two).unwrap();
+    let stats = cached.stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions, stats.write_backs), (3, 4, 2, 1));
+
+    cached.write_sector(3, &[3; 512]).unwrap();
+    cached.flush_sector(3).unwrap();
+    cached.flush_sector(3).unwrap();
+    let stats = cached.stats();
+    assert_eq!((stats.write_backs, stats.dirty_sectors), (2, 0));
+
+    // an invalidated sector is read from the source again
+    reads.lock().unwrap().clear();
+    cached.read_sector(3, &mut buf).unwrap();
+    assert!(reads.lock().unwrap().is_empty());
+    cached.invalidate(3);
+    cached.read_sector(3, &mut buf).unwrap();
+    assert_eq!(*reads.lock().unwrap(), vec![3]);
+    assert_eq!(buf, [3; 512]);
+    assert_eq!(cached.stats().misses, 5);
+}
+
+#[test]
+fn cached_device_write_back_order() {
+    use self::mock::{FaultyDevice, InstrumentedDevice};
+    use MemoryBlockDevice;
+    use cache::CachedDevice;
+
+    let source = FaultyDevice::new(InstrumentedDevice::new(MemoryBlockDevice::with_capacity_sectors(16, 512)));
+    let faults = source.faults.clone();
+    let writes = source.inner.writes.clone();
+    let mut cached = CachedDevice::new(source);
+    for &sector in &[9, 3, 12, 7, 1, 5] {
+        cached.write_sector(sector, &[sector as u8; 512]).unwrap();
+    }
+
+    cached.flush_range(4..10).unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![5, 7, 9]);
+    assert_eq!(cached.stats().dirty_sectors, 3);
+    cached.flush_range(4..10).unwrap();
+    assert_eq!(writes.lock().unwrap().len(), 3);
+
+    // a sync failing after two more writes has written the lowest sectors
+    writes.lock().unwrap().clear();
+    cached.write_sector(9, &[0xFF; 512]).unwrap();
+    {
+        let mut faults = faults.lock().unwrap();
+        faults.fail_nth_write = Some(faults.writes + 2);
+    }
+    assert!(cached.sync().is_err());
+    assert_eq!(*writes.lock().unwrap(), vec![1, 3]);
+    assert_eq!(cached.stats().dirty_sectors, 2);
+
+    writes.lock().unwrap().clear();
+    cached.sync().unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![9, 12]);
+}
+
+#[test]
+fn vfat_create_flushes_fat_first() {
+    use self::mock::InstrumentedDevice;
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let writes = device.writes.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let data_start = {
+        let vfat = vfat.lock();
+        vfat.params().data_start_sector() * vfat.params().bytes_per_logical_sector as u64 / 512
+    };
+
+    for path in &["/ordered.txt", "/ordered"] {
+        writes.lock().unwrap().clear();
+        if path.ends_with(".txt") {
+            vfat.create_file(path).unwrap();
+        } else {
+            vfat.create_dir(path).unwrap();
+        }
+        let writes = writes.lock().unwrap();
+        let first_data_write = writes.iter().position(|&sector| sector >= data_start).unwrap();
+        assert!(first_data_write > 0, "no FAT write before the directory entry of {}", path);
+        assert!(writes[first_data_write..].iter().all(|&sector| sector >= data_start), "{:?}", *writes);
+        let mut sorted = writes[first_data_write..].to_vec();
+        sorted.sort();
+        assert_eq!(&writes[first_data_write..], &sorted[..]);
+    }
+}
+
+#[test]
+fn vfat_barriers_order_entry_updates() {
+    use self::mock::InstrumentedDevice;
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let writes = device.writes.clone();
+    let barriers = device.barriers.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let (data_start, cluster_sectors) = {
+        let vfat = vfat.lock();
+        (vfat.params().data_start_sector() * vfat.params().bytes_per_logical_sector as u64 / 512, vfat.cluster_size_bytes() as u64 / 512)
+    };
+    let sectors_of = |clusters: Vec<u32>| -> Vec<u64> {
+        clusters.iter()
+            .flat_map(|&c| (0..cluster_sectors).map(move |i| data_start + (c as u64 - 2) * cluster_sectors + i))
+            .collect()
+    };
+
+    // The data and the FAT of a grown file are written before its size.
+    let mut file = vfat.create_file("/barrier.bin").unwrap();
+    let entry = vfat.get_entry("/barrier.bin").unwrap();
+    file.write_all(&[0x5A; 1500]).unwrap();
+    writes.lock().unwrap().clear();
+    file.flush().unwrap();
+    let file_sectors = sectors_of(vfat.lock().fat().chain_clusters(entry.metadata.first_cluster).unwrap());
+    {
+        let writes = writes.lock().unwrap();
+        let last_data = writes.iter().rposition(|sector| file_sectors.contains(sector)).unwrap();
+        let first_fat = writes.iter().position(|&sector| sector < data_start).unwrap();
+        let last_fat = writes.iter().rposition(|&sector| sector < data_start).unwrap();
+        let entry_write = writes.iter().rposition(|&sector| sector >= data_start && !file_sectors.contains(&sector))
+            .unwrap();
+        assert!(last_data < first_fat && last_fat < entry_write, "{:?}", *writes);
+    }
+    drop(file);
+
+    // A removed entry is cleared before its clusters are freed.
+    writes.lock().unwrap().clear();
+    barriers.lock().unwrap().clear();
+    vfat.remove_entry(entry).unwrap();
+    vfat.sync().unwrap();
+    let writes = writes.lock().unwrap();
+    let barrier = barriers.lock().unwrap()[0];
+    assert!(writes[..barrier].iter().all(|&sector| sector >= data_start), "{:?} before {}", *writes, barrier);
+    assert!(!writes[..barrier].is_empty());
+    assert!(writes[barrier..].iter().any(|&sector| sector < data_start), "{:?}", *writes);
+}
+
+#[test]
+fn vfat_barriers_order_create_dir() {
+    use self::mock::{InstrumentedDevice, SparseDevice};
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let device = InstrumentedDevice::new(device);
+    let writes = device.writes.clone();
+    let barriers = device.barriers.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let (data_start, cluster_sectors) = {
+        let vfat = vfat.lock();
+        (vfat.params().data_start_sector() * vfat.params().bytes_per_logical_sector as u64 / 512, vfat.cluster_size_bytes() as u64 / 512)
+    };
+
+    // The cluster of a new directory holds "." and ".." before the entry
+    // pointing to it is written.
+    writes.lock().unwrap().clear();
+    barriers.lock().unwrap().clear();
+    vfat.create_dir("/new").unwrap();
+    vfat.sync().unwrap();
+    let first_cluster = vfat.get_entry("/new").unwrap().metadata.first_cluster;
+    let dir_sectors = (0..cluster_sectors)
+        .map(|i| data_start + (first_cluster as u64 - 2) * cluster_sectors + i)
+        .collect::<Vec<_>>();
+    let writes = writes.lock().unwrap();
+    let barrier = barriers.lock().unwrap()[0];
+    assert!(writes[..barrier].contains(&dir_sectors[0]), "{:?} before {}", *writes, barrier);
+    assert!(writes[..barrier].iter().all(|&sector| sector < data_start || dir_sectors.contains(&sector)),
+            "{:?} before {}", *writes, barrier);
+    assert!(writes[barrier..].iter().any(|&sector| sector >= data_start && !dir_sectors.contains(&sector)),
+            "{:?}", *writes);
+}
+
+#[test]
+fn vfat_file_flush_writes_back_only_its_sectors() {
+    use self::mock::InstrumentedDevice;
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let writes = device.writes.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    let (data_start, cluster_sectors) = {
+        let vfat = vfat.lock();
+        (vfat.params().data_start_sector() * vfat.params().bytes_per_logical_sector as u64 / 512, vfat.cluster_size_bytes() as u64 / 512)
+    };
+    let sectors_of = |path: &str| -> Vec<u64> {
+        let first_cluster = vfat.get_entry(path).unwrap().metadata.first_cluster;
+        vfat.lock().fat().chain_clusters(first_cluster).unwrap().iter()
+            .flat_map(|&c| (0..cluster_sectors).map(move |i| data_start + (c as u64 - 2) * cluster_sectors + i))
+            .collect()
+    };
+
+    let mut a = vfat.create_file("/a.bin").unwrap();
+    let mut b = vfat.create_file("/b.bin").unwrap();
+    a.write_all(&[0xAA; 3000]).unwrap();
+    b.write_all(&[0xBB; 3000]).unwrap();
+    a.flush().unwrap();
+    b.flush().unwrap();
+    a.write_all(&[0xA5; 700]).unwrap();
+    b.write_all(&[0xB5; 700]).unwrap();
+    let (a_sectors, b_sectors) = (sectors_of("/a.bin"), sectors_of("/b.bin"));
+
+    writes.lock().unwrap().clear();
+    a.flush().unwrap();
+    {
+        let writes = writes.lock().unwrap();
+        assert!(writes.iter().any(|sector| a_sectors.contains(sector)), "{:?}", *writes);
+        assert!(!writes.iter().any(|sector| b_sectors.contains(sector)), "{:?}", *writes);
+    }
+    assert!(vfat.lock().cache_stats().unwrap().dirty_sectors > 0);
+
+    writes.lock().unwrap().clear();
+    b.flush().unwrap();
+    {
+        let writes = writes.lock().unwrap();
+        assert!(writes.iter().any(|sector| b_sectors.contains(sector)), "{:?}", *writes);
+        assert!(!writes.iter().any(|sector| a_sectors.contains(sector)), "{:?}", *writes);
+    }
+    drop((a, b));
+
+    let mut data = Vec::new();
+    vfat.open_file("/b.bin", FileOpenMode::Read).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data.len(), 3700);
+    assert!(data[3000..].iter().all(|&byte| byte == 0xB5));
+}
+
+#[test]
+fn vfat_cache_stats() {
+    use vfat::MountOptions;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    hash_dir_recursive_from(vfat.clone(), "/");
+    let stats = vfat.lock().cache_stats().unwrap();
+    assert!(stats.misses > 0);
+    assert!(stats.resident_sectors > 0);
+    hash_dir_recursive_from(vfat.clone(), "/");
+    let again = vfat.lock().cache_stats().unwrap();
+    assert_eq!(again.misses, stats.misses);
+    assert!(again.hits > stats.hits);
+
+    let options = MountOptions { disable_cache: true, ..MountOptions::default() };
+    let vfat = VFatFileSystem::from_with_options(Box::new(load_partition("mock1.fat32.img")), options).unwrap();
+    assert_eq!(vfat.lock().cache_stats(), None);
+}
+
+#[test]
+fn vfat_cache_read_ahead() {
+    use self::mock::InstrumentedDevice;
+    use vfat::MountOptions;
+
+    // (source read requests, sectors read) of reading with `read_ahead`
+    fn source_reads<F: FnOnce(&ArcMutex<VFatFileSystem>)>(image: Box<BlockDevice>, read_ahead: u64, f: F) -> (Box<BlockDevice>, usize, usize) {
+        let device = InstrumentedDevice::new(image);
+        let reads = device.reads.clone();
+        let multi_reads = device.multi_reads.clone();
+        let options = MountOptions { read_ahead_sectors: Some(read_ahead), ..MountOptions::default() };
+        let vfat = VFatFileSystem::from_with_options(Box::new(device), options).unwrap();
+        reads.lock().unwrap().clear();
+        multi_reads.lock().unwrap().clear();
+        f(&vfat);
+        let sectors = reads.lock().unwrap().len();
+        let multi_reads = multi_reads.lock().unwrap();
+        let in_multi_reads: u64 = multi_reads.iter().map(|&(_, count)| count).sum();
+        let requests = sectors - in_multi_reads as usize + multi_reads.len();
+        drop(multi_reads);
+        let device = vfat.try_unmount().unwrap();
+        (device, requests, sectors)
+    }
+
+    let data: Vec<u8> = (0..1024 * 1024).map(|i| (i * 13 % 251) as u8).collect();
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    vfat.create_file("/stream.bin").unwrap().write_all(&data).unwrap();
+    let device = vfat.try_unmount().unwrap();
+
+    let read_all = |vfat: &ArcMutex<VFatFileSystem>| {
+        let mut read_back = Vec::new();
+        vfat.open_file("/stream.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read_back).unwrap();
+        assert!(read_back == data);
+    };
+    let (device, plain_requests, plain_sectors) = source_reads(device, 0, &read_all);
+    let (device, requests, sectors) = source_reads(device, 32, &read_all);
+    assert!(plain_requests >= 2048, "{} requests without read-ahead", plain_requests);
+    assert!(requests * 10 < plain_requests, "{} requests with read-ahead, {} without", requests, plain_requests);
+    assert!(sectors <= plain_sectors + 32);
+
+    // random reads of single sectors at most read ahead once, e.g. in the
+    // metadata read to open the file
+    let read_random = |vfat: &ArcMutex<VFatFileSystem>| {
+        let mut file = vfat.open_file("/stream.bin", FileOpenMode::Read).unwrap();
+        let mut buf = [0; 100];
+        for i in 0..200u64 {
+            let offset = i * 7919 % 2047 * 512;
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[offset as usize..offset as usize + 100]);
+        }
+    };
+    let (device, plain_requests, plain_sectors) = source_reads(device, 0, &read_random);
+    let (_, requests, sectors) = source_reads(device, 32, &read_random);
+    assert!(requests <= plain_requests, "{} requests with read-ahead, {} without", requests, plain_requests);
+    assert!(sectors <= plain_sectors + 32, "{} sectors read with read-ahead, {} without", sectors, plain_sectors);
+}
+
+#[test]
+fn vfat_cluster_read_is_one_device_read() {
+    use self::mock::InstrumentedDevice;
+    use vfat::logical_block_device::LogicalBlockDevice;
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let reads = device.reads.clone();
+    let multi_reads = device.multi_reads.clone();
+    // a 4 KiB cluster of 512-byte sectors
+    let logical = LogicalBlockDevice::new(Box::new(device), 4096);
+
+    let mut cluster = vec![0; 4096];
+    logical.read_by_offset(3 * 4096, &mut cluster).unwrap();
+    assert_eq!(*multi_reads.lock().unwrap(), vec![(24, 8)]);
+    assert_eq!(reads.lock().unwrap().len(), 8);
+
+    // unaligned ends are read on their own, the middle at once
+    multi_reads.lock().unwrap().clear();
+    reads.lock().unwrap().clear();
+    let mut buf = vec![0; 3 * 512];
+    logical.source.read_by_offset(100, &mut buf).unwrap();
+    assert_eq!(*multi_reads.lock().unwrap(), vec![(1, 2)]);
+    assert_eq!(*reads.lock().unwrap(), vec![0, 1, 2, 3]);
+
+    let mut expected = vec![0; 4096];
+    for (i, chunk) in expected.chunks_mut(512).enumerate() {
+        logical.source.read_sector(24 + i as u64, chunk).unwrap();
+    }
+    assert_eq!(cluster, expected);
+}
+
+#[test]
+fn logical_device_reads_source_sectors_directly() {
+    use self::mock::InstrumentedDevice;
+    use vfat::logical_block_device::LogicalBlockDevice;
+
+    let device = InstrumentedDevice::new(load_partition("mock1.fat32.img"));
+    let reads = device.reads.clone();
+    let multi_reads = device.multi_reads.clone();
+    let logical = LogicalBlockDevice::new(Box::new(device), 4096);
+
+    // aligned to the 512-byte source sectors, not to the 4 KiB ones
+    let mut buf = vec![0; 3 * 512];
+    logical.read_by_offset(4096 + 512, &mut buf).unwrap();
+    assert_eq!(*multi_reads.lock().unwrap(), vec![(9, 3)]);
+    assert_eq!(*reads.lock().unwrap(), vec![9, 10, 11]);
+
+    // only the source sectors at the ends are bounced
+    multi_reads.lock().unwrap().clear();
+    reads.lock().unwrap().clear();
+    let mut unaligned = vec![0; 3 * 512];
+    logical.read_by_offset(4096 + 100, &mut unaligned).unwrap();
+    assert_eq!(*multi_reads.lock().unwrap(), vec![(9, 2)]);
+    assert_eq!(*reads.lock().unwrap(), vec![8, 9, 10, 11]);
+
+    let mut expected = vec![0; 4 * 512];
+    logical.source.read_by_offset(4096, &mut expected).unwrap();
+    assert_eq!(&buf[..], &expected[512..]);
+    assert_eq!(&unaligned[..], &expected[100..100 + 3 * 512]);
+}
+
+#[test]
+fn cached_device_read_by_offset() {
+    use self::mock::InstrumentedDevice;
+    use MemoryBlockDevice;
+    use cache::CachedDevice;
+
+    let original: Vec<u8> = (0..8 * 512).map(|i| (i * 7 % 251) as u8).collect();
+    let offsets = [0u64, 1, 100, 511, 512, 513, 1000, 1536];
+    let lengths = [0usize, 1, 12, 511, 512, 513, 1024, 1500, 2048];
+    for &offset in &offsets {
+        for &len in &lengths {
+            let device = InstrumentedDevice::new(MemoryBlockDevice::from_vec(original.clone(), 512));
+            let reads = device.reads.clone();
+            let cached = CachedDevice::new(device);
+            let mut buf = vec![0; len];
+            cached.read_by_offset(offset, &mut buf).unwrap();
+            assert_eq!(&buf[..], &original[offset as usize..offset as usize + len], "read of {} bytes at {}", len, offset);
+
+            // every sector is read from the source once and then cached
+            let first = offset / 512;
+            let end = if len == 0 { first } else { (offset + len as u64 + 511) / 512 };
+            let mut sectors = reads.lock().unwrap().clone();
+            sectors.sort();
+            assert_eq!(sectors, (first..end).collect::<Vec<_>>(), "read of {} bytes at {}", len, offset);
+            cached.read_by_offset(offset, &mut buf).unwrap();
+            assert_eq!(reads.lock().unwrap().len(), (end - first) as usize);
+        }
+    }
+}
+
+#[test]
+fn block_device_unaligned_offsets() {
+    use MemoryBlockDevice;
+
+    let original: Vec<u8> = (0..8 * 512).map(|i| (i * 7 % 251) as u8).collect();
+    let offsets = [0u64, 1, 100, 511, 512, 513, 1000, 1023, 1024, 1536];
+    let lengths = [0usize, 1, 12, 511, 512, 513, 1024, 1025, 1500, 2048];
+    for &offset in &offsets {
+        for &len in &lengths {
+            let mut device = MemoryBlockDevice::from_vec(original.clone(), 512);
+            let range = offset as usize..offset as usize + len;
+
+            let mut buf = vec![0; len];
+            device.read_by_offset(offset, &mut buf).unwrap();
+            assert_eq!(&buf[..], &original[range.clone()], "read of {} bytes at {}", len, offset);
+
+            let data: Vec<u8> = (0..len).map(|i| !(i as u8)).collect();
+            device.write_by_offset(offset, &data).unwrap();
+            let mut expected = original.clone();
+            expected[range].copy_from_slice(&data);
+            assert_eq!(device.into_inner(), expected, "write of {} bytes at {}", len, offset);
+        }
+    }
+}
+
+#[test]
+fn block_device_short_buffers() {
+    use MemoryBlockDevice;
+    use cache::CachedDevice;
+    use vfat::logical_block_device::LogicalBlockDevice;
+    use std::io::ErrorKind;
+
+    let original: Vec<u8> = (0..16 * 512).map(|i| (i % 251) as u8).collect();
+    let mut logical = LogicalBlockDevice::new(Box::new(MemoryBlockDevice::from_vec(original.clone(), 512)), 4096);
+    let mut cached = CachedDevice::new(MemoryBlockDevice::from_vec(original.clone(), 4096));
+    for device in &mut [&mut logical as &mut BlockDevice, &mut cached as &mut BlockDevice] {
+        // a 512-byte buffer against a 4096-byte sector
+        let mut short = [0; 512];
+        assert_eq!(device.read_sector(1, &mut short).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(device.write_sector(1, &short).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        // longer buffers only have their first sector used
+        let mut long = [0xEE; 5000];
+        device.read_sector(1, &mut long).unwrap();
+        assert_eq!(&long[..4096], &original[4096..8192]);
+        assert!(long[4096..].iter().all(|&byte| byte == 0xEE));
+        device.write_sector(0, &long).unwrap();
+        let mut sector = [0; 4096];
+        device.read_sector(0, &mut sector).unwrap();
+        assert_eq!(&sector[..], &long[..4096]);
+        device.read_sector(1, &mut sector).unwrap();
+        assert_eq!(&sector[..], &original[4096..8192]);
+    }
+}
+
+#[test]
+fn cached_device_into_inner() {
+    use MemoryBlockDevice;
+    use cache::CachedDevice;
+
+    let mut cached = CachedDevice::new(MemoryBlockDevice::with_capacity_sectors(4, 512));
+    cached.write_sector(2, &[0xA5; 512]).unwrap();
+    // the dirty sector is written back before the source is handed out
+    let source = cached.into_inner().unwrap();
+    let mut buf = [0; 512];
+    source.read_sector(2, &mut buf).unwrap();
+    assert_eq!(&buf[..], &[0xA5; 512][..]);
+}
+
+#[test]
+fn cached_device_read_only_source() {
+    use MemoryBlockDevice;
+    use ReadOnlyDevice;
+    use cache::CachedDevice;
+
+    let mut cached = CachedDevice::new(ReadOnlyDevice::new(MemoryBlockDevice::with_capacity_sectors(4, 512)));
+    // the sector would only fail once it is written back
+    let error = cached.write_sector(2, &[0xA5; 512]).unwrap_err();
+    assert_matches!(::error::Error::of(&error), Some(&::error::Error::ReadOnly));
+    let error = cached.write_sectors(0, &[0xA5; 1024]).unwrap_err();
+    assert_matches!(::error::Error::of(&error), Some(&::error::Error::ReadOnly));
+    assert_eq!(cached.stats().dirty_sectors, 0);
+    let mut buf = [0xFF; 512];
+    cached.read_sector(2, &mut buf).unwrap();
+    assert_eq!(&buf[..], &[0; 512][..]);
+}
+
+#[test]
+fn cached_device_lru_eviction() {
+    use MemoryBlockDevice;
+    use cache::CachedDevice;
+
+    let source = ArcMutex::new(MemoryBlockDevice::with_capacity_sectors(64, 512));
+    let mut cached = CachedDevice::with_capacity(source.clone(), 4 * 512);
+    for sector in 0..64u64 {
+        cached.write_sector(sector, &[sector as u8; 512]).unwrap();
+        assert!(cached.cached_sectors() <= 4);
+    }
+    // everything but the last four sectors has been written back on eviction
+    let mut buf = [0; 512];
+    source.read_sector(59, &mut buf).unwrap();
+    assert_eq!(buf, [59; 512]);
+    source.read_sector(60, &mut buf).unwrap();
+    assert_eq!(buf, [0; 512]);
+
+    // recently used sectors survive
+    cached.read_sector(60, &mut buf).unwrap();
+    cached.read_sector(0, &mut buf).unwrap();
+    assert_eq!(buf, [0; 512]);
+    cached.read_sector(1, &mut buf).unwrap();
+    source.read_sector(61, &mut buf).unwrap();
+    assert_eq!(buf, [61; 512]);
+    source.read_sector(60, &mut buf).unwrap();
+    assert_eq!(buf, [0; 512]);
+
+    let mut all = vec![0; 64 * 512];
+    cached.read_sectors(0, &mut all).unwrap();
+    assert_eq!(cached.cached_sectors(), 4);
+    for (sector, data) in all.chunks(512).enumerate() {
+        assert!(data.iter().all(|&byte| byte == sector as u8), "sector {}", sector);
+    }
+
+    cached.sync().unwrap();
+    source.read_sector(60, &mut buf).unwrap();
+    assert_eq!(buf, [60; 512]);
+    drop(cached);
+    let data = source.unwrap().into_inner();
+    for (sector, data) in data.chunks(512).enumerate() {
+        assert!(data.iter().all(|&byte| byte == sector as u8), "sector {}", sector);
+    }
+}
+
+#[test]
+fn vfat_partition_larger_than_image() {
+    let mut image = load_disk_image_part("mock1.fat32.img").into_inner();
+    let full_len = image.len();
+    image.truncate(full_len / 2);
+
+    // the MBR still claims the whole partition
+    let partition = get_partition(RefCell::from(Cursor::new(image)), 0).expect("get_partition failed");
+    assert_eq!(partition.num_sectors(), (full_len / 2 / 512) as u64 - 1);
+    let mut buf = vec![0; 1024];
+    let end = partition.num_sectors() * 512;
+    partition.read_by_offset(end - 1024, &mut buf).unwrap();
+    let error = partition.read_by_offset(end - 512, &mut buf).unwrap_err();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::UnexpectedEof);
+    let error = partition.read_sectors(partition.num_sectors() - 1, &mut buf).unwrap_err();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::UnexpectedEof);
+
+    assert_matches!(VFatFileSystem::from(Box::new(partition)).err(),
+                    Some(::vfat::Error::InvalidGeometry { field: "total_logical_sectors" }));
+}
+
+#[test]
+fn vfat_4k_sector_device() {
+    use MemoryBlockDevice;
+
+    let expected = hash_dir_recursive_from(vfat_from_resource("mock1.fat32.img"), "/");
+
+    // the MBR of the whole image is found within the first 4 KiB sector
+    let image = load_disk_image_part("mock1.fat32.img").into_inner();
+    let device = MemoryBlockDevice::from_vec(image.clone(), 4096);
+    let mbr = MasterBootRecord::read_from(&device).unwrap();
+    assert_eq!({ mbr.entries[0].start_lba }, 1);
+
+    // the partition holds a FAT volume with 512-byte sectors
+    let mut partition = image[512..].to_vec();
+    let padded_len = (partition.len() + 4095) / 4096 * 4096;
+    partition.resize(padded_len, 0);
+    let device = MemoryBlockDevice::from_vec(partition, 4096);
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    assert_eq!(vfat.lock().params().bytes_per_logical_sector, 512);
+    let hash = hash_dir_recursive_from(vfat.clone(), "/");
+    assert_hash_eq("mock 1 on a 4K sector device", &hash, &expected);
+
+    let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    vfat.create_file("/4k.bin").unwrap().write_all(&data).unwrap();
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    let mut read_back = Vec::new();
+    vfat.open_file("/4k.bin", FileOpenMode::Read).unwrap().read_to_end(&mut read_back).unwrap();
+    assert_eq!(read_back, data);
+}
+
+#[test]
+fn vfat_time_provider() {
+    use vfat::{FixedTime, fat_epoch};
+
+    // The raw 32-byte regular directory entry of `path`
+    fn raw_entry(vfat: &ArcMutex<VFatFileSystem>, path: &str) -> [u8; VFatDirEntry::SIZE] {
+        let entry = vfat.get_entry(path).unwrap();
+        let raw_entry = entry.dir.0.lock().get_raw_entry(entry.dir_entry_index_range.end).unwrap().unwrap();
+        raw_entry.to_bytes()
+    }
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let time = Date::from_ymd(2018, 5, 17).and_hms(13, 45, 30);
+    vfat.lock().set_time_provider(Box::new(FixedTime(time)));
+    vfat.create_file("/stamp.txt").unwrap();
+    vfat.lock().set_time_provider(Box::new(fat_epoch));
+    vfat.create_dir("/epoch").unwrap();
+
+    let entry = raw_entry(&vfat, "/stamp.txt");
+    assert_eq!(&entry[14..20], &[0xAF, 0x6D, 0xB1, 0x4C, 0xB1, 0x4C]);
+    assert_eq!(&entry[22..26], &[0xAF, 0x6D, 0xB1, 0x4C]);
+    let entry = raw_entry(&vfat, "/epoch");
+    assert_eq!(&entry[14..20], &[0x00, 0x00, 0x21, 0x00, 0x21, 0x00]);
+    assert_eq!(&entry[22..26], &[0x00, 0x00, 0x21, 0x00]);
+}
+
+#[test]
+fn vfat_error_variants() {
+    use error::Error;
+    use std::io;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    assert_matches!(fs_error(vfat.open_dir("/rpi3-docs/RPi3-Schematics.pdf")), Error::NotADirectory);
+    assert_matches!(fs_error(vfat.open_file("/rpi3-docs", FileOpenMode::Read)), Error::IsADirectory);
+    assert_matches!(fs_error(vfat.open_dir("/rpi3-docs/missing")), Error::NotFound);
+    assert_matches!(fs_error(vfat.get_entry("rpi3-docs")), Error::InvalidInput { .. });
+    assert_matches!(fs_error(vfat.create_file(&format!("/{}", "x".repeat(255)))), Error::NameInvalid { .. });
+    assert_matches!(fs_error(::vfat::ShortName::parse("a b")), Error::NameInvalid { .. });
+    let mut file = vfat.create_file("/big.bin").unwrap();
+    assert_matches!(fs_error(file.preallocate(1 << 32)), Error::FileTooLarge);
+
+    // the kinds of the converted errors
+    let error: io::Error = Error::Busy.into();
+    assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    assert_matches!(Error::of(&error), Some(&Error::Busy));
+    let error: io::Error = Error::Corrupted { what: "test" }.into();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(error.to_string(), "file system is corrupted: test");
+    let error: io::Error = Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof)).into();
+    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(Error::of(&error).is_none());
+    assert_matches!(Error::from(::vfat::Error::NotFat32), Error::UnsupportedFs);
+}
+
+#[test]
+fn error_display_and_source() {
+    use std::error::Error;
+    use std::io;
+
+    fn boxed<E: Error + Send + Sync + 'static>(error: E) -> Box<Error + Send + Sync> {
+        Box::new(error)
+    }
+
+    let mbr_errors = vec![
+        (::mbr::Error::Io(io::Error::new(io::ErrorKind::Other, "disk on fire")), "I/O error while accessing the MBR: disk on fire"),
+        (::mbr::Error::UnknownBootIndicator(2), "partition 2 has an unknown boot indicator"),
+        (::mbr::Error::BadSignature, "the MBR signature is invalid"),
+        (::mbr::Error::OverlappingPartitions(0, 3), "partitions 0 and 3 overlap"),
+        (::mbr::Error::PartitionOutOfRange(1), "partition 1 extends past the end of the device"),
+    ];
+    for (error, message) in mbr_errors {
+        let has_source = error.io_error().is_some();
+        let error = boxed(error);
+        assert_eq!(error.to_string(), message);
+        assert_eq!(error.source().is_some(), has_source);
+        assert!(error.downcast_ref::<::mbr::Error>().is_some());
+    }
+    assert_eq!(::mbr::Error::UnknownBootIndicator(2).partition(), Some(2));
+    assert_eq!(::mbr::Error::OverlappingPartitions(0, 3).partition(), Some(0));
+    assert!(::mbr::Error::BadSignature.is_bad_signature());
+    assert_matches!(::mbr::Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)), ::mbr::Error::Io(_));
+
+    let vfat_errors = vec![
+        (::vfat::Error::Mbr(::mbr::Error::BadSignature), "invalid MBR: the MBR signature is invalid"),
+        (::vfat::Error::Io(io::Error::new(io::ErrorKind::Other, "disk on fire")), "I/O error while mounting: disk on fire"),
+        (::vfat::Error::BadSignature, "the boot sector signature is invalid"),
+        (::vfat::Error::NotFound, "no FAT32 partition found"),
+        (::vfat::Error::NotFat32, "the volume is formatted as FAT12 or FAT16"),
+        (::vfat::Error::UnsupportedFilesystem(::vfat::ForeignFs::Ntfs), "the volume is formatted as NTFS, not FAT"),
+        (::vfat::Error::InvalidGeometry { field: "number_of_fats" }, "the EBPB field number_of_fats holds an invalid value"),
+    ];
+    for (error, message) in vfat_errors {
+        let has_source = match error {
+            ::vfat::Error::Mbr(_) | ::vfat::Error::Io(_) => true,
+            _ => false,
+        };
+        let error = boxed(error);
+        assert_eq!(error.to_string(), message);
+        assert_eq!(error.source().is_some(), has_source);
+        assert!(error.downcast_ref::<::vfat::Error>().is_some());
+    }
+    let error = ::vfat::Error::Mbr(::mbr::Error::BadSignature);
+    assert!(error.source().unwrap().downcast_ref::<::mbr::Error>().unwrap().is_bad_signature());
+
+    let mut data = [0u8; 512];
+    load_disk_image_part("ebpb1.img").read_exact(&mut data).expect("read resource data");
+    let debug = format!("{:?}", BiosParameterBlock::from_bytes(&data));
+    assert!(debug.contains("bytes_per_logical_sector"), "{}", debug);
+}
+
+#[test]
+fn vfat_open_node() {
+    use vfat::VFatNode;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let node = vfat.open_node("/rpi3-docs/RPi3-Schematics.pdf", FileOpenMode::Read).unwrap();
+    assert!(node.is_file() && !node.is_dir());
+    let mut file = node.into_file().unwrap();
+    assert_eq!(file.size(), 76735);
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic).unwrap();
+    assert_eq!(&magic, b"%PDF");
+
+    let node = vfat.open_node("/rpi3-docs", FileOpenMode::Read).unwrap();
+    assert!(node.is_dir());
+    let dir = node.into_dir().unwrap();
+    assert!(dir.find("RPi3-Schematics.pdf").is_ok());
+    drop(dir);
+
+    assert!(vfat.open_node("/", FileOpenMode::Read).unwrap().into_dir().unwrap().find("rpi3-docs").is_ok());
+    assert!(vfat.open_node("/rpi3-docs", FileOpenMode::Write).unwrap().into_file().is_none());
+    assert_matches!(fs_error(vfat.open_node("/missing", FileOpenMode::Read)), ::error::Error::NotFound);
+
+    drop(vfat.create_file("/node.txt").unwrap());
+    let entry = vfat.get_entry("/node.txt").unwrap();
+    let mut file = VFatNode::from_entry(&entry, FileOpenMode::Write).unwrap().into_file().unwrap();
+    file.write_all(b"node").unwrap();
+}
+
+fn root_names<FS: FileSystem>(fs: &FS) -> ::std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut entries = fs.root()?.entries()?;
+    while let Some(entry) = entries.next()? {
+        names.push(entry.name().to_string());
+    }
+    Ok(names)
+}
+
+#[test]
+fn dummy_file_system() {
+    // Instantiating generic code with `Dummy` type checks it against nothing
+    // but the trait bounds.
+    let _: fn(&Dummy) -> ::std::io::Result<Vec<String>> = root_names::<Dummy>;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    assert!(root_names(&vfat).unwrap().iter().any(|name| name == "rpi3-docs"));
+
+    let result = ::std::panic::catch_unwind(|| Dummy.root().map(|_| ()));
+    assert!(result.is_err());
+}
+
+#[test]
+fn dir_entries_std_iter() {
+    for name in &["mock1.fat32.img", "mock2.fat32.img", "mock3.fat32.img", "mock4.fat32.img"] {
+        let vfat = vfat_from_resource(name);
+        let root = vfat.root().unwrap();
+
+        let mut expected = Vec::new();
+        let mut entries = root.entries().unwrap();
+        while let Some(entry) = entries.next().unwrap() {
+            expected.push(entry.path());
+        }
+
+        let mut actual = Vec::new();
+        for entry in root.entries().unwrap().std_iter() {
+            actual.push(entry.unwrap().path());
+        }
+        assert_eq!(actual, expected, "{}", name);
+
+        let collected: Vec<String> = root.collect_entries().unwrap().iter().map(|e| e.path()).collect();
+        assert_eq!(collected, expected, "{}", name);
+        let names: Vec<String> = expected.iter().map(|path| path[1..].to_string()).collect();
+        assert_eq!(root.names().unwrap(), names, "{}", name);
+    }
+}
+
+#[test]
+fn dir_entries_std_iter_stops_after_error() {
+    struct Failing(usize);
+
+    impl FallibleIterator for Failing {
+        type Item = usize;
+        type Error = ::std::io::Error;
+
+        fn next(&mut self) -> ::std::io::Result<Option<usize>> {
+            self.0 += 1;
+            match self.0 {
+                1 => Ok(Some(1)),
+                _ => Err(::error::Error::Corrupted { what: "test" }.into()),
+            }
+        }
+    }
+
+    let mut iter = Failing(0).std_iter();
+    assert_eq!(iter.next().unwrap().unwrap(), 1);
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().is_none());
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn vfat_file_set_len() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let cluster_size = vfat.lock().cluster_size_bytes() as u64;
+    let mut file = vfat.create_file("/set_len.txt").unwrap();
+    file.write_all(b"0123456789").unwrap();
+    file.set_len(4).unwrap();
+    assert_eq!(file.size(), 4);
+    assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 4);
+
+    file.set_len(cluster_size + 6).unwrap();
+    assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 4);
+    drop(file);
+
+    let mut contents = Vec::new();
+    let mut file = vfat.open_file("/set_len.txt", FileOpenMode::Read).unwrap();
+    file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents.len() as u64, cluster_size + 6);
+    assert_eq!(&contents[..4], b"0123");
+    assert!(contents[4..].iter().all(|&b| b == 0));
+    assert_matches!(fs_error(file.set_len(0)), ::error::Error::NotOpenForWriting);
+    drop(file);
+
+    let mut file = vfat.open_file("/set_len.txt", FileOpenMode::Write).unwrap();
+    file.set_len(0).unwrap();
+    drop(file);
+    assert_eq!(vfat.open_file("/set_len.txt", FileOpenMode::Read).unwrap().size(), 0);
+}
+
+#[test]
+fn vfat_file_open_with_timeout() {
+    use std::thread;
+    use std::time::Duration;
+    use vfat::VFatFile;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let path = "/rpi3-docs/RPi3-Schematics.pdf";
+    let writer = vfat.open_file(path, FileOpenMode::Write).unwrap();
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(150));
+        drop(writer);
+    });
+
+    let entry = vfat.get_entry(path).unwrap();
+    assert_matches!(fs_error(VFatFile::from_entry(&entry, FileOpenMode::Read)), ::error::Error::Busy);
+    assert_matches!(fs_error(VFatFile::from_entry_with_timeout(&entry, FileOpenMode::Read, Duration::from_millis(20))),
+                    ::error::Error::Busy);
+    let file = VFatFile::from_entry_with_timeout(&entry, FileOpenMode::Read, Duration::from_secs(5)).unwrap();
+    assert_eq!(file.size(), 76735);
+    holder.join().unwrap();
+}
+
+#[test]
+fn vfat_blocking_operations_wait_for_handles() {
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let path = "/rpi3-docs/RPi3-Schematics.pdf";
+    let writer = vfat.open_file(path, FileOpenMode::Write).unwrap();
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(writer);
+    });
+
+    let error = vfat.open_file(path, FileOpenMode::Read).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    let reader = vfat.open_file_blocking(path, FileOpenMode::Read).unwrap();
+    assert_eq!(reader.size(), 76735);
+    holder.join().unwrap();
+
+    let error = vfat.remove(path).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(reader);
+    });
+    vfat.remove_blocking(path).unwrap();
+    holder.join().unwrap();
+    assert!(vfat.get_entry(path).is_err());
+
+    assert_matches!(fs_error(vfat.open_file_blocking("/rpi3-docs", FileOpenMode::Read)),
+                    ::error::Error::IsADirectory);
+}
+
+fn deferred_delete_vfat() -> ArcMutex<VFatFileSystem> {
+    use vfat::MountOptions;
+
+    let options = MountOptions { deferred_delete: true, ..MountOptions::default() };
+    VFatFileSystem::from_with_options(Box::new(load_partition("mock1.fat32.img")), options).unwrap()
+}
+
+fn read_file(vfat: &ArcMutex<VFatFileSystem>, path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    vfat.open_file(path, FileOpenMode::Read).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn vfat_deferred_delete_open_file() {
+    use std::io;
+
+    let vfat = deferred_delete_vfat();
+    let data: Vec<u8> = (0..10000).map(|i| (i * 7 % 253) as u8).collect();
+    vfat.create_file("/deferred.bin").unwrap().write_all(&data).unwrap();
+    let cluster_size = vfat.lock().cluster_size_bytes() as usize;
+    let clusters = ((data.len() + cluster_size - 1) / cluster_size) as u32;
+    let free = vfat.lock().fs_info().free_count;
+
+    let mut file = vfat.open_file("/deferred.bin", FileOpenMode::Read).unwrap();
+    vfat.remove("/deferred.bin").unwrap();
+    assert_eq!(vfat.get_entry("/deferred.bin").err().unwrap().kind(), io::ErrorKind::NotFound);
+    assert_eq!(vfat.open_file("/deferred.bin", FileOpenMode::Read).err().unwrap().kind(), io::ErrorKind::NotFound);
+    assert_eq!(vfat.lock().fs_info().free_count, free);
+
+    let mut read_back = Vec::new();
+    file.read_to_end(&mut read_back).unwrap();
+    assert_eq!(read_back, data);
+    drop(file);
+    assert_eq!(vfat.lock().fs_info().free_count, free + clusters);
+
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    assert_eq!(vfat.get_entry("/deferred.bin").err().unwrap().kind(), io::ErrorKind::NotFound);
+    assert_eq!(vfat.lock().fs_info().free_count, free + clusters);
+}
+
+#[test]
+fn vfat_deferred_delete_waits_for_waiting_lock() {
+    use std::thread;
+    use std::time::Duration;
+    use vfat::LockKey;
+    use vfat::fat::Status;
+
+    let vfat = deferred_delete_memory_vfat();
+    vfat.create_file("/deferred.bin").unwrap().write_all(&[7; 1000]).unwrap();
+    let first_cluster = vfat.get_entry("/deferred.bin").unwrap().metadata.first_cluster;
+    let (fat, lock_manager) = {
+        let vfat = vfat.lock();
+        (vfat.fat(), vfat.lock_manager())
+    };
+    let key = LockKey::Cluster(first_cluster);
+    let read_lock = lock_manager.lock(key, LockMode::Read);
+    assert!(lock_manager.free_when_released(fat.clone(), first_cluster));
+
+    // the writer waits for the reader, holding on to the lock of the chain
+    let writer = {
+        let (fat, lock_manager) = (fat.clone(), lock_manager.clone());
+        thread::spawn(move || {
+            let write_lock = lock_manager.lock(key, LockMode::Write);
+            let status = fat.get(first_cluster).unwrap().status();
+            drop(write_lock);
+            status
+        })
+    };
+    while lock_manager.snapshot().iter().all(|snapshot| snapshot.waiters == 0) {
+        thread::sleep(Duration::from_millis(1));
+    }
+    drop(read_lock);
+    assert_ne!(writer.join().unwrap(), Status::Free);
+    // the chain is freed once the writer is done with it
+    assert_eq!(fat.get(first_cluster).unwrap().status(), Status::Free);
+}
+
+#[test]
+fn vfat_deferred_delete_rename_over_open_file() {
+    use std::io;
+
+    let vfat = deferred_delete_vfat();
+    vfat.create_file("/old.txt").unwrap().write_all(b"old").unwrap();
+    vfat.create_file("/new.txt").unwrap().write_all(b"new").unwrap();
+    let free = vfat.lock().fs_info().free_count;
+
+    let mut file = vfat.open_file("/old.txt", FileOpenMode::Write).unwrap();
+    vfat.rename("/new.txt", "/old.txt").unwrap();
+    assert_eq!(vfat.get_entry("/new.txt").err().unwrap().kind(), io::ErrorKind::NotFound);
+    assert_eq!(read_file(&vfat, "/old.txt"), b"new");
+
+    // The size of the replaced file mustn't end up in the new entry.
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(b" and more").unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut read_back = Vec::new();
+    file.read_to_end(&mut read_back).unwrap();
+    assert_eq!(read_back, b"old and more");
+    drop(file);
+    assert_eq!(read_file(&vfat, "/old.txt"), b"new");
+    assert_eq!(vfat.lock().fs_info().free_count, free + 1);
+
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    assert_eq!(read_file(&vfat, "/old.txt"), b"new");
+}
+
+/// An empty FAT32 volume in memory, mounted with deferred deletion.
+fn deferred_delete_memory_vfat() -> ArcMutex<VFatFileSystem> {
+    use tests::mock::SparseDevice;
+    use vfat::MountOptions;
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let options = MountOptions { deferred_delete: true, ..MountOptions::default() };
+    VFatFileSystem::from_with_options(Box::new(device), options).unwrap()
+}
+
+#[test]
+fn vfat_try_unmount_busy() {
+    use vfat::error::is_busy;
+
+    let vfat = deferred_delete_memory_vfat();
+    let mut file = vfat.create_file("/open.txt").unwrap();
+    file.write_all(b"still open").unwrap();
+    file.flush().unwrap();
+    // the open file keeps the file system mounted
+    let error = vfat.try_unmount().err().unwrap();
+    assert!(is_busy(error.error()), "{:?}", error);
+    let vfat = error.into_inner().unwrap();
+    assert_eq!(vfat.get_entry("/open.txt").unwrap().metadata.size, 10);
+
+    drop(file);
+    let vfat = VFatFileSystem::from(vfat.try_unmount().unwrap()).unwrap();
+    let mut data = Vec::new();
+    vfat.open_file("/open.txt", FileOpenMode::Read).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(&data[..], b"still open");
+}
+
+#[test]
+fn vfat_file_upgrade_and_downgrade() {
+    use vfat::error::is_busy;
+
+    let vfat = deferred_delete_memory_vfat();
+    vfat.create_file("/shared.txt").unwrap().write_all(b"before").unwrap();
+    let mut file = vfat.open_file("/shared.txt", FileOpenMode::Read).unwrap();
+    let other = vfat.open_file("/shared.txt", FileOpenMode::Read).unwrap();
+    assert!(is_busy(&file.try_upgrade().err().unwrap()));
+    assert!(file.write_all(b"x").is_err());
+    drop(other);
+
+    file.try_upgrade().unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(b" and after").unwrap();
+    assert!(is_busy(&vfat.open_file("/shared.txt", FileOpenMode::Read).err().unwrap()));
+    // the size is written back, so new readers see the whole file
+    file.downgrade().unwrap();
+    assert_eq!(read_file(&vfat, "/shared.txt"), b"before and after");
+    assert!(file.write_all(b"x").is_err());
+}
+
+#[test]
+fn vfat_deferred_delete_reused_slot() {
+    use vfat::metadata::Attributes;
+
+    let vfat = deferred_delete_memory_vfat();
+    let root = vfat.root().unwrap();
+    let mut metadata = new_metadata(&vfat, Attributes::new(false));
+    vfat.lock().fat().free_chain(metadata.first_cluster).unwrap();
+    metadata.first_cluster = 0;
+    let index_range = root.create_entry("a.txt", &metadata).unwrap().dir_entry_index_range;
+    let free = vfat.lock().fat().free_cluster_count().unwrap();
+
+    let mut file = vfat.open_file("/a.txt", FileOpenMode::Write).unwrap();
+    vfat.remove("/a.txt").unwrap();
+    // An entry without clusters that takes the place of the removed one is
+    // a different file, not one that was removed.
+    let entry = root.create_entry("b.txt", &metadata).unwrap();
+    assert_eq!(entry.dir_entry_index_range, index_range);
+    let mut new_file = vfat.open_file("/b.txt", FileOpenMode::Write).unwrap();
+    new_file.write_all(b"new").unwrap();
+    drop(new_file);
+
+    // and the removed file doesn't write to it
+    file.write_all(b"old").unwrap();
+    drop(file);
+    assert_eq!(read_file(&vfat, "/b.txt"), b"new");
+    assert_eq!(vfat.lock().fat().free_cluster_count().unwrap(), free - 1);
+    assert!(vfat.check().unwrap().is_clean());
+}
+
+#[test]
+fn vfat_deferred_delete_failed_rename_keeps_target() {
+    use vfat::ShortName;
+    use vfat::metadata::Attributes;
+
+    let vfat = deferred_delete_memory_vfat();
+    let root = vfat.root().unwrap();
+    let short_name = ShortName::parse("T.TXT").unwrap();
+    root.create_entry_with_short_name("T.TXT", &short_name, &new_metadata(&vfat, Attributes::new(false))).unwrap();
+    vfat.open_file("/T.TXT", FileOpenMode::Write).unwrap().write_all(b"target").unwrap();
+    vfat.create_file("/source.txt").unwrap().write_all(b"source").unwrap();
+    let mut metadata = new_metadata(&vfat, Attributes::new(false));
+    let mut fat = vfat.lock().fat();
+    fat.free_chain(metadata.first_cluster).unwrap();
+    metadata.first_cluster = 0;
+    let free = fat.free_cluster_count().unwrap();
+    fat.alloc_contiguous(free).unwrap();
+    for i in 0.. {
+        let name = format!("F{}", i);
+        if root.create_entry_with_short_name(&name, &ShortName::parse(&name).unwrap(), &metadata).is_err() {
+            break;
+        }
+    }
+
+    // The root directory is full and can't grow. The new entry needs a long
+    // name entry more than the target, so it can't be created, and the
+    // target has to stay.
+    let _file = vfat.open_file("/T.TXT", FileOpenMode::Read).unwrap();
+    assert!(vfat.rename("/source.txt", "/T.TXT").is_err());
+    assert_eq!(read_file(&vfat, "/T.TXT"), b"target");
+    assert_eq!(read_file(&vfat, "/source.txt"), b"source");
+}
+
+#[test]
+fn vfat_zero_cluster_entries_lock_independently() {
+    use vfat::lock_manager::LockKey;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let root = vfat.root().unwrap();
+    let mut metadata = new_metadata(&vfat, ::vfat::metadata::Attributes::new(false));
+    metadata.first_cluster = 0;
+    let a = root.create_entry("a.txt", &metadata).unwrap();
+    let b = root.create_entry("b.txt", &metadata).unwrap();
+    assert_ne!(a.lock_key, b.lock_key);
+    assert_ne!(a.lock_key, LockKey::Cluster(0));
+    assert_eq!(a.lock_key, vfat.get_entry("/a.txt").unwrap().lock_key);
+
+    let lock_manager = vfat.lock().lock_manager();
+    let b_key = b.lock_key;
+    drop(b);
+    // `a` is referenced, which mustn't keep `b` from being deleted.
+    assert!(lock_manager.try_lock(a.lock_key, LockMode::Delete).is_none());
+    assert!(lock_manager.try_lock(b_key, LockMode::Delete).is_some());
+    assert_eq!(lock_manager.ref_lock_count(LockKey::Cluster(0)), 0);
+}
+
+#[test]
+fn vfat_recreated_file_locks_fresh_key() {
+    use vfat::lock_manager::LockKey;
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let lock_manager = vfat.lock().lock_manager();
+    let root = vfat.root().unwrap();
+    let mut metadata = new_metadata(&vfat, ::vfat::metadata::Attributes::new(false));
+    metadata.first_cluster = 0;
+    let empty = root.create_entry("empty.txt", &metadata).unwrap();
+
+    drop(vfat.create_file("/old.txt").unwrap());
+    let old_key = vfat.get_entry("/old.txt").unwrap().lock_key;
+    vfat.remove("/old.txt").unwrap();
+    assert_eq!(lock_manager.ref_lock_count(old_key), 0);
+
+    let mut new_file = vfat.create_file("/new.txt").unwrap();
+    let new_key = vfat.get_entry("/new.txt").unwrap().lock_key;
+    match new_key {
+        LockKey::Cluster(cluster) => assert_ne!(cluster, 0),
+        key => panic!("unexpected key {:?}", key),
+    }
+    new_file.write_all(b"new").unwrap();
+    drop(new_file);
+    assert_eq!(lock_manager.ref_lock_count(empty.lock_key), 1);
+    assert_eq!(lock_manager.ref_lock_count(new_key), 0);
+    vfat.open_file("/new.txt", FileOpenMode::Write).unwrap();
+}
+
+#[test]
+fn vfat_usable_after_panic_with_lock_held() {
+    use std::thread;
+    use std::sync::atomic::Ordering;
+    use tests::mock::PanickingReadsDevice;
+
+    let device = PanickingReadsDevice::new(load_partition("mock1.fat32.img"));
+    let armed = device.armed.clone();
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+
+    // The device panics inside the cache, the logical device and the file
+    // system locks.
+    let mut file = vfat.open_file("/rpi3-docs/RPi3-Schematics.pdf", FileOpenMode::Read).unwrap();
+    armed.store(true, Ordering::SeqCst);
+    let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        let mut buf = [0; 4];
+        file.seek(SeekFrom::Start(70000)).unwrap();
+        let _ = file.read(&mut buf);
+    }));
+    assert!(result.is_err());
+    assert!(!armed.load(Ordering::SeqCst));
+    drop(file);
+
+    let vfat2 = vfat.clone();
+    let result = thread::spawn(move || {
+        let _vfat = vfat2.lock();
+        panic!("panic with the file system locked");
+    }).join();
+    assert!(result.is_err());
+
+    let dir = vfat.open_dir("/rpi3-docs").unwrap();
+    let dir2 = dir.clone();
+    let result = thread::spawn(move || {
+        let _dir = dir2.0.lock();
+        panic!("panic with a directory locked");
+    }).join();
+    assert!(result.is_err());
+
+    assert!(dir.find("RPi3-Schematics.pdf").is_ok());
+    let mut file = vfat.open_file("/rpi3-docs/RPi3-Schematics.pdf", FileOpenMode::Read).unwrap();
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic).unwrap();
+    assert_eq!(&magic, b"%PDF");
+    drop(file);
+
+    let mut file = vfat.create_file("/after-panic.txt").unwrap();
+    file.write_all(b"still writable").unwrap();
+    drop(file);
+    assert_eq!(vfat.open_file("/after-panic.txt", FileOpenMode::Read).unwrap().size(), 14);
+}
+
+#[test]
+fn vfat_debug_locks() {
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    assert!(vfat.lock().debug_locks().iter().all(|lock| !lock.write_locked && lock.read_locks == 0));
+
+    let entry = vfat.get_entry("/rpi3-docs/RPi3-Schematics.pdf").unwrap();
+    let file = entry.open_file(FileOpenMode::Write).unwrap();
+    let locks = vfat.lock().debug_locks();
+    let lock = locks.iter().find(|lock| lock.key == entry.lock_key).unwrap();
+    assert!(lock.write_locked);
+    // `entry` and the file's own copy of it.
+    assert_eq!(lock.ref_locks, 2);
+    assert_eq!(lock.waiters, 0);
+
+    drop(file);
+    drop(entry);
+    let key = vfat.get_entry("/rpi3-docs/RPi3-Schematics.pdf").unwrap().lock_key;
+    assert!(vfat.lock().debug_locks().iter().all(|lock| lock.key != key));
+}
+
+/// Needs `/dev/fuse` and permission to mount, so it only runs on request:
+/// `cargo test --features fuse -- --ignored fuse_mount`.
+#[cfg(feature = "fuse")]
+#[test]
+#[ignore]
+fn fuse_mount_mock1() {
+    use std::fs;
+    use fuse::{self, FuseFs};
+
+    let vfat = vfat_from_resource("mock1.fat32.img");
+    let mut expected = Vec::new();
+    vfat.open_file("/rpi3-docs/RPi3-Schematics.pdf", FileOpenMode::Read).unwrap()
+        .read_to_end(&mut expected).unwrap();
+
+    let mountpoint = ::std::env::temp_dir().join(format!("fat32-fuse-{}", ::std::process::id()));
+    fs::create_dir_all(&mountpoint).unwrap();
+    {
+        let _session = fuse::spawn_mount(FuseFs::new(vfat.clone()), &mountpoint).unwrap();
+        let mounted = fs::read(mountpoint.join("rpi3-docs/RPi3-Schematics.pdf")).unwrap();
+        assert!(mounted == expected);
+
+        let names: Vec<String> = fs::read_dir(&mountpoint).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert!(names.iter().any(|name| name == "rpi3-docs"));
+
+        fs::create_dir(mountpoint.join("dir")).unwrap();
+        fs::write(mountpoint.join("dir/hello.txt"), b"hello, world").unwrap();
+        fs::rename(mountpoint.join("dir/hello.txt"), mountpoint.join("dir/moved.txt")).unwrap();
+        assert_eq!(fs::read(mountpoint.join("dir/moved.txt")).unwrap(), b"hello, world");
+        assert_eq!(fs::read_dir(mountpoint.join("rpi3-docs/RPi3-Schematics.pdf")).unwrap_err().raw_os_error(),
+                   Some(::libc::ENOTDIR));
+        assert_eq!(fs::remove_dir(mountpoint.join("dir")).unwrap_err().raw_os_error(),
+                   Some(::libc::ENOTEMPTY));
+    }
+    fs::remove_dir(&mountpoint).unwrap();
+
+    let mut contents = String::new();
+    vfat.open_file("/dir/moved.txt", FileOpenMode::Read).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "hello, world");
+}
+
+#[cfg(feature = "fuse")]
+#[test]
+fn fuse_inodes_follow_lookups() {
+    use fuse::FuseFs;
+    use tests::mock::SparseDevice;
+
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    vfat.create_file("/file.txt").unwrap();
+    let mut fs = FuseFs::new(vfat);
+
+    // a path that doesn't exist gets no inode
+    let missing = fs.resolve(Path::new("/missing.txt")).err().unwrap();
+    assert_eq!(missing.kind(), ::std::io::ErrorKind::NotFound);
+    let (inode, entry) = fs.resolve(Path::new("/file.txt")).unwrap();
+    assert!(entry.is_some());
+    assert_eq!(inode, ::fuser::FUSE_ROOT_ID + 1);
+
+    // the inode stays until every lookup is forgotten
+    fs.count_lookup(inode);
+    fs.count_lookup(inode);
+    fs.forget_inode(inode, 1);
+    assert_eq!(fs.resolve(Path::new("/file.txt")).unwrap().0, inode);
+    fs.forget_inode(inode, 1);
+    assert_ne!(fs.resolve(Path::new("/file.txt")).unwrap().0, inode);
+
+    // the root is never forgotten
+    fs.forget_inode(::fuser::FUSE_ROOT_ID, 1);
+    assert_eq!(fs.resolve(Path::new("/")).unwrap().0, ::fuser::FUSE_ROOT_ID);
+}
+
+#[cfg(feature = "fuse")]
+#[test]
+fn fuse_inodes_follow_entries() {
+    use std::ffi::OsStr;
+    use fuse::FuseFs;
+    use tests::mock::SparseDevice;
+    use vfat::ShortName;
+    use vfat::metadata::Attributes;
+
+    let root = ::fuser::FUSE_ROOT_ID;
+    let mut device = SparseDevice::new(70000);
+    format_fat32(&mut device, 0, 70000);
+    let vfat = VFatFileSystem::from(Box::new(device)).unwrap();
+    for name in &["a.txt", "b.txt"] {
+        let short_name = ShortName::parse(&name.to_uppercase()).unwrap();
+        vfat.root().unwrap()
+            .create_entry_with_short_name(name, &short_name, &new_metadata(&vfat, Attributes::new(false)))
+            .unwrap();
+    }
+    vfat.create_dir("/dir").unwrap();
+    vfat.create_file("/dir/x.txt").unwrap();
+    let mut fs = FuseFs::new(vfat.clone());
+
+    // every spelling of a name gets the inode of its entry
+    let a = fs.resolve(Path::new("/a.txt")).unwrap().0;
+    assert_eq!(fs.resolve(Path::new("/A.TXT")).unwrap().0, a);
+    assert_eq!(fs.resolve(Path::new("/a.TXT")).unwrap().0, a);
+
+    // renaming onto another spelling of itself keeps the file
+    fs.rename_entry(root, OsStr::new("a.txt"), root, OsStr::new("A.TXT")).unwrap();
+    assert_eq!(fs.resolve(Path::new("/a.txt")).unwrap().0, a);
+
+    // renaming onto another spelling of the target drops the target's inode
+    let b = fs.resolve(Path::new("/b.txt")).unwrap().0;
+    fs.rename_entry(root, OsStr::new("a.txt"), root, OsStr::new("B.TXT")).unwrap();
+    assert_eq!(fs.path(b).unwrap_err().kind(), ::std::io::ErrorKind::NotFound);
+    assert_eq!(fs.path(a).unwrap(), Path::new("/B.TXT"));
+    assert_eq!(fs.resolve(Path::new("/B.TXT")).unwrap().0, a);
+
+    // the entries below a moved directory keep their inodes
+    let dir = fs.resolve(Path::new("/dir")).unwrap().0;
+    let x = fs.resolve(Path::new("/dir/x.txt")).unwrap().0;
+    fs.rename_entry(root, OsStr::new("dir"), root, OsStr::new("moved")).unwrap();
+    assert_eq!(fs.resolve(Path::new("/moved")).unwrap().0, dir);
+    assert_eq!(fs.path(x).unwrap(), Path::new("/moved/x.txt"));
+    assert_eq!(fs.resolve(Path::new("/moved/x.txt")).unwrap().0, x);
+}
@@ -1,72 +1,38 @@
 use std::io;
 use std::cmp::min;
-use std::ops::Range;
 use std::ops::{Deref, DerefMut};
 use arc_mutex::ArcMutex;
 
-struct IOOperationChunk {
-    sector: u64,
-    buf_offset: usize,
-    sector_offset: usize,
-    size: usize,
-}
-impl IOOperationChunk {
-    fn buf_range(&self) -> Range<usize> {
-        self.buf_offset..self.buf_offset + self.size
-    }
-    fn sector_range(&self) -> Range<usize> {
-        self.sector_offset..self.sector_offset + self.size
-    }
-}
-
-struct IOOperationIterator {
-    sector_size: usize,
-    buf_size: usize,
-    current_sector: u64,
-    current_buf_offset: usize,
-    current_sector_offset: usize,
+/// The largest sector size served by a stack buffer in `with_sector_buf`.
+const STACK_SECTOR_BUF_SIZE: usize = 4096;
 
-}
-
-impl IOOperationIterator {
-    fn new (sector_size: usize,
-            buf_size: usize,
-            offset: u64) -> IOOperationIterator {
-        IOOperationIterator {
-            sector_size,
-            buf_size,
-            current_sector: offset / sector_size as u64,
-            current_sector_offset: (offset % sector_size as u64) as usize,
-            current_buf_offset: 0,
-        }
+/// Calls `f` with a scratch buffer of `sector_size` bytes, which lives on the
+/// stack unless the sector size is unusually large.
+fn with_sector_buf<F>(sector_size: usize, f: F) -> io::Result<()>
+    where F: FnOnce(&mut [u8]) -> io::Result<()>
+{
+    if sector_size <= STACK_SECTOR_BUF_SIZE {
+        let mut sector_buf = [0; STACK_SECTOR_BUF_SIZE];
+        f(&mut sector_buf[..sector_size])
+    } else {
+        f(&mut vec![0; sector_size])
     }
 }
 
-impl Iterator for IOOperationIterator {
-    type Item = IOOperationChunk;
-    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        let size = min(self.buf_size - self.current_buf_offset,
-                       self.sector_size - self.current_sector_offset);
-        if size == 0 {
-            return None;
+/// Fails with `UnexpectedEof` if the `len` bytes at `offset` don't lie
+/// within the sectors of `device`.
+pub(crate) fn check_range<T: BlockDevice + ?Sized>(device: &T, offset: u64, len: usize) -> io::Result<()> {
+    let device_size = device.num_sectors().checked_mul(device.sector_size());
+    let end = offset.checked_add(len as u64);
+    match (device_size, end) {
+        (Some(device_size), Some(end)) if end > device_size => {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "access past the end of the device"))
         }
-        let result = IOOperationChunk {
-            sector: self.current_sector,
-            buf_offset: self.current_buf_offset,
-            sector_offset: self.current_sector_offset,
-            size: size,
-        };
-
-        self.current_sector += 1;
-        self.current_buf_offset += size;
-        self.current_sector_offset = 0;
-        Some(result)
+        (Some(_), None) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "access past the end of the device")),
+        _ => Ok(()),
     }
 }
 
-
-
-
 /// Trait implemented by devices that can be read/written in sector
 /// granularities.
 pub trait BlockDevice: Send {
@@ -75,40 +41,130 @@ pub trait BlockDevice: Send {
         512
     }
 
+    /// The number of sectors on the device. Defaults to `u64::MAX` for
+    /// devices that don't know their size, which disables the range checks
+    /// of the default methods.
+    fn num_sectors(&self) -> u64 {
+        ::std::u64::MAX
+    }
+
     /// Read sector number `n` into `buf`.
     ///
-    /// `self.sector_size()` or `buf.len()` bytes, whichever is less, are read
-    /// into `buf`. The number of bytes read is returned.
+    /// The sector is read into the first `self.sector_size()` bytes of `buf`;
+    /// any bytes beyond are left untouched.
     ///
     /// # Errors
     ///
-    /// Returns an error if seeking or reading from `self` fails.
+    /// Returns an error if seeking or reading from `self` fails. Returns an
+    /// error of `InvalidInput` if the length of `buf` is less than
+    /// `self.sector_size()`.
     fn read_sector(&self, sector: u64, buf: &mut [u8]) -> io::Result<()>;
 
+    /// Reads the sectors starting at `first` into `buf`, whose length should
+    /// be a multiple of `self.sector_size()`.
+    ///
+    /// The default implementation calls `read_sector` for every sector;
+    /// devices that can read several sectors at once should override it.
+    fn read_sectors(&self, first: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self, first * self.sector_size(), buf.len())?;
+        let sector_size = self.sector_size() as usize;
+        for (i, chunk) in buf.chunks_mut(sector_size).enumerate() {
+            self.read_sector(first + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf`, whose length should be a multiple of
+    /// `self.sector_size()`, to the sectors starting at `first`.
+    ///
+    /// The default implementation calls `write_sector` for every sector;
+    /// devices that can write several sectors at once should override it.
+    fn write_sectors(&mut self, first: u64, buf: &[u8]) -> io::Result<()> {
+        check_range(self, first * self.sector_size(), buf.len())?;
+        let sector_size = self.sector_size() as usize;
+        for (i, chunk) in buf.chunks(sector_size).enumerate() {
+            self.write_sector(first + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes starting at byte `offset_bytes`. The whole
+    /// sectors in the middle of the range are read straight into `buf` with
+    /// a single call to `read_sectors`; only partially covered sectors go
+    /// through a bounce buffer.
     fn read_by_offset(&self, offset_bytes: u64, buf: &mut [u8]) -> io::Result<()> {
-        let mut read_sector_buf = Vec::new();
-        read_sector_buf.resize(self.sector_size() as usize, 0);
-        for chunk in IOOperationIterator::new(self.sector_size() as usize,
-                                              buf.len(), offset_bytes) {
-            self.read_sector(chunk.sector, &mut read_sector_buf)?;
-            buf[chunk.buf_range()].copy_from_slice(&read_sector_buf[chunk.sector_range()]);
+        if buf.is_empty() {
+            return Ok(());
+        }
+        check_range(self, offset_bytes, buf.len())?;
+        let sector_size = self.sector_size() as usize;
+        let mut sector = offset_bytes / sector_size as u64;
+        let head_offset = (offset_bytes % sector_size as u64) as usize;
+        let mut done = 0;
+        if head_offset != 0 || buf.len() < sector_size {
+            let size = min(buf.len(), sector_size - head_offset);
+            with_sector_buf(sector_size, |sector_buf| {
+                self.read_sector(sector, sector_buf)?;
+                buf[..size].copy_from_slice(&sector_buf[head_offset..head_offset + size]);
+                Ok(())
+            })?;
+            done = size;
+            sector += 1;
+        }
+        let aligned = (buf.len() - done) / sector_size * sector_size;
+        if aligned > 0 {
+            self.read_sectors(sector, &mut buf[done..done + aligned])?;
+            done += aligned;
+            sector += (aligned / sector_size) as u64;
+        }
+        if done < buf.len() {
+            let tail = &mut buf[done..];
+            with_sector_buf(sector_size, |sector_buf| {
+                self.read_sector(sector, sector_buf)?;
+                let size = tail.len();
+                tail.copy_from_slice(&sector_buf[..size]);
+                Ok(())
+            })?;
         }
         Ok(())
     }
 
+    /// Writes `buf` starting at byte `offset_bytes`. Partially covered sectors
+    /// are read, patched and written back; the whole sectors in the middle of
+    /// the range are written straight from `buf` with a single call to
+    /// `write_sectors`.
     fn write_by_offset(&mut self, offset_bytes: u64, buf: &[u8]) -> io::Result<()> {
-        let mut read_sector_buf = Vec::new();
-        read_sector_buf.resize(self.sector_size() as usize, 0);
-        for chunk in IOOperationIterator::new(self.sector_size() as usize,
-                                              buf.len(), offset_bytes) {
-            let buf_slice = &buf[chunk.buf_range()];
-            if chunk.size == self.sector_size() as usize {
-                self.write_sector(chunk.sector, buf_slice)?;
-            } else {
-                self.read_sector(chunk.sector, &mut read_sector_buf)?;
-                read_sector_buf[chunk.sector_range()].copy_from_slice(buf_slice);
-                self.write_sector(chunk.sector, &read_sector_buf)?;
-            }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        check_range(self, offset_bytes, buf.len())?;
+        let sector_size = self.sector_size() as usize;
+        let mut sector = offset_bytes / sector_size as u64;
+        let head_offset = (offset_bytes % sector_size as u64) as usize;
+        let mut done = 0;
+        if head_offset != 0 || buf.len() < sector_size {
+            let size = min(buf.len(), sector_size - head_offset);
+            with_sector_buf(sector_size, |sector_buf| {
+                self.read_sector(sector, sector_buf)?;
+                sector_buf[head_offset..head_offset + size].copy_from_slice(&buf[..size]);
+                self.write_sector(sector, sector_buf)
+            })?;
+            done = size;
+            sector += 1;
+        }
+        let aligned = (buf.len() - done) / sector_size * sector_size;
+        if aligned > 0 {
+            self.write_sectors(sector, &buf[done..done + aligned])?;
+            done += aligned;
+            sector += (aligned / sector_size) as u64;
+        }
+        if done < buf.len() {
+            let tail = &buf[done..];
+            with_sector_buf(sector_size, |sector_buf| {
+                self.read_sector(sector, sector_buf)?;
+                sector_buf[..tail.len()].copy_from_slice(tail);
+                self.write_sector(sector, sector_buf)
+            })?;
         }
         Ok(())
     }
@@ -138,17 +194,46 @@ pub trait BlockDevice: Send {
 
     /// Overwrites sector `n` with the contents of `buf`.
     ///
-    /// `self.sector_size()` or `buf.len()` bytes, whichever is less, are written
-    /// to the sector. The number of byte written is returned.
+    /// The first `self.sector_size()` bytes of `buf` are written to the
+    /// sector; any bytes beyond are ignored.
     ///
     /// # Errors
     ///
     /// Returns an error if seeking or writing to `self` fails. Returns an
-    /// error of `UnexpectedEof` if the length of `buf` is less than
+    /// error of `InvalidInput` if the length of `buf` is less than
     /// `self.sector_size()`.
     fn write_sector(&mut self, sector: u64, buf: &[u8]) -> io::Result<()>;
 
     fn sync(&mut self) -> io::Result<()>;
+
+    /// Like `sync`, but only the `count` sectors starting at `first` need to
+    /// become durable, e.g. to flush one file without writing back what a
+    /// cache holds for others. The default implementation syncs everything.
+    fn sync_range(&mut self, _first: u64, _count: u64) -> io::Result<()> {
+        self.sync()
+    }
+
+    /// Makes sure that everything written so far reaches the device before
+    /// anything written afterwards. Unlike `sync`, this need not wait for the
+    /// writes to become durable, only order them. The default implementation
+    /// syncs.
+    fn barrier(&mut self) -> io::Result<()> {
+        self.sync()
+    }
+
+    /// Tells the device that the `count` sectors starting at `sector` no
+    /// longer hold useful data, e.g. to issue a TRIM on flash media. Their
+    /// contents are undefined afterwards. The default implementation does
+    /// nothing.
+    fn discard(&mut self, _sector: u64, _count: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns `true` if the device rejects all writes. The default
+    /// implementation returns `false`.
+    fn is_read_only(&self) -> bool {
+        false
+    }
 }
 
 /*impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
@@ -166,6 +251,10 @@ impl BlockDevice for Box<BlockDevice> {
         self.deref().sector_size()
     }
 
+    fn num_sectors(&self) -> u64 {
+        self.deref().num_sectors()
+    }
+
     fn read_sector(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
         self.deref().read_sector(sector, buf)
     }
@@ -174,9 +263,37 @@ impl BlockDevice for Box<BlockDevice> {
         self.deref_mut().write_sector(sector, buf)
     }
 
+    fn read_sectors(&self, first: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.deref().read_sectors(first, buf)
+    }
+
+    fn write_sectors(&mut self, first: u64, buf: &[u8]) -> io::Result<()> {
+        self.deref_mut().write_sectors(first, buf)
+    }
+
+    fn read_by_offset(&self, offset_bytes: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.deref().read_by_offset(offset_bytes, buf)
+    }
+
     fn sync(&mut self) -> io::Result<()> {
         self.deref_mut().sync()
     }
+
+    fn sync_range(&mut self, first: u64, count: u64) -> io::Result<()> {
+        self.deref_mut().sync_range(first, count)
+    }
+
+    fn barrier(&mut self) -> io::Result<()> {
+        self.deref_mut().barrier()
+    }
+
+    fn discard(&mut self, sector: u64, count: u64) -> io::Result<()> {
+        self.deref_mut().discard(sector, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.deref().is_read_only()
+    }
 }
 
 impl<T: BlockDevice> BlockDevice for ArcMutex<T> {
@@ -184,6 +301,10 @@ impl<T: BlockDevice> BlockDevice for ArcMutex<T> {
         self.lock().sector_size()
     }
 
+    fn num_sectors(&self) -> u64 {
+        self.lock().num_sectors()
+    }
+
     fn read_sector(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
         self.lock().read_sector(sector, buf)
     }
@@ -192,7 +313,35 @@ impl<T: BlockDevice> BlockDevice for ArcMutex<T> {
         self.lock().write_sector(sector, buf)
     }
 
+    fn read_sectors(&self, first: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.lock().read_sectors(first, buf)
+    }
+
+    fn write_sectors(&mut self, first: u64, buf: &[u8]) -> io::Result<()> {
+        self.lock().write_sectors(first, buf)
+    }
+
+    fn read_by_offset(&self, offset_bytes: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.lock().read_by_offset(offset_bytes, buf)
+    }
+
     fn sync(&mut self) -> io::Result<()> {
         self.lock().sync()
     }
+
+    fn sync_range(&mut self, first: u64, count: u64) -> io::Result<()> {
+        self.lock().sync_range(first, count)
+    }
+
+    fn barrier(&mut self) -> io::Result<()> {
+        self.lock().barrier()
+    }
+
+    fn discard(&mut self, sector: u64, count: u64) -> io::Result<()> {
+        self.lock().discard(sector, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.lock().is_read_only()
+    }
 }
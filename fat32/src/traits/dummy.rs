@@ -0,0 +1,148 @@
+use std::io;
+use std::path::Path;
+
+use fallible_iterator::FallibleIterator;
+use traits::*;
+
+/// A type that implements all of the file system traits.
+///
+/// Every method panics; `Dummy` only exists so that code generic over the
+/// traits can be type checked without a real file system.
+#[derive(Copy, Clone, Debug)]
+pub struct Dummy;
+
+impl io::Write for Dummy {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        panic!("Dummy")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        panic!("Dummy")
+    }
+}
+
+impl io::Read for Dummy {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        panic!("Dummy")
+    }
+}
+
+impl io::Seek for Dummy {
+    fn seek(&mut self, _pos: io::SeekFrom) -> io::Result<u64> {
+        panic!("Dummy")
+    }
+}
+
+impl FallibleIterator for Dummy {
+    type Item = Dummy;
+    type Error = io::Error;
+
+    fn next(&mut self) -> io::Result<Option<Self::Item>> {
+        panic!("Dummy")
+    }
+}
+
+impl File for Dummy {
+    fn size(&self) -> u64 {
+        panic!("Dummy")
+    }
+}
+
+impl Dir for Dummy {
+    type Entry = Dummy;
+    type Iter = Dummy;
+
+    fn entries(&self) -> io::Result<Self::Iter> {
+        panic!("Dummy")
+    }
+
+    fn entry(&self) -> Option<Self::Entry> {
+        panic!("Dummy")
+    }
+}
+
+impl Entry for Dummy {
+    type Metadata = Dummy;
+    type File = Dummy;
+    type Dir = Dummy;
+
+    fn name(&self) -> &str {
+        panic!("Dummy")
+    }
+
+    fn metadata(&self) -> &Self::Metadata {
+        panic!("Dummy")
+    }
+
+    fn parent(&self) -> Self::Dir {
+        panic!("Dummy")
+    }
+
+    fn open_file(&self, _mode: FileOpenMode) -> io::Result<Self::File> {
+        panic!("Dummy")
+    }
+
+    fn open_dir(&self) -> io::Result<Self::Dir> {
+        panic!("Dummy")
+    }
+}
+
+impl Metadata for Dummy {
+    fn is_dir(&self) -> bool {
+        panic!("Dummy")
+    }
+
+    fn is_read_only(&self) -> bool {
+        panic!("Dummy")
+    }
+
+    fn is_hidden(&self) -> bool {
+        panic!("Dummy")
+    }
+
+    fn created(&self) -> DateTime {
+        panic!("Dummy")
+    }
+
+    fn accessed(&self) -> Date {
+        panic!("Dummy")
+    }
+
+    fn modified(&self) -> DateTime {
+        panic!("Dummy")
+    }
+
+    fn size(&self) -> u64 {
+        panic!("Dummy")
+    }
+}
+
+impl FileSystem for Dummy {
+    type File = Dummy;
+    type Dir = Dummy;
+    type Entry = Dummy;
+
+    fn get_entry<P: AsRef<Path>>(&self, _path: P) -> io::Result<Self::Entry> {
+        panic!("Dummy")
+    }
+
+    fn root(&self) -> io::Result<Self::Dir> {
+        panic!("Dummy")
+    }
+
+    fn create_file<P: AsRef<Path>>(&self, _path: P) -> io::Result<Self::File> {
+        panic!("Dummy")
+    }
+
+    fn create_dir<P: AsRef<Path>>(&self, _path: P) -> io::Result<Self::Dir> {
+        panic!("Dummy")
+    }
+
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&self, _from: P, _to: Q) -> io::Result<()> {
+        panic!("Dummy")
+    }
+
+    fn remove_entry(&self, _entry: Self::Entry) -> io::Result<()> {
+        panic!("Dummy")
+    }
+}
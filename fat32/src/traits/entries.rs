@@ -0,0 +1,41 @@
+use std::io;
+
+use fallible_iterator::FallibleIterator;
+
+/// Extension methods for the `FallibleIterator` returned by `Dir::entries`.
+pub trait EntriesExt: FallibleIterator<Error = io::Error> + Sized {
+    /// Converts `self` into a `std::iter::Iterator` over `io::Result`s so that
+    /// it can be used in a `for` loop. The iterator ends after yielding the
+    /// first error.
+    fn std_iter(self) -> StdIter<Self> {
+        StdIter { inner: Some(self) }
+    }
+}
+
+impl<I: FallibleIterator<Error = io::Error>> EntriesExt for I {}
+
+/// A `std::iter::Iterator` adapter for a `FallibleIterator`, returned by
+/// `EntriesExt::std_iter`.
+#[derive(Debug)]
+pub struct StdIter<I> {
+    inner: Option<I>,
+}
+
+impl<I: FallibleIterator<Error = io::Error>> Iterator for StdIter<I> {
+    type Item = io::Result<I::Item>;
+
+    fn next(&mut self) -> Option<io::Result<I::Item>> {
+        let result = self.inner.as_mut()?.next();
+        match result {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => {
+                self.inner = None;
+                None
+            }
+            Err(e) => {
+                self.inner = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
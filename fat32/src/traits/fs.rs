@@ -4,6 +4,7 @@ use std::path::Path;
 use traits::Metadata;
 use fallible_iterator::FallibleIterator;
 use std::ffi::OsStr;
+use error::Error;
 
 /// Trait implemented by files in the file system.
 pub trait File: io::Read + io::Write + io::Seek + Sized {
@@ -34,9 +35,9 @@ pub trait Dir: Sized {
     /// is returned.
     fn find<P: AsRef<OsStr>>(&self, name: P) -> io::Result<Self::Entry> {
         if let Some(name) = name.as_ref().to_str() {
-            self.entries()?.find(|entry| entry.name() == name)?.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+            self.entries()?.find(|entry| entry.name() == name)?.ok_or_else(|| Error::NotFound.into())
         } else {
-            Err(io::Error::from(io::ErrorKind::NotFound))
+            Err(Error::NameInvalid { reason: "not valid UTF-8" }.into())
         }
     }
 
@@ -221,7 +222,7 @@ pub trait FileSystem: Sized {
 
     fn remove_dir_recursively(&self, dir: Self::Dir) -> io::Result<()> {
         if dir.entry().is_none() {
-            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "can't remove root dir"));
+            return Err(Error::InvalidInput { what: "the root directory can't be removed" }.into());
         }
         {
             let mut iterator = dir.entries()?;
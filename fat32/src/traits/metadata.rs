@@ -1,6 +1,4 @@
-pub type Date = ::chrono::NaiveDate;
-pub type Time = ::chrono::NaiveTime;
-pub type DateTime = ::chrono::NaiveDateTime;
+use traits::{Date, DateTime};
 
 /// Trait for directory entry metadata.
 pub trait Metadata: Sized {
@@ -15,10 +13,30 @@ pub trait Metadata: Sized {
     /// The timestamp when the entry was created.
     fn created(&self) -> DateTime;
 
-    /// The timestamp for the entry's last access.
-    fn accessed(&self) -> DateTime;
+    /// The date of the entry's last access. File systems such as FAT only
+    /// record the date, so there is no time of day.
+    fn accessed(&self) -> Date;
 
     /// The timestamp for the entry's last modification.
     fn modified(&self) -> DateTime;
+
+    /// The creation timestamp, or `None` if it is unknown or invalid.
+    fn created_opt(&self) -> Option<DateTime> {
+        Some(self.created())
+    }
+
+    /// The last access date, or `None` if it is unknown or invalid.
+    fn accessed_opt(&self) -> Option<Date> {
+        Some(self.accessed())
+    }
+
+    /// The last modification timestamp, or `None` if it is unknown or
+    /// invalid.
+    fn modified_opt(&self) -> Option<DateTime> {
+        Some(self.modified())
+    }
+
+    /// The size of the file in bytes. Directories may return 0.
+    fn size(&self) -> u64;
 }
 
@@ -1,7 +1,14 @@
 mod fs;
 mod block_device;
 mod metadata;
+mod time;
+mod dummy;
+mod entries;
 
 pub use self::fs::{Dir, Entry, File, FileSystem, FileOpenMode};
-pub use self::metadata::{Metadata, Date, Time, DateTime};
+pub use self::metadata::Metadata;
+pub use self::time::{Date, Time, DateTime};
 pub use self::block_device::BlockDevice;
+pub(crate) use self::block_device::check_range;
+pub use self::dummy::Dummy;
+pub use self::entries::{EntriesExt, StdIter};
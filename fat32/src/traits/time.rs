@@ -0,0 +1,247 @@
+use std::fmt;
+
+/// A calendar date in the proleptic Gregorian calendar.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    year: i32,
+    month: u8,
+    day: u8,
+}
+
+/// A time of day with a resolution of one second.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Time {
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+/// A date and a time of day, without a time zone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    date: Date,
+    time: Time,
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+fn is_leap_year(year: i32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The number of days from 1970-01-01 to `date`.
+fn days_from_epoch(date: Date) -> i64 {
+    // Counts from March, so that the leap day is the last day of a year.
+    let (month, day) = (date.month as i64, date.day as i64);
+    let year = date.year as i64 - if month <= 2 { 1 } else { 0 };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date `days` days after 1970-01-01, the inverse of `days_from_epoch`.
+fn date_from_epoch(days: i64) -> Date {
+    let days = days + 719_468;
+    let era = (if days >= 0 { days } else { days - 146_096 }) / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = (shifted_month + 2) % 12 + 1;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    Date { year: year as i32, month: month as u8, day: day as u8 }
+}
+
+impl Date {
+    /// Returns the date, or `None` if there is no such day.
+    pub fn from_ymd_opt(year: i32, month: u32, day: u32) -> Option<Date> {
+        if month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        Some(Date { year, month: month as u8, day: day as u8 })
+    }
+
+    /// Like `from_ymd_opt`, but panics if there is no such day.
+    pub fn from_ymd(year: i32, month: u32, day: u32) -> Date {
+        Date::from_ymd_opt(year, month, day).expect("invalid date")
+    }
+
+    pub fn year(&self) -> i32 {
+        self.year
+    }
+
+    /// The month, from 1 to 12.
+    pub fn month(&self) -> u32 {
+        self.month as u32
+    }
+
+    /// The day of the month, starting at 1.
+    pub fn day(&self) -> u32 {
+        self.day as u32
+    }
+
+    /// The time `hour:minute:second` on this date. Panics if there is no such
+    /// time, like `Time::from_hms`.
+    pub fn and_hms(&self, hour: u32, minute: u32, second: u32) -> DateTime {
+        DateTime::new(*self, Time::from_hms(hour, minute, second))
+    }
+}
+
+impl Time {
+    /// Returns the time, or `None` if there is no such time. Leap seconds
+    /// aren't supported.
+    pub fn from_hms_opt(hour: u32, minute: u32, second: u32) -> Option<Time> {
+        if hour >= 24 || minute >= 60 || second >= 60 {
+            return None;
+        }
+        Some(Time { hour: hour as u8, minute: minute as u8, second: second as u8 })
+    }
+
+    /// Like `from_hms_opt`, but panics if there is no such time.
+    pub fn from_hms(hour: u32, minute: u32, second: u32) -> Time {
+        Time::from_hms_opt(hour, minute, second).expect("invalid time")
+    }
+
+    pub fn hour(&self) -> u32 {
+        self.hour as u32
+    }
+
+    pub fn minute(&self) -> u32 {
+        self.minute as u32
+    }
+
+    pub fn second(&self) -> u32 {
+        self.second as u32
+    }
+}
+
+impl DateTime {
+    pub fn new(date: Date, time: Time) -> DateTime {
+        DateTime { date, time }
+    }
+
+    /// The time `seconds` seconds after 1970-01-01 00:00:00.
+    pub fn from_timestamp(seconds: i64) -> DateTime {
+        let mut days = seconds / SECONDS_PER_DAY;
+        let mut seconds = seconds % SECONDS_PER_DAY;
+        if seconds < 0 {
+            days -= 1;
+            seconds += SECONDS_PER_DAY;
+        }
+        let time = Time::from_hms((seconds / 3600) as u32, (seconds / 60 % 60) as u32, (seconds % 60) as u32);
+        DateTime::new(date_from_epoch(days), time)
+    }
+
+    /// The number of seconds since 1970-01-01 00:00:00, taking this time as
+    /// UTC.
+    pub fn timestamp(&self) -> i64 {
+        let time = &self.time;
+        days_from_epoch(self.date) * SECONDS_PER_DAY
+            + (time.hour as i64 * 3600 + time.minute as i64 * 60 + time.second as i64)
+    }
+
+    pub fn date(&self) -> Date {
+        self.date
+    }
+
+    pub fn time(&self) -> Time {
+        self.time
+    }
+
+    pub fn year(&self) -> i32 {
+        self.date.year()
+    }
+
+    pub fn month(&self) -> u32 {
+        self.date.month()
+    }
+
+    pub fn day(&self) -> u32 {
+        self.date.day()
+    }
+
+    pub fn hour(&self) -> u32 {
+        self.time.hour()
+    }
+
+    pub fn minute(&self) -> u32 {
+        self.time.minute()
+    }
+
+    pub fn second(&self) -> u32 {
+        self.time.second()
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}", self.hour, self.minute, self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.date, self.time)
+    }
+}
+
+#[cfg(feature = "chrono")]
+mod chrono_conversions {
+    use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+    use super::{Date, DateTime, Time};
+
+    impl From<NaiveDate> for Date {
+        fn from(date: NaiveDate) -> Date {
+            Date::from_ymd(date.year(), date.month(), date.day())
+        }
+    }
+
+    impl From<Date> for NaiveDate {
+        fn from(date: Date) -> NaiveDate {
+            NaiveDate::from_ymd(date.year(), date.month(), date.day())
+        }
+    }
+
+    /// Drops fractions of a second, including leap seconds.
+    impl From<NaiveTime> for Time {
+        fn from(time: NaiveTime) -> Time {
+            Time::from_hms(time.hour(), time.minute(), time.second())
+        }
+    }
+
+    impl From<Time> for NaiveTime {
+        fn from(time: Time) -> NaiveTime {
+            NaiveTime::from_hms(time.hour(), time.minute(), time.second())
+        }
+    }
+
+    impl From<NaiveDateTime> for DateTime {
+        fn from(date_time: NaiveDateTime) -> DateTime {
+            DateTime::new(date_time.date().into(), date_time.time().into())
+        }
+    }
+
+    impl From<DateTime> for NaiveDateTime {
+        fn from(date_time: DateTime) -> NaiveDateTime {
+            NaiveDateTime::new(date_time.date().into(), date_time.time().into())
+        }
+    }
+}
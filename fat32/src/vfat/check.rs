@@ -0,0 +1,789 @@
+use std::io;
+use std::collections::HashMap;
+
+use arc_mutex::ArcMutex;
+use error::Error;
+use fallible_iterator::FallibleIterator;
+use traits::{Dir, Entry, FileSystem};
+use vfat::VFatFileSystem;
+use vfat::dir::SharedVFatDir;
+use vfat::fat::{SharedFat, Status};
+use vfat::metadata::{VFatMetadata, Attributes};
+use vfat::dir::{VFatDirEntry, VFatLfnDirEntry, VFatRegularDirEntry, long_name};
+use vfat::short_name;
+
+/// Clusters that are allocated in the FAT but not referenced by any
+/// directory entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostClusterReport {
+    /// The first cluster of every orphaned chain.
+    pub chains: Vec<u32>,
+    /// The total number of orphaned clusters, including those in chains
+    /// without a recognizable head (e.g. cycles).
+    pub lost_clusters: u32,
+}
+
+/// What to do with orphaned chains.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReclaimMode {
+    /// Return the clusters to the free pool.
+    Free,
+    /// Save every chain as `FILEnnnn.CHK` in a new `/FOUND.nnn` directory.
+    SaveToFiles,
+}
+
+/// Two directory entries whose chains share a cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrossLink {
+    /// The first cluster claimed by both chains.
+    pub cluster: u32,
+    /// The path of the entry whose chain was visited first.
+    pub first_owner: String,
+    /// The path of the entry whose chain runs into `first_owner`'s chain.
+    pub second_owner: String,
+    /// The cluster of `second_owner` that links to `cluster`, or `None` if
+    /// `cluster` is the first cluster of `second_owner`.
+    pub previous_cluster: Option<u32>,
+}
+
+/// What is wrong with the chain of a `BrokenChain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainProblem {
+    /// The chain runs into `cluster`, which is free, reserved, bad or beyond
+    /// the data region.
+    Dangling { cluster: u32 },
+    /// The file is larger than its chain can hold.
+    TooShort { size: u32, chain_bytes: u64 },
+}
+
+/// A directory entry whose chain can't hold its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenChain {
+    pub path: String,
+    pub problem: ChainProblem,
+}
+
+/// Returns what is wrong with the chain starting at `first_cluster` of an
+/// entry, if anything. `file_size` is the size of a file, `None` for
+/// directories.
+fn check_chain(fat: &SharedFat, first_cluster: u32, file_size: Option<u32>,
+               cluster_size_bytes: u32) -> io::Result<Option<ChainProblem>> {
+    let end = fat.cluster_count() + 2;
+    let mut clusters = 0u64;
+    let mut cluster = first_cluster;
+    if cluster != 0 {
+        loop {
+            if cluster < 2 || cluster >= end {
+                return Ok(Some(ChainProblem::Dangling { cluster }));
+            }
+            clusters += 1;
+            match fat.get(cluster)?.status() {
+                // cycles are reported by the FAT checks, not here
+                Status::Data(next) if clusters < end as u64 => cluster = next,
+                Status::Data(_) | Status::Eoc(_) => break,
+                _ => return Ok(Some(ChainProblem::Dangling { cluster })),
+            }
+        }
+    }
+    let chain_bytes = clusters * cluster_size_bytes as u64;
+    match file_size {
+        Some(size) if size as u64 > chain_bytes => Ok(Some(ChainProblem::TooShort { size, chain_bytes })),
+        _ => Ok(None),
+    }
+}
+
+/// How serious a `Finding` is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Wasted space or lost names; the data of every entry is intact.
+    Warning,
+    /// Entries may read wrong data or can't be accessed.
+    Error,
+}
+
+/// Where a `Finding` was made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    /// The raw entry `index` of the directory at `dir`, whose first cluster
+    /// is `dir_cluster`. `repair` finds the directory by its cluster, since
+    /// damaged entries may keep `dir` from being resolved.
+    Entry { dir: String, dir_cluster: u32, index: u64 },
+    /// A cluster, or its FAT entry.
+    Cluster(u32),
+}
+
+/// A problem found by `check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The copies of the FAT differ in the entry for the cluster.
+    FatMirrorMismatch,
+    /// The chain of the entry can't hold its contents.
+    BrokenChain(ChainProblem),
+    /// The cluster is claimed by two entries.
+    CrossLink(CrossLink),
+    /// The cluster starts an allocated chain no entry refers to.
+    LostChain,
+    /// `count` LFN entries starting at the entry that aren't followed by a
+    /// regular entry.
+    OrphanLfn { count: u64 },
+    /// `count` LFN entries starting at the entry whose checksum doesn't match
+    /// the short name of the regular entry after them.
+    LfnChecksumMismatch { count: u64 },
+    /// `count` LFN entries starting at the entry whose sequence numbers are
+    /// missing, out of order or inconsistent.
+    BadLfnSequence { count: u64 },
+    /// The short name of the entry is empty or contains invalid characters.
+    InvalidShortName,
+    /// The "." or ".." entry of the directory is missing or refers to a
+    /// cluster other than `expected`.
+    BadDotEntry { expected: u32, found: Option<u32> },
+}
+
+/// One problem found by `check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub location: Location,
+    pub problem: Problem,
+}
+
+/// The result of `check`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FsckReport {
+    pub findings: Vec<Finding>,
+}
+
+impl FsckReport {
+    /// Returns `true` if nothing was found.
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Returns `true` if any finding is an `Error`.
+    pub fn has_errors(&self) -> bool {
+        self.findings.iter().any(|f| f.severity == Severity::Error)
+    }
+}
+
+/// The problems `repair` fixes. The default fixes nothing.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RepairPolicy {
+    /// Free the clusters of `LostChain`s.
+    pub free_lost_clusters: bool,
+    /// Reduce the size of files larger than their chain to the size of the
+    /// chain.
+    pub truncate_oversized_files: bool,
+    /// Free LFN entries that don't belong to a regular entry. Entries whose
+    /// LFN entries are freed are left with their short name.
+    pub drop_orphan_lfns: bool,
+}
+
+impl RepairPolicy {
+    /// Fixes everything `repair` can fix.
+    pub fn all() -> RepairPolicy {
+        RepairPolicy {
+            free_lost_clusters: true,
+            truncate_oversized_files: true,
+            drop_orphan_lfns: true,
+        }
+    }
+}
+
+/// Returns the directory at `cluster` opened by `open_dirs_by_cluster`.
+fn find_dir(dirs: &HashMap<u32, SharedVFatDir>, cluster: u32) -> io::Result<&SharedVFatDir> {
+    dirs.get(&cluster).ok_or_else(|| Error::NotFound.into())
+}
+
+/// A directory waiting to be checked.
+struct PendingDir {
+    dir: SharedVFatDir,
+    path: String,
+    cluster: u32,
+    /// The cluster of the parent directory, `None` for the root directory.
+    parent_cluster: Option<u32>,
+}
+
+/// The state of one pass of `check`.
+struct Checker {
+    fat: SharedFat,
+    cluster_size_bytes: u32,
+    root_cluster: u32,
+    owners: ClusterOwners,
+    findings: Vec<Finding>,
+}
+
+impl Checker {
+    fn add(&mut self, severity: Severity, location: Location, problem: Problem) {
+        self.findings.push(Finding { severity, location, problem });
+    }
+
+    /// Checks the raw entries of `pending` and returns its subdirectories.
+    fn check_dir(&mut self, pending: PendingDir) -> io::Result<Vec<PendingDir>> {
+        let PendingDir { dir, path, cluster, parent_cluster } = pending;
+        let dir_path = if path.is_empty() { "/".to_string() } else { path.clone() };
+        let location = |index| Location::Entry { dir: dir_path.clone(), dir_cluster: cluster, index };
+        let mut subdirs = Vec::new();
+        let mut dots = [None, None];
+        // The LFN entries since the last regular or free entry, and whether
+        // their sequence numbers and checksums are consistent so far.
+        let mut run: Vec<VFatLfnDirEntry> = Vec::new();
+        let mut run_start = 0;
+        let mut run_valid = true;
+        let mut index = 0;
+        loop {
+            let raw_entry = match dir.0.lock().get_raw_entry(index)? {
+                Some(raw_entry) => raw_entry,
+                None => break,
+            };
+            if raw_entry.is_lfn() {
+                let lfn = raw_entry.long_filename();
+                let sequence_number = lfn.sequence_number();
+                if sequence_number & 0x40 != 0 {
+                    self.add_orphan_run(&run, run_valid, location(run_start));
+                    run.clear();
+                    run_start = index;
+                    run_valid = sequence_number & 0x1F != 0;
+                } else {
+                    if run.is_empty() {
+                        run_start = index;
+                        run_valid = false;
+                    } else {
+                        let expected = (run[run.len() - 1].sequence_number() & 0x1F).wrapping_sub(1);
+                        if sequence_number & 0x1F != expected || lfn.checksum() != run[0].checksum() {
+                            run_valid = false;
+                        }
+                    }
+                }
+                run.push(lfn);
+            } else if raw_entry.is_regular() {
+                let regular = raw_entry.regular();
+                let mut name = None;
+                if !run.is_empty() {
+                    let count = run.len() as u64;
+                    let complete = run_valid && run[run.len() - 1].sequence_number() & 0x1F == 1 &&
+                        run[0].sequence_number() & 0x1F == run.len() as u8;
+                    if !complete {
+                        self.add(Severity::Error, location(run_start), Problem::BadLfnSequence { count });
+                    } else if run[0].checksum() != regular.checksum() {
+                        self.add(Severity::Warning, location(run_start), Problem::LfnChecksumMismatch { count });
+                    } else {
+                        name = long_name(&run);
+                    }
+                    run.clear();
+                }
+                if regular.is_dot_entry() {
+                    if index < 2 && regular.short_name()? == [".", ".."][index as usize] {
+                        dots[index as usize] = Some(regular.first_cluster());
+                    }
+                } else if !regular.attributes().is_volume_id() {
+                    let name = match name {
+                        Some(name) => name,
+                        None => regular.short_name().unwrap_or_else(|_| format!("#{}", index)),
+                    };
+                    let entry_path = format!("{}/{}", path, name);
+                    if let Some(subdir) = self.check_entry(&dir, &regular, index, entry_path, location(index))? {
+                        subdirs.push(subdir);
+                    }
+                }
+            } else {
+                self.add_orphan_run(&run, run_valid, location(run_start));
+                run.clear();
+            }
+            index += 1;
+        }
+        self.add_orphan_run(&run, run_valid, location(run_start));
+
+        if let Some(parent_cluster) = parent_cluster {
+            self.check_dot_entry(dots[0], cluster, location(0));
+            // ".." of a directory in the root directory is usually 0.
+            let found = match dots[1] {
+                Some(0) if parent_cluster == self.root_cluster => Some(parent_cluster),
+                found => found,
+            };
+            self.check_dot_entry(found, parent_cluster, location(1));
+        }
+        Ok(subdirs)
+    }
+
+    fn add_orphan_run(&mut self, run: &[VFatLfnDirEntry], run_valid: bool, location: Location) {
+        if run.is_empty() {
+            return;
+        }
+        let count = run.len() as u64;
+        if run_valid {
+            self.add(Severity::Warning, location, Problem::OrphanLfn { count });
+        } else {
+            self.add(Severity::Error, location, Problem::BadLfnSequence { count });
+        }
+    }
+
+    fn check_dot_entry(&mut self, found: Option<u32>, expected: u32, location: Location) {
+        if found != Some(expected) {
+            self.add(Severity::Error, location, Problem::BadDotEntry { expected, found });
+        }
+    }
+
+    /// Checks the regular entry `regular` at `index` of `dir`. Returns the
+    /// directory it refers to if that has to be checked too.
+    fn check_entry(&mut self, dir: &SharedVFatDir, regular: &VFatRegularDirEntry, index: u64, path: String,
+                   location: Location) -> io::Result<Option<PendingDir>> {
+        let (name, ext) = regular.raw_name();
+        if !short_name::is_valid_stored(name, ext) {
+            self.add(Severity::Warning, location.clone(), Problem::InvalidShortName);
+        }
+        let is_dir = regular.attributes().is_dir();
+        let first_cluster = regular.first_cluster();
+        let file_size = if is_dir { None } else { Some(regular.size()) };
+        if let Some(problem) = check_chain(&self.fat, first_cluster, file_size, self.cluster_size_bytes)? {
+            let dangling = match problem {
+                ChainProblem::Dangling { .. } => true,
+                ChainProblem::TooShort { .. } => false,
+            };
+            self.add(Severity::Error, location, Problem::BrokenChain(problem));
+            // a chain that is merely too short is still owned by the entry
+            if dangling {
+                return Ok(None);
+            }
+        }
+        if first_cluster == 0 {
+            return Ok(None);
+        }
+        let visited = self.owners.is_referenced(first_cluster);
+        self.owners.claim_chain(&self.fat, first_cluster, path.clone())?;
+        if !is_dir || visited {
+            return Ok(None);
+        }
+        let subdir = match dir.entry_at(index)? {
+            Some(entry) => entry.open_dir()?,
+            None => return Ok(None),
+        };
+        Ok(Some(PendingDir {
+            dir: subdir,
+            path,
+            cluster: first_cluster,
+            parent_cluster: Some(dir.0.lock().chain.first_cluster),
+        }))
+    }
+}
+
+/// Returns the clusters that are allocated in `fat` but not claimed in
+/// `owners`.
+fn lost_clusters(fat: &SharedFat, owners: &ClusterOwners) -> io::Result<LostClusterReport> {
+    let size = fat.cluster_count() + 2;
+    let mut lost = vec![false; size as usize];
+    let mut has_predecessor = vec![false; size as usize];
+    let mut lost_clusters = 0;
+    for cluster in 2..size {
+        if owners.is_referenced(cluster) {
+            continue;
+        }
+        match fat.get(cluster)?.status() {
+            Status::Data(next) => {
+                if next < size {
+                    has_predecessor[next as usize] = true;
+                }
+            }
+            Status::Eoc(_) => {}
+            _ => continue,
+        }
+        lost[cluster as usize] = true;
+        lost_clusters += 1;
+    }
+
+    let chains = (2..size)
+        .filter(|&c| lost[c as usize] && !has_predecessor[c as usize])
+        .collect();
+    Ok(LostClusterReport { chains, lost_clusters })
+}
+
+/// Records which entry owns every cluster reachable from the root directory.
+struct ClusterOwners {
+    /// Index into `paths` plus one for every cluster, 0 if unreferenced.
+    owners: Vec<usize>,
+    paths: Vec<String>,
+    cross_links: Vec<CrossLink>,
+}
+
+impl ClusterOwners {
+    fn new(size: u32) -> ClusterOwners {
+        ClusterOwners {
+            owners: vec![0; size as usize],
+            paths: Vec::new(),
+            cross_links: Vec::new(),
+        }
+    }
+
+    fn is_referenced(&self, cluster: u32) -> bool {
+        self.owners[cluster as usize] != 0
+    }
+
+    /// Walks every directory from the root and claims the clusters of every
+    /// entry.
+    fn scan(&mut self, fat: &SharedFat, root: SharedVFatDir, root_cluster: u32) -> io::Result<()> {
+        self.claim_chain(fat, root_cluster, "/".to_string())?;
+        let mut dirs = vec![(root, String::new())];
+        while let Some((dir, dir_path)) = dirs.pop() {
+            let mut entries = dir.entries()?;
+            while let Some(entry) = entries.next()? {
+                let first_cluster = entry.metadata.first_cluster;
+                if first_cluster < 2 || first_cluster as usize >= self.owners.len() {
+                    continue;
+                }
+                let path = format!("{}/{}", dir_path, entry.name());
+                let visited = self.is_referenced(first_cluster);
+                self.claim_chain(fat, first_cluster, path.clone())?;
+                if entry.is_dir() && !visited {
+                    dirs.push((entry.open_dir()?, path));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Claims the chain starting at `first_cluster` for `path`. Stops at the
+    /// first cluster that is already claimed, recording a cross-link if it
+    /// belongs to another entry; cycles within the chain just terminate.
+    fn claim_chain(&mut self, fat: &SharedFat, first_cluster: u32, path: String) -> io::Result<()> {
+        self.paths.push(path);
+        let owner = self.paths.len();
+        let mut previous_cluster = None;
+        let mut cluster = first_cluster;
+        loop {
+            if cluster as usize >= self.owners.len() {
+                return Ok(());
+            }
+            let current_owner = self.owners[cluster as usize];
+            if current_owner == owner {
+                return Ok(());
+            }
+            if current_owner != 0 {
+                self.cross_links.push(CrossLink {
+                    cluster,
+                    first_owner: self.paths[current_owner - 1].clone(),
+                    second_owner: self.paths[owner - 1].clone(),
+                    previous_cluster,
+                });
+                return Ok(());
+            }
+            self.owners[cluster as usize] = owner;
+            match fat.get(cluster)?.status() {
+                Status::Data(next) => {
+                    previous_cluster = Some(cluster);
+                    cluster = next;
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+impl ArcMutex<VFatFileSystem> {
+    /// Walks every directory from the root and reports the clusters that are
+    /// allocated in the FAT but not reachable from any directory entry.
+    pub fn find_lost_clusters(&self) -> io::Result<LostClusterReport> {
+        let (fat, root_cluster) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.root_dir_cluster())
+        };
+        let mut owners = ClusterOwners::new(fat.cluster_count() + 2);
+        owners.scan(&fat, self.root()?, root_cluster)?;
+        lost_clusters(&fat, &owners)
+    }
+
+    /// Frees or saves the orphaned chains in `report`, which must come from
+    /// `find_lost_clusters` with no modifications to the file system since.
+    pub fn reclaim_lost_clusters(&self, report: &LostClusterReport, mode: ReclaimMode) -> io::Result<()> {
+        self.lock().check_writable()?;
+        let (mut fat, root_cluster) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.root_dir_cluster())
+        };
+        let size = fat.cluster_count() + 2;
+        let mut owners = ClusterOwners::new(size);
+        owners.scan(&fat, self.root()?, root_cluster)?;
+
+        // Orphaned chains may run into referenced clusters; those are kept.
+        let mut lost = vec![false; size as usize];
+        for &head in &report.chains {
+            let mut cluster = head;
+            loop {
+                lost[cluster as usize] = true;
+                match fat.get(cluster)?.status() {
+                    Status::Data(next) if next < size && !lost[next as usize] && !owners.is_referenced(next) => cluster = next,
+                    _ => break,
+                }
+            }
+        }
+
+        match mode {
+            ReclaimMode::Free => {
+                for cluster in 2..size {
+                    if lost[cluster as usize] {
+                        fat.free_cluster(cluster)?;
+                    }
+                }
+            }
+            ReclaimMode::SaveToFiles => {
+                let dir = self.create_found_dir()?;
+                let cluster_size_bytes = self.lock().cluster_size_bytes() as u64;
+                for (index, &head) in report.chains.iter().enumerate() {
+                    let mut length = 1;
+                    let mut cluster = head;
+                    while let Status::Data(next) = fat.get(cluster)?.status() {
+                        if next >= size || !lost[next as usize] {
+                            break;
+                        }
+                        length += 1;
+                        cluster = next;
+                    }
+                    // Detach the chain from whatever it ran into.
+                    fat.terminate_chain(cluster)?;
+
+                    let size_bytes = ::std::cmp::min(length * cluster_size_bytes, ::std::u32::MAX as u64);
+                    let current_time = self.lock().now();
+                    let metadata = VFatMetadata {
+                        attributes: Attributes::new(false),
+                        created: Some(current_time),
+                        accessed: Some(current_time.date()),
+                        modified: Some(current_time),
+                        first_cluster: head,
+                        size: size_bytes as u32,
+                    };
+                    dir.create_entry(&format!("FILE{:04}.CHK", index), &metadata)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks every directory from the root and reports every cluster that is
+    /// claimed by more than one directory entry.
+    pub fn find_cross_links(&self) -> io::Result<Vec<CrossLink>> {
+        let (fat, root_cluster) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.root_dir_cluster())
+        };
+        let mut owners = ClusterOwners::new(fat.cluster_count() + 2);
+        owners.scan(&fat, self.root()?, root_cluster)?;
+        Ok(owners.cross_links)
+    }
+
+    /// Gives `cross_link.second_owner` a private copy of the shared tail of
+    /// its chain. The first owner keeps the original clusters.
+    ///
+    /// # Errors
+    ///
+    /// If the shared cluster is the first cluster of the second owner, an
+    /// error of `InvalidInput` is returned: that would require rewriting its
+    /// directory entry.
+    pub fn repair_cross_link(&self, cross_link: &CrossLink) -> io::Result<()> {
+        self.lock().check_writable()?;
+        let mut previous_cluster = cross_link.previous_cluster.ok_or_else(|| {
+            Error::InvalidInput { what: "can't repair a cross-link at the head of a chain" }
+        })?;
+        let (mut fat, cluster_size_bytes) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.cluster_size_bytes())
+        };
+        let mut buf = vec![0; cluster_size_bytes as usize];
+        let mut shared_cluster = Some(cross_link.cluster);
+        let mut copied = Vec::new();
+        while let Some(cluster) = shared_cluster {
+            // a cycle in the shared tail ends the copy
+            if copied.contains(&cluster) {
+                break;
+            }
+            copied.push(cluster);
+            self.lock().read_cluster(cluster, 0, &mut buf)?;
+            let new_cluster = fat.alloc_for_chain(previous_cluster)?;
+            self.lock().write_cluster(new_cluster, 0, &buf)?;
+            previous_cluster = new_cluster;
+            shared_cluster = fat.get_next_in_chain(cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Walks every directory from the root and reports every entry whose
+    /// chain runs into a cluster that isn't allocated or is too short for the
+    /// size of the file. These are what an interrupted update may leave
+    /// behind if written out of order.
+    pub fn find_broken_chains(&self) -> io::Result<Vec<BrokenChain>> {
+        let (fat, cluster_size_bytes) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.cluster_size_bytes())
+        };
+        let mut broken = Vec::new();
+        let mut visited = Vec::new();
+        let mut dirs = vec![(self.root()?, String::new())];
+        while let Some((dir, dir_path)) = dirs.pop() {
+            let mut entries = dir.entries()?;
+            while let Some(entry) = entries.next()? {
+                let path = format!("{}/{}", dir_path, entry.name());
+                let file_size = if entry.is_dir() { None } else { Some(entry.metadata.size) };
+                match check_chain(&fat, entry.metadata.first_cluster, file_size, cluster_size_bytes)? {
+                    Some(problem) => broken.push(BrokenChain { path, problem }),
+                    None => {
+                        let first_cluster = entry.metadata.first_cluster;
+                        if entry.is_dir() && first_cluster != 0 && !visited.contains(&first_cluster) {
+                            visited.push(first_cluster);
+                            dirs.push((entry.open_dir()?, path));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(broken)
+    }
+
+    /// Checks the whole volume in one pass: the copies of the FAT, the chain
+    /// and the names of every entry reachable from the root directory, the
+    /// "." and ".." entries of every directory, cross-linked and lost
+    /// clusters. Damaged LFN entries are reported rather than failing the
+    /// walk.
+    pub fn check(&self) -> io::Result<FsckReport> {
+        let (fat, root_cluster, cluster_size_bytes) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.root_dir_cluster(), vfat.cluster_size_bytes())
+        };
+        let mut checker = Checker {
+            owners: ClusterOwners::new(fat.cluster_count() + 2),
+            fat,
+            cluster_size_bytes,
+            root_cluster,
+            findings: Vec::new(),
+        };
+        for cluster in checker.fat.verify_mirrors()? {
+            checker.add(Severity::Warning, Location::Cluster(cluster), Problem::FatMirrorMismatch);
+        }
+
+        checker.owners.claim_chain(&checker.fat, root_cluster, "/".to_string())?;
+        let mut dirs = vec![PendingDir { dir: self.root()?, path: String::new(), cluster: root_cluster, parent_cluster: None }];
+        while let Some(pending) = dirs.pop() {
+            dirs.extend(checker.check_dir(pending)?);
+        }
+
+        for cross_link in ::std::mem::replace(&mut checker.owners.cross_links, Vec::new()) {
+            checker.add(Severity::Error, Location::Cluster(cross_link.cluster), Problem::CrossLink(cross_link));
+        }
+        for head in lost_clusters(&checker.fat, &checker.owners)?.chains {
+            checker.add(Severity::Warning, Location::Cluster(head), Problem::LostChain);
+        }
+        Ok(FsckReport { findings: checker.findings })
+    }
+
+    /// Fixes the findings of `report` that `policy` allows. `report` must come
+    /// from `check` with no modifications to the file system since. Returns the
+    /// number of findings fixed.
+    pub fn repair(&self, report: &FsckReport, policy: RepairPolicy) -> io::Result<usize> {
+        self.lock().check_writable()?;
+        let mut repaired = 0;
+        let dirs = self.open_dirs_by_cluster(report)?;
+        if policy.drop_orphan_lfns {
+            let lfn_runs: Vec<(u32, u64, u64)> = report.findings.iter().filter_map(|finding| {
+                match (&finding.location, &finding.problem) {
+                    (&Location::Entry { dir_cluster, index, .. }, &Problem::OrphanLfn { count }) |
+                    (&Location::Entry { dir_cluster, index, .. }, &Problem::LfnChecksumMismatch { count }) |
+                    (&Location::Entry { dir_cluster, index, .. }, &Problem::BadLfnSequence { count }) => {
+                        Some((dir_cluster, index, count))
+                    }
+                    _ => None,
+                }
+            }).collect();
+            for (dir_cluster, index, count) in lfn_runs {
+                let dir = find_dir(&dirs, dir_cluster)?;
+                let mut dir = dir.0.lock();
+                for index in index..index + count {
+                    dir.set_raw_entry(index, &VFatDirEntry::new_free())?;
+                }
+                repaired += 1;
+            }
+        }
+
+        if policy.truncate_oversized_files {
+            for finding in &report.findings {
+                if let (&Location::Entry { dir_cluster, index, .. }, &Problem::BrokenChain(ChainProblem::TooShort { chain_bytes, .. })) =
+                    (&finding.location, &finding.problem) {
+                    find_dir(&dirs, dir_cluster)?.0.lock().set_file_size(index, chain_bytes as u32)?;
+                    repaired += 1;
+                }
+            }
+        }
+
+        if policy.free_lost_clusters {
+            let heads: Vec<u32> = report.findings.iter().filter_map(|finding| {
+                match (&finding.location, &finding.problem) {
+                    (&Location::Cluster(cluster), &Problem::LostChain) => Some(cluster),
+                    _ => None,
+                }
+            }).collect();
+            if !heads.is_empty() {
+                let mut lost = self.find_lost_clusters()?;
+                lost.chains.retain(|head| heads.contains(head));
+                repaired += lost.chains.len();
+                self.reclaim_lost_clusters(&lost, ReclaimMode::Free)?;
+            }
+        }
+        Ok(repaired)
+    }
+
+    /// Opens the directories the `Entry` findings of `report` are in. Every
+    /// directory is found by walking the regular entries from the root, so
+    /// damaged LFN entries and names that aren't unique can't hide it.
+    fn open_dirs_by_cluster(&self, report: &FsckReport) -> io::Result<HashMap<u32, SharedVFatDir>> {
+        let mut wanted: Vec<u32> = report.findings.iter().filter_map(|finding| match finding.location {
+            Location::Entry { dir_cluster, .. } => Some(dir_cluster),
+            Location::Cluster(_) => None,
+        }).collect();
+        let mut found = HashMap::new();
+        if wanted.is_empty() {
+            return Ok(found);
+        }
+        let (end, root_cluster) = {
+            let vfat = self.lock();
+            (vfat.fat().cluster_count() + 2, vfat.root_dir_cluster())
+        };
+        let mut visited = vec![root_cluster];
+        let mut dirs = vec![(root_cluster, self.root()?)];
+        while let Some((cluster, dir)) = dirs.pop() {
+            if wanted.contains(&cluster) {
+                wanted.retain(|&c| c != cluster);
+                found.insert(cluster, dir.clone());
+                if wanted.is_empty() {
+                    break;
+                }
+            }
+            let mut index = 0;
+            loop {
+                let raw_entry = match dir.0.lock().get_raw_entry(index)? {
+                    Some(raw_entry) => raw_entry,
+                    None => break,
+                };
+                if raw_entry.is_regular() {
+                    let regular = raw_entry.regular();
+                    let first_cluster = regular.first_cluster();
+                    if regular.attributes().is_dir() && !regular.is_dot_entry() && first_cluster >= 2 &&
+                        first_cluster < end && !visited.contains(&first_cluster) {
+                        visited.push(first_cluster);
+                        if let Some(entry) = dir.entry_at(index)? {
+                            dirs.push((first_cluster, entry.open_dir()?));
+                        }
+                    }
+                }
+                index += 1;
+            }
+        }
+        Ok(found)
+    }
+
+    fn create_found_dir(&self) -> io::Result<SharedVFatDir> {
+        for index in 0..1000 {
+            let path = format!("/FOUND.{:03}", index);
+            match self.get_entry(&path) {
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return self.create_dir(&path),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::AlreadyExists.into())
+    }
+}
@@ -0,0 +1,91 @@
+/// One bit per data cluster of a volume, set if the cluster is allocated.
+///
+/// Clusters are addressed by their cluster number, so valid indices run from
+/// 2 to `cluster_count() + 1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterBitmap {
+    bits: Vec<u64>,
+    cluster_count: u32,
+}
+
+impl ClusterBitmap {
+    /// Returns a bitmap for `cluster_count` clusters, all of them free.
+    pub fn new(cluster_count: u32) -> ClusterBitmap {
+        ClusterBitmap {
+            bits: vec![0; (cluster_count as usize + 63) / 64],
+            cluster_count,
+        }
+    }
+
+    /// The number of data clusters covered by the bitmap.
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    pub(crate) fn set_allocated(&mut self, cluster: u32) {
+        let index = (cluster - 2) as usize;
+        self.bits[index / 64] |= 1 << (index % 64);
+    }
+
+    /// Returns `true` if `cluster` is allocated. Clusters outside of the data
+    /// region are reported as allocated.
+    pub fn is_allocated(&self, cluster: u32) -> bool {
+        if cluster < 2 || cluster - 2 >= self.cluster_count {
+            return true;
+        }
+        let index = (cluster - 2) as usize;
+        self.bits[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Returns the number of allocated clusters.
+    pub fn allocated_count(&self) -> u32 {
+        self.bits.iter().map(|word| word.count_ones()).sum()
+    }
+
+    /// Returns an iterator over the runs of free clusters as
+    /// `(first_cluster, length)` pairs, in ascending order.
+    pub fn iter_free_runs(&self) -> FreeRuns {
+        FreeRuns {
+            bitmap: self,
+            cluster: 2,
+        }
+    }
+}
+
+/// Iterator over the free extents of a `ClusterBitmap`.
+pub struct FreeRuns<'a> {
+    bitmap: &'a ClusterBitmap,
+    cluster: u32,
+}
+
+impl<'a> Iterator for FreeRuns<'a> {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<(u32, u32)> {
+        let end = self.bitmap.cluster_count + 2;
+        while self.cluster < end && self.bitmap.is_allocated(self.cluster) {
+            self.cluster += 1;
+        }
+        if self.cluster >= end {
+            return None;
+        }
+        let start = self.cluster;
+        while self.cluster < end && !self.bitmap.is_allocated(self.cluster) {
+            self.cluster += 1;
+        }
+        Some((start, self.cluster - start))
+    }
+}
+
+#[test]
+fn test_free_runs() {
+    let mut bitmap = ClusterBitmap::new(130);
+    for cluster in (2..5).chain(70..100).chain(131..132) {
+        bitmap.set_allocated(cluster);
+    }
+    assert_eq!(bitmap.allocated_count(), 34);
+    assert!(bitmap.is_allocated(71));
+    assert!(!bitmap.is_allocated(100));
+    assert!(bitmap.is_allocated(132));
+    assert_eq!(bitmap.iter_free_runs().collect::<Vec<_>>(), vec![(5, 65), (100, 31)]);
+}
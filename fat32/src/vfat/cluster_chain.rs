@@ -1,41 +1,123 @@
 use std::cmp::min;
 use std::io::{self, SeekFrom};
+use std::mem;
+use std::ops::Range;
 
 use vfat::{VFatFileSystem};
-use traits::BlockDevice;
 use vfat::fat::SharedFat;
+use vfat::data_region::DataRegion;
 use vfat::lock_manager::LockMode;
 use vfat::lock_manager::FSObjectGuard;
+use vfat::lock_manager::{LockKey, LockWait};
 use arc_mutex::ArcMutex;
+use error::Error;
+
+/// Bytes of a chain that couldn't be read and were zero-filled instead, as
+/// recorded with `MountOptions::read_recovery`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BadRange {
+    /// The cluster the bytes are in.
+    pub cluster: u32,
+    /// The position of the first byte in the chain.
+    pub offset: u64,
+    pub len: u64,
+}
 
 pub struct ClusterChain {
     pub(crate) vfat: ArcMutex<VFatFileSystem>,
     fat: SharedFat,
+    data: DataRegion,
+    /// 0 for a chain without clusters. Writing to such a chain allocates its
+    /// first cluster, and the owner has to store the new value wherever the
+    /// chain is referenced from.
     pub(crate) first_cluster: u32,
+    /// Set for the fixed root directory region of FAT12 and FAT16 volumes,
+    /// which is read as a single read-only cluster with number 0.
+    root_region: bool,
     cluster_size_bytes: u32,
     previous_cluster: Option<u32>,
     current_cluster: Option<u32>,
+    cluster_count: Option<u64>,
     pub(crate) position: u64,
+    /// The sectors written through the chain since the last flush, which
+    /// `flush` syncs instead of the whole device.
+    dirty_sectors: Vec<Range<u64>>,
+    /// Zero-fill clusters that can't be read instead of failing, recording
+    /// them in `read_errors`.
+    pub(crate) recover_reads: bool,
+    read_errors: Vec<BadRange>,
     pub(crate) guard: FSObjectGuard,
 }
 
 impl ClusterChain {
-    pub fn open(vfat: ArcMutex<VFatFileSystem>, first_cluster: u32, mode: LockMode) -> Option<ClusterChain> {
+    /// Opens the chain starting at `first_cluster` and locks `key` in
+    /// `mode`. If that is locked in a conflicting mode, waits for the lock to
+    /// be released as long as `wait` says, and returns `None` if it isn't.
+    pub fn open_with(vfat: ArcMutex<VFatFileSystem>, first_cluster: u32, key: LockKey, mode: LockMode,
+                     wait: LockWait) -> Option<ClusterChain> {
+        // The file system must not stay locked while waiting for the chain.
+        let lock_manager = vfat.lock().lock_manager();
+        let guard = lock_manager.lock_with(key, mode, wait)?;
         let vfat2 = vfat.lock();
-        if let Some(guard) = vfat2.lock_manager().try_lock(first_cluster, mode) {
-            Some(ClusterChain {
-                fat: vfat2.fat(),
-                vfat: vfat.clone(),
-                first_cluster,
-                cluster_size_bytes: vfat2.cluster_size_bytes(),
-                current_cluster: Some(first_cluster),
-                previous_cluster: None,
-                position: 0,
-                guard,
-            })
-        } else {
-            None
+        Some(ClusterChain {
+            fat: vfat2.fat(),
+            data: vfat2.data_region(),
+            vfat: vfat.clone(),
+            first_cluster,
+            root_region: false,
+            cluster_size_bytes: vfat2.cluster_size_bytes(),
+            current_cluster: Self::head(first_cluster),
+            previous_cluster: None,
+            cluster_count: None,
+            position: 0,
+            dirty_sectors: Vec::new(),
+            recover_reads: false,
+            read_errors: Vec::new(),
+            guard,
+        })
+    }
+
+    /// Opens the fixed root directory region of a FAT12 or FAT16 volume like
+    /// `open_with`. Writing to it fails with `UnsupportedFs`.
+    pub(crate) fn open_root_region(vfat: ArcMutex<VFatFileSystem>, key: LockKey, mode: LockMode,
+                                   wait: LockWait) -> Option<ClusterChain> {
+        let mut chain = ClusterChain::open_with(vfat, 0, key, mode, wait)?;
+        chain.root_region = true;
+        chain.cluster_size_bytes = chain.data.root_region_size_bytes();
+        chain.current_cluster = Some(0);
+        Some(chain)
+    }
+
+    /// Returns the ranges zero-filled so far because they couldn't be read.
+    pub fn read_errors(&self) -> &[BadRange] {
+        &self.read_errors
+    }
+
+    /// Records that `len` bytes of `cluster` at position `offset` were
+    /// zero-filled, extending the last range if they follow it.
+    fn record_read_error(&mut self, cluster: u32, offset: u64, len: u64) {
+        if self.read_errors.iter().any(|range| range.offset <= offset && offset + len <= range.offset + range.len) {
+            return;
+        }
+        if let Some(last) = self.read_errors.last_mut() {
+            if last.cluster == cluster && last.offset + last.len == offset {
+                last.len += len;
+                return;
+            }
         }
+        self.read_errors.push(BadRange { cluster, offset, len });
+    }
+
+    fn head(first_cluster: u32) -> Option<u32> {
+        if first_cluster == 0 { None } else { Some(first_cluster) }
+    }
+
+    pub(crate) fn fat(&self) -> &SharedFat {
+        &self.fat
+    }
+
+    pub fn cluster_size_bytes(&self) -> u32 {
+        self.cluster_size_bytes
     }
 
     pub fn at_end(&self) -> bool {
@@ -45,7 +127,7 @@ impl ClusterChain {
     fn rewind(&mut self) {
         self.position = 0;
         self.previous_cluster = None;
-        self.current_cluster = Some(self.first_cluster);
+        self.current_cluster = if self.root_region { Some(0) } else { Self::head(self.first_cluster) };
     }
 
     fn cluster_index(&self, pos: u64) -> u64 {
@@ -65,7 +147,11 @@ impl ClusterChain {
                 self.position = final_pos;
                 break;
             }
-            let next_cluster = self.fat.get_next_in_chain(self.current_cluster.unwrap())?;
+            let next_cluster = if self.root_region {
+                None
+            } else {
+                self.fat.get_next_in_chain(self.current_cluster.unwrap())?
+            };
             self.position = next_cluster_start_pos;
             self.previous_cluster = self.current_cluster;
             self.current_cluster = next_cluster;
@@ -73,6 +159,123 @@ impl ClusterChain {
         Ok(())
     }
 
+    /// Returns the number of clusters in the chain and the last of them.
+    fn last_cluster(&self) -> io::Result<(u64, Option<u32>)> {
+        if self.first_cluster == 0 {
+            return Ok((0, None));
+        }
+        let clusters = self.fat.chain_clusters(self.first_cluster)?;
+        Ok((clusters.len() as u64, clusters.last().cloned()))
+    }
+
+    /// Appends `count` clusters, consecutive if possible, after `last_cluster`,
+    /// or makes them the chain if it has no clusters yet. Returns the first
+    /// of them.
+    fn alloc_after(&mut self, last_cluster: Option<u32>, count: u32) -> io::Result<u32> {
+        match last_cluster {
+            Some(last_cluster) => self.fat.alloc_contiguous_for_chain(last_cluster, count),
+            None => {
+                let first_cluster = self.fat.alloc_contiguous(count)?;
+                self.first_cluster = first_cluster;
+                Ok(first_cluster)
+            }
+        }
+    }
+
+    /// Frees the clusters after `last_cluster`, or all of them if it is
+    /// `None`.
+    fn truncate_after(&mut self, last_cluster: Option<u32>) -> io::Result<()> {
+        match last_cluster {
+            Some(last_cluster) => self.fat.truncate_chain(last_cluster),
+            None => {
+                let first_cluster = self.first_cluster;
+                self.first_cluster = 0;
+                self.fat.free_chain(first_cluster)
+            }
+        }
+    }
+
+    /// Returns the number of clusters in the chain, counting them on first use.
+    fn cluster_count(&mut self) -> io::Result<u64> {
+        if self.cluster_count.is_none() {
+            self.cluster_count = Some(self.last_cluster()?.0);
+        }
+        Ok(self.cluster_count.unwrap())
+    }
+
+    /// Fails with a `NoSpace` error if writing `len` bytes at the current
+    /// position needs more new clusters than the volume has free.
+    fn check_free_space(&mut self, len: u64) -> io::Result<()> {
+        let end = self.position + len;
+        let needed = (end + self.cluster_size_bytes as u64 - 1) / self.cluster_size_bytes as u64;
+        let count = self.cluster_count()?;
+        if needed > count && needed - count > self.fat.free_cluster_count()? as u64 {
+            return Err(Error::NoSpace.into());
+        }
+        Ok(())
+    }
+
+    /// Extends the chain with consecutive clusters, if possible, so that it
+    /// can hold at least `bytes` bytes without further allocations.
+    pub fn preallocate(&mut self, bytes: u64) -> io::Result<()> {
+        if self.root_region {
+            return Err(Error::UnsupportedFs.into());
+        }
+        let needed = (bytes + self.cluster_size_bytes as u64 - 1) / self.cluster_size_bytes as u64;
+        let (count, last_cluster) = self.last_cluster()?;
+        if needed <= count {
+            return Ok(());
+        }
+        if needed - count > ::std::u32::MAX as u64 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let first_new = self.alloc_after(last_cluster, (needed - count) as u32)?;
+        self.cluster_count = Some(needed);
+        if self.current_cluster.is_none() {
+            self.current_cluster = match self.previous_cluster {
+                Some(previous_cluster) => self.fat.get_next_in_chain(previous_cluster)?,
+                None => Some(first_new),
+            };
+        }
+        Ok(())
+    }
+
+    /// Frees the clusters that are not needed to hold `bytes` bytes. The first
+    /// cluster is always kept.
+    pub fn truncate(&mut self, bytes: u64) -> io::Result<()> {
+        if self.first_cluster == 0 {
+            return Ok(());
+        }
+        let keep = ((bytes + self.cluster_size_bytes as u64 - 1) / self.cluster_size_bytes as u64).max(1);
+        let mut cluster = self.first_cluster;
+        for _ in 1..keep {
+            cluster = self.fat.get_next_in_chain(cluster)?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        }
+        self.fat.truncate_chain(cluster)?;
+        self.cluster_count = Some(keep);
+
+        let position = min(self.position, bytes);
+        self.rewind();
+        self.advance(position)
+    }
+
+    /// Remembers that `sectors` were written, merging them into the last
+    /// range where they overlap or adjoin it.
+    fn mark_dirty(&mut self, sectors: Range<u64>) {
+        if self.dirty_sectors.iter().any(|dirty| dirty.start <= sectors.start && sectors.end <= dirty.end) {
+            return;
+        }
+        if let Some(last) = self.dirty_sectors.last_mut() {
+            if sectors.start <= last.end && last.start <= sectors.end {
+                last.start = min(last.start, sectors.start);
+                last.end = ::std::cmp::max(last.end, sectors.end);
+                return;
+            }
+        }
+        self.dirty_sectors.push(sectors);
+    }
+
     fn advance_to_end(&mut self) -> io::Result<()> {
         let next_cluster_index = self.cluster_index(self.position) + 1;
         let next_cluster_start_pos = next_cluster_index * self.cluster_size_bytes as u64;
@@ -87,9 +290,147 @@ impl ClusterChain {
 
 }
 
+impl ClusterChain {
+    const MAX_BAD_CLUSTERS_PER_WRITE: usize = 3;
+
+    /// Appends a cluster to the chain and writes `buf` to its start. If the
+    /// write fails, the cluster is marked bad and replaced by another one.
+    fn write_to_new_cluster(&mut self, buf: &[u8]) -> io::Result<()> {
+        let previous_cluster = self.previous_cluster;
+        let mut attempt = 0;
+        loop {
+            let new_cluster = match previous_cluster {
+                Some(previous_cluster) => self.fat.alloc_for_chain(previous_cluster)?,
+                None => {
+                    self.first_cluster = self.fat.new_chain()?;
+                    self.first_cluster
+                }
+            };
+            let result = self.data.write_cluster(new_cluster, 0, buf);
+            match result {
+                Ok(()) => {
+                    let sectors = self.data.cluster_sectors(new_cluster, 0, buf.len());
+                    self.mark_dirty(sectors);
+                    self.current_cluster = Some(new_cluster);
+                    if let Some(ref mut count) = self.cluster_count {
+                        *count += 1;
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    attempt += 1;
+                    self.truncate_after(previous_cluster)
+                        .and_then(|_| self.fat.mark_bad(new_cluster))?;
+                    if attempt == Self::MAX_BAD_CLUSTERS_PER_WRITE {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes the whole clusters at the start of `buf` from the current
+    /// position, which must be at the start of a cluster. The clusters the
+    /// chain lacks are allocated at once, and every run of consecutive
+    /// clusters is written with a single device write.
+    ///
+    /// Returns the number of bytes written. If a write fails, this is what
+    /// the runs before it hold, and the clusters allocated for the rest are
+    /// freed again.
+    fn write_clusters(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let cluster_size = self.cluster_size_bytes as usize;
+        let count = buf.len() / cluster_size;
+        let mut clusters = Vec::with_capacity(count);
+        let mut next_cluster = self.current_cluster;
+        while let Some(cluster) = next_cluster {
+            if clusters.len() == count {
+                break;
+            }
+            clusters.push(cluster);
+            next_cluster = self.fat.get_next_in_chain(cluster)?;
+        }
+        let existing = clusters.len();
+        if existing < count {
+            let last_cluster = clusters.last().cloned().or(self.previous_cluster);
+            let first_new = self.alloc_after(last_cluster, (count - existing) as u32)?;
+            clusters.extend(self.fat.chain_clusters(first_new)?);
+            if let Some(ref mut cluster_count) = self.cluster_count {
+                *cluster_count += (count - existing) as u64;
+            }
+        }
+
+        let mut written = 0;
+        let mut start = 0;
+        while start < count {
+            let mut end = start + 1;
+            while end < count && clusters[end] == clusters[end - 1] + 1 {
+                end += 1;
+            }
+            let run = &buf[start * cluster_size..end * cluster_size];
+            if self.data.write_clusters(clusters[start], run).is_err() {
+                break;
+            }
+            let sectors = self.data.cluster_sectors(clusters[start], 0, run.len());
+            self.mark_dirty(sectors);
+            written = end;
+            start = end;
+        }
+
+        if written < count && existing < count {
+            let keep = ::std::cmp::max(written, existing);
+            let last_cluster = if keep > 0 { Some(clusters[keep - 1]) } else { self.previous_cluster };
+            self.truncate_after(last_cluster)?;
+            self.cluster_count = None;
+            next_cluster = None;
+            clusters.truncate(keep);
+        }
+        if written > 0 {
+            self.position += (written * cluster_size) as u64;
+            self.previous_cluster = Some(clusters[written - 1]);
+            self.current_cluster = match clusters.get(written) {
+                Some(&cluster) => Some(cluster),
+                None => next_cluster,
+            };
+        }
+        Ok(written * cluster_size)
+    }
+}
+
+impl ClusterChain {
+    /// Reads whole clusters into the start of `buf`, which must hold at
+    /// least one, from the current position, which must be at the start of a
+    /// cluster. Only the run of consecutive clusters starting at the current
+    /// one is read, with a single device read.
+    ///
+    /// Returns the number of bytes read.
+    fn read_clusters(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let cluster_size = self.cluster_size_bytes as usize;
+        let count = buf.len() / cluster_size;
+        let first = self.current_cluster.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let mut run = 1;
+        let mut next_cluster = self.fat.get_next_in_chain(first)?;
+        while run < count {
+            match next_cluster {
+                Some(cluster) if cluster as u64 == first as u64 + run as u64 => {
+                    run += 1;
+                    next_cluster = self.fat.get_next_in_chain(cluster)?;
+                }
+                _ => break,
+            }
+        }
+        let len = run * cluster_size;
+        self.data.read_clusters(first, &mut buf[..len])?;
+        self.position += len as u64;
+        self.previous_cluster = Some(first + run as u32 - 1);
+        self.current_cluster = next_cluster;
+        Ok(len)
+    }
+}
+
 impl io::Read for ClusterChain {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let mut total_read_size = 0;
+        let mut read_runs = true;
         loop {
             if self.current_cluster.is_none() {
                 break;
@@ -97,12 +438,40 @@ impl io::Read for ClusterChain {
             let buf_tail = &mut buf[total_read_size..];
 
             let cluster_offset = self.position % self.cluster_size_bytes as u64;
+            // Whole clusters are read from the device in runs.
+            if read_runs && !self.root_region && cluster_offset == 0 &&
+                buf_tail.len() >= 2 * self.cluster_size_bytes as usize {
+                match self.read_clusters(buf_tail) {
+                    Ok(size) => {
+                        total_read_size += size;
+                        continue;
+                    }
+                    // find the unreadable clusters of the run one by one
+                    Err(_) if self.recover_reads => read_runs = false,
+                    Err(e) => return Err(e),
+                }
+            }
             let read_size = min(self.cluster_size_bytes as u64 - cluster_offset, buf_tail.len() as u64);
             if read_size == 0 {
                 break;
             }
-            self.vfat.lock().read_cluster(self.current_cluster.unwrap(), cluster_offset as u32,
-                                                &mut buf_tail[..read_size as usize])?;
+            let chunk = &mut buf_tail[..read_size as usize];
+            if self.root_region {
+                self.data.read_root_region(cluster_offset as u32, chunk)?;
+            } else {
+                let cluster = self.current_cluster.unwrap();
+                match self.data.read_cluster(cluster, cluster_offset as u32, chunk) {
+                    Ok(()) => {}
+                    Err(_) if self.recover_reads => {
+                        for byte in chunk.iter_mut() {
+                            *byte = 0;
+                        }
+                        let position = self.position;
+                        self.record_read_error(cluster, position, read_size);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
             self.advance(read_size)?;
             total_read_size += read_size as usize;
         }
@@ -112,10 +481,15 @@ impl io::Read for ClusterChain {
 
 impl io::Write for ClusterChain {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.root_region {
+            return Err(Error::UnsupportedFs.into());
+        }
         if self.guard.mode() != Some(LockMode::Write) {
-            return Err(io::Error::new(io::ErrorKind::Other, "file is opened for reading only"));
+            return Err(Error::NotOpenForWriting.into());
         }
+        self.check_free_space(buf.len() as u64)?;
         let mut total_write_size = 0;
+        let mut write_runs = true;
         loop {
             let buf_tail = &buf[total_write_size..];
 
@@ -125,21 +499,56 @@ impl io::Write for ClusterChain {
                 break;
             }
 
-            if self.current_cluster.is_none() {
-                let new_cluster = self.fat.alloc_for_chain(self.previous_cluster.unwrap())?;
-                self.current_cluster = Some(new_cluster);
+            // Whole clusters go to the device in runs. What is left over when
+            // a write fails is retried cluster by cluster below, replacing
+            // bad clusters.
+            let cluster_size = self.cluster_size_bytes as usize;
+            if write_runs && cluster_offset == 0 && buf_tail.len() >= 2 * cluster_size {
+                match self.write_clusters(buf_tail) {
+                    Ok(size) => {
+                        total_write_size += size;
+                        write_runs = size == buf_tail.len() / cluster_size * cluster_size;
+                        continue;
+                    }
+                    Err(_) if total_write_size > 0 => break,
+                    Err(e) => return Err(e),
+                }
             }
 
-            self.vfat.lock().write_cluster(self.current_cluster.unwrap(), cluster_offset as u32,
-                                                &buf_tail[..write_size as usize])?;
+            if self.current_cluster.is_none() {
+                match self.write_to_new_cluster(&buf_tail[..write_size as usize]) {
+                    // report what has been written; the next write fails
+                    Err(_) if total_write_size > 0 => break,
+                    r => r?,
+                }
+            } else {
+                let cluster = self.current_cluster.unwrap();
+                match self.data.write_cluster(cluster, cluster_offset as u32, &buf_tail[..write_size as usize]) {
+                    Err(_) if total_write_size > 0 => break,
+                    r => r?,
+                }
+                let sectors = self.data.cluster_sectors(cluster, cluster_offset as u32, write_size as usize);
+                self.mark_dirty(sectors);
+            }
             self.advance(write_size)?;
             total_write_size += write_size as usize;
         }
         Ok(total_write_size)
     }
 
+    /// Syncs the sectors written through the chain since the last flush in
+    /// ascending order. Devices that can't sync part of themselves sync
+    /// everything.
     fn flush(&mut self) -> io::Result<()> {
-        self.vfat.lock().device.sync()
+        let mut dirty = mem::replace(&mut self.dirty_sectors, Vec::new());
+        dirty.sort_by_key(|sectors| sectors.start);
+        for i in 0..dirty.len() {
+            if let Err(e) = self.data.sync_sectors(dirty[i].clone()) {
+                self.dirty_sectors.extend(dirty.drain(i..));
+                return Err(e);
+            }
+        }
+        Ok(())
     }
 }
 
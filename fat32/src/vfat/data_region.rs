@@ -0,0 +1,179 @@
+use std::io;
+use std::ops::Range;
+
+use arc_mutex::Arc;
+use error::Error;
+use traits::BlockDevice;
+use vfat::fat::SharedFat;
+use vfat::logical_block_device::SharedLogicalBlockDevice;
+use vfat::metrics::{Counters, Event};
+
+/// Returns the byte offset of `cluster` in a volume whose cluster 2 starts at
+/// `data_start_sector`. Clusters below 2 are placed at cluster 2.
+pub(crate) fn cluster_offset(data_start_sector: u64, sectors_per_cluster: u8, bytes_per_sector: u16, cluster: u32) -> u64 {
+    let cluster_sector = data_start_sector + (cluster as u64).saturating_sub(2) * sectors_per_cluster as u64;
+    cluster_sector * bytes_per_sector as u64
+}
+
+/// The clusters of a volume, read and written without locking the file
+/// system, only the device. Cluster chains keep a copy taken when they were
+/// opened.
+///
+/// Every access still locks the shared device, as `BlockDevice`
+/// implementations needn't be `Sync`. So I/O on one file doesn't wait for
+/// directory operations or mount-level state, but I/O on several files is
+/// serialized at the device just as before.
+#[derive(Clone)]
+pub(crate) struct DataRegion {
+    device: SharedLogicalBlockDevice,
+    fat: SharedFat,
+    counters: Arc<Counters>,
+    bytes_per_sector: u16,
+    sectors_per_cluster: u8,
+    data_start_sector: u64,
+    /// The size of the fixed root directory region of FAT12 and FAT16
+    /// volumes, which ends where the data region starts. 0 on FAT32.
+    root_dir_sectors: u64,
+    cluster_count: u32,
+    read_only: bool,
+}
+
+impl DataRegion {
+    pub(crate) fn new(device: SharedLogicalBlockDevice, fat: SharedFat, counters: Arc<Counters>,
+                      bytes_per_sector: u16, sectors_per_cluster: u8, data_start_sector: u64,
+                      root_dir_sectors: u64, cluster_count: u32, read_only: bool) -> DataRegion {
+        DataRegion {
+            device, fat, counters, bytes_per_sector, sectors_per_cluster, data_start_sector,
+            root_dir_sectors, cluster_count, read_only,
+        }
+    }
+
+    pub(crate) fn cluster_size_bytes(&self) -> u32 {
+        self.sectors_per_cluster as u32 * self.bytes_per_sector as u32
+    }
+
+    /// Returns the byte offset of `cluster` from the start of the device.
+    /// The cluster isn't checked to exist.
+    pub(crate) fn cluster_offset(&self, cluster: u32) -> u64 {
+        cluster_offset(self.data_start_sector, self.sectors_per_cluster, self.bytes_per_sector, cluster)
+    }
+
+    /// Returns the byte offset on the device of `buf_len` bytes at `offset`
+    /// of `cluster`. Fails with `InvalidInput` if they don't fit into the
+    /// cluster and with `Corrupted` if the cluster is beyond the data region.
+    fn get_full_offset(&self, cluster: u32, offset: u32, buf_len: usize) -> io::Result<u64> {
+        if cluster < 2 {
+            return Err(Error::InvalidInput { what: "clusters are numbered from 2" }.into());
+        }
+        if cluster as u64 >= self.cluster_count as u64 + 2 {
+            return Err(Error::Corrupted { what: "cluster is beyond the data region" }.into());
+        }
+        let end = (offset as u64).checked_add(buf_len as u64);
+        match end {
+            Some(end) if end <= self.cluster_size_bytes() as u64 => Ok(self.cluster_offset(cluster) + offset as u64),
+            _ => Err(Error::InvalidInput { what: "range exceeds the cluster" }.into()),
+        }
+    }
+
+    /// Returns the byte offset on the device of `buf_len` bytes starting at
+    /// the consecutive clusters from `first_cluster` on. Fails like
+    /// `get_full_offset`.
+    fn get_run_offset(&self, first_cluster: u32, buf_len: usize) -> io::Result<u64> {
+        if first_cluster < 2 {
+            return Err(Error::InvalidInput { what: "clusters are numbered from 2" }.into());
+        }
+        let cluster_size = self.cluster_size_bytes() as u64;
+        let clusters = (buf_len as u64 + cluster_size - 1) / cluster_size;
+        if first_cluster as u64 + clusters > self.cluster_count as u64 + 2 {
+            return Err(Error::Corrupted { what: "cluster is beyond the data region" }.into());
+        }
+        Ok(self.cluster_offset(first_cluster))
+    }
+
+    /// Returns the first sector and the number of sectors touched by `len`
+    /// bytes at byte `offset` of the volume.
+    fn sector_span(&self, offset: u64, len: usize) -> (u64, u64) {
+        let sector_size = self.bytes_per_sector as u64;
+        let first = offset / sector_size;
+        let end = (offset + len as u64 + sector_size - 1) / sector_size;
+        (first, end - first)
+    }
+
+    /// Returns the sectors of the volume touched by `len` bytes at `offset`
+    /// of `cluster`, or of the clusters following it if `offset + len`
+    /// exceeds the cluster size.
+    pub(crate) fn cluster_sectors(&self, cluster: u32, offset: u32, len: usize) -> Range<u64> {
+        let (first, count) = self.sector_span(self.cluster_offset(cluster) + offset as u64, len);
+        first..first + count
+    }
+
+    /// Reads `buf.len()` bytes at `offset` of `cluster`. Whole sectors are
+    /// read straight into `buf`.
+    pub(crate) fn read_cluster(&self, cluster: u32, offset: u32, buf: &mut [u8]) -> io::Result<()> {
+        let full_offset = self.get_full_offset(cluster, offset, buf.len())?;
+        let (sector, count) = self.sector_span(full_offset, buf.len());
+        self.counters.record(Event::SectorsRead { sector, count });
+        let sector_size = self.bytes_per_sector as u64;
+        if full_offset % sector_size == 0 && buf.len() as u64 % sector_size == 0 {
+            return self.device.read_sectors(sector, buf);
+        }
+        self.device.read_by_offset(full_offset, buf)
+    }
+
+    /// Returns the size of the fixed root directory region in bytes.
+    pub(crate) fn root_region_size_bytes(&self) -> u32 {
+        (self.root_dir_sectors * self.bytes_per_sector as u64) as u32
+    }
+
+    /// Reads `buf.len()` bytes at `offset` of the fixed root directory region.
+    pub(crate) fn read_root_region(&self, offset: u32, buf: &mut [u8]) -> io::Result<()> {
+        match (offset as u64).checked_add(buf.len() as u64) {
+            Some(end) if end <= self.root_region_size_bytes() as u64 => {}
+            _ => return Err(Error::InvalidInput { what: "range exceeds the root directory region" }.into()),
+        }
+        let region_start = (self.data_start_sector - self.root_dir_sectors) * self.bytes_per_sector as u64;
+        let full_offset = region_start + offset as u64;
+        let (sector, count) = self.sector_span(full_offset, buf.len());
+        self.counters.record(Event::SectorsRead { sector, count });
+        self.device.read_by_offset(full_offset, buf)
+    }
+
+    /// Reads the consecutive clusters starting at `first_cluster` into `buf`
+    /// with a single device read.
+    pub(crate) fn read_clusters(&self, first_cluster: u32, buf: &mut [u8]) -> io::Result<()> {
+        let full_offset = self.get_run_offset(first_cluster, buf.len())?;
+        let (sector, count) = self.sector_span(full_offset, buf.len());
+        self.counters.record(Event::SectorsRead { sector, count });
+        self.device.read_sectors(sector, buf)
+    }
+
+    /// Writes `buf` at `offset` of `cluster`, marking the volume dirty first.
+    pub(crate) fn write_cluster(&mut self, cluster: u32, offset: u32, buf: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly.into());
+        }
+        let full_offset = self.get_full_offset(cluster, offset, buf.len())?;
+        let (sector, count) = self.sector_span(full_offset, buf.len());
+        self.counters.record(Event::SectorsWritten { sector, count });
+        self.fat.mark_volume_dirty()?;
+        self.device.write_by_offset(full_offset, buf)
+    }
+
+    /// Writes `buf` to the consecutive clusters starting at `first_cluster`
+    /// with a single device write, marking the volume dirty first.
+    pub(crate) fn write_clusters(&mut self, first_cluster: u32, buf: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly.into());
+        }
+        let full_offset = self.get_run_offset(first_cluster, buf.len())?;
+        let (sector, count) = self.sector_span(full_offset, buf.len());
+        self.counters.record(Event::SectorsWritten { sector, count });
+        self.fat.mark_volume_dirty()?;
+        self.device.write_by_offset(full_offset, buf)
+    }
+
+    /// Flushes `sectors` of the device.
+    pub(crate) fn sync_sectors(&mut self, sectors: Range<u64>) -> io::Result<()> {
+        self.device.sync_range(sectors.start, sectors.end - sectors.start)
+    }
+}
@@ -0,0 +1,90 @@
+use std::io;
+use std::path::Path;
+
+use arc_mutex::ArcMutex;
+use error::Error;
+use traits::{Entry, FileSystem};
+use vfat::VFatFileSystem;
+use vfat::lock_manager::{LockMode, LockWait};
+
+/// Returns the number of runs of consecutive clusters in `clusters`.
+fn extent_count(clusters: &[u32]) -> u32 {
+    if clusters.is_empty() {
+        return 0;
+    }
+    1 + clusters.windows(2).filter(|pair| pair[1] != pair[0] + 1).count() as u32
+}
+
+impl ArcMutex<VFatFileSystem> {
+    /// Returns the number of extents, i.e. runs of consecutive clusters, per
+    /// cluster of the file or directory at `path`: `1 / clusters` if it is
+    /// contiguous, `1` if no two of its clusters are adjacent and `0` if it
+    /// has no clusters.
+    pub fn fragmentation_of<P: AsRef<Path>>(&self, path: P) -> io::Result<f32> {
+        let entry = self.get_entry(path)?;
+        let first_cluster = entry.metadata.first_cluster;
+        if first_cluster == 0 {
+            return Ok(0.0);
+        }
+        let clusters = self.lock().fat().chain_clusters(first_cluster)?;
+        Ok(extent_count(&clusters) as f32 / clusters.len() as f32)
+    }
+
+    /// Moves the file at `path` to a run of consecutive free clusters.
+    ///
+    /// Fails with `Busy` if the file is in use and with `NoContiguousSpace`
+    /// if no run of free clusters is large enough; the file is left as it
+    /// was then. The data is copied before the directory entry is pointed
+    /// at the new clusters, and the old ones are freed last, so a crash
+    /// leaves at most lost clusters behind.
+    pub fn defragment_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.lock().check_writable()?;
+        let mut entry = self.get_entry(path)?;
+        if entry.is_dir() {
+            return Err(Error::IsADirectory.into());
+        }
+        let old_first_cluster = entry.metadata.first_cluster;
+        if old_first_cluster == 0 {
+            return Ok(());
+        }
+        // The first cluster is the lock key of the file, so no other handle
+        // or entry may remain that still refers to it.
+        entry.ref_guard.take();
+        let lock_manager = self.lock().lock_manager();
+        let _lock = lock_manager.lock_with(entry.lock_key, LockMode::Delete, LockWait::NoWait)
+            .ok_or(Error::Busy)?;
+
+        let mut fat = self.lock().fat();
+        let old_clusters = fat.chain_clusters(old_first_cluster)?;
+        if extent_count(&old_clusters) == 1 {
+            return Ok(());
+        }
+        let count = old_clusters.len() as u32;
+        let new_first_cluster = fat.usage_bitmap()?.iter_free_runs()
+            .find(|&(_, length)| length >= count)
+            .map(|(first_cluster, _)| first_cluster)
+            .ok_or(Error::NoContiguousSpace)?;
+        fat.alloc_run(new_first_cluster, count)?;
+        if let Err(e) = self.copy_clusters(&old_clusters, new_first_cluster) {
+            let _ = fat.free_chain(new_first_cluster);
+            return Err(e);
+        }
+
+        self.write_barrier()?;
+        entry.dir.0.lock().set_first_cluster(entry.dir_entry_index_range.end, new_first_cluster)?;
+        self.write_barrier()?;
+        fat.free_chain(old_first_cluster)
+    }
+
+    /// Copies the contents of `clusters` to the run of clusters starting at
+    /// `first_cluster`.
+    fn copy_clusters(&self, clusters: &[u32], first_cluster: u32) -> io::Result<()> {
+        let mut buf = vec![0; self.lock().cluster_size_bytes() as usize];
+        for (i, &cluster) in clusters.iter().enumerate() {
+            let mut vfat = self.lock();
+            vfat.read_cluster(cluster, 0, &mut buf)?;
+            vfat.write_cluster(first_cluster + i as u32, 0, &buf)?;
+        }
+        Ok(())
+    }
+}
@@ -1,26 +1,42 @@
 use std::io;
 
-use vfat::{VFatFileSystem, VFatEntry};
-use std::mem;
+use vfat::{VFatFileSystem, VFatEntry, NameSource, FatKind};
+use byteorder::{LittleEndian, ByteOrder};
 use std::io::{Read, Write, Seek, SeekFrom};
 use fallible_iterator::FallibleIterator;
 use traits::{Dir, Date, Time, DateTime, Entry};
 use vfat::metadata::VFatMetadata;
 use vfat::metadata::Attributes;
 use vfat::cluster_chain::ClusterChain;
-use vfat::lock_manager::LockMode;
-use chrono::{Datelike, Timelike};
+use vfat::short_name::ShortName;
+use vfat::lock_manager::{LockKey, LockMode, LockWait, SharedLockManager};
 use std::ops::RangeInclusive;
+use std::ffi::OsStr;
 use arc_mutex::ArcMutex;
+use error::Error;
 
+/// Locks are taken in the order: the file system, a directory, the lock
+/// manager. A directory is never locked while waiting for an object lock,
+/// and the file system is never locked while holding a directory, so code
+/// holding a directory uses `lock_manager` instead of the file system's.
 pub struct VFatDir {
     pub(crate) vfat: ArcMutex<VFatFileSystem>,
     pub(crate) chain: ClusterChain,
+    lock_manager: SharedLockManager,
+    read_buffer: DirReadBuffer,
 
-    #[allow(unused)]
     entry: Option<VFatEntry>,
 }
 
+/// The most recently read cluster of a directory. Raw entries are served from
+/// this buffer so that scanning a directory reads each of its clusters once
+/// instead of seeking the cluster chain for every 32-byte entry.
+struct DirReadBuffer {
+    /// Index of the buffered cluster within the directory's cluster chain.
+    cluster_index: Option<u64>,
+    data: Vec<u8>,
+}
+
 #[derive(Clone)]
 pub struct SharedVFatDir(pub(crate) ArcMutex<VFatDir>);
 
@@ -28,11 +44,12 @@ pub struct SharedVFatDir(pub(crate) ArcMutex<VFatDir>);
 pub(crate) struct VFatSimpleDirEntry {
     name: String,
     short_name: String,
+    name_source: NameSource,
     metadata: VFatMetadata,
     entry_index_range: RangeInclusive<u64>,
+    lock_key: LockKey,
 }
 
-#[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct VFatRegularDirEntry {
     file_name: [u8; 8],
@@ -63,28 +80,83 @@ fn time_to_vfat_repr(time: &Time) -> u16 {
 
 impl VFatRegularDirEntry {
     fn from(name: &str, ext: &str, metadata: &VFatMetadata) -> Self {
-        let mut file_name = [0; 8];
+        let mut file_name = [b' '; 8];
         file_name[..name.len()].copy_from_slice(name.as_bytes());
-        let mut file_ext = [0; 3];
+        let mut file_ext = [b' '; 3];
         file_ext[..ext.len()].copy_from_slice(ext.as_bytes());
+        Self::from_raw_name(file_name, file_ext, metadata)
+    }
+
+    fn from_short_name(short_name: &ShortName, metadata: &VFatMetadata) -> Self {
+        Self::from_raw_name(*short_name.name_bytes(), *short_name.ext_bytes(), metadata)
+    }
+
+    fn from_raw_name(file_name: [u8; 8], file_ext: [u8; 3], metadata: &VFatMetadata) -> Self {
         Self {
             file_name,
             file_ext,
             attributes: metadata.attributes.0,
             _reserved: 0,
             created_time_hundredths: 0,
-            created_time: time_to_vfat_repr(&metadata.created.time()),
-            created_date: date_to_vfat_repr(&metadata.created.date()),
-            accessed_date: date_to_vfat_repr(&metadata.accessed),
+            created_time: metadata.created.map_or(0, |created| time_to_vfat_repr(&created.time())),
+            created_date: metadata.created.map_or(0, |created| date_to_vfat_repr(&created.date())),
+            accessed_date: metadata.accessed.map_or(0, |accessed| date_to_vfat_repr(&accessed)),
             cluster_high: (metadata.first_cluster >> 16) as u16,
             cluster_low: metadata.first_cluster as u16,
-            modified_time: time_to_vfat_repr(&metadata.modified.time()),
-            modified_date: date_to_vfat_repr(&metadata.modified.date()),
+            modified_time: metadata.modified.map_or(0, |modified| time_to_vfat_repr(&modified.time())),
+            modified_date: metadata.modified.map_or(0, |modified| date_to_vfat_repr(&modified.date())),
             size: metadata.size,
         }
     }
 
-    fn checksum(&self) -> u8 {
+    pub(crate) fn is_dot_entry(&self) -> bool {
+        match bytes_to_short_filename(&self.file_name) {
+            Ok(name) => name == "." || name == "..",
+            Err(_) => false,
+        }
+    }
+
+    pub(crate) fn attributes(&self) -> Attributes {
+        Attributes(self.attributes)
+    }
+
+    pub(crate) fn first_cluster(&self) -> u32 {
+        ((self.cluster_high as u32) << 16) | (self.cluster_low as u32)
+    }
+
+    pub(crate) fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Decodes the attributes, timestamps, first cluster and size.
+    fn metadata(&self) -> io::Result<VFatMetadata> {
+        Ok(VFatMetadata {
+            attributes: Attributes(self.attributes),
+            created: decode_date_time(self.created_date, self.created_time),
+            accessed: decode_date(self.accessed_date),
+            modified: decode_date_time(self.modified_date, self.modified_time),
+            first_cluster: self.first_cluster(),
+            size: self.size,
+        })
+    }
+
+    /// The stored base name and extension, without the dot.
+    pub(crate) fn raw_name(&self) -> (&[u8; 8], &[u8; 3]) {
+        (&self.file_name, &self.file_ext)
+    }
+
+    /// The short name in the form `NAME.EXT` or `NAME`.
+    pub(crate) fn short_name(&self) -> io::Result<String> {
+        let file_name = bytes_to_short_filename(&self.file_name)?;
+        let file_ext = bytes_to_short_filename(&self.file_ext)?;
+        if file_ext.len() > 0 {
+            Ok(format!("{}.{}", file_name, file_ext))
+        } else {
+            Ok(file_name.to_string())
+        }
+    }
+
+    pub(crate) fn checksum(&self) -> u8 {
         let mut sum = 0u8;
         for b in self.file_name.iter().chain(self.file_ext.iter()) {
             sum = (sum >> 1) + ((sum & 1) << 7);  /* rotate */
@@ -93,12 +165,53 @@ impl VFatRegularDirEntry {
         sum
     }
 
-    fn as_union(&self) -> &VFatDirEntry {
-        unsafe { mem::transmute(self) }
+    /// Parses the entry from the first `VFatDirEntry::SIZE` bytes of `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than `VFatDirEntry::SIZE`.
+    pub fn from_bytes(buf: &[u8]) -> VFatRegularDirEntry {
+        let mut file_name = [0; 8];
+        file_name.copy_from_slice(&buf[0..8]);
+        let mut file_ext = [0; 3];
+        file_ext.copy_from_slice(&buf[8..11]);
+        VFatRegularDirEntry {
+            file_name,
+            file_ext,
+            attributes: buf[11],
+            _reserved: buf[12],
+            created_time_hundredths: buf[13],
+            created_time: LittleEndian::read_u16(&buf[14..16]),
+            created_date: LittleEndian::read_u16(&buf[16..18]),
+            accessed_date: LittleEndian::read_u16(&buf[18..20]),
+            cluster_high: LittleEndian::read_u16(&buf[20..22]),
+            modified_time: LittleEndian::read_u16(&buf[22..24]),
+            modified_date: LittleEndian::read_u16(&buf[24..26]),
+            cluster_low: LittleEndian::read_u16(&buf[26..28]),
+            size: LittleEndian::read_u32(&buf[28..32]),
+        }
+    }
+
+    /// Returns the on-disk representation of the entry.
+    pub fn to_bytes(&self) -> [u8; VFatDirEntry::SIZE] {
+        let mut buf = [0; VFatDirEntry::SIZE];
+        buf[0..8].copy_from_slice(&self.file_name);
+        buf[8..11].copy_from_slice(&self.file_ext);
+        buf[11] = self.attributes;
+        buf[12] = self._reserved;
+        buf[13] = self.created_time_hundredths;
+        LittleEndian::write_u16(&mut buf[14..16], self.created_time);
+        LittleEndian::write_u16(&mut buf[16..18], self.created_date);
+        LittleEndian::write_u16(&mut buf[18..20], self.accessed_date);
+        LittleEndian::write_u16(&mut buf[20..22], self.cluster_high);
+        LittleEndian::write_u16(&mut buf[22..24], self.modified_time);
+        LittleEndian::write_u16(&mut buf[24..26], self.modified_date);
+        LittleEndian::write_u16(&mut buf[26..28], self.cluster_low);
+        LittleEndian::write_u32(&mut buf[28..32], self.size);
+        buf
     }
 }
 
-#[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct VFatLfnDirEntry {
     sequence_number: u8,
@@ -112,11 +225,67 @@ pub struct VFatLfnDirEntry {
 }
 
 impl VFatLfnDirEntry {
-    fn as_union(&self) -> &VFatDirEntry {
-        unsafe { mem::transmute(self) }
+    pub(crate) fn sequence_number(&self) -> u8 {
+        self.sequence_number
+    }
+
+    /// The checksum of the short name of the entry this LFN entry belongs to.
+    pub(crate) fn checksum(&self) -> u8 {
+        self.checksum
+    }
+
+    /// Parses the entry from the first `VFatDirEntry::SIZE` bytes of `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than `VFatDirEntry::SIZE`.
+    pub fn from_bytes(buf: &[u8]) -> VFatLfnDirEntry {
+        let mut entry = VFatLfnDirEntry {
+            sequence_number: buf[0],
+            name: [0; 5],
+            attributes: buf[11],
+            _always_zero: buf[12],
+            checksum: buf[13],
+            name2: [0; 6],
+            _always_zero2: [buf[26], buf[27]],
+            name3: [0; 2],
+        };
+        LittleEndian::read_u16_into(&buf[1..11], &mut entry.name);
+        LittleEndian::read_u16_into(&buf[14..26], &mut entry.name2);
+        LittleEndian::read_u16_into(&buf[28..32], &mut entry.name3);
+        entry
+    }
+
+    /// Returns the on-disk representation of the entry.
+    pub fn to_bytes(&self) -> [u8; VFatDirEntry::SIZE] {
+        let mut buf = [0; VFatDirEntry::SIZE];
+        buf[0] = self.sequence_number;
+        LittleEndian::write_u16_into(&self.name, &mut buf[1..11]);
+        buf[11] = self.attributes;
+        buf[12] = self._always_zero;
+        buf[13] = self.checksum;
+        LittleEndian::write_u16_into(&self.name2, &mut buf[14..26]);
+        buf[26..28].copy_from_slice(&self._always_zero2);
+        LittleEndian::write_u16_into(&self.name3, &mut buf[28..32]);
+        buf
     }
 }
 
+/// Assembles the long name stored in `entries`, which are in the order they
+/// appear in the directory. Returns `None` if it isn't valid UTF-16.
+pub(crate) fn long_name(entries: &[VFatLfnDirEntry]) -> Option<String> {
+    let mut filename_buf = Vec::new();
+    for entry in entries.iter().rev() {
+        filename_buf.extend_from_slice(&entry.name);
+        filename_buf.extend_from_slice(&entry.name2);
+        filename_buf.extend_from_slice(&entry.name3);
+    }
+    if let Some(index) = filename_buf.iter().position(|x| *x == 0x0000) {
+        filename_buf.resize(index, 0);
+    }
+    String::from_utf16(&filename_buf).ok()
+}
+
 fn create_lfn_entries(file_name: &str, checksum: u8) -> Vec<VFatLfnDirEntry> {
     assert!((file_name.len() < 255) && (file_name.len() > 0));
     let utf16_file_name: Vec<_> = file_name.encode_utf16().collect();
@@ -127,10 +296,16 @@ fn create_lfn_entries(file_name: &str, checksum: u8) -> Vec<VFatLfnDirEntry> {
             part[chunk.len()] = 0;
         }
 
-        let mut entry: VFatLfnDirEntry = unsafe { mem::zeroed() };
-        entry.attributes = 0x0f;
-        entry.checksum = checksum;
-        entry.sequence_number = index as u8 + 1;
+        let mut entry = VFatLfnDirEntry {
+            sequence_number: index as u8 + 1,
+            name: [0; 5],
+            attributes: 0x0f,
+            _always_zero: 0,
+            checksum,
+            name2: [0; 6],
+            _always_zero2: [0; 2],
+            name3: [0; 2],
+        };
         entry.name.copy_from_slice(&part[..5]);
         entry.name2.copy_from_slice(&part[5..11]);
         entry.name3.copy_from_slice(&part[11..]);
@@ -143,36 +318,56 @@ fn create_lfn_entries(file_name: &str, checksum: u8) -> Vec<VFatLfnDirEntry> {
     }).collect()
 }
 
-#[repr(C, packed)]
-#[derive(Copy, Clone, Debug)]
-pub struct VFatUnknownDirEntry {
-    first_byte: u8,
-    _unknown: [u8; 10],
-    attributes: u8,
-    _unknown2: [u8; 20],
-}
-
-pub union VFatDirEntry {
-    unknown: VFatUnknownDirEntry,
-    regular: VFatRegularDirEntry,
-    long_filename: VFatLfnDirEntry,
-}
+/// A raw directory entry of any kind. Whether it is a regular entry or an LFN
+/// entry is decided by its attributes byte.
+#[derive(Copy, Clone)]
+pub struct VFatDirEntry([u8; 32]);
 
 impl VFatDirEntry {
-    pub const SIZE: usize = ::std::mem::size_of::<Self>();
+    pub const SIZE: usize = 32;
 
     pub fn new_free() -> Self {
-        unsafe {
-            let mut entry: VFatDirEntry = mem::zeroed();
-            entry.unknown.first_byte = 0xe5;
-            entry
-        }
+        let mut buf = [0; VFatDirEntry::SIZE];
+        buf[0] = 0xe5;
+        VFatDirEntry(buf)
     }
 
     pub fn new_eof_mark() -> Self {
-        unsafe {
-            mem::zeroed()
-        }
+        VFatDirEntry([0; VFatDirEntry::SIZE])
+    }
+
+    /// Copies the entry from the first `SIZE` bytes of `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than `SIZE`.
+    pub fn from_bytes(buf: &[u8]) -> VFatDirEntry {
+        let mut bytes = [0; VFatDirEntry::SIZE];
+        bytes.copy_from_slice(&buf[..VFatDirEntry::SIZE]);
+        VFatDirEntry(bytes)
+    }
+
+    /// Returns the on-disk representation of the entry.
+    pub fn to_bytes(&self) -> [u8; VFatDirEntry::SIZE] {
+        self.0
+    }
+
+    fn first_byte(&self) -> u8 {
+        self.0[0]
+    }
+
+    fn attributes(&self) -> u8 {
+        self.0[11]
+    }
+
+    /// The entry interpreted as a regular entry.
+    pub fn regular(&self) -> VFatRegularDirEntry {
+        VFatRegularDirEntry::from_bytes(&self.0)
+    }
+
+    /// The entry interpreted as an LFN entry.
+    pub fn long_filename(&self) -> VFatLfnDirEntry {
+        VFatLfnDirEntry::from_bytes(&self.0)
     }
 
     pub fn is_regular(&self) -> bool {
@@ -180,56 +375,173 @@ impl VFatDirEntry {
     }
 
     pub fn is_lfn(&self) -> bool {
-        self.is_valid() && unsafe { self.unknown }.attributes == 0x0f
+        self.is_valid() && self.attributes() == 0x0f
     }
 
     pub fn is_valid(&self) -> bool {
-        unsafe { self.unknown }.first_byte != 0xe5
+        self.first_byte() != 0xe5
+    }
+
+    /// Returns `true` if this is the regular entry of a file or directory,
+    /// i.e. neither free, an LFN entry, a volume id nor a "." or ".." entry.
+    fn is_user_entry(&self) -> bool {
+        if !self.is_regular() {
+            return false;
+        }
+        let regular = self.regular();
+        !Attributes(regular.attributes).is_volume_id() && !regular.is_dot_entry()
     }
 }
 
+impl From<VFatRegularDirEntry> for VFatDirEntry {
+    fn from(entry: VFatRegularDirEntry) -> VFatDirEntry {
+        VFatDirEntry(entry.to_bytes())
+    }
+}
 
+impl From<VFatLfnDirEntry> for VFatDirEntry {
+    fn from(entry: VFatLfnDirEntry) -> VFatDirEntry {
+        VFatDirEntry(entry.to_bytes())
+    }
+}
 
 impl VFatDir {
     pub fn open(vfat: ArcMutex<VFatFileSystem>, first_cluster: u32, entry: Option<VFatEntry>) -> Option<SharedVFatDir> {
-        ClusterChain::open(vfat.clone(), first_cluster, LockMode::Write).map(|chain| {
+        VFatDir::open_with(vfat, first_cluster, entry, LockWait::NoWait)
+    }
+
+    /// Like `open`, but if the directory is open already, waits for it to be
+    /// closed as long as `wait` says.
+    pub(crate) fn open_with(vfat: ArcMutex<VFatFileSystem>, first_cluster: u32, entry: Option<VFatEntry>,
+                            wait: LockWait) -> Option<SharedVFatDir> {
+        let key = LockKey::Cluster(first_cluster);
+        // Cluster 0 stands for the root directory, which on FAT12 and FAT16
+        // is a fixed region before the data region.
+        let chain = if first_cluster == 0 && vfat.lock().fat_kind() != FatKind::Fat32 {
+            ClusterChain::open_root_region(vfat.clone(), key, LockMode::Write, wait)
+        } else {
+            ClusterChain::open_with(vfat.clone(), first_cluster, key, LockMode::Write, wait)
+        };
+        chain.map(|chain| {
             SharedVFatDir(ArcMutex::new(VFatDir {
                 chain,
                 vfat: vfat.clone(),
+                lock_manager: vfat.lock().lock_manager(),
+                read_buffer: DirReadBuffer {
+                    cluster_index: None,
+                    data: Vec::new(),
+                },
                 entry,
             }))
         })
     }
 
+    /// Drops the cluster kept from the last read, so that the next read goes
+    /// to the device, e.g. after the directory was written around it.
+    pub(crate) fn discard_read_buffer(&mut self) {
+        self.read_buffer.cluster_index = None;
+    }
+
+    /// Returns `true` unless the directory was opened through an entry other
+    /// than `entry`, e.g. one that was removed since.
+    pub(crate) fn is_opened_from(&self, entry: &VFatEntry) -> bool {
+        match self.entry {
+            Some(ref own) => ArcMutex::ptr_eq(&own.dir.0, &entry.dir.0) &&
+                own.dir_entry_index_range == entry.dir_entry_index_range,
+            None => true,
+        }
+    }
+
     pub fn set_file_size(&mut self, raw_entry_index: u64, size: u32) -> io::Result<()> {
-        let mut entry = self.get_raw_entry(raw_entry_index)?.ok_or_else(|| io::Error::from(io::ErrorKind::Other))?;
+        let entry = self.get_raw_entry(raw_entry_index)?.ok_or(Error::Corrupted { what: "directory entry is missing" })?;
+        if entry.is_regular() {
+            let mut regular = entry.regular();
+            regular.size = size;
+            self.set_raw_entry(raw_entry_index, &regular.into())
+        } else {
+            Err(Error::Corrupted { what: "directory entry is not a regular entry" }.into())
+        }
+    }
+
+    pub(crate) fn set_first_cluster(&mut self, raw_entry_index: u64, first_cluster: u32) -> io::Result<()> {
+        let entry = self.get_raw_entry(raw_entry_index)?.ok_or(Error::Corrupted { what: "directory entry is missing" })?;
+        if entry.is_regular() {
+            let mut regular = entry.regular();
+            regular.cluster_high = (first_cluster >> 16) as u16;
+            regular.cluster_low = first_cluster as u16;
+            self.set_raw_entry(raw_entry_index, &regular.into())
+        } else {
+            Err(Error::Corrupted { what: "directory entry is not a regular entry" }.into())
+        }
+    }
+
+    /// Points the ".." entry to the directory starting at
+    /// `parent_first_cluster`, which is 0 for the root directory, after this
+    /// directory was moved. A directory without a ".." entry is left as is.
+    pub(crate) fn set_parent_cluster(&mut self, parent_first_cluster: u32) -> io::Result<()> {
+        match self.get_raw_entry(1)? {
+            Some(ref entry) if entry.is_regular() && entry.regular().short_name()? == ".." => {
+                self.set_first_cluster(1, parent_first_cluster)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn set_times(&mut self, raw_entry_index: u64, accessed: Date, modified: DateTime) -> io::Result<()> {
+        let entry = self.get_raw_entry(raw_entry_index)?.ok_or(Error::Corrupted { what: "directory entry is missing" })?;
         if entry.is_regular() {
-            unsafe { entry.regular.size = size; }
-            self.set_raw_entry(raw_entry_index, &entry)
+            let mut regular = entry.regular();
+            regular.accessed_date = date_to_vfat_repr(&accessed);
+            regular.modified_time = time_to_vfat_repr(&modified.time());
+            regular.modified_date = date_to_vfat_repr(&modified.date());
+            self.set_raw_entry(raw_entry_index, &regular.into())
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, "invalid entry type"))
+            Err(Error::Corrupted { what: "directory entry is not a regular entry" }.into())
+        }
+    }
+
+    /// Reads the metadata of the regular entry at `raw_entry_index` along
+    /// with its lock key. Fails with `NotFound` if the slot no longer holds
+    /// a regular entry with the short name `short_name`.
+    pub(crate) fn reread_entry(&mut self, raw_entry_index: u64, short_name: &str) -> io::Result<(VFatMetadata, LockKey)> {
+        let regular = match self.get_raw_entry(raw_entry_index)? {
+            Some(ref entry) if entry.is_regular() => entry.regular(),
+            _ => return Err(Error::NotFound.into()),
+        };
+        if regular.short_name()? != short_name {
+            return Err(Error::NotFound.into());
         }
+        let metadata = regular.metadata()?;
+        let lock_key = LockKey::for_entry(metadata.first_cluster, self.chain.first_cluster, raw_entry_index);
+        Ok((metadata, lock_key))
     }
 
     pub fn get_file_size(&mut self, raw_entry_index: u64) -> io::Result<u32> {
-        let entry = self.get_raw_entry(raw_entry_index)?.ok_or_else(|| io::Error::from(io::ErrorKind::Other))?;
+        let entry = self.get_raw_entry(raw_entry_index)?.ok_or(Error::Corrupted { what: "directory entry is missing" })?;
         if entry.is_regular() {
-            let entry = unsafe { entry.regular };
-            Ok(entry.size)
+            Ok(entry.regular().size)
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, "invalid entry type"))
+            Err(Error::Corrupted { what: "directory entry is not a regular entry" }.into())
         }
     }
 
     pub(crate) fn get_raw_entry(&mut self, index: u64) -> io::Result<Option<VFatDirEntry>> {
-        self.chain.seek(SeekFrom::Start(index * VFatDirEntry::SIZE as u64))?;
-        if self.chain.at_end() {
-            return Ok(None);
+        let offset = index * VFatDirEntry::SIZE as u64;
+        let cluster_size = self.chain.cluster_size_bytes() as u64;
+        let cluster_index = offset / cluster_size;
+        if self.read_buffer.cluster_index != Some(cluster_index) {
+            self.read_buffer.cluster_index = None;
+            self.chain.seek(SeekFrom::Start(cluster_index * cluster_size))?;
+            if self.chain.at_end() {
+                return Ok(None);
+            }
+            self.read_buffer.data.resize(cluster_size as usize, 0);
+            self.chain.read_exact(&mut self.read_buffer.data)?;
+            self.read_buffer.cluster_index = Some(cluster_index);
         }
-        let mut buf = [0; VFatDirEntry::SIZE];
-        self.chain.read_exact(&mut buf)?;
-        let entry: VFatDirEntry = unsafe { mem::transmute(buf) };
-        if unsafe { entry.unknown }.first_byte != 0x00 {
+        let buffer_offset = (offset % cluster_size) as usize;
+        let entry = VFatDirEntry::from_bytes(&self.read_buffer.data[buffer_offset..]);
+        if entry.first_byte() != 0x00 {
             Ok(Some(entry))
         } else {
             Ok(None)
@@ -238,12 +550,16 @@ impl VFatDir {
 
     pub(crate) fn set_raw_entry(&mut self, index: u64, entry: &VFatDirEntry) -> io::Result<()> {
         self.chain.seek(SeekFrom::Start(index * VFatDirEntry::SIZE as u64))?;
-
-        assert_eq!(VFatDirEntry::SIZE, mem::size_of::<VFatDirEntry>());
-        let buf = unsafe {
-            ::std::slice::from_raw_parts(entry as *const VFatDirEntry as *const u8, VFatDirEntry::SIZE)
-        };
-        self.chain.write_all(buf)
+        let buf = entry.to_bytes();
+        self.chain.write_all(&buf)?;
+
+        let offset = index * VFatDirEntry::SIZE as u64;
+        let cluster_size = self.chain.cluster_size_bytes() as u64;
+        if self.read_buffer.cluster_index == Some(offset / cluster_size) {
+            let buffer_offset = (offset % cluster_size) as usize;
+            self.read_buffer.data[buffer_offset..buffer_offset + VFatDirEntry::SIZE].copy_from_slice(&buf);
+        }
+        Ok(())
     }
 
     pub fn remove_entry(&mut self, entry: &VFatEntry) -> io::Result<()> {
@@ -254,14 +570,41 @@ impl VFatDir {
     }
 
     pub(crate) fn create_entry(&mut self, file_name: &str, metadata: &VFatMetadata) -> io::Result<VFatSimpleDirEntry> {
+        self.create_entry_with(file_name, None, metadata)
+    }
+
+    /// Creates an entry named `file_name`. If `short_name` is `None`, a short
+    /// name alias is generated. If the long name is identical to the given
+    /// short name, no LFN entries are written.
+    pub(crate) fn create_entry_with(&mut self, file_name: &str, short_name: Option<&ShortName>,
+                                    metadata: &VFatMetadata) -> io::Result<VFatSimpleDirEntry> {
+        self.create_entry_replacing(file_name, short_name, metadata, None)
+    }
+
+    /// Like `create_entry_with`, but the entry whose regular entry is at raw
+    /// index `replaced` doesn't count as an existing entry with the same
+    /// name, so that it can be removed once the new entry is written.
+    pub(crate) fn create_entry_replacing(&mut self, file_name: &str, short_name: Option<&ShortName>,
+                                         metadata: &VFatMetadata, replaced: Option<u64>)
+                                         -> io::Result<VFatSimpleDirEntry> {
         if (file_name.len() >= 255) || (file_name.len() == 0) {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "incorrect file name length"));
+            return Err(Error::NameInvalid { reason: "name must have 1 to 254 characters" }.into());
         }
-        if self.has_entry_with_name(file_name)? {
-            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        if self.has_entry_with_name(file_name, replaced)? {
+            return Err(Error::AlreadyExists.into());
         }
-        let utf16_file_name: Vec<_> = file_name.encode_utf16().collect();
-        let total_entry_count = (utf16_file_name.len() + 12) / 13 + 1;
+        if let Some(short_name) = short_name {
+            if self.has_entry_with_short_name(&short_name.to_string(), replaced)? {
+                return Err(Error::AlreadyExists.into());
+            }
+        }
+        let needs_lfn = short_name.map(|s| s.to_string() != file_name).unwrap_or(true);
+        let total_entry_count = if needs_lfn {
+            let utf16_file_name: Vec<_> = file_name.encode_utf16().collect();
+            (utf16_file_name.len() + 12) / 13 + 1
+        } else {
+            1
+        };
 
         let mut free_count: u64 = 0;
         let mut index = 0;
@@ -284,26 +627,40 @@ impl VFatDir {
             }
             index += 1;
         }
-        let alloc_index = index - free_count + 1;
-        let short_file_name = format!("_~{}", alloc_index);
-        let regular_entry = VFatRegularDirEntry::from(&short_file_name, "", metadata);
-        let lfn_entries = create_lfn_entries(file_name, regular_entry.checksum());
+        let alloc_index = index + 1 - free_count;
+        let (regular_entry, short_file_name) = match short_name {
+            Some(short_name) => (VFatRegularDirEntry::from_short_name(short_name, metadata), short_name.to_string()),
+            None => {
+                let short_file_name = format!("_~{}", alloc_index);
+                (VFatRegularDirEntry::from(&short_file_name, "", metadata), short_file_name)
+            }
+        };
+        let lfn_entries = if needs_lfn {
+            create_lfn_entries(file_name, regular_entry.checksum())
+        } else {
+            Vec::new()
+        };
         assert_eq!(lfn_entries.len() + 1, total_entry_count);
 
-        for (i, entry) in lfn_entries.iter().enumerate() {
-            self.set_raw_entry(alloc_index + i as u64, entry.as_union())?;
-        }
+        // Write back to front so that the entry only becomes visible with the
+        // write of its first slot: a failed write leaves no half-made entry
+        // in front of the end of the directory.
         let regular_entry_index = alloc_index + lfn_entries.len() as u64;
-        self.set_raw_entry(regular_entry_index, regular_entry.as_union())?;
         if at_end {
             self.set_raw_entry(regular_entry_index + 1, &VFatDirEntry::new_eof_mark())?;
         }
+        self.set_raw_entry(regular_entry_index, &regular_entry.into())?;
+        for (i, entry) in lfn_entries.iter().enumerate().rev() {
+            self.set_raw_entry(alloc_index + i as u64, &(*entry).into())?;
+        }
 
         let entry = VFatSimpleDirEntry {
             name: file_name.to_string(),
             short_name: short_file_name,
+            name_source: if needs_lfn { NameSource::Long } else { NameSource::Short },
             metadata: metadata.clone(),
             entry_index_range: alloc_index..=regular_entry_index,
+            lock_key: LockKey::for_entry(metadata.first_cluster, self.chain.first_cluster, regular_entry_index),
         };
         Ok(entry)
     }
@@ -316,9 +673,9 @@ impl VFatDir {
 
         if let Some((raw_index, entry)) = raw_iterator.find(|&(_, ref entry)| entry.is_valid())? {
             let (long_name, regular_entry, regular_entry_index) = if entry.is_lfn() {
-                let lfn_entry = unsafe { entry.long_filename };
+                let lfn_entry = entry.long_filename();
                 if lfn_entry.sequence_number & 0x40 == 0 {
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid sequence number for the first LFN entry"));
+                    return Err(Error::Corrupted { what: "invalid sequence number for the first LFN entry" }.into());
                 }
                 let lfn_entries_count = lfn_entry.sequence_number & 0x1F;
 
@@ -326,65 +683,51 @@ impl VFatDir {
                 for i in 1..lfn_entries_count {
                     if let Some((_, entry)) = raw_iterator.next()? {
                         if entry.is_lfn() {
-                            let lfn_entry = unsafe { entry.long_filename };
+                            let lfn_entry = entry.long_filename();
                             let lfn_entry_index = lfn_entry.sequence_number & 0x1F;
                             if lfn_entry_index != (lfn_entries_count - i) {
-                                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid sequence number"));
+                                return Err(Error::Corrupted { what: "invalid LFN sequence number" }.into());
                             }
-                            entries.push(unsafe { entry.long_filename });
+                            entries.push(entry.long_filename());
                         } else {
-                            return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected LFN entry"));
+                            return Err(Error::Corrupted { what: "LFN run ends early" }.into());
                         }
                     } else {
                         return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
                     }
                 }
 
-                let mut filename_buf = Vec::new();
-                for entry in entries.iter().rev() {
-                    filename_buf.extend_from_slice(&entry.name);
-                    filename_buf.extend_from_slice(&entry.name2);
-                    filename_buf.extend_from_slice(&entry.name3);
-                }
-                if let Some(index) = filename_buf.iter().position(|x| *x == 0x0000) {
-                    filename_buf.resize(index, 0);
-                }
-                let long_name = String::from_utf16(&filename_buf).ok();
-
-                let (next_entry_index, next_entry) = raw_iterator.next()?.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "can't find regular entry after long entry"))?;
+                let (next_entry_index, next_entry) = raw_iterator.next()?.ok_or(Error::Corrupted { what: "can't find regular entry after long entry" })?;
                 if !next_entry.is_regular() {
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, "next entry is not regular"));
+                    return Err(Error::Corrupted { what: "LFN run isn't followed by a regular entry" }.into());
                 }
+                // A long name that doesn't belong to the short name is
+                // ignored, as other implementations do.
+                let checksum = next_entry.regular().checksum();
+                let long_name = if entries.iter().all(|entry| entry.checksum() == checksum) {
+                    long_name(&entries)
+                } else {
+                    None
+                };
                 (long_name, next_entry, next_entry_index)
             } else {
                 assert!(entry.is_regular());
                 (None, entry, raw_index)
             };
 
-            let regular_entry = unsafe { regular_entry.regular };
-            let short_file_name = {
-                let file_name = bytes_to_short_filename(&regular_entry.file_name)?;
-                let file_ext = bytes_to_short_filename(&regular_entry.file_ext)?;
-                if file_ext.len() > 0 {
-                    format!("{}.{}", file_name, file_ext)
-                } else {
-                    file_name.to_string()
-                }
-            };
+            let regular_entry = regular_entry.regular();
+            let short_file_name = regular_entry.short_name()?;
+            let name_source = if long_name.is_some() { NameSource::Long } else { NameSource::Short };
             let file_name = long_name.unwrap_or_else(|| short_file_name.clone());
-            let metadata = VFatMetadata {
-                attributes: Attributes(regular_entry.attributes),
-                created: DateTime::new(decode_date(regular_entry.created_date), decode_time(regular_entry.created_time)?),
-                accessed: decode_date(regular_entry.accessed_date),
-                modified: DateTime::new(decode_date(regular_entry.modified_date), decode_time(regular_entry.modified_time)?),
-                first_cluster: ((regular_entry.cluster_high as u32) << 16) | (regular_entry.cluster_low as u32),
-                size: regular_entry.size,
-            };
+            let metadata = regular_entry.metadata()?;
+            let lock_key = LockKey::for_entry(metadata.first_cluster, self.chain.first_cluster, regular_entry_index as u64);
             let entry = VFatSimpleDirEntry {
                 name: file_name,
                 short_name: short_file_name,
+                name_source,
                 metadata,
                 entry_index_range: (raw_index as u64)..=(regular_entry_index as u64),
+                lock_key,
             };
             Ok(Some(entry))
         } else {
@@ -392,48 +735,114 @@ impl VFatDir {
         }
     }
 
-    fn has_entry_with_name(&mut self, name: &str) -> io::Result<bool> {
+    /// Returns `true` if this directory contains no entries other than ".",
+    /// ".." and the volume id.
+    pub(crate) fn is_empty(&mut self) -> io::Result<bool> {
+        let mut iterator = RawDirIterator {
+            dir: self,
+            raw_index: 0,
+        };
+        while let Some((_, entry)) = iterator.next()? {
+            if entry.is_user_entry() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the number of entries in this directory, not counting ".",
+    /// ".." and the volume id.
+    pub(crate) fn entry_count(&mut self) -> io::Result<usize> {
+        let mut iterator = RawDirIterator {
+            dir: self,
+            raw_index: 0,
+        };
+        let mut count = 0;
+        while let Some((_, entry)) = iterator.next()? {
+            if entry.is_user_entry() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Returns the raw index of the first entry of the entry run that contains
+    /// the raw entry `index`. If `index` points into the middle of an LFN run
+    /// (or at the regular entry that terminates one), the index of the first
+    /// LFN entry of that run is returned.
+    fn entry_run_start(&mut self, index: u64) -> io::Result<u64> {
+        let mut start = index;
+        while start > 0 {
+            if let Some(entry) = self.get_raw_entry(start)? {
+                if entry.is_lfn() && entry.long_filename().sequence_number & 0x40 != 0 {
+                    break;
+                }
+            }
+            match self.get_raw_entry(start - 1)? {
+                Some(ref entry) if entry.is_lfn() => start -= 1,
+                _ => break,
+            }
+        }
+        Ok(start)
+    }
+
+    /// Finds the entry whose long name is `name` or whose short name matches
+    /// `name` case-insensitively, without constructing a `VFatEntry` for every
+    /// entry that precedes it.
+    pub(crate) fn find_simple_entry(&mut self, name: &str) -> io::Result<Option<VFatSimpleDirEntry>> {
+        let options = DirOptions::default();
+        let mut index = 0;
+        while let Some(simple_entry) = self.next_simple_entry(index)? {
+            index = simple_entry.entry_index_range.end + 1;
+            let matches = simple_entry.name == name || simple_entry.short_name.eq_ignore_ascii_case(name);
+            if options.accepts(&simple_entry) && matches {
+                return Ok(Some(simple_entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Skips the entry whose regular entry is at raw index `except`.
+    fn has_entry_with_short_name(&mut self, short_name: &str, except: Option<u64>) -> io::Result<bool> {
         let mut index = 0;
         while let Some(simple_entry) = self.next_simple_entry(index)? {
             index = simple_entry.entry_index_range.end + 1;
-            if &simple_entry.name == name {
+            if simple_entry.short_name == short_name && Some(simple_entry.entry_index_range.end) != except {
                 return Ok(true);
             }
         }
         Ok(false)
     }
 
-    pub(crate) fn init_empty(&mut self, time: DateTime) -> io::Result<()> {
-        if self.entry.is_some() {
-            let dot_metadata = VFatMetadata {
-                attributes: Attributes::new(true),
-                created: time,
-                accessed: time.date(),
-                modified: time,
-                first_cluster: self.chain.first_cluster,
-                size: 0,
-            };
-            let dot_entry = VFatRegularDirEntry::from(".", "", &dot_metadata);
-            self.set_raw_entry(0, &dot_entry.as_union())?;
-
-            let parent_dir = self.entry.as_ref().unwrap().parent();
-            let parent_first_cluster = parent_dir.0.lock().chain.first_cluster;
-            let dotdot_metadata = VFatMetadata {
-                first_cluster: parent_first_cluster,
-                ..dot_metadata
-            };
-            let dotdot_entry = VFatRegularDirEntry::from("..", "", &dotdot_metadata);
-            self.set_raw_entry(1, &dotdot_entry.as_union())?;
-
-            self.set_raw_entry(2, &VFatDirEntry::new_eof_mark())?;
-        } else {
-            self.set_raw_entry(0, &VFatDirEntry::new_eof_mark())?;
+    /// Skips the entry whose regular entry is at raw index `except`.
+    fn has_entry_with_name(&mut self, name: &str, except: Option<u64>) -> io::Result<bool> {
+        let mut index = 0;
+        while let Some(simple_entry) = self.next_simple_entry(index)? {
+            index = simple_entry.entry_index_range.end + 1;
+            if &simple_entry.name == name && Some(simple_entry.entry_index_range.end) != except {
+                return Ok(true);
+            }
         }
-
-        Ok(())
+        Ok(false)
     }
 }
 
+/// Returns the contents of the first cluster of a new directory with
+/// `metadata`, in the directory starting at `parent_first_cluster`: the "."
+/// and ".." entries, followed by zeroes, which end the directory. ".." of a
+/// directory in the root directory points to cluster 0.
+pub(crate) fn empty_dir_cluster(cluster_size: u32, metadata: &VFatMetadata, parent_first_cluster: u32) -> Vec<u8> {
+    // The dot entries have the attributes of the directory and, as for
+    // every directory, a size of 0.
+    let dot_metadata = VFatMetadata { size: 0, ..metadata.clone() };
+    let dotdot_metadata = VFatMetadata { first_cluster: parent_first_cluster, ..dot_metadata.clone() };
+    let mut buf = vec![0; cluster_size as usize];
+    buf[..VFatDirEntry::SIZE].copy_from_slice(&VFatRegularDirEntry::from(".", "", &dot_metadata).to_bytes());
+    buf[VFatDirEntry::SIZE..2 * VFatDirEntry::SIZE]
+        .copy_from_slice(&VFatRegularDirEntry::from("..", "", &dotdot_metadata).to_bytes());
+    buf
+}
+
 
 
 pub(crate) struct RawDirIterator<'a> {
@@ -453,9 +862,46 @@ impl<'a> FallibleIterator for RawDirIterator<'a> {
     }
 }
 
+/// Controls which entries are yielded by a `DirIterator`.
+///
+/// The default options yield hidden and system entries but skip the volume id
+/// entry and the "." and ".." entries.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DirOptions {
+    /// Skip entries with the hidden attribute set.
+    pub skip_hidden: bool,
+    /// Skip entries with the system attribute set.
+    pub skip_system: bool,
+    /// Yield the "." and ".." entries.
+    pub include_dot_entries: bool,
+    /// Yield the volume id (volume label) entry.
+    pub include_volume_id: bool,
+}
+
+impl DirOptions {
+    fn accepts(&self, entry: &VFatSimpleDirEntry) -> bool {
+        let attributes = entry.metadata.attributes;
+        if attributes.is_volume_id() {
+            return self.include_volume_id;
+        }
+        if entry.name == "." || entry.name == ".." {
+            return self.include_dot_entries;
+        }
+        if self.skip_hidden && attributes.is_hidden() {
+            return false;
+        }
+        if self.skip_system && attributes.is_system() {
+            return false;
+        }
+        true
+    }
+}
+
 pub struct DirIterator {
     index: u64,
     dir: SharedVFatDir,
+    lock_manager: SharedLockManager,
+    options: DirOptions,
 }
 
 fn bytes_to_short_filename(bytes: &[u8]) -> io::Result<&str> {
@@ -466,24 +912,42 @@ fn bytes_to_short_filename(bytes: &[u8]) -> io::Result<&str> {
     };
 
     if !data.iter().all(|c| c.is_ascii()) {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "filename contains non-ascii characters"));
+        return Err(Error::Corrupted { what: "short name contains non-ASCII characters" }.into());
     }
 
-    ::std::str::from_utf8(data).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "can't parse filename as UTF-8"))
+    ::std::str::from_utf8(data).map_err(|_| Error::Corrupted { what: "short name isn't valid UTF-8" }.into())
 }
 
-fn decode_date(raw_date: u16) -> Date {
+/// Decodes a FAT date: bits 15-9 are the year since 1980, 8-5 the month and
+/// 4-0 the day. Returns `None` for a month or day of 0, which some writers
+/// use for an unknown date, and for dates that don't exist.
+pub(crate) fn decode_date(raw_date: u16) -> Option<Date> {
     let year = (raw_date >> 9) + 1980;
     let month = (raw_date >> 5) & 0b1111;
-    let second = raw_date & 0b11111;
-    Date::from_ymd_opt(year as i32, month as u32, second as u32).unwrap_or_else(|| Date::from_ymd(1980, 1, 1))
+    let day = raw_date & 0b11111;
+    Date::from_ymd_opt(year as i32, month as u32, day as u32)
 }
 
-fn decode_time(raw_time: u16) -> io::Result<Time> {
+/// Decodes a FAT time: bits 15-11 are the hour, 10-5 the minute and 4-0 the
+/// second divided by 2. Returns `None` if a field is out of range.
+pub(crate) fn decode_time(raw_time: u16) -> Option<Time> {
     let hour = raw_time >> 11;
-    let minute = (raw_time >> 5) & 0b11_11_11;
+    let minute = (raw_time >> 5) & 0b111111;
     let second = 2 * (raw_time & 0b11111);
-    Time::from_hms_opt(hour as u32, minute as u32, second as u32).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid time"))
+    Time::from_hms_opt(hour as u32, minute as u32, second as u32)
+}
+
+fn decode_date_time(raw_date: u16, raw_time: u16) -> Option<DateTime> {
+    Some(DateTime::new(decode_date(raw_date)?, decode_time(raw_time)?))
+}
+
+impl DirIterator {
+    /// Returns the raw index of the next entry to be examined by this
+    /// iterator. The value can be passed to `SharedVFatDir::entries_from` to
+    /// resume the iteration later.
+    pub fn position(&self) -> u64 {
+        self.index
+    }
 }
 
 impl FallibleIterator for DirIterator {
@@ -491,16 +955,12 @@ impl FallibleIterator for DirIterator {
     type Error = io::Error;
 
     fn next(&mut self) -> io::Result<Option<VFatEntry>> {
-        let vfat = self.dir.0.lock().vfat.clone();
         while let Some(simple_entry) = self.dir.0.lock().next_simple_entry(self.index)? {
             self.index = simple_entry.entry_index_range.end + 1;
-            if simple_entry.metadata.attributes.is_volume_id() { // skip volume id
-                continue;
-            }
-            if simple_entry.name == "." || simple_entry.name == ".." {
+            if !self.options.accepts(&simple_entry) {
                 continue;
             }
-            let entry = self.dir.convert_entry(simple_entry, vfat);
+            let entry = self.dir.convert_entry(simple_entry, &self.lock_manager);
             return Ok(Some(entry));
         }
         Ok(None)
@@ -513,10 +973,30 @@ impl Dir for SharedVFatDir {
     type Iter = DirIterator;
 
     fn entries(&self) -> io::Result<DirIterator> {
-        Ok(DirIterator {
-            index: 0,
-            dir: self.clone(),
-        })
+        self.entries_with(DirOptions::default())
+    }
+
+    /// Finds the entry `name`. "." is the entry of this directory and ".."
+    /// that of its parent; these fail with `InvalidInput` if they would be
+    /// the root directory, which has no entry.
+    fn find<P: AsRef<OsStr>>(&self, name: P) -> io::Result<VFatEntry> {
+        let name = name.as_ref().to_str().ok_or(Error::NameInvalid { reason: "not valid UTF-8" })?;
+        let dot_entry = match name {
+            "." => Some(self.entry()),
+            ".." => Some(self.entry().and_then(|entry| entry.parent().entry())),
+            _ => None,
+        };
+        if let Some(entry) = dot_entry {
+            return entry.ok_or_else(|| Error::InvalidInput { what: "the root directory has no entry" }.into());
+        }
+        let (simple_entry, lock_manager) = {
+            let mut dir = self.0.lock();
+            (dir.find_simple_entry(name)?, dir.lock_manager.clone())
+        };
+        match simple_entry {
+            Some(simple_entry) => Ok(self.convert_entry(simple_entry, &lock_manager)),
+            None => Err(Error::NotFound.into()),
+        }
     }
 
     fn entry(&self) -> Option<VFatEntry> {
@@ -525,21 +1005,109 @@ impl Dir for SharedVFatDir {
 }
 
 impl SharedVFatDir {
-    fn convert_entry(&self, raw_entry: VFatSimpleDirEntry, vfat: ArcMutex<VFatFileSystem>) -> VFatEntry {
-        let ref_guard = vfat.lock().lock_manager().lock(raw_entry.metadata.first_cluster, LockMode::Ref);
+    /// Returns an iterator over the entries in this directory filtered
+    /// according to `options`.
+    pub fn entries_with(&self, options: DirOptions) -> io::Result<DirIterator> {
+        Ok(DirIterator {
+            index: 0,
+            dir: self.clone(),
+            lock_manager: self.lock_manager(),
+            options,
+        })
+    }
+
+    /// Collects the entries in this directory into a `Vec`.
+    pub fn collect_entries(&self) -> io::Result<Vec<VFatEntry>> {
+        self.entries()?.collect()
+    }
+
+    /// Returns the names of the entries in this directory.
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        self.entries()?.map(|entry| entry.name().to_string()).collect()
+    }
+
+    /// Returns `true` if this directory has no entries. Unlike
+    /// `entries()?.next()`, this scans the raw directory entries directly.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        self.0.lock().is_empty()
+    }
+
+    /// Returns the number of entries in this directory, not counting ".",
+    /// ".." and the volume id.
+    pub fn entry_count(&self) -> io::Result<usize> {
+        self.0.lock().entry_count()
+    }
+
+    /// Returns an iterator over the entries in this directory starting at the
+    /// raw entry index `raw_index`, usually obtained from
+    /// `DirIterator::position`.
+    ///
+    /// If `raw_index` points into the middle of a long file name entry run, it
+    /// is rounded down to the start of that run.
+    pub fn entries_from(&self, raw_index: u64) -> io::Result<DirIterator> {
+        let index = self.0.lock().entry_run_start(raw_index)?;
+        Ok(DirIterator {
+            index,
+            dir: self.clone(),
+            lock_manager: self.lock_manager(),
+            options: DirOptions::default(),
+        })
+    }
+
+    /// Returns the entry whose regular directory entry is the raw entry
+    /// `raw_index`, named by its short name. LFN entries in front of it are
+    /// ignored, so this works even if they are damaged.
+    pub(crate) fn entry_at(&self, raw_index: u64) -> io::Result<Option<VFatEntry>> {
+        let (simple_entry, lock_manager) = {
+            let mut dir = self.0.lock();
+            match dir.get_raw_entry(raw_index)? {
+                Some(ref entry) if entry.is_regular() => {}
+                _ => return Ok(None),
+            }
+            (dir.next_simple_entry(raw_index)?, dir.lock_manager.clone())
+        };
+        Ok(simple_entry.map(|simple_entry| self.convert_entry(simple_entry, &lock_manager)))
+    }
+
+    /// The lock manager of the file system, which can be used without
+    /// locking the file system.
+    pub(crate) fn lock_manager(&self) -> SharedLockManager {
+        self.0.lock().lock_manager.clone()
+    }
+
+    /// Must be called without holding the directory: taking the lock may
+    /// wait for a deletion, which needs the directory.
+    fn convert_entry(&self, raw_entry: VFatSimpleDirEntry, lock_manager: &SharedLockManager) -> VFatEntry {
+        let ref_guard = lock_manager.lock(raw_entry.lock_key, LockMode::Ref);
         VFatEntry {
             name: raw_entry.name,
+            short_name: raw_entry.short_name,
+            name_source: raw_entry.name_source,
             metadata: raw_entry.metadata,
             dir: self.clone(),
             dir_entry_index_range: raw_entry.entry_index_range,
+            lock_key: raw_entry.lock_key,
             ref_guard,
         }
     }
 
     pub fn create_entry(&self, file_name: &str, metadata: &VFatMetadata) -> io::Result<VFatEntry> {
-        let mut dir = self.0.lock();
-        let raw_entry = dir.create_entry(file_name, metadata)?;
+        let (raw_entry, lock_manager) = {
+            let mut dir = self.0.lock();
+            (dir.create_entry(file_name, metadata)?, dir.lock_manager.clone())
+        };
+        Ok(self.convert_entry(raw_entry, &lock_manager))
+    }
 
-        Ok(self.convert_entry(raw_entry, dir.vfat.clone()))
+    /// Creates an entry named `long_name` whose regular directory entry stores
+    /// exactly `short_name` instead of a generated alias. This is useful for
+    /// consumers that only read 8.3 names, such as boot firmware.
+    pub fn create_entry_with_short_name(&self, long_name: &str, short_name: &ShortName,
+                                        metadata: &VFatMetadata) -> io::Result<VFatEntry> {
+        let (raw_entry, lock_manager) = {
+            let mut dir = self.0.lock();
+            (dir.create_entry_with(long_name, Some(short_name), metadata)?, dir.lock_manager.clone())
+        };
+        Ok(self.convert_entry(raw_entry, &lock_manager))
     }
 }
@@ -1,67 +1,535 @@
 use std::fmt;
+use std::io;
+
+use byteorder::{LittleEndian, ByteOrder};
 
 use traits::BlockDevice;
-use vfat::Error;
+use vfat::{Error, FatKind, ForeignFs};
 
-#[repr(C, packed)]
+/// The FAT32 extended BIOS parameter block in the boot sector of a volume.
+/// Sector counts and locations are in logical sectors, counted from the
+/// boot sector.
 pub struct BiosParameterBlock {
     pub _data: [u8; 0xb],
     // DOS 2.0 BPB
     pub bytes_per_logical_sector: u16,
     pub logical_sectors_per_cluster: u8,
+    /// The sectors before the first FAT, including the boot sector.
     pub reserved_logical_sectors: u16,
     pub number_of_fats: u8,
+    /// Always 0 on FAT32, whose root directory is a cluster chain.
     pub root_directory_entries: u16,
+    /// The number of sectors if it fits in 16 bits, otherwise 0 and the
+    /// number is in `large_total_logical_sectors`. See `total_sectors`.
     pub total_logical_sectors: u16,
     pub media_descriptor: u8,
+    /// The size of each FAT on FAT12 and FAT16, 0 on FAT32. See
+    /// `fat_size_sectors`.
     pub _logical_sectors_per_fat_legacy: u16,
 
     // DOS 3.31 BPB
     pub physical_sectors_per_track: u16,
     pub number_of_heads: u16,
+    /// The sectors on the disk before the volume.
     pub hidden_sectors: u32,
     pub large_total_logical_sectors: u32,
 
     // DOS 7.1 EBPB
+    /// The size of each FAT.
     pub logical_sectors_per_fat: u32,
+    /// If bit 7 is set, only the FAT selected by bits 0-3 is in use;
+    /// otherwise all the FATs are kept identical.
     pub mirroring_flags: u16,
     pub version: u16,
     pub root_directory_cluster: u32,
+    /// The sector of the FSInfo sector, 0 or 0xFFFF if there is none.
     pub fs_information_sector_location: u16,
+    /// The sector of the backup boot sector, 0 or 0xFFFF if there is none.
     pub backup_sector_location: u16,
     pub _reserved: [u8; 12],
     pub physical_driver_number: u8,
     pub flags: u8,
     pub extended_boot_signature: u8,
     pub volume_serial_number: u32,
+    /// The volume label, padded with spaces.
     pub volume_label: [u8; 11],
+    /// Informational only, usually "FAT32   ".
     pub fs_type: [u8; 8],
     pub _data2: [u8; 420],
     pub signature: u16,
 }
 
 impl BiosParameterBlock {
-    /// Reads the FAT32 extended BIOS parameter block from sector `sector` of
-    /// device `device`.
+    /// The logical sector holding the backup copy of the boot sector.
+    pub const BACKUP_SECTOR: u64 = 6;
+
+    /// The size of the on-disk representation in bytes.
+    pub const SIZE: usize = 512;
+
+    /// The most clusters a FAT32 volume can use. Cluster numbers are 28 bits
+    /// wide and start at 2, and the values from 0x0FFFFFF0 on are reserved,
+    /// so the last cluster is 0x0FFFFFEF.
+    pub const MAX_CLUSTER_COUNT: u64 = 0x0FFFFFEF - 1;
+
+    /// Parses the EBPB from the first `SIZE` bytes of `buf` without checking
+    /// the signature.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than `SIZE`.
+    pub fn from_bytes(buf: &[u8]) -> BiosParameterBlock {
+        let mut data = [0; 0xb];
+        data.copy_from_slice(&buf[0x00..0x0b]);
+        let mut reserved = [0; 12];
+        reserved.copy_from_slice(&buf[0x34..0x40]);
+        let mut volume_label = [0; 11];
+        volume_label.copy_from_slice(&buf[0x47..0x52]);
+        let mut fs_type = [0; 8];
+        fs_type.copy_from_slice(&buf[0x52..0x5a]);
+        let mut data2 = [0; 420];
+        data2.copy_from_slice(&buf[0x5a..0x1fe]);
+        BiosParameterBlock {
+            _data: data,
+            bytes_per_logical_sector: LittleEndian::read_u16(&buf[0x0b..]),
+            logical_sectors_per_cluster: buf[0x0d],
+            reserved_logical_sectors: LittleEndian::read_u16(&buf[0x0e..]),
+            number_of_fats: buf[0x10],
+            root_directory_entries: LittleEndian::read_u16(&buf[0x11..]),
+            total_logical_sectors: LittleEndian::read_u16(&buf[0x13..]),
+            media_descriptor: buf[0x15],
+            _logical_sectors_per_fat_legacy: LittleEndian::read_u16(&buf[0x16..]),
+            physical_sectors_per_track: LittleEndian::read_u16(&buf[0x18..]),
+            number_of_heads: LittleEndian::read_u16(&buf[0x1a..]),
+            hidden_sectors: LittleEndian::read_u32(&buf[0x1c..]),
+            large_total_logical_sectors: LittleEndian::read_u32(&buf[0x20..]),
+            logical_sectors_per_fat: LittleEndian::read_u32(&buf[0x24..]),
+            mirroring_flags: LittleEndian::read_u16(&buf[0x28..]),
+            version: LittleEndian::read_u16(&buf[0x2a..]),
+            root_directory_cluster: LittleEndian::read_u32(&buf[0x2c..]),
+            fs_information_sector_location: LittleEndian::read_u16(&buf[0x30..]),
+            backup_sector_location: LittleEndian::read_u16(&buf[0x32..]),
+            _reserved: reserved,
+            physical_driver_number: buf[0x40],
+            flags: buf[0x41],
+            extended_boot_signature: buf[0x42],
+            volume_serial_number: LittleEndian::read_u32(&buf[0x43..]),
+            volume_label,
+            fs_type,
+            _data2: data2,
+            signature: LittleEndian::read_u16(&buf[0x1fe..]),
+        }
+    }
+
+    /// Returns the on-disk representation of the EBPB.
+    pub fn to_bytes(&self) -> [u8; BiosParameterBlock::SIZE] {
+        let mut buf = [0; BiosParameterBlock::SIZE];
+        buf[0x00..0x0b].copy_from_slice(&self._data);
+        LittleEndian::write_u16(&mut buf[0x0b..], self.bytes_per_logical_sector);
+        buf[0x0d] = self.logical_sectors_per_cluster;
+        LittleEndian::write_u16(&mut buf[0x0e..], self.reserved_logical_sectors);
+        buf[0x10] = self.number_of_fats;
+        LittleEndian::write_u16(&mut buf[0x11..], self.root_directory_entries);
+        LittleEndian::write_u16(&mut buf[0x13..], self.total_logical_sectors);
+        buf[0x15] = self.media_descriptor;
+        LittleEndian::write_u16(&mut buf[0x16..], self._logical_sectors_per_fat_legacy);
+        LittleEndian::write_u16(&mut buf[0x18..], self.physical_sectors_per_track);
+        LittleEndian::write_u16(&mut buf[0x1a..], self.number_of_heads);
+        LittleEndian::write_u32(&mut buf[0x1c..], self.hidden_sectors);
+        LittleEndian::write_u32(&mut buf[0x20..], self.large_total_logical_sectors);
+        LittleEndian::write_u32(&mut buf[0x24..], self.logical_sectors_per_fat);
+        LittleEndian::write_u16(&mut buf[0x28..], self.mirroring_flags);
+        LittleEndian::write_u16(&mut buf[0x2a..], self.version);
+        LittleEndian::write_u32(&mut buf[0x2c..], self.root_directory_cluster);
+        LittleEndian::write_u16(&mut buf[0x30..], self.fs_information_sector_location);
+        LittleEndian::write_u16(&mut buf[0x32..], self.backup_sector_location);
+        buf[0x34..0x40].copy_from_slice(&self._reserved);
+        buf[0x40] = self.physical_driver_number;
+        buf[0x41] = self.flags;
+        buf[0x42] = self.extended_boot_signature;
+        LittleEndian::write_u32(&mut buf[0x43..], self.volume_serial_number);
+        buf[0x47..0x52].copy_from_slice(&self.volume_label);
+        buf[0x52..0x5a].copy_from_slice(&self.fs_type);
+        buf[0x5a..0x1fe].copy_from_slice(&self._data2);
+        LittleEndian::write_u16(&mut buf[0x1fe..], self.signature);
+        buf
+    }
+
+    /// Reads the FAT32 extended BIOS parameter block from sector 0 of device
+    /// `device`.
     ///
     /// # Errors
     ///
     /// If the EBPB signature is invalid, returns an error of `BadSignature`.
+    /// If the boot sector is that of an exFAT or NTFS volume, returns
+    /// `UnsupportedFilesystem`.
     pub fn read_from<T: BlockDevice>(
         device: &T
     ) -> Result<BiosParameterBlock, Error> {
-        let mut buf = [0; 512];
-        device.read_sector(0, &mut buf).map_err(|e| Error::Io(e))?;
-        let bpb: BiosParameterBlock = unsafe { ::std::mem::transmute(buf) };
+        BiosParameterBlock::read_offset_from(device, 0)
+    }
+
+    /// Reads the EBPB of the volume on `device` the way it is mounted: from
+    /// sector 0, or if that doesn't hold a valid EBPB, from the backup.
+    /// Returns the EBPB and whether it is the backup.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error for sector 0 if there is no valid backup either, and
+    /// `InvalidGeometry` if the volume is larger than the device.
+    pub(crate) fn read_valid_from<T: BlockDevice>(
+        device: &T
+    ) -> Result<(BiosParameterBlock, bool), Error> {
+        let primary = BiosParameterBlock::read_from(device).and_then(|ebpb| {
+            ebpb.validate_any()?;
+            Ok(ebpb)
+        });
+        let (ebpb, is_backup) = match primary {
+            Ok(ebpb) => (ebpb, false),
+            // another file system has no FAT backup boot sector to fall back to
+            Err(e @ Error::UnsupportedFilesystem(_)) => return Err(e),
+            Err(e) => match BiosParameterBlock::read_backup_from(device) {
+                Ok(ebpb) => (ebpb, true),
+                Err(_) => return Err(e),
+            },
+        };
+        let device_size = device.num_sectors().saturating_mul(device.sector_size());
+        if ebpb.total_sectors().saturating_mul(ebpb.bytes_per_logical_sector as u64) > device_size {
+            return Err(Error::InvalidGeometry { field: "total_logical_sectors" });
+        }
+        Ok((ebpb, is_backup))
+    }
+
+    /// Reads the backup copy of the EBPB from device `device`. The backup
+    /// lives at logical sector `BACKUP_SECTOR`, so every supported logical
+    /// sector size is tried until a valid EBPB of that sector size is found.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error for the last location tried if none of them holds a
+    /// valid EBPB.
+    pub fn read_backup_from<T: BlockDevice>(
+        device: &T
+    ) -> Result<BiosParameterBlock, Error> {
+        let mut result = Err(Error::BadSignature);
+        for &logical_sector_size in &[512u64, 1024, 2048, 4096] {
+            let offset = BiosParameterBlock::BACKUP_SECTOR * logical_sector_size;
+            result = BiosParameterBlock::read_offset_from(device, offset).and_then(|bpb| {
+                bpb.validate()?;
+                if bpb.bytes_per_logical_sector as u64 != logical_sector_size {
+                    return Err(Error::InvalidGeometry { field: "bytes_per_logical_sector" });
+                }
+                Ok(bpb)
+            });
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Reads an EBPB from byte `offset` of `device`, which may have sectors
+    /// larger than the EBPB.
+    fn read_offset_from<T: BlockDevice>(
+        device: &T,
+        offset: u64
+    ) -> Result<BiosParameterBlock, Error> {
+        let mut buf = [0; BiosParameterBlock::SIZE];
+        device.read_by_offset(offset, &mut buf).map_err(|e| Error::Io(e))?;
+        let bpb = BiosParameterBlock::from_bytes(&buf);
         if bpb.signature != 0xAA55 {
             return Err(Error::BadSignature)
         }
+        if let Some(kind) = BiosParameterBlock::detect_foreign(&buf) {
+            return Err(Error::UnsupportedFilesystem(kind));
+        }
+        Ok(bpb)
+    }
+
+    /// Recognizes the boot sectors of exFAT and NTFS volumes, which carry the
+    /// same signature as FAT boot sectors, by their OEM names. exFAT also
+    /// zeroes the region where FAT keeps its BPB.
+    fn detect_foreign(buf: &[u8]) -> Option<ForeignFs> {
+        match &buf[0x03..0x0b] {
+            b"EXFAT   " if buf[0x0b..0x40].iter().all(|&byte| byte == 0) => Some(ForeignFs::ExFat),
+            b"NTFS    " => Some(ForeignFs::Ntfs),
+            _ => None,
+        }
+    }
+
+    /// Writes the EBPB, including the `0xAA55` signature, to the first 512
+    /// bytes of sector `sector` of device `device`.
+    pub fn write_to<T: BlockDevice>(&self, device: &mut T, sector: u64) -> io::Result<()> {
+        let mut buf = self.to_bytes();
+        buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+        device.write_by_offset(sector * device.sector_size(), &buf)
+    }
+
+    /// The number of sectors in the volume, from whichever of the two total
+    /// sector fields is in use.
+    pub fn total_sectors(&self) -> u64 {
+        if self.total_logical_sectors != 0 {
+            self.total_logical_sectors as u64
+        } else {
+            self.large_total_logical_sectors as u64
+        }
+    }
+
+    /// The size of each FAT, from the FAT12/FAT16 field if it is set and
+    /// from the FAT32 one otherwise.
+    pub fn fat_size_sectors(&self) -> u32 {
+        if self._logical_sectors_per_fat_legacy != 0 {
+            self._logical_sectors_per_fat_legacy as u32
+        } else {
+            self.logical_sectors_per_fat
+        }
+    }
+
+    /// The first sector of the fixed root directory region of FAT12 and
+    /// FAT16 volumes, right after the FATs.
+    pub fn root_dir_start_sector(&self) -> u64 {
+        self.reserved_logical_sectors as u64 +
+            self.number_of_fats as u64 * self.fat_size_sectors() as u64
+    }
+
+    /// The number of sectors of the fixed root directory region, 0 on FAT32.
+    pub fn root_dir_sectors(&self) -> u64 {
+        let bytes_per_sector = self.bytes_per_logical_sector as u64;
+        (self.root_directory_entries as u64 * 32 + bytes_per_sector - 1) / bytes_per_sector
+    }
+
+    /// The first sector past the reserved sectors, the FATs and the fixed
+    /// root directory region.
+    pub fn data_start_sector(&self) -> u64 {
+        self.root_dir_start_sector() + self.root_dir_sectors()
+    }
+
+    /// The number of whole clusters that fit in the data region.
+    pub fn data_cluster_count(&self) -> u64 {
+        self.total_sectors().saturating_sub(self.data_start_sector()) / self.logical_sectors_per_cluster as u64
+    }
+
+    /// The FAT type of the volume, which follows from the number of data
+    /// clusters alone.
+    pub fn fat_kind(&self) -> FatKind {
+        FatKind::from_cluster_count(self.data_cluster_count())
+    }
+
+    /// Checks that the EBPB describes a FAT32 volume with a sane geometry.
+    ///
+    /// # Errors
+    ///
+    /// If the volume is a FAT12 or FAT16 volume, returns `NotFat32`. If a
+    /// field holds an impossible value, returns `InvalidGeometry` naming it.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.validate_any()? {
+            FatKind::Fat32 => Ok(()),
+            FatKind::Fat12 | FatKind::Fat16 => Err(Error::NotFat32),
+        }
+    }
+
+    /// Checks that the EBPB describes a FAT12, FAT16 or FAT32 volume with a
+    /// sane geometry and returns its FAT type.
+    ///
+    /// # Errors
+    ///
+    /// If the volume has the FAT32 layout but too few clusters for FAT32,
+    /// returns `NotFat32`. If a field holds an impossible value, returns
+    /// `InvalidGeometry` naming it.
+    pub fn validate_any(&self) -> Result<FatKind, Error> {
+        let bytes_per_sector = self.bytes_per_logical_sector;
+        if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 512 || bytes_per_sector > 4096 {
+            return Err(Error::InvalidGeometry { field: "bytes_per_logical_sector" });
+        }
+        let sectors_per_cluster = self.logical_sectors_per_cluster;
+        if !sectors_per_cluster.is_power_of_two() || sectors_per_cluster > 128 {
+            return Err(Error::InvalidGeometry { field: "logical_sectors_per_cluster" });
+        }
+        if self.number_of_fats == 0 {
+            return Err(Error::InvalidGeometry { field: "number_of_fats" });
+        }
+        let fat32_layout = self._logical_sectors_per_fat_legacy == 0;
+        if fat32_layout && (self.root_directory_entries != 0 || self.logical_sectors_per_fat == 0) {
+            return Err(Error::NotFat32);
+        }
+        if !fat32_layout && self.root_directory_entries == 0 {
+            return Err(Error::InvalidGeometry { field: "root_directory_entries" });
+        }
+        if self.data_start_sector() >= self.total_sectors() {
+            return Err(Error::InvalidGeometry { field: "total_logical_sectors" });
+        }
+        let cluster_count = self.data_cluster_count();
+        let kind = self.fat_kind();
+        if fat32_layout != (kind == FatKind::Fat32) {
+            return Err(if fat32_layout {
+                Error::NotFat32
+            } else {
+                Error::InvalidGeometry { field: "total_logical_sectors" }
+            });
+        }
+        let root_directory_cluster = self.root_directory_cluster;
+        if fat32_layout && (root_directory_cluster < 2 || root_directory_cluster as u64 >= cluster_count + 2) {
+            return Err(Error::InvalidGeometry { field: "root_directory_cluster" });
+        }
+        Ok(kind)
+    }
+}
+
+/// Computes the EBPB of a new FAT32 volume from its size.
+///
+/// Unless set explicitly, the cluster size follows the Microsoft table for
+/// FAT32 volumes and the FAT size is computed the same way `mkfs.vfat` does,
+/// without aligning the data region to the cluster size.
+#[derive(Debug, Clone)]
+pub struct BiosParameterBlockBuilder {
+    total_sectors: u32,
+    bytes_per_sector: u16,
+    sectors_per_cluster: Option<u8>,
+    reserved_sectors: u16,
+    number_of_fats: u8,
+    volume_serial_number: u32,
+    volume_label: [u8; 11],
+}
+
+impl BiosParameterBlockBuilder {
+    /// Returns a builder for a volume of `total_sectors` sectors, which are
+    /// 512 bytes unless set otherwise.
+    pub fn new(total_sectors: u32) -> BiosParameterBlockBuilder {
+        BiosParameterBlockBuilder {
+            total_sectors,
+            bytes_per_sector: 512,
+            sectors_per_cluster: None,
+            reserved_sectors: 32,
+            number_of_fats: 2,
+            volume_serial_number: 0,
+            volume_label: *b"NO NAME    ",
+        }
+    }
+
+    pub fn bytes_per_sector(mut self, bytes_per_sector: u16) -> BiosParameterBlockBuilder {
+        self.bytes_per_sector = bytes_per_sector;
+        self
+    }
+
+    pub fn sectors_per_cluster(mut self, sectors_per_cluster: u8) -> BiosParameterBlockBuilder {
+        self.sectors_per_cluster = Some(sectors_per_cluster);
+        self
+    }
+
+    pub fn reserved_sectors(mut self, reserved_sectors: u16) -> BiosParameterBlockBuilder {
+        self.reserved_sectors = reserved_sectors;
+        self
+    }
+
+    pub fn number_of_fats(mut self, number_of_fats: u8) -> BiosParameterBlockBuilder {
+        self.number_of_fats = number_of_fats;
+        self
+    }
+
+    pub fn volume_serial_number(mut self, volume_serial_number: u32) -> BiosParameterBlockBuilder {
+        self.volume_serial_number = volume_serial_number;
+        self
+    }
+
+    /// Sets the volume label, truncated or padded with spaces to 11 bytes.
+    pub fn volume_label(mut self, volume_label: &str) -> BiosParameterBlockBuilder {
+        self.volume_label = *b"           ";
+        for (dst, &src) in self.volume_label.iter_mut().zip(volume_label.as_bytes()) {
+            *dst = src;
+        }
+        self
+    }
+
+    fn default_sectors_per_cluster(&self) -> u8 {
+        let size_in_512_byte_sectors = self.total_sectors as u64 * self.bytes_per_sector as u64 / 512;
+        let sectors_per_cluster_512: u64 = match size_in_512_byte_sectors {
+            0...532480 => 1,
+            532481...16777216 => 8,
+            16777217...33554432 => 16,
+            33554433...67108864 => 32,
+            _ => 64,
+        };
+        let sectors_per_cluster = (sectors_per_cluster_512 * 512).checked_div(self.bytes_per_sector as u64).unwrap_or(0);
+        ::std::cmp::max(1, sectors_per_cluster) as u8
+    }
+
+    fn fat_size(&self, sectors_per_cluster: u8) -> u32 {
+        let bytes_per_sector = self.bytes_per_sector as u64;
+        let number_of_fats = self.number_of_fats as u64;
+        let fat_data = (self.total_sectors as u64).saturating_sub(self.reserved_sectors as u64);
+        let clusters = (fat_data * bytes_per_sector + number_of_fats * 8) /
+            (sectors_per_cluster as u64 * bytes_per_sector + number_of_fats * 4);
+        (((clusters + 2) * 4 + bytes_per_sector - 1) / bytes_per_sector) as u32
+    }
+
+    /// Returns the EBPB for the volume.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `BiosParameterBlock::validate` if the resulting
+    /// volume isn't a valid FAT32 volume, e.g. because it is too small.
+    /// Returns `InvalidGeometry` if the reserved sectors don't cover the
+    /// FSInfo sector and the backup boot sector.
+    pub fn build(&self) -> Result<BiosParameterBlock, Error> {
+        if self.reserved_sectors as u64 <= BiosParameterBlock::BACKUP_SECTOR {
+            return Err(Error::InvalidGeometry { field: "reserved_logical_sectors" });
+        }
+        let sectors_per_cluster = self.sectors_per_cluster.unwrap_or_else(|| self.default_sectors_per_cluster());
+        let logical_sectors_per_fat = if sectors_per_cluster == 0 || self.bytes_per_sector == 0 {
+            0
+        } else {
+            self.fat_size(sectors_per_cluster)
+        };
+        let mut data = [0; 0xb];
+        data[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        data[3..].copy_from_slice(b"MSWIN4.1");
+        let bpb = BiosParameterBlock {
+            _data: data,
+            bytes_per_logical_sector: self.bytes_per_sector,
+            logical_sectors_per_cluster: sectors_per_cluster,
+            reserved_logical_sectors: self.reserved_sectors,
+            number_of_fats: self.number_of_fats,
+            root_directory_entries: 0,
+            total_logical_sectors: 0,
+            media_descriptor: 0xF8,
+            _logical_sectors_per_fat_legacy: 0,
+            physical_sectors_per_track: 32,
+            number_of_heads: 64,
+            hidden_sectors: 0,
+            large_total_logical_sectors: self.total_sectors,
+            logical_sectors_per_fat,
+            mirroring_flags: 0,
+            version: 0,
+            root_directory_cluster: 2,
+            fs_information_sector_location: 1,
+            backup_sector_location: BiosParameterBlock::BACKUP_SECTOR as u16,
+            _reserved: [0; 12],
+            physical_driver_number: 0x80,
+            flags: 0,
+            extended_boot_signature: 0x29,
+            volume_serial_number: self.volume_serial_number,
+            volume_label: self.volume_label,
+            fs_type: *b"FAT32   ",
+            _data2: [0; 420],
+            signature: 0xAA55,
+        };
+        bpb.validate()?;
         Ok(bpb)
     }
 }
 
 impl fmt::Debug for BiosParameterBlock {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "BiosParameterBlock {{ fs_type={:?} }}", self.fs_type)
+        f.debug_struct("BiosParameterBlock")
+            .field("bytes_per_logical_sector", &self.bytes_per_logical_sector)
+            .field("logical_sectors_per_cluster", &self.logical_sectors_per_cluster)
+            .field("reserved_logical_sectors", &self.reserved_logical_sectors)
+            .field("number_of_fats", &self.number_of_fats)
+            .field("total_sectors", &self.total_sectors())
+            .field("logical_sectors_per_fat", &self.logical_sectors_per_fat)
+            .field("root_directory_cluster", &self.root_directory_cluster)
+            .field("volume_serial_number", &format_args!("{:#010x}", self.volume_serial_number))
+            .field("volume_label", &String::from_utf8_lossy(&self.volume_label))
+            .field("fs_type", &String::from_utf8_lossy(&self.fs_type))
+            .finish()
     }
 }
@@ -1,26 +1,53 @@
 use traits::{Entry, Metadata};
 use vfat::metadata::VFatMetadata;
-use std::io;
+use std::io::{self, Write};
 use vfat::lock_manager::FSObjectGuard;
 use vfat::VFatFile;
-use vfat::lock_manager::LockMode;
+use vfat::lock_manager::{LockKey, LockMode};
 use vfat::VFatFileSystem;
 use traits::FileOpenMode;
 use vfat::dir::SharedVFatDir;
 use std::ops::RangeInclusive;
 use arc_mutex::ArcMutex;
+use error::Error;
+
+/// Where the name of an entry comes from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NameSource {
+    /// The long file name stored in LFN entries.
+    Long,
+    /// The 8.3 short name, as the entry has no long file name or it is
+    /// damaged.
+    Short,
+}
 
 pub struct VFatEntry {
     pub(crate) name: String,
+    pub(crate) short_name: String,
+    pub(crate) name_source: NameSource,
     pub(crate) metadata: VFatMetadata,
     pub(crate) dir: SharedVFatDir,
     pub(crate) dir_entry_index_range: RangeInclusive<u64>,
+    pub(crate) lock_key: LockKey,
 
     #[allow(unused)]
     pub(crate) ref_guard: FSObjectGuard,
 }
 
 impl VFatEntry {
+    /// The 8.3 short name stored in the regular directory entry, e.g.
+    /// `RPI3-D~1` for an entry named `rpi3-docs`. For entries without a long
+    /// file name this is the same as `name()`.
+    pub fn short_name(&self) -> &str {
+        &self.short_name
+    }
+
+    /// Whether `name()` is the long file name or was taken from the short
+    /// name, e.g. because the LFN entries don't match the short name.
+    pub fn name_source(&self) -> NameSource {
+        self.name_source
+    }
+
     pub(crate) fn vfat(&self) -> ArcMutex<VFatFileSystem> {
         self.dir.0.lock().vfat.clone()
     }
@@ -30,20 +57,83 @@ impl VFatEntry {
         self.dir.0.lock().set_file_size(self.dir_entry_index_range.end, size)
     }
 
+    /// The key of this entry once it points to the chain starting at
+    /// `first_cluster`, see `LockKey::for_entry`.
+    pub(crate) fn lock_key_for(&self, first_cluster: u32) -> LockKey {
+        let dir_cluster = self.dir.0.lock().chain.first_cluster;
+        LockKey::for_entry(first_cluster, dir_cluster, self.dir_entry_index_range.end)
+    }
+
+    /// The key of the slot holding the regular directory entry. Unlike
+    /// `lock_key` it stays the same when a file gets or loses its clusters.
+    #[cfg(feature = "fuse")]
+    pub(crate) fn slot_key(&self) -> LockKey {
+        self.lock_key_for(0)
+    }
+
+    /// Points the entry to the chain starting at `first_cluster` and moves
+    /// its reference lock to the matching key, like `refresh`. Files opened
+    /// through the entry have to move their own locks before.
+    pub(crate) fn set_first_cluster(&mut self, first_cluster: u32) -> io::Result<()> {
+        assert!(!self.metadata.is_dir());
+        self.dir.0.lock().set_first_cluster(self.dir_entry_index_range.end, first_cluster)?;
+        self.metadata.first_cluster = first_cluster;
+        let lock_key = self.lock_key_for(first_cluster);
+        if lock_key != self.lock_key {
+            let lock_manager = self.dir.lock_manager();
+            self.ref_guard = lock_manager.lock(lock_key, LockMode::Ref);
+            self.lock_key = lock_key;
+        }
+        Ok(())
+    }
+
+    /// Flushes the sectors of the parent directory written since its last
+    /// flush, such as the one holding this entry.
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.dir.0.lock().chain.flush()
+    }
+
     pub(crate) fn current_file_size(&self) -> io::Result<u32> {
         self.dir.0.lock().get_file_size(self.dir_entry_index_range.end)
     }
+
+    /// Re-reads the metadata from the directory entry, e.g. after the file
+    /// was changed through another handle.
+    ///
+    /// Fails with `NotFound` if the entry was removed or renamed since it
+    /// was listed; the entry is left as it was then.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        let (metadata, lock_key) = self.dir.0.lock().reread_entry(self.dir_entry_index_range.end, &self.short_name)?;
+        if lock_key != self.lock_key {
+            // A file that got its first cluster, or lost it, has a new key.
+            let lock_manager = self.dir.lock_manager();
+            self.ref_guard = lock_manager.lock(lock_key, LockMode::Ref);
+            self.lock_key = lock_key;
+        }
+        self.metadata = metadata;
+        Ok(())
+    }
+
+    /// Returns `true` if the size or the modification time in the directory
+    /// entry differ from the metadata of this entry. Fails like `refresh`.
+    pub fn has_changed(&self) -> io::Result<bool> {
+        let (metadata, _) = self.dir.0.lock().reread_entry(self.dir_entry_index_range.end, &self.short_name)?;
+        Ok(metadata.size != self.metadata.size || metadata.modified != self.metadata.modified)
+    }
 }
 
 impl Clone for VFatEntry {
     fn clone(&self) -> Self {
-        let vfat = self.vfat();
-        let ref_guard = vfat.lock().lock_manager().lock(self.metadata.first_cluster, LockMode::Ref);
+        let lock_manager = self.dir.lock_manager();
+        let ref_guard = lock_manager.lock(self.lock_key, LockMode::Ref);
         Self {
             name: self.name.clone(),
+            short_name: self.short_name.clone(),
+            name_source: self.name_source,
             metadata: self.metadata.clone(),
             dir: self.dir.clone(),
             dir_entry_index_range: self.dir_entry_index_range.clone(),
+            lock_key: self.lock_key,
             ref_guard,
         }
     }
@@ -78,15 +168,15 @@ impl Entry for VFatEntry {
         if !self.metadata.is_dir() {
             VFatFile::from_entry(self, mode)
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, "not a regular file"))
+            Err(Error::IsADirectory.into())
         }
     }
 
     fn open_dir(&self) -> io::Result<SharedVFatDir> {
         if self.metadata.is_dir() {
-            self.vfat().get_dir(self.metadata.first_cluster, Some(self.clone())).ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "open_dir failed"))
+            self.vfat().get_dir(self.metadata.first_cluster, Some(self.clone())).ok_or_else(|| Error::Busy.into())
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, "not a directory"))
+            Err(Error::NotADirectory.into())
         }
     }
 }
@@ -1,13 +1,76 @@
+use std::error;
+use std::fmt;
 use std::io;
 
+use arc_mutex::ArcMutex;
 use mbr;
+use vfat::VFatFileSystem;
+
+/// A file system other than FAT that a volume was found to hold.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ForeignFs {
+    ExFat,
+    Ntfs,
+}
+
+impl fmt::Display for ForeignFs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ForeignFs::ExFat => write!(f, "exFAT"),
+            ForeignFs::Ntfs => write!(f, "NTFS"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
     Mbr(mbr::Error),
     Io(io::Error),
     BadSignature,
-    NotFound
+    NotFound,
+    /// The volume is formatted as FAT12 or FAT16.
+    NotFat32,
+    /// The volume holds another file system, which can't be mounted.
+    UnsupportedFilesystem(ForeignFs),
+    /// The EBPB field `field` holds a value that can't describe a FAT32
+    /// volume.
+    InvalidGeometry { field: &'static str },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Mbr(ref e) => write!(f, "invalid MBR: {}", e),
+            Error::Io(ref e) => write!(f, "I/O error while mounting: {}", e),
+            Error::BadSignature => write!(f, "the boot sector signature is invalid"),
+            Error::NotFound => write!(f, "no FAT32 partition found"),
+            Error::NotFat32 => write!(f, "the volume is formatted as FAT12 or FAT16"),
+            Error::UnsupportedFilesystem(kind) => write!(f, "the volume is formatted as {}, not FAT", kind),
+            Error::InvalidGeometry { field } => write!(f, "the EBPB field {} holds an invalid value", field),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Mbr(_) => "invalid MBR",
+            Error::Io(_) => "I/O error while mounting",
+            Error::BadSignature => "invalid boot sector signature",
+            Error::NotFound => "no FAT32 partition found",
+            Error::NotFat32 => "not a FAT32 volume",
+            Error::UnsupportedFilesystem(_) => "not a FAT volume",
+            Error::InvalidGeometry { .. } => "invalid EBPB geometry",
+        }
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            Error::Mbr(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<mbr::Error> for Error {
@@ -21,3 +84,70 @@ impl From<io::Error> for Error {
         Error::Io(error)
     }
 }
+
+/// The error of `try_unmount`, which hands the file system back.
+pub struct UnmountError {
+    error: io::Error,
+    file_system: Option<ArcMutex<VFatFileSystem>>,
+}
+
+impl UnmountError {
+    pub(crate) fn new(error: io::Error, file_system: Option<ArcMutex<VFatFileSystem>>) -> UnmountError {
+        UnmountError { error, file_system }
+    }
+
+    /// Returns why the file system couldn't be unmounted.
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    /// Returns the file system, which is still mounted, or `None` if it
+    /// had already been taken apart when writing back the cache failed.
+    pub fn into_inner(self) -> Option<ArcMutex<VFatFileSystem>> {
+        self.file_system
+    }
+}
+
+impl fmt::Debug for UnmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("UnmountError").field(&self.error).finish()
+    }
+}
+
+impl fmt::Display for UnmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can't unmount: {}", self.error)
+    }
+}
+
+impl error::Error for UnmountError {
+    fn description(&self) -> &str {
+        "can't unmount"
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<UnmountError> for io::Error {
+    fn from(error: UnmountError) -> io::Error {
+        error.error
+    }
+}
+
+/// Returns `true` if `error` reports that the volume ran out of free clusters.
+pub fn is_storage_full(error: &io::Error) -> bool {
+    match ::error::Error::of(error) {
+        Some(&::error::Error::NoSpace) => true,
+        _ => false,
+    }
+}
+
+/// Returns `true` if `error` reports that an entry is in use.
+pub fn is_busy(error: &io::Error) -> bool {
+    match ::error::Error::of(error) {
+        Some(&::error::Error::Busy) | Some(&::error::Error::BusyEntry { .. }) => true,
+        _ => false,
+    }
+}
@@ -1,3 +1,4 @@
+use std::cmp::min;
 use std::fmt;
 use std::io;
 use traits::BlockDevice;
@@ -5,6 +6,53 @@ use vfat::logical_block_device::SharedLogicalBlockDevice;
 use vfat::BiosParameterBlock;
 use byteorder::{LittleEndian, ByteOrder};
 use arc_mutex::ArcMutex;
+use vfat::fs_info::FsInfo;
+use vfat::cluster_bitmap::ClusterBitmap;
+use vfat::MountOptions;
+use error::Error;
+use std::collections::{HashMap, HashSet};
+use arc_mutex::Arc;
+use vfat::metrics::{Counters, Event};
+
+/// Returns the number of entries in each FAT of the volume described by
+/// `params`, including the two reserved entries. Entries past the last
+/// possible cluster number are left out.
+pub(crate) fn fat_entry_count(params: &BiosParameterBlock) -> u32 {
+    let fat_size_bytes = params.fat_size_sectors() as u64 * params.bytes_per_logical_sector as u64;
+    let entry_bits = params.fat_kind().entry_bits() as u64;
+    min(fat_size_bytes * 8 / entry_bits, BiosParameterBlock::MAX_CLUSTER_COUNT + 2) as u32
+}
+
+/// The width of the FAT entries of a volume, which the FAT specification
+/// derives from the number of data clusters alone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatKind {
+    /// Returns the FAT type of a volume with `cluster_count` data clusters.
+    pub fn from_cluster_count(cluster_count: u64) -> FatKind {
+        if cluster_count < 4085 {
+            FatKind::Fat12
+        } else if cluster_count < 65525 {
+            FatKind::Fat16
+        } else {
+            FatKind::Fat32
+        }
+    }
+
+    /// The number of bits in each FAT entry.
+    pub fn entry_bits(&self) -> u32 {
+        match *self {
+            FatKind::Fat12 => 12,
+            FatKind::Fat16 => 16,
+            FatKind::Fat32 => 32,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Status {
@@ -22,7 +70,6 @@ pub enum Status {
     Eoc(u32)
 }
 
-#[repr(C, packed)]
 #[derive(Clone)]
 pub struct FatEntry(pub u32);
 
@@ -55,28 +102,56 @@ struct SingleFat {
     device: SharedLogicalBlockDevice,
     offset: u64,
     size: u32,
+    kind: FatKind,
+    counters: Arc<Counters>,
 }
 
 impl SingleFat {
     const FAT_ENTRY_SIZE: u64 = 4;
 
-    fn new(device: SharedLogicalBlockDevice, params: &BiosParameterBlock, index: u8) -> SingleFat {
-        let fat_size_bytes = params.logical_sectors_per_fat as u64 * params.bytes_per_logical_sector as u64;
-        let size = (fat_size_bytes / Self::FAT_ENTRY_SIZE) as u32;
+    fn new(device: SharedLogicalBlockDevice, params: &BiosParameterBlock, index: u8, counters: Arc<Counters>) -> SingleFat {
+        let fat_size_bytes = params.fat_size_sectors() as u64 * params.bytes_per_logical_sector as u64;
+        let size = fat_entry_count(params);
+        let kind = params.fat_kind();
         let first_fat_offset = params.reserved_logical_sectors as u64 * params.bytes_per_logical_sector as u64;
         let offset = first_fat_offset + index as u64 * fat_size_bytes;
         Self {
-            offset, size, device,
+            offset, size, kind, device, counters,
         }
     }
 
+    /// Reads the entry of `cluster`. FAT12 and FAT16 entries are widened so
+    /// that their reserved, bad and end of chain values decode to the same
+    /// `Status` as the FAT32 ones.
     fn get(&self, cluster: u32) -> io::Result<FatEntry> {
         if cluster >= self.size {
             return Err(io::Error::from(io::ErrorKind::InvalidInput));
         }
-        let mut buf = [0; 4];
-        self.device.read_by_offset(self.offset + cluster as u64 * Self::FAT_ENTRY_SIZE, &mut buf)?;
-        let entry = LittleEndian::read_u32(&buf);
+        let (offset, entry) = match self.kind {
+            FatKind::Fat32 => {
+                let offset = cluster as u64 * Self::FAT_ENTRY_SIZE;
+                let mut buf = [0; 4];
+                self.device.read_by_offset(self.offset + offset, &mut buf)?;
+                (offset, LittleEndian::read_u32(&buf))
+            }
+            FatKind::Fat16 => {
+                let offset = cluster as u64 * 2;
+                let mut buf = [0; 2];
+                self.device.read_by_offset(self.offset + offset, &mut buf)?;
+                let entry = LittleEndian::read_u16(&buf) as u32;
+                (offset, if entry >= 0xFFF0 { entry | 0x0FFF0000 } else { entry })
+            }
+            FatKind::Fat12 => {
+                // entries are packed in pairs into three bytes
+                let offset = cluster as u64 * 3 / 2;
+                let mut buf = [0; 2];
+                self.device.read_by_offset(self.offset + offset, &mut buf)?;
+                let pair = LittleEndian::read_u16(&buf) as u32;
+                let entry = if cluster % 2 == 0 { pair & 0xFFF } else { pair >> 4 };
+                (offset, if entry >= 0xFF0 { entry | 0x0FFFF000 } else { entry })
+            }
+        };
+        self.counters.record(Event::FatRead { offset, len: (self.kind.entry_bits() as usize + 7) / 8 });
         Ok(FatEntry(entry))
     }
 
@@ -84,61 +159,630 @@ impl SingleFat {
         if cluster >= self.size {
             return Err(io::Error::from(io::ErrorKind::InvalidInput));
         }
+        if self.kind != FatKind::Fat32 {
+            return Err(Error::UnsupportedFs.into());
+        }
         let mut buf = [0; 4];
         LittleEndian::write_u32(&mut buf, entry);
+        self.counters.record(Event::FatWritten { offset: cluster as u64 * Self::FAT_ENTRY_SIZE, len: 4 });
         self.device.write_by_offset(self.offset + cluster as u64 * Self::FAT_ENTRY_SIZE, &buf)
     }
 
     fn size(&self) -> u32 {
         self.size
     }
+
+    fn size_bytes(&self) -> u64 {
+        (self.size as u64 * self.kind.entry_bits() as u64 + 7) / 8
+    }
+
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.counters.record(Event::FatRead { offset, len: buf.len() });
+        self.device.read_by_offset(self.offset + offset, buf)
+    }
+
+    fn write_bytes(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.counters.record(Event::FatWritten { offset, len: buf.len() });
+        self.device.write_by_offset(self.offset + offset, buf)
+    }
+}
+
+/// How much of the FAT is kept in memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FatCacheMode {
+    /// Every FAT access goes to the device.
+    Disabled,
+    /// FAT sectors are cached as they are accessed.
+    Lazy,
+    /// The whole FAT is read into memory at mount.
+    Preload,
+}
+
+impl Default for FatCacheMode {
+    fn default() -> FatCacheMode {
+        FatCacheMode::Disabled
+    }
+}
+
+/// Sectors of the active FAT held in memory. Modified sectors are written
+/// back to the device by `Fat::flush_cache`.
+struct FatCache {
+    sectors: HashMap<u64, Vec<u8>>,
+    dirty: HashSet<u64>,
 }
 
 pub struct Fat {
     fats: Vec<SingleFat>,
+    kind: FatKind,
+    cluster_count: u32,
+    data_start_sector: u64,
+    sectors_per_cluster: u64,
+    discard: bool,
+    cache: Option<FatCache>,
+    active_fat: usize,
+    mirroring: bool,
+    device: SharedLogicalBlockDevice,
+    next_free: u32,
+    fs_info: FsInfo,
+    fs_info_offset: Option<u64>,
+    fs_info_dirty: bool,
+    volume_dirty: bool,
+    mounted_dirty: bool,
+    counters: Arc<Counters>,
 }
 
 impl Fat {
-    fn get(&self, cluster: u32) -> io::Result<FatEntry> {
-        self.fats[0].get(cluster)
+    /// Set in FAT[1] while the volume is cleanly unmounted.
+    const CLEAN_SHUTDOWN_BIT: u32 = 0x08000000;
+
+    /// Returns the cached copy of sector `sector` of the active FAT, reading
+    /// it from the device if necessary.
+    fn cached_sector(&mut self, sector: u64) -> io::Result<&mut Vec<u8>> {
+        let sector_size = self.device.sector_size();
+        let active = &self.fats[self.active_fat];
+        let cache = self.cache.as_mut().expect("FAT cache is disabled");
+        if !cache.sectors.contains_key(&sector) {
+            let offset = sector * sector_size;
+            let mut buf = vec![0; min(sector_size, active.size_bytes() - offset) as usize];
+            active.read_bytes(offset, &mut buf)?;
+            cache.sectors.insert(sector, buf);
+        }
+        Ok(cache.sectors.get_mut(&sector).unwrap())
+    }
+
+    fn preload_cache(&mut self) -> io::Result<()> {
+        let sector_size = self.device.sector_size();
+        let sectors = (self.fats[self.active_fat].size_bytes() + sector_size - 1) / sector_size;
+        for sector in 0..sectors {
+            self.cached_sector(sector)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the modified cached FAT sectors back to the device. A sector
+    /// stays modified until it was written to every FAT it goes to, so that
+    /// a flush that failed can be retried.
+    pub fn flush_cache(&mut self) -> io::Result<()> {
+        let sector_size = self.device.sector_size();
+        let cache = match self.cache {
+            Some(ref mut cache) => cache,
+            None => return Ok(()),
+        };
+        let mut dirty: Vec<u64> = cache.dirty.iter().cloned().collect();
+        dirty.sort();
+        for sector in dirty {
+            let data = &cache.sectors[&sector];
+            for (index, fat) in self.fats.iter_mut().enumerate() {
+                if self.mirroring || index == self.active_fat {
+                    fat.write_bytes(sector * sector_size, data)?;
+                }
+            }
+            cache.dirty.remove(&sector);
+        }
+        Ok(())
+    }
+
+    fn check_cluster(&self, cluster: u32) -> io::Result<()> {
+        // FAT[0] and FAT[1] hold the media descriptor and the volume flags,
+        // so a chain referring to clusters 0 or 1 must not reach them.
+        if cluster < 2 {
+            return Err(Error::Corrupted { what: "cluster is before the data region" }.into());
+        }
+        if cluster >= self.end() {
+            return Err(Error::Corrupted { what: "cluster is beyond the data region" }.into());
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, cluster: u32) -> io::Result<FatEntry> {
+        self.check_cluster(cluster)?;
+        self.get_entry(cluster)
+    }
+
+    /// Returns the FAT entry `n` without checking that it belongs to a data
+    /// cluster.
+    fn get_entry(&mut self, n: u32) -> io::Result<FatEntry> {
+        if self.cache.is_none() {
+            return self.fats[self.active_fat].get(n);
+        }
+        let offset = n as u64 * SingleFat::FAT_ENTRY_SIZE;
+        let sector_size = self.device.sector_size();
+        let data = self.cached_sector(offset / sector_size)?;
+        let index = (offset % sector_size) as usize;
+        Ok(FatEntry(LittleEndian::read_u32(&data[index..index + 4])))
     }
 
     fn set(&mut self, cluster: u32, entry: u32) -> io::Result<()> {
+        self.check_cluster(cluster)?;
+        if !self.volume_dirty {
+            self.set_volume_dirty(true)?;
+        }
+        if self.cache.is_some() {
+            let offset = cluster as u64 * SingleFat::FAT_ENTRY_SIZE;
+            let sector_size = self.device.sector_size();
+            {
+                let data = self.cached_sector(offset / sector_size)?;
+                let index = (offset % sector_size) as usize;
+                LittleEndian::write_u32(&mut data[index..index + 4], entry);
+            }
+            self.cache.as_mut().unwrap().dirty.insert(offset / sector_size);
+            return Ok(());
+        }
+        self.write_through(cluster, entry)
+    }
+
+    /// Writes `entry` to the device immediately, bypassing the cache but
+    /// keeping a cached copy of the sector up to date.
+    fn write_through(&mut self, cluster: u32, entry: u32) -> io::Result<()> {
+        if let Some(ref mut cache) = self.cache {
+            let offset = cluster as u64 * SingleFat::FAT_ENTRY_SIZE;
+            let sector_size = self.device.sector_size();
+            if let Some(data) = cache.sectors.get_mut(&(offset / sector_size)) {
+                let index = (offset % sector_size) as usize;
+                LittleEndian::write_u32(&mut data[index..index + 4], entry);
+            }
+        }
+        if !self.mirroring {
+            return self.fats[self.active_fat].set(cluster, entry);
+        }
         for fat in &mut self.fats {
             fat.set(cluster, entry)?;
         }
         Ok(())
     }
 
+    /// Clears (`dirty == true`) or sets the clean shutdown bit in FAT[1].
+    fn set_volume_dirty(&mut self, dirty: bool) -> io::Result<()> {
+        let value = self.get_entry(1)?.0;
+        let new_value = if dirty {
+            value & !Self::CLEAN_SHUTDOWN_BIT
+        } else {
+            value | Self::CLEAN_SHUTDOWN_BIT
+        };
+        if new_value != value {
+            self.write_through(1, new_value)?;
+        }
+        self.volume_dirty = dirty;
+        Ok(())
+    }
+
     fn size(&self) -> u32 {
-        self.fats[0].size()
+        self.fats[self.active_fat].size()
+    }
+
+    /// One past the last valid cluster number. FAT entries from here on are
+    /// slack that doesn't correspond to any cluster.
+    fn end(&self) -> u32 {
+        self.cluster_count + 2
+    }
+
+    fn set_next_free(&mut self, cluster: u32) {
+        self.next_free = if cluster >= 2 && cluster < self.end() { cluster } else { 2 };
+        self.fs_info.next_free = self.next_free;
+        self.fs_info_dirty = true;
     }
 
     fn alloc(&mut self, value: u32) -> io::Result<u32> {
-        for i in 2..self.size() {
+        let start = self.next_free;
+        for i in (start..self.end()).chain(2..start) {
             if self.get(i)?.status() == Status::Free {
                 self.set(i, value)?;
+                self.set_next_free(i + 1);
+                if self.fs_info.free_count != FsInfo::UNKNOWN {
+                    self.fs_info.free_count = self.fs_info.free_count.saturating_sub(1);
+                }
+                self.fs_info_dirty = true;
+                self.counters.record(Event::ClustersAllocated { first_cluster: i, count: 1 });
                 return Ok(i);
             }
         }
-        Err(io::Error::new(io::ErrorKind::Other, "no free clusters"))
+        Err(Error::NoSpace.into())
+    }
+
+    /// Finds the first run of `count` free clusters starting at the next free
+    /// cursor, links them into a chain and returns its first cluster. Returns
+    /// `None` if there is no such run.
+    fn alloc_contiguous(&mut self, count: u32) -> io::Result<Option<u32>> {
+        if count == 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let size = self.end();
+        let start = self.next_free;
+        let mut run_start = None;
+        let mut run_length = 0;
+        for i in (start..size).chain(2..start) {
+            if i == 2 {
+                // runs can't wrap around the end of the FAT
+                run_length = 0;
+            }
+            if self.get(i)?.status() == Status::Free {
+                if run_length == 0 {
+                    run_start = Some(i);
+                }
+                run_length += 1;
+                if run_length == count {
+                    break;
+                }
+            } else {
+                run_length = 0;
+            }
+        }
+        if run_length < count {
+            return Ok(None);
+        }
+        let first_cluster = run_start.unwrap();
+        self.link_run(first_cluster, count)?;
+        Ok(Some(first_cluster))
+    }
+
+    /// Allocates a chain of `count` clusters, consecutive if possible, and
+    /// returns its first cluster. If that fails, nothing stays allocated.
+    fn alloc_chain(&mut self, count: u32) -> io::Result<u32> {
+        if let Some(first_cluster) = self.alloc_contiguous(count)? {
+            return Ok(first_cluster);
+        }
+        let first_cluster = self.alloc(0xFFFFFFF)?;
+        let mut last_cluster = first_cluster;
+        for _ in 1..count {
+            let linked = self.alloc(0xFFFFFFF).and_then(|new_last_cluster| {
+                match self.set(last_cluster, new_last_cluster) {
+                    Ok(()) => Ok(new_last_cluster),
+                    Err(e) => {
+                        let _ = self.free_chain(new_last_cluster);
+                        Err(e)
+                    }
+                }
+            });
+            match linked {
+                Ok(new_last_cluster) => last_cluster = new_last_cluster,
+                Err(e) => {
+                    // Nothing refers to the clusters allocated so far.
+                    let _ = self.free_chain(first_cluster);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(first_cluster)
+    }
+
+    /// Links the free clusters `first_cluster..first_cluster + count` into a
+    /// chain. Fails with `NoSpace` if any of them is in use.
+    fn alloc_run(&mut self, first_cluster: u32, count: u32) -> io::Result<()> {
+        if count == 0 || first_cluster < 2 || first_cluster as u64 + count as u64 > self.end() as u64 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        for cluster in first_cluster..first_cluster + count {
+            if self.get(cluster)?.status() != Status::Free {
+                return Err(Error::NoSpace.into());
+            }
+        }
+        self.link_run(first_cluster, count)
+    }
+
+    fn link_run(&mut self, first_cluster: u32, count: u32) -> io::Result<()> {
+        let last_cluster = first_cluster + count - 1;
+        for cluster in first_cluster..last_cluster {
+            self.set(cluster, cluster + 1)?;
+        }
+        self.set(last_cluster, 0xFFFFFFF)?;
+        self.set_next_free(last_cluster + 1);
+        if self.fs_info.free_count != FsInfo::UNKNOWN {
+            self.fs_info.free_count = self.fs_info.free_count.saturating_sub(count);
+        }
+        self.fs_info_dirty = true;
+        self.counters.record(Event::ClustersAllocated { first_cluster, count });
+        Ok(())
+    }
+
+    fn mark_bad(&mut self, cluster: u32) -> io::Result<()> {
+        if cluster < 2 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let status = self.get(cluster)?.status();
+        self.set(cluster, 0xFFFFFF7)?;
+        if status == Status::Free && self.fs_info.free_count != FsInfo::UNKNOWN {
+            self.fs_info.free_count = self.fs_info.free_count.saturating_sub(1);
+            self.fs_info_dirty = true;
+        }
+        Ok(())
+    }
+
+    fn bad_cluster_count(&mut self) -> io::Result<u32> {
+        let mut count = 0;
+        for cluster in 2..self.end() {
+            if self.get(cluster)?.status() == Status::Bad {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn release(&mut self, cluster: u32) -> io::Result<()> {
+        self.set(cluster, 0)?;
+        if cluster < self.next_free {
+            self.set_next_free(cluster);
+        }
+        if self.fs_info.free_count != FsInfo::UNKNOWN {
+            self.fs_info.free_count += 1;
+        }
+        self.fs_info_dirty = true;
+        self.counters.record(Event::ClustersFreed { first_cluster: cluster, count: 1 });
+        Ok(())
+    }
+
+    /// Builds a bitmap of the allocated data clusters with one sequential pass
+    /// over the active FAT.
+    pub fn usage_bitmap(&mut self) -> io::Result<ClusterBitmap> {
+        self.flush_cache()?;
+        let mut bitmap = ClusterBitmap::new(self.cluster_count);
+        if self.kind != FatKind::Fat32 {
+            // FAT12 and FAT16 volumes are small enough to go entry by entry
+            for cluster in 2..self.end() {
+                if self.get(cluster)?.status() != Status::Free {
+                    bitmap.set_allocated(cluster);
+                }
+            }
+            return Ok(bitmap);
+        }
+        let end = self.cluster_count as u64 + 2;
+        let sector_size = self.device.sector_size();
+        let entries_per_sector = sector_size / SingleFat::FAT_ENTRY_SIZE;
+        let mut buf = vec![0; sector_size as usize];
+        let mut first_cluster = 0;
+        while first_cluster < end {
+            let count = min(entries_per_sector, end - first_cluster);
+            let len = (count * SingleFat::FAT_ENTRY_SIZE) as usize;
+            self.fats[self.active_fat].read_bytes(first_cluster * SingleFat::FAT_ENTRY_SIZE, &mut buf[..len])?;
+            for (i, raw_entry) in buf[..len].chunks(4).enumerate() {
+                let cluster = (first_cluster + i as u64) as u32;
+                if cluster >= 2 && FatEntry(LittleEndian::read_u32(raw_entry)).status() != Status::Free {
+                    bitmap.set_allocated(cluster);
+                }
+            }
+            first_cluster += count;
+        }
+        Ok(bitmap)
+    }
+
+    /// Compares every copy of the FAT with the active one sector by sector and
+    /// returns the clusters whose entries differ in at least one copy.
+    pub fn verify_mirrors(&mut self) -> io::Result<Vec<u32>> {
+        self.flush_cache()?;
+        let mut mismatches = Vec::new();
+        if self.kind != FatKind::Fat32 {
+            let active = &self.fats[self.active_fat];
+            for cluster in 0..active.size() {
+                let expected = active.get(cluster)?.0;
+                for fat in &self.fats {
+                    if fat.get(cluster)?.0 != expected {
+                        mismatches.push(cluster);
+                        break;
+                    }
+                }
+            }
+            return Ok(mismatches);
+        }
+        let sector_size = self.device.sector_size();
+        let active = &self.fats[self.active_fat];
+        let mut expected = vec![0; sector_size as usize];
+        let mut actual = vec![0; sector_size as usize];
+        let mut offset = 0;
+        while offset < active.size_bytes() {
+            let len = min(sector_size, active.size_bytes() - offset) as usize;
+            active.read_bytes(offset, &mut expected[..len])?;
+            let first_cluster = (offset / SingleFat::FAT_ENTRY_SIZE) as u32;
+            let mut differing = Vec::new();
+            for (index, fat) in self.fats.iter().enumerate() {
+                if index == self.active_fat {
+                    continue;
+                }
+                fat.read_bytes(offset, &mut actual[..len])?;
+                for (i, (a, b)) in expected[..len].chunks(4).zip(actual[..len].chunks(4)).enumerate() {
+                    let cluster = first_cluster + i as u32;
+                    if a != b && !differing.contains(&cluster) {
+                        differing.push(cluster);
+                    }
+                }
+            }
+            differing.sort();
+            mismatches.extend(differing);
+            offset += sector_size;
+        }
+        Ok(mismatches)
+    }
+
+    /// Copies FAT number `source_index` over every other copy of the FAT.
+    pub fn repair_mirrors(&mut self, source_index: u8) -> io::Result<()> {
+        let source_index = source_index as usize;
+        if source_index >= self.fats.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "FAT index is out of range"));
+        }
+        self.flush_cache()?;
+        if let Some(ref mut cache) = self.cache {
+            cache.sectors.clear();
+        }
+        let sector_size = self.device.sector_size();
+        let size_bytes = self.fats[source_index].size_bytes();
+        let mut buf = vec![0; sector_size as usize];
+        let mut offset = 0;
+        while offset < size_bytes {
+            let len = min(sector_size, size_bytes - offset) as usize;
+            self.fats[source_index].read_bytes(offset, &mut buf[..len])?;
+            for (index, fat) in self.fats.iter_mut().enumerate() {
+                if index != source_index {
+                    fat.write_bytes(offset, &buf[..len])?;
+                }
+            }
+            offset += sector_size;
+        }
+        Ok(())
+    }
+
+    /// Writes back the cached FAT sectors and the FSInfo sector.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.flush_cache()?;
+        self.sync_fs_info()
+    }
+
+    /// Writes back the cached FAT sectors and flushes the sectors of all
+    /// FATs on the device, but not the rest of it.
+    pub fn flush_fats(&mut self) -> io::Result<()> {
+        self.flush_cache()?;
+        let sector_size = self.device.sector_size();
+        let first = self.fats[0].offset / sector_size;
+        let last_fat = &self.fats[self.fats.len() - 1];
+        let end = (last_fat.offset + last_fat.size_bytes() + sector_size - 1) / sector_size;
+        self.device.sync_range(first, end - first)
+    }
+
+    /// Writes the in-memory free cluster count and next free cluster hint
+    /// back to the FSInfo sector, if they have changed since the last sync.
+    pub fn sync_fs_info(&mut self) -> io::Result<()> {
+        if !self.fs_info_dirty {
+            return Ok(());
+        }
+        if let Some(offset) = self.fs_info_offset {
+            let mut buf = [0; FsInfo::SIZE];
+            self.device.read_by_offset(offset, &mut buf)?;
+            self.fs_info.write_into(&mut buf);
+            self.device.write_by_offset(offset, &buf)?;
+        }
+        self.fs_info_dirty = false;
+        Ok(())
     }
 
     pub fn free_chain(&mut self, first_cluster: u32) -> io::Result<()> {
+        let mut clusters = Vec::new();
+        let mut result = Ok(());
         let mut current_cluster = first_cluster;
         loop {
+            if clusters.len() >= self.end() as usize {
+                result = Err(Error::Corrupted { what: "cluster chain has a cycle" }.into());
+                break;
+            }
             match self.get(current_cluster)?.status() {
                 Status::Data(next) => {
-                    self.set(current_cluster, 0)?;
+                    clusters.push(current_cluster);
                     current_cluster = next;
                 },
                 Status::Eoc(_) => {
-                    self.set(current_cluster, 0)?;
-                    return Ok(());
+                    clusters.push(current_cluster);
+                    break;
+                }
+                // bad clusters stay marked; nothing can follow them
+                Status::Bad => break,
+                _ => {
+                    result = Err(Error::Corrupted { what: "cluster chain is broken" }.into());
+                    break;
                 }
-                _ => return Err(io::Error::from(io::ErrorKind::InvalidData)),
             }
         }
+        self.release_all(&clusters)?;
+        result
+    }
+
+    /// Frees `clusters`, the clusters of a chain in order, writing every
+    /// affected FAT sector once. The sector holding the head of the chain is
+    /// written last so that an interrupted free never leaves a valid head
+    /// pointing to freed clusters.
+    fn release_all(&mut self, clusters: &[u32]) -> io::Result<()> {
+        if clusters.is_empty() {
+            return Ok(());
+        }
+        if self.cache.is_some() {
+            // the cache already combines writes to the same sector
+            for &cluster in clusters.iter().rev() {
+                self.release(cluster)?;
+            }
+            self.discard_clusters(clusters);
+            return Ok(());
+        }
+        if !self.volume_dirty {
+            self.set_volume_dirty(true)?;
+        }
+
+        let sector_size = self.device.sector_size();
+        let entries_per_sector = (sector_size / SingleFat::FAT_ENTRY_SIZE) as u32;
+        let head_sector = clusters[0] / entries_per_sector;
+        let mut by_sector: HashMap<u32, Vec<u32>> = HashMap::new();
+        for &cluster in clusters {
+            by_sector.entry(cluster / entries_per_sector).or_insert_with(Vec::new).push(cluster);
+        }
+        let mut sectors: Vec<u32> = by_sector.keys().cloned().filter(|&s| s != head_sector).collect();
+        sectors.sort();
+        sectors.push(head_sector);
+
+        let mut buf = vec![0; sector_size as usize];
+        for sector in sectors {
+            let offset = sector as u64 * sector_size;
+            let len = min(sector_size, self.fats[self.active_fat].size_bytes() - offset) as usize;
+            self.fats[self.active_fat].read_bytes(offset, &mut buf[..len])?;
+            for &cluster in &by_sector[&sector] {
+                let index = ((cluster % entries_per_sector) as u64 * SingleFat::FAT_ENTRY_SIZE) as usize;
+                LittleEndian::write_u32(&mut buf[index..index + 4], 0);
+            }
+            for (index, fat) in self.fats.iter_mut().enumerate() {
+                if self.mirroring || index == self.active_fat {
+                    fat.write_bytes(offset, &buf[..len])?;
+                }
+            }
+        }
+
+        let lowest = *clusters.iter().min().unwrap();
+        if lowest < self.next_free {
+            self.set_next_free(lowest);
+        }
+        if self.fs_info.free_count != FsInfo::UNKNOWN {
+            self.fs_info.free_count += clusters.len() as u32;
+        }
+        self.fs_info_dirty = true;
+        self.counters.record(Event::ClustersFreed { first_cluster: clusters[0], count: clusters.len() as u32 });
+        self.discard_clusters(clusters);
+        Ok(())
+    }
+
+    /// Discards the data sectors of the freed `clusters`, merging runs of
+    /// consecutive clusters into a single call.
+    fn discard_clusters(&mut self, clusters: &[u32]) {
+        if !self.discard {
+            return;
+        }
+        let mut clusters = clusters.to_vec();
+        clusters.sort();
+        let mut index = 0;
+        while index < clusters.len() {
+            let start = clusters[index];
+            let mut end = start + 1;
+            index += 1;
+            while index < clusters.len() && clusters[index] == end {
+                end += 1;
+                index += 1;
+            }
+            let sector = self.data_start_sector + (start as u64 - 2) * self.sectors_per_cluster;
+            let count = (end - start) as u64 * self.sectors_per_cluster;
+            // discarding is only a hint; the clusters are free regardless
+            let _ = self.device.discard(sector, count);
+        }
     }
 }
 
@@ -146,15 +790,76 @@ impl Fat {
 pub struct SharedFat(ArcMutex<Fat>);
 
 impl SharedFat {
-    pub fn new(device: &SharedLogicalBlockDevice, params: &BiosParameterBlock) -> Self {
-        let fat = Fat {
-            fats: (0..params.number_of_fats).map(|i| SingleFat::new(device.clone(), params, i)).collect(),
+    pub(crate) fn new(device: &SharedLogicalBlockDevice, params: &BiosParameterBlock, options: &MountOptions,
+                      counters: Arc<Counters>) -> io::Result<Self> {
+        // FAT12 and FAT16 boot sectors have neither an FSInfo sector nor
+        // mirroring flags; those offsets hold the volume label and boot code.
+        let kind = params.fat_kind();
+        let is_fat32 = kind == FatKind::Fat32;
+        let fs_info_offset = match params.fs_information_sector_location {
+            _ if !is_fat32 => None,
+            0 | 0xFFFF => None,
+            sector => Some(sector as u64 * params.bytes_per_logical_sector as u64),
         };
-        SharedFat(ArcMutex::new(fat))
-    }
-
-    pub(crate) fn unwrap(self) -> ArcMutex<Fat> {
-        self.0
+        let fs_info = match fs_info_offset {
+            Some(offset) => {
+                let mut buf = [0; FsInfo::SIZE];
+                device.read_by_offset(offset, &mut buf)?;
+                FsInfo::parse(&buf)
+            }
+            None => FsInfo::unknown(),
+        };
+        let fats: Vec<_> = (0..params.number_of_fats).map(|i| SingleFat::new(device.clone(), params, i, counters.clone())).collect();
+        // Bit 7 of the mirroring flags disables mirroring; bits 0-3 then
+        // select the only FAT in use.
+        let mirroring = !is_fat32 || params.mirroring_flags & 0x80 == 0;
+        let active_fat = if mirroring { 0 } else { (params.mirroring_flags & 0xF) as usize };
+        if active_fat >= fats.len() {
+            return Err(Error::Corrupted { what: "active FAT index is out of range" }.into());
+        }
+        let cache = match options.fat_cache {
+            _ if !is_fat32 => None,
+            FatCacheMode::Disabled => None,
+            FatCacheMode::Lazy | FatCacheMode::Preload => Some(FatCache {
+                sectors: HashMap::new(),
+                dirty: HashSet::new(),
+            }),
+        };
+        // Clusters that don't fit into the data region or have no FAT entry
+        // can't be used.
+        let cluster_count = min(params.data_cluster_count(), fats[active_fat].size().saturating_sub(2) as u64) as u32;
+        let next_free = if fs_info.next_free >= 2 && fs_info.next_free < cluster_count + 2 {
+            fs_info.next_free
+        } else {
+            2
+        };
+        let mut fat = Fat {
+            fats,
+            kind,
+            cluster_count,
+            data_start_sector: params.data_start_sector(),
+            sectors_per_cluster: params.logical_sectors_per_cluster as u64,
+            discard: !options.disable_discard,
+            cache,
+            active_fat,
+            mirroring,
+            device: device.clone(),
+            next_free,
+            fs_info,
+            fs_info_offset,
+            fs_info_dirty: false,
+            volume_dirty: false,
+            mounted_dirty: false,
+            counters,
+        };
+        fat.mounted_dirty = fat.get_entry(1)?.0 & Fat::CLEAN_SHUTDOWN_BIT == 0;
+        // A volume that was not unmounted cleanly stays dirty until synced.
+        // FAT12 and FAT16 volumes are never written, so they stay as they are.
+        fat.volume_dirty = fat.mounted_dirty && is_fat32;
+        if options.fat_cache == FatCacheMode::Preload && fat.cache.is_some() {
+            fat.preload_cache()?;
+        }
+        Ok(SharedFat(ArcMutex::new(fat)))
     }
 
     pub fn new_chain(&mut self) -> io::Result<u32> {
@@ -169,12 +874,195 @@ impl SharedFat {
         Ok(new_last_cluster)
     }
 
+    /// Returns the raw FAT entry for `cluster`.
+    pub fn get(&self, cluster: u32) -> io::Result<FatEntry> {
+        self.0.lock().get(cluster)
+    }
+
+    /// Returns a bitmap of the allocated data clusters.
+    pub fn usage_bitmap(&self) -> io::Result<ClusterBitmap> {
+        self.0.lock().usage_bitmap()
+    }
+
+    /// Returns the number of data clusters, which may be less than the
+    /// number of entries in the FAT.
+    pub fn cluster_count(&self) -> u32 {
+        self.0.lock().cluster_count
+    }
+
+    /// Returns the number of free clusters. If the FSInfo sector didn't
+    /// provide it, the FAT is scanned once and the result is kept up to date
+    /// from then on.
+    pub fn free_cluster_count(&self) -> io::Result<u32> {
+        let mut fat = self.0.lock();
+        if fat.fs_info.free_count == FsInfo::UNKNOWN {
+            let bitmap = fat.usage_bitmap()?;
+            fat.fs_info.free_count = bitmap.cluster_count() - bitmap.allocated_count();
+            fat.fs_info_dirty = true;
+        }
+        Ok(fat.fs_info.free_count)
+    }
+
+    /// Returns the number of entries in a single FAT, including the two
+    /// reserved entries.
+    pub fn size(&self) -> u32 {
+        self.0.lock().size()
+    }
+
+    /// Returns the index of the FAT that is read from. Unless mirroring is
+    /// disabled, this is always 0.
+    pub fn active_fat(&self) -> u8 {
+        self.0.lock().active_fat as u8
+    }
+
+    /// Returns `true` if updates are written to every copy of the FAT.
+    pub fn is_mirrored(&self) -> bool {
+        self.0.lock().mirroring
+    }
+
+    /// Returns the clusters whose entries differ between the copies of the FAT.
+    pub fn verify_mirrors(&self) -> io::Result<Vec<u32>> {
+        self.0.lock().verify_mirrors()
+    }
+
+    /// Makes every copy of the FAT identical to FAT number `source_index`.
+    pub fn repair_mirrors(&mut self, source_index: u8) -> io::Result<()> {
+        self.0.lock().repair_mirrors(source_index)
+    }
+
+    /// Returns the current free cluster count and next free cluster hint.
+    pub fn fs_info(&self) -> FsInfo {
+        self.0.lock().fs_info
+    }
+
+    /// Writes back the cached FAT sectors and the FSInfo sector.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.0.lock().sync()
+    }
+
+    /// Writes back the cached FAT sectors and flushes the FATs on the device.
+    pub fn flush_fats(&mut self) -> io::Result<()> {
+        self.0.lock().flush_fats()
+    }
+
+    /// Clears the clean shutdown bit in FAT[1] unless it is already cleared.
+    /// Called before the first write after mount or sync.
+    pub fn mark_volume_dirty(&mut self) -> io::Result<()> {
+        let mut fat = self.0.lock();
+        if fat.volume_dirty {
+            return Ok(());
+        }
+        fat.set_volume_dirty(true)
+    }
+
+    /// Sets the clean shutdown bit in FAT[1]. All other changes must already
+    /// be on the device.
+    pub fn mark_volume_clean(&mut self) -> io::Result<()> {
+        let mut fat = self.0.lock();
+        if !fat.volume_dirty {
+            return Ok(());
+        }
+        fat.set_volume_dirty(false)
+    }
+
+    /// Returns `true` if the clean shutdown bit was cleared at mount.
+    pub fn was_mounted_dirty(&self) -> bool {
+        self.0.lock().mounted_dirty
+    }
+
+    /// Allocates a chain of `count` consecutive clusters and returns its first
+    /// cluster. If no run of `count` free clusters exists, the chain is built
+    /// from scattered clusters instead.
+    pub fn alloc_contiguous(&mut self, count: u32) -> io::Result<u32> {
+        self.0.lock().alloc_chain(count)
+    }
+
+    /// Appends `count` clusters, consecutive if possible, after `last_cluster`
+    /// and returns the first of them.
+    pub fn alloc_contiguous_for_chain(&mut self, last_cluster: u32, count: u32) -> io::Result<u32> {
+        let mut fat = self.0.lock();
+        let first_cluster = fat.alloc_chain(count)?;
+        if let Err(e) = fat.set(last_cluster, first_cluster) {
+            let _ = fat.free_chain(first_cluster);
+            return Err(e);
+        }
+        Ok(first_cluster)
+    }
+
+    /// Allocates the free clusters `first_cluster..first_cluster + count` as
+    /// one chain, e.g. a run found with `ClusterBitmap::iter_free_runs`.
+    pub(crate) fn alloc_run(&mut self, first_cluster: u32, count: u32) -> io::Result<()> {
+        self.0.lock().alloc_run(first_cluster, count)
+    }
+
+    /// Marks the single cluster `cluster` as free, regardless of the chain it
+    /// belongs to.
+    pub(crate) fn free_cluster(&mut self, cluster: u32) -> io::Result<()> {
+        let mut fat = self.0.lock();
+        fat.release(cluster)?;
+        fat.discard_clusters(&[cluster]);
+        Ok(())
+    }
+
+    /// Makes `cluster` the last cluster of its chain without freeing the
+    /// clusters that followed it.
+    pub(crate) fn terminate_chain(&mut self, cluster: u32) -> io::Result<()> {
+        self.0.lock().set(cluster, 0xFFFFFFF)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_raw(&mut self, cluster: u32, entry: u32) -> io::Result<()> {
+        self.0.lock().set(cluster, entry)
+    }
+
+    /// Marks `cluster` as bad so that it is never allocated again. The cluster
+    /// must not be referenced by any chain afterwards.
+    pub fn mark_bad(&mut self, cluster: u32) -> io::Result<()> {
+        self.0.lock().mark_bad(cluster)
+    }
+
+    /// Returns the number of clusters marked as bad.
+    pub fn bad_cluster_count(&self) -> io::Result<u32> {
+        self.0.lock().bad_cluster_count()
+    }
+
+    /// Returns the clusters of the chain starting at `first_cluster`, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if the chain runs into a cluster that
+    /// isn't in use or has more clusters than the FAT has entries, i.e. it
+    /// contains a loop.
+    pub fn chain_clusters(&self, first_cluster: u32) -> io::Result<Vec<u32>> {
+        let mut fat = self.0.lock();
+        let size = fat.end() as usize;
+        let mut clusters = Vec::new();
+        let mut cluster = first_cluster;
+        loop {
+            if clusters.len() >= size {
+                return Err(Error::Corrupted { what: "cluster chain has a cycle" }.into());
+            }
+            clusters.push(cluster);
+            match fat.get(cluster)?.status() {
+                Status::Data(next) => cluster = next,
+                Status::Eoc(_) => return Ok(clusters),
+                _ => return Err(Error::Corrupted { what: "cluster chain is broken" }.into()),
+            }
+        }
+    }
+
+    /// Returns the number of clusters in the chain starting at
+    /// `first_cluster`. Fails like `chain_clusters`.
+    pub fn chain_length(&self, first_cluster: u32) -> io::Result<u32> {
+        self.chain_clusters(first_cluster).map(|clusters| clusters.len() as u32)
+    }
+
     pub fn get_next_in_chain(&self, cluster: u32) -> io::Result<Option<u32>> {
-        let fat = self.0.lock();
+        let mut fat = self.0.lock();
         match fat.get(cluster)?.status() {
             Status::Data(next) => Ok(Some(next)),
             Status::Eoc(_) => Ok(None),
-            _ => Err(io::Error::from(io::ErrorKind::InvalidData))
+            _ => Err(Error::Corrupted { what: "cluster chain is broken" }.into())
         }
     }
 
@@ -183,17 +1071,17 @@ impl SharedFat {
         fat.free_chain(first_cluster)
     }
 
-    // TODO: add set_len to File
-    #[allow(dead_code)]
     pub fn truncate_chain(&mut self, last_cluster: u32) -> io::Result<()> {
         let mut fat = self.0.lock();
         match fat.get(last_cluster)?.status() {
             Status::Data(next) => {
-                fat.free_chain(next)?;
+                // cut the chain first so that a crash leaves lost clusters
+                // rather than a chain running into free ones
                 fat.set(last_cluster, 0xFFFFFFF)?;
+                fat.free_chain(next)?;
             }
             Status::Eoc(_) => {}
-            _ => return Err(io::Error::from(io::ErrorKind::InvalidData))
+            _ => return Err(Error::Corrupted { what: "cluster chain is broken" }.into())
         }
         Ok(())
     }
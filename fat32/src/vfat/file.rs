@@ -1,50 +1,253 @@
 use std::cmp::min;
-use std::io::{self, Write, SeekFrom};
+use std::hash::Hasher;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::time::Duration;
 
-use vfat::cluster_chain::ClusterChain;
+use vfat::cluster_chain::{ClusterChain, BadRange};
 use traits::File;
 use vfat::VFatEntry;
 use traits::FileOpenMode;
-use vfat::lock_manager::LockMode;
-use traits::BlockDevice;
+use vfat::lock_manager::{LockMode, LockWait};
+use error::Error;
 
 pub struct VFatFile {
     chain: ClusterChain,
     size: u32,
     old_size: u32,
     entry: VFatEntry,
+    preallocated: bool,
 }
 
 impl Drop for VFatFile {
     fn drop(&mut self) {
+        if self.preallocated {
+            let size = self.size as u64;
+            let _ = self.chain.truncate(size);
+        }
         let _ = self.flush();
+        if self.chain.first_cluster != self.entry.metadata.first_cluster && self.is_removed() {
+            // The removed entry never pointed to the chain, so nobody else
+            // frees it.
+            let _ = self.chain.fat().clone().free_chain(self.chain.first_cluster);
+        }
     }
 }
 
 impl VFatFile {
+    /// Opens the file of `entry`. If the file is open in a conflicting mode,
+    /// i.e. either of them is `Write`, fails with `Busy` right away.
     pub fn from_entry(entry: &VFatEntry, mode: FileOpenMode) -> io::Result<VFatFile> {
+        VFatFile::open(entry, mode, LockWait::NoWait)
+    }
+
+    /// Like `from_entry`, but if the file is open in a conflicting mode,
+    /// waits up to `timeout` for it to be closed before failing with `Busy`.
+    pub fn from_entry_with_timeout(entry: &VFatEntry, mode: FileOpenMode, timeout: Duration) -> io::Result<VFatFile> {
+        VFatFile::open(entry, mode, LockWait::Timeout(timeout))
+    }
+
+    /// Like `from_entry`, but if the file is open in a conflicting mode,
+    /// blocks until it is closed. This never returns if the conflicting
+    /// handle belongs to the calling thread.
+    pub fn from_entry_blocking(entry: &VFatEntry, mode: FileOpenMode) -> io::Result<VFatFile> {
+        VFatFile::open(entry, mode, LockWait::Forever)
+    }
+
+    fn open(entry: &VFatEntry, mode: FileOpenMode, wait: LockWait) -> io::Result<VFatFile> {
         let vfat = entry.vfat();
+        if mode == FileOpenMode::Write {
+            vfat.lock().check_writable()?;
+        }
         let mode = match mode {
             FileOpenMode::Read => LockMode::Read,
             FileOpenMode::Write => LockMode::Write,
         };
-        let chain = ClusterChain::open(vfat, entry.metadata.first_cluster, mode)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "can't lock file"))?;
+        let mut chain = ClusterChain::open_with(vfat.clone(), entry.metadata.first_cluster, entry.lock_key, mode, wait)
+            .ok_or(Error::Busy)?;
+        chain.recover_reads = vfat.lock().read_recovery();
+        // `entry` may have been removed while the file was open elsewhere.
+        if entry.ref_guard.is_removed() || vfat.lock().lock_manager().is_pending_free(entry.lock_key) {
+            return Err(Error::NotFound.into());
+        }
 
         let size = entry.current_file_size()?;
+        let clusters = match entry.metadata.first_cluster {
+            0 => 0,
+            first_cluster => chain.fat().chain_length(first_cluster)?,
+        };
+        if size as u64 > clusters as u64 * chain.cluster_size_bytes() as u64 {
+            return Err(Error::Corrupted { what: "file size exceeds its cluster chain" }.into());
+        }
         Ok(VFatFile {
             chain,
             size,
             old_size: size,
             entry: entry.clone(),
+            preallocated: false,
+        })
+    }
+
+    /// Reserves space for `bytes` bytes ahead of sequential writes, using
+    /// consecutive clusters where possible. Clusters that end up unused are
+    /// freed when the file is closed.
+    pub fn preallocate(&mut self, bytes: u64) -> io::Result<()> {
+        if self.chain.guard.mode() != Some(LockMode::Write) {
+            return Err(Error::NotOpenForWriting.into());
+        }
+        if bytes > ::std::u32::MAX as u64 {
+            return Err(Error::FileTooLarge.into());
+        }
+        self.chain.preallocate(bytes)?;
+        self.preallocated = true;
+        Ok(())
+    }
+
+    /// Truncates or extends the file to `size` bytes. The new space of an
+    /// extended file is filled with zeroes. The position is kept, unless it is
+    /// past the new end of the file.
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        if self.chain.guard.mode() != Some(LockMode::Write) {
+            return Err(Error::NotOpenForWriting.into());
+        }
+        if size > ::std::u32::MAX as u64 {
+            return Err(Error::FileTooLarge.into());
+        }
+        if size < self.size as u64 {
+            // The new size has to be on the device before the clusters are
+            // freed.
+            self.size = size as u32;
+            self.flush()?;
+            self.chain.truncate(size)?;
+        } else if size > self.size as u64 {
+            let position = self.chain.position;
+            self.seek(SeekFrom::End(0))?;
+            let zeroes = [0; 512];
+            let mut remaining = size - self.size as u64;
+            while remaining > 0 {
+                let len = min(remaining, zeroes.len() as u64) as usize;
+                self.write_all(&zeroes[..len])?;
+                remaining -= len as u64;
+            }
+            self.seek(SeekFrom::Start(position))?;
+        }
+        Ok(())
+    }
+
+    /// Makes a file opened for reading writable without closing it, so that
+    /// no writer can get in between. Fails with `Busy` right away if the file
+    /// is open for reading elsewhere too.
+    pub fn try_upgrade(&mut self) -> io::Result<()> {
+        if self.chain.guard.mode() == Some(LockMode::Write) {
+            return Ok(());
+        }
+        self.chain.vfat.lock().check_writable()?;
+        self.chain.guard.try_upgrade()
+    }
+
+    /// Like `try_upgrade`, but waits until the other readers have closed the
+    /// file. If another reader is already waiting here, fails with `Busy`
+    /// instead: both would wait for each other forever. That reader proceeds
+    /// once this file is closed.
+    pub fn upgrade(&mut self) -> io::Result<()> {
+        if self.chain.guard.mode() == Some(LockMode::Write) {
+            return Ok(());
+        }
+        self.chain.vfat.lock().check_writable()?;
+        self.chain.guard.upgrade()
+    }
+
+    /// Writes back the file and makes it read-only, letting others open it
+    /// for reading.
+    pub fn downgrade(&mut self) -> io::Result<()> {
+        if self.chain.guard.mode() != Some(LockMode::Write) {
+            return Ok(());
+        }
+        self.flush()?;
+        self.chain.guard.downgrade()
+    }
+
+    /// Writes the buffers one after the other like `write`. They are gathered
+    /// into one first, so that they are written like a single large buffer,
+    /// with whole clusters going to the device in runs.
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let mut non_empty = bufs.iter().filter(|buf| !buf.is_empty());
+        match (non_empty.next(), non_empty.next()) {
+            (None, _) => Ok(0),
+            (Some(buf), None) => self.write(buf),
+            _ => {
+                let mut data = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+                for buf in bufs {
+                    data.extend_from_slice(buf);
+                }
+                self.write(&data)
+            }
+        }
+    }
+
+    /// The size of the chunks `read_chunks` passes on, rounded up to whole
+    /// clusters.
+    const CHUNK_BYTES: usize = 64 * 1024;
+
+    /// Reads the whole file from its start and calls `f` with consecutive
+    /// chunks of it, each a number of whole clusters except for the last one.
+    /// Runs of consecutive clusters are read with a single device read.
+    /// Returns the size of the file and leaves the position at its end.
+    ///
+    /// As the file is open, nobody writes to it meanwhile, so the chunks are
+    /// its contents at a single point in time.
+    pub fn read_chunks<F>(&mut self, mut f: F) -> io::Result<u64>
+        where F: FnMut(&[u8]) -> io::Result<()>
+    {
+        self.seek(SeekFrom::Start(0))?;
+        let cluster_size = self.chain.cluster_size_bytes() as usize;
+        let chunk_clusters = ::std::cmp::max(1, (Self::CHUNK_BYTES + cluster_size - 1) / cluster_size);
+        let mut buf = vec![0; chunk_clusters * cluster_size];
+        let mut total = 0;
+        loop {
+            let mut len = 0;
+            while len < buf.len() {
+                match self.read(&mut buf[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            if len == 0 {
+                break;
+            }
+            f(&buf[..len])?;
+            total += len as u64;
+        }
+        Ok(total)
+    }
+
+    /// Feeds the whole file to `hasher` with `read_chunks` and returns its
+    /// size.
+    pub fn hash_contents<H: Hasher>(&mut self, hasher: &mut H) -> io::Result<u64> {
+        self.read_chunks(|chunk| {
+            hasher.write(chunk);
+            Ok(())
         })
     }
 
+    /// Returns the parts of the file that couldn't be read and were read as
+    /// zeros since it was opened. Always empty unless the volume was mounted
+    /// with `MountOptions::read_recovery`.
+    pub fn read_errors(&self) -> &[BadRange] {
+        self.chain.read_errors()
+    }
+
     pub fn at_end(&self) -> bool {
         self.chain.position == self.size as u64
     }
 
     pub fn close(self) {}
+
+    /// Returns `true` if the file was removed while open. Its entry is gone
+    /// then, and so the size isn't written back.
+    fn is_removed(&self) -> bool {
+        self.chain.guard.is_removed() ||
+            self.chain.vfat.lock().lock_manager().is_pending_free(self.entry.lock_key)
+    }
 }
 
 impl io::Read for VFatFile {
@@ -58,25 +261,45 @@ impl io::Read for VFatFile {
 }
 
 impl io::Write for VFatFile {
+    /// Writes as much of `buf` as fits below the largest file size FAT
+    /// can record, 4 GiB - 1, and fails with `FileTooLarge` once the file
+    /// has reached it.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = ::std::u32::MAX as u64 - self.chain.position;
+        if room == 0 && !buf.is_empty() {
+            return Err(Error::FileTooLarge.into());
+        }
+        let buf = &buf[..min(buf.len() as u64, room) as usize];
         let write_size = self.chain.write(buf)?;
 
         if self.chain.position > self.size as u64 {
-            if self.chain.position > ::std::u32::MAX as u64 {
-                return Err(io::Error::new(io::ErrorKind::Other, "File is too fat for FAT32"));
-            }
             self.size = self.chain.position as u32;
         }
         Ok(write_size)
     }
 
+    /// Flushes the sectors of this file only: its data, then the FATs and
+    /// then, if the size or the first cluster changed, the directory entry.
+    /// Sectors other files have written stay in the cache.
     fn flush(&mut self) -> io::Result<()> {
         self.chain.flush()?;
-        if self.size != self.old_size {
+        let first_cluster = self.chain.first_cluster;
+        let new_chain = first_cluster != self.entry.metadata.first_cluster;
+        if (new_chain || self.size != self.old_size) && !self.is_removed() {
+            // The data and the clusters holding it go before the entry
+            // pointing to them and the new size.
+            self.chain.fat().clone().flush_fats()?;
+            if new_chain {
+                // Listings key the entry by its first cluster, so the lock
+                // has to move before the entry points to the new chain, or
+                // the file could be opened a second time under the new key.
+                self.chain.guard.rekey(self.entry.lock_key_for(first_cluster))?;
+                self.entry.set_first_cluster(first_cluster)?;
+            }
             self.entry.set_file_size(self.size)?;
             self.old_size = self.size;
+            self.entry.flush()?;
         }
-        self.chain.vfat.lock().device.sync()?;
         Ok(())
     }
 }
@@ -0,0 +1,72 @@
+use byteorder::{LittleEndian, ByteOrder};
+
+/// The contents of the FAT32 FSInfo sector: the number of free clusters and
+/// a hint for where to start looking for free clusters.
+///
+/// Either value may be `FsInfo::UNKNOWN`, in which case it must be ignored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FsInfo {
+    pub free_count: u32,
+    pub next_free: u32,
+}
+
+impl FsInfo {
+    pub const UNKNOWN: u32 = 0xFFFFFFFF;
+    pub const SIZE: usize = 512;
+
+    const LEAD_SIGNATURE: u32 = 0x41615252;
+    const STRUCT_SIGNATURE: u32 = 0x61417272;
+    const TRAIL_SIGNATURE: u32 = 0xAA550000;
+
+    /// Returns an `FsInfo` with both values unknown.
+    pub fn unknown() -> FsInfo {
+        FsInfo {
+            free_count: FsInfo::UNKNOWN,
+            next_free: FsInfo::UNKNOWN,
+        }
+    }
+
+    /// Returns `true` if `sector` carries all three FSInfo signatures.
+    pub fn is_valid(sector: &[u8]) -> bool {
+        sector.len() >= FsInfo::SIZE &&
+            LittleEndian::read_u32(&sector[0..4]) == FsInfo::LEAD_SIGNATURE &&
+            LittleEndian::read_u32(&sector[484..488]) == FsInfo::STRUCT_SIGNATURE &&
+            LittleEndian::read_u32(&sector[508..512]) == FsInfo::TRAIL_SIGNATURE
+    }
+
+    /// Parses the FSInfo sector `sector`. If any of the signatures is invalid,
+    /// both values are reported as unknown.
+    pub fn parse(sector: &[u8]) -> FsInfo {
+        if !FsInfo::is_valid(sector) {
+            return FsInfo::unknown();
+        }
+        FsInfo {
+            free_count: LittleEndian::read_u32(&sector[488..492]),
+            next_free: LittleEndian::read_u32(&sector[492..496]),
+        }
+    }
+
+    /// Writes the signatures and values of `self` into `sector`, leaving the
+    /// reserved areas untouched.
+    pub fn write_into(&self, sector: &mut [u8]) {
+        LittleEndian::write_u32(&mut sector[0..4], FsInfo::LEAD_SIGNATURE);
+        LittleEndian::write_u32(&mut sector[484..488], FsInfo::STRUCT_SIGNATURE);
+        LittleEndian::write_u32(&mut sector[488..492], self.free_count);
+        LittleEndian::write_u32(&mut sector[492..496], self.next_free);
+        LittleEndian::write_u32(&mut sector[508..512], FsInfo::TRAIL_SIGNATURE);
+    }
+}
+
+#[test]
+fn test_fs_info_round_trip() {
+    let mut sector = [0u8; 512];
+    assert_eq!(FsInfo::parse(&sector), FsInfo::unknown());
+
+    let fs_info = FsInfo { free_count: 1234, next_free: 56 };
+    fs_info.write_into(&mut sector);
+    assert!(FsInfo::is_valid(&sector));
+    assert_eq!(FsInfo::parse(&sector), fs_info);
+
+    sector[0] = 0;
+    assert_eq!(FsInfo::parse(&sector), FsInfo::unknown());
+}
@@ -0,0 +1,56 @@
+use std::fmt;
+
+use vfat::data_region;
+
+/// Where the regions of a FAT32 volume are, as returned by
+/// `VFatFileSystem::layout`. Sectors are logical sectors of the volume,
+/// counted from its boot sector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeLayout {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    /// The sectors before the first FAT, including the boot sector.
+    pub reserved_sectors: u16,
+    pub fat_count: u8,
+    /// The first sector of each FAT.
+    pub fat_start_sectors: Vec<u64>,
+    /// The size of each FAT in sectors.
+    pub fat_sectors: u32,
+    /// The first sector of cluster 2.
+    pub data_start_sector: u64,
+    /// The number of data clusters, numbered from 2.
+    pub cluster_count: u32,
+    /// 0 on FAT12 and FAT16, whose root directory precedes the data region.
+    pub root_dir_cluster: u32,
+}
+
+impl VolumeLayout {
+    /// The size of a cluster in bytes.
+    pub fn cluster_size_bytes(&self) -> u64 {
+        self.sectors_per_cluster as u64 * self.bytes_per_sector as u64
+    }
+
+    /// The byte offset of `cluster` from the boot sector. The cluster isn't
+    /// checked to exist.
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        data_region::cluster_offset(self.data_start_sector, self.sectors_per_cluster, self.bytes_per_sector, cluster)
+    }
+}
+
+impl fmt::Display for VolumeLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes_per_sector = self.bytes_per_sector as u64;
+        writeln!(f, "sector size:   {} bytes", bytes_per_sector)?;
+        writeln!(f, "cluster size:  {} bytes ({} sectors per cluster)", self.cluster_size_bytes(), self.sectors_per_cluster)?;
+        writeln!(f, "reserved:      sectors 0..{}", self.reserved_sectors)?;
+        for (index, &start) in self.fat_start_sectors.iter().enumerate() {
+            writeln!(f, "FAT {}:         sectors {}..{} (byte {:#x})", index, start,
+                     start + self.fat_sectors as u64, start * bytes_per_sector)?;
+        }
+        writeln!(f, "data:          sectors {}..{} ({} clusters)", self.data_start_sector,
+                 self.data_start_sector + self.cluster_count as u64 * self.sectors_per_cluster as u64,
+                 self.cluster_count)?;
+        writeln!(f, "cluster 2:     byte {:#x}", self.data_start_sector * bytes_per_sector)?;
+        write!(f, "root dir:      cluster {}", self.root_dir_cluster)
+    }
+}
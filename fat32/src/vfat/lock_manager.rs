@@ -1,13 +1,76 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::io;
 use arc_mutex::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard};
 use std::sync::Condvar;
-#[cfg(test)]
-use std::time::Duration;
-use arc_mutex::ArcMutex;
+use std::time::{Duration, Instant};
+use arc_mutex::{ArcMutex, ignore_poison};
+use error::Error;
+use vfat::fat::SharedFat;
+
+/// Identifies the object a lock is taken on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LockKey {
+    /// A file or directory, by its first cluster.
+    Cluster(u32),
+    /// An entry without clusters, such as an empty file, by the cluster of
+    /// the directory holding it and the index of its regular directory entry.
+    DirSlot { dir_cluster: u32, index: u64 },
+}
+
+impl LockKey {
+    /// The key of the entry at raw index `index` of the directory starting at
+    /// `dir_cluster`. Entries all share the first cluster 0 when they have no
+    /// clusters, so these are told apart by their location instead.
+    pub fn for_entry(first_cluster: u32, dir_cluster: u32, index: u64) -> LockKey {
+        if first_cluster == 0 {
+            LockKey::DirSlot { dir_cluster, index }
+        } else {
+            LockKey::Cluster(first_cluster)
+        }
+    }
+}
+
+/// How long to wait for a lock that is held in a conflicting mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockWait {
+    /// Fail right away.
+    NoWait,
+    /// Wait at most this long.
+    Timeout(Duration),
+    /// Wait until the lock is released.
+    Forever,
+}
 
 struct LockManager {
-    locks: HashMap<u32, Arc<SharedFSObjectLockInfo>>,
+    locks: HashMap<LockKey, Arc<SharedFSObjectLockInfo>>,
+    /// The cluster chains of removed files that were still in use, keyed by
+    /// their first cluster, which are freed when their last lock is released.
+    pending_free: HashMap<LockKey, (SharedFat, u32)>,
+}
+
+/// The state of the lock on one object, as returned by
+/// `SharedLockManager::snapshot`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct LockSnapshot {
+    pub key: LockKey,
+    pub read_locks: usize,
+    pub ref_locks: usize,
+    pub write_locked: bool,
+    pub delete_locked: bool,
+    /// The number of threads blocked waiting for the lock.
+    pub waiters: usize,
+}
+
+/// Formats the snapshot as a row of a table, so that a list of snapshots
+/// printed line by line lines up.
+impl fmt::Debug for LockSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<36} read: {:>3}  ref: {:>3}  write: {:<5}  delete: {:<5}  waiters: {:>3}",
+               format!("{:?}", self.key), self.read_locks, self.ref_locks, self.write_locked,
+               self.delete_locked, self.waiters)
+    }
 }
 
 #[derive(Clone)]
@@ -17,56 +80,151 @@ impl SharedLockManager {
     pub fn new() -> Self {
         let lock_manager = LockManager {
             locks: HashMap::new(),
+            pending_free: HashMap::new(),
         };
         SharedLockManager(ArcMutex::new(lock_manager))
     }
 
-    fn get_lock_info(&self, cluster: u32) -> Arc<SharedFSObjectLockInfo> {
+    fn get_lock_info(&self, key: LockKey) -> Arc<SharedFSObjectLockInfo> {
         let mut inner = self.0.lock();
-        Arc::clone(inner.locks.entry(cluster).or_insert_with(|| Arc::default()))
+        Arc::clone(inner.locks.entry(key).or_insert_with(|| Arc::default()))
+    }
+
+    fn guard(&self, key: LockKey, lock_info: &Arc<SharedFSObjectLockInfo>, mode: LockMode) -> FSObjectGuard {
+        let valid_guard = FSObjectValidGuard {
+            lock_manager: self.clone(),
+            key,
+            lock_info: Arc::clone(lock_info),
+            mode
+        };
+        FSObjectGuard(Some(valid_guard))
     }
 
-    pub fn lock(&self, cluster: u32, mode: LockMode) -> FSObjectGuard {
-        let lock_info = self.get_lock_info(cluster);
-        let mut data = lock_info.data.lock().unwrap();
+    pub fn lock(&self, key: LockKey, mode: LockMode) -> FSObjectGuard {
+        let lock_info = self.get_lock_info(key);
+        let mut data = ignore_poison(lock_info.data.lock());
         loop {
             if data.try_add_lock(mode) {
-                let valid_guard = FSObjectValidGuard {
-                    lock_manager: self.clone(),
-                    cluster,
-                    lock_info: Arc::clone(&lock_info),
-                    mode
-                };
-                return FSObjectGuard(Some(valid_guard));
+                return self.guard(key, &lock_info, mode);
             }
-            data = lock_info.condvar.wait(data).unwrap();
+            data = lock_info.wait(data, None);
         }
     }
 
-    // TODO: use informative result, handle mutex errors
-    pub fn try_lock(&self, cluster: u32, mode: LockMode) -> Option<FSObjectGuard> {
-        let lock_info = self.get_lock_info(cluster);
-        let mut data = lock_info.data.lock().unwrap();
+    /// Like `lock`, but gives up and returns `None` if the lock can't be
+    /// acquired within `timeout`.
+    pub fn lock_timeout(&self, key: LockKey, mode: LockMode, timeout: Duration) -> Option<FSObjectGuard> {
+        let deadline = Instant::now() + timeout;
+        let lock_info = self.get_lock_info(key);
+        let mut data = ignore_poison(lock_info.data.lock());
+        loop {
+            if data.try_add_lock(mode) {
+                return Some(self.guard(key, &lock_info, mode));
+            }
+            // The wait may end early or spuriously, so the remaining time is
+            // recomputed on every iteration.
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            data = lock_info.wait(data, Some(deadline - now));
+        }
+    }
+
+    /// Takes the lock, waiting for it as long as `wait` says. Returns `None`
+    /// if it couldn't be taken in that time.
+    pub fn lock_with(&self, key: LockKey, mode: LockMode, wait: LockWait) -> Option<FSObjectGuard> {
+        match wait {
+            LockWait::NoWait => self.try_lock(key, mode),
+            LockWait::Timeout(timeout) => self.lock_timeout(key, mode, timeout),
+            LockWait::Forever => Some(self.lock(key, mode)),
+        }
+    }
+
+    /// Takes the lock if it is free in `mode` right now, or returns `None`,
+    /// which callers report as `Busy`. Poisoned mutexes are ignored, see
+    /// `ignore_poison`.
+    pub fn try_lock(&self, key: LockKey, mode: LockMode) -> Option<FSObjectGuard> {
+        let lock_info = self.get_lock_info(key);
+        let mut data = ignore_poison(lock_info.data.lock());
         if data.try_add_lock(mode) {
-            let valid_guard = FSObjectValidGuard {
-                lock_manager: self.clone(),
-                cluster,
-                lock_info: Arc::clone(&lock_info),
-                mode
-            };
-            return Some(FSObjectGuard(Some(valid_guard)));
+            Some(self.guard(key, &lock_info, mode))
         } else {
             None
         }
     }
 
+    /// Returns the state of the locks that are held or waited for, ordered by
+    /// key. Meant for debugging hangs.
+    pub fn snapshot(&self) -> Vec<LockSnapshot> {
+        // Copy the lock infos out so that the manager stays unlocked while
+        // waiting for the lock of each object.
+        let lock_infos: Vec<(LockKey, Arc<SharedFSObjectLockInfo>)> = self.0.lock().locks.iter()
+            .map(|(&key, lock_info)| (key, Arc::clone(lock_info)))
+            .collect();
+        let mut snapshots: Vec<LockSnapshot> = lock_infos.into_iter()
+            .map(|(key, lock_info)| {
+                let data = ignore_poison(lock_info.data.lock());
+                LockSnapshot {
+                    key,
+                    read_locks: data.read_locks,
+                    ref_locks: data.ref_locks,
+                    write_locked: data.is_write_locked,
+                    delete_locked: data.is_delete_locked,
+                    waiters: data.waiters,
+                }
+            })
+            .filter(|snapshot| snapshot.read_locks > 0 || snapshot.ref_locks > 0 || snapshot.write_locked ||
+                               snapshot.delete_locked || snapshot.waiters > 0)
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.key);
+        snapshots
+    }
+
+    #[cfg(test)]
+    pub(crate) fn ref_lock_count(&self, key: LockKey) -> usize {
+        let inner = self.0.lock();
+        inner.locks.get(&key).map(|info| ignore_poison(info.data.lock()).ref_locks).unwrap_or(0)
+    }
+
+    /// Frees the chain starting at `first_cluster`, which must not be 0, when
+    /// its last lock is released. Returns `false` if the chain isn't locked,
+    /// in which case the caller has to free it itself.
+    pub(crate) fn free_when_released(&self, fat: SharedFat, first_cluster: u32) -> bool {
+        assert_ne!(first_cluster, 0, "an empty chain can't be freed");
+        let key = LockKey::Cluster(first_cluster);
+        let mut inner = self.0.lock();
+        let is_locked = inner.locks.get(&key).map_or(false, |info| ignore_poison(info.data.lock()).is_locked());
+        if is_locked {
+            inner.pending_free.insert(key, (fat, first_cluster));
+        }
+        is_locked
+    }
+
+    /// Returns `true` if the file locked by `key` was removed while in use.
+    pub(crate) fn is_pending_free(&self, key: LockKey) -> bool {
+        self.0.lock().pending_free.contains_key(&key)
+    }
+
+    /// Marks the locks held on `key` as locks of a removed entry, see
+    /// `FSObjectGuard::is_removed`, and detaches them from `key`, so that an
+    /// entry that takes the place of the removed one is locked independently.
+    /// Meant for entries without clusters, whose keys are their places.
+    pub(crate) fn detach_removed(&self, key: LockKey) {
+        let mut inner = self.0.lock();
+        if let Some(lock_info) = inner.locks.remove(&key) {
+            ignore_poison(lock_info.data.lock()).is_removed = true;
+        }
+    }
+
     fn release(&self, guard: &mut FSObjectGuard) {
-        let cluster_to_free = if let Some(ref guard) = guard.0 {
-            let mut data = guard.lock_info.data.lock().unwrap();
+        let key_to_free = if let Some(ref guard) = guard.0 {
+            let mut data = ignore_poison(guard.lock_info.data.lock());
             data.remove_lock(guard.mode);
             guard.lock_info.condvar.notify_all();
-            if !data.is_locked() {
-                Some(guard.cluster)
+            // Detached locks aren't in `locks` anymore.
+            if !data.is_locked() && !data.is_removed {
+                Some(guard.key)
             } else {
                 None
             }
@@ -75,14 +233,23 @@ impl SharedLockManager {
         };
         guard.0 = None;
 
-        if let Some(cluster) = cluster_to_free {
-            let mut inner = self.0.lock();
-            if let Some(lock_info) = inner.locks.remove(&cluster) {
-                match Arc::try_unwrap(lock_info) {
-                    Ok(_) => {},
-                    Err(lock_info) => {
-                        inner.locks.insert(cluster, lock_info);
-                    },
+        if let Some(key) = key_to_free {
+            let pending = {
+                let mut inner = self.0.lock();
+                match inner.locks.remove(&key).map(Arc::try_unwrap) {
+                    Some(Ok(_)) | None => inner.pending_free.remove(&key),
+                    // Another thread is about to take the lock; the chain is
+                    // freed when that one is released.
+                    Some(Err(lock_info)) => {
+                        inner.locks.insert(key, lock_info);
+                        None
+                    }
+                }
+            };
+            // There is no one to report an error to.
+            if let Some((mut fat, first_cluster)) = pending {
+                if first_cluster != 0 {
+                    let _ = fat.free_chain(first_cluster);
                 }
             }
         }
@@ -95,12 +262,34 @@ struct SharedFSObjectLockInfo {
     condvar: Condvar,
 }
 
+impl SharedFSObjectLockInfo {
+    /// Waits until the lock state changes, at most `timeout` if given. The
+    /// caller has to check again whether it can take the lock.
+    fn wait<'a>(&self, mut data: MutexGuard<'a, FSObjectLockInfo>,
+                timeout: Option<Duration>) -> MutexGuard<'a, FSObjectLockInfo> {
+        data.waiters += 1;
+        let mut data = match timeout {
+            Some(timeout) => ignore_poison(self.condvar.wait_timeout(data, timeout)).0,
+            None => ignore_poison(self.condvar.wait(data)),
+        };
+        data.waiters -= 1;
+        data
+    }
+}
+
 #[derive(Default, Debug)]
 struct FSObjectLockInfo {
     ref_locks: usize,
     read_locks: usize,
     is_write_locked: bool,
     is_delete_locked: bool,
+    /// A reader is waiting in `FSObjectGuard::upgrade`.
+    is_upgrade_pending: bool,
+    /// The number of threads blocked in `SharedFSObjectLockInfo::wait`.
+    waiters: usize,
+    /// The entry was removed while locked, see
+    /// `SharedLockManager::detach_removed`.
+    is_removed: bool,
 }
 
 impl FSObjectLockInfo {
@@ -134,6 +323,22 @@ impl FSObjectLockInfo {
         true
     }
 
+    /// Turns the only read lock into a write lock.
+    fn try_upgrade(&mut self) -> bool {
+        if self.read_locks != 1 {
+            return false;
+        }
+        self.read_locks = 0;
+        self.is_write_locked = true;
+        true
+    }
+
+    fn downgrade(&mut self) {
+        assert!(self.is_write_locked, "downgrade without a write lock");
+        self.is_write_locked = false;
+        self.read_locks += 1;
+    }
+
     fn remove_lock(&mut self, mode: LockMode) {
         match mode {
             LockMode::Read => {
@@ -165,7 +370,7 @@ impl FSObjectLockInfo {
 
 pub struct FSObjectValidGuard {
     lock_manager: SharedLockManager,
-    cluster: u32,
+    key: LockKey,
     lock_info: Arc<SharedFSObjectLockInfo>,
     mode: LockMode,
 }
@@ -190,6 +395,80 @@ impl FSObjectGuard {
     pub fn mode(&self) -> Option<LockMode> {
         self.0.as_ref().map(|g| g.mode)
     }
+    /// Returns `true` if the locked entry was removed while locked, see
+    /// `SharedLockManager::detach_removed`.
+    pub fn is_removed(&self) -> bool {
+        self.0.as_ref().map_or(false, |g| ignore_poison(g.lock_info.data.lock()).is_removed)
+    }
+
+    fn valid_guard(&mut self, mode: LockMode) -> io::Result<&mut FSObjectValidGuard> {
+        match self.0 {
+            Some(ref mut guard) if guard.mode == mode => Ok(guard),
+            Some(_) => Err(Error::InvalidInput { what: "lock has the wrong mode" }.into()),
+            None => Err(Error::InvalidInput { what: "lock was released" }.into()),
+        }
+    }
+
+    /// Moves the lock to `key`, keeping its mode. Fails with `Busy` if `key`
+    /// is locked in a conflicting mode, in which case the lock stays as it
+    /// was.
+    pub fn rekey(&mut self, key: LockKey) -> io::Result<()> {
+        let (lock_manager, mode) = match self.0 {
+            Some(ref guard) if guard.key == key => return Ok(()),
+            Some(ref guard) => (guard.lock_manager.clone(), guard.mode),
+            None => return Err(Error::InvalidInput { what: "lock was released" }.into()),
+        };
+        *self = lock_manager.try_lock(key, mode).ok_or(Error::Busy)?;
+        Ok(())
+    }
+
+    /// Turns a read lock into a write lock. Fails with `Busy` if someone
+    /// else holds a read lock too.
+    ///
+    /// Prefer this over `upgrade`: the caller keeps its read lock either way
+    /// and can release it to let the other readers progress.
+    pub fn try_upgrade(&mut self) -> io::Result<()> {
+        let guard = self.valid_guard(LockMode::Read)?;
+        if !ignore_poison(guard.lock_info.data.lock()).try_upgrade() {
+            return Err(Error::Busy.into());
+        }
+        guard.mode = LockMode::Write;
+        Ok(())
+    }
+
+    /// Turns a read lock into a write lock, waiting until all the other read
+    /// locks are released.
+    ///
+    /// Two readers that upgrade at the same time would wait for each other
+    /// forever. Only one reader can wait here: the second one fails with
+    /// `Busy` instead, and must release its read lock for the first one to
+    /// proceed.
+    pub fn upgrade(&mut self) -> io::Result<()> {
+        let guard = self.valid_guard(LockMode::Read)?;
+        {
+            let lock_info = &guard.lock_info;
+            let mut data = ignore_poison(lock_info.data.lock());
+            if data.is_upgrade_pending {
+                return Err(Error::Busy.into());
+            }
+            data.is_upgrade_pending = true;
+            while !data.try_upgrade() {
+                data = lock_info.wait(data, None);
+            }
+            data.is_upgrade_pending = false;
+        }
+        guard.mode = LockMode::Write;
+        Ok(())
+    }
+
+    /// Turns a write lock into a read lock, letting other readers in.
+    pub fn downgrade(&mut self) -> io::Result<()> {
+        let guard = self.valid_guard(LockMode::Write)?;
+        ignore_poison(guard.lock_info.data.lock()).downgrade();
+        guard.lock_info.condvar.notify_all();
+        guard.mode = LockMode::Read;
+        Ok(())
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -206,7 +485,7 @@ fn test_locks(locks: &[(LockMode, bool)]) {
 
     let mut locks_vec = Vec::new();
     for &(lock_mode, result) in locks {
-        let lock = manager.try_lock(42, lock_mode);
+        let lock = manager.try_lock(LockKey::Cluster(42), lock_mode);
         assert_eq!(lock.is_some(), result);
         locks_vec.push(lock);
     }
@@ -240,27 +519,27 @@ fn test_all_locks() {
 fn test_unlock1() {
     let manager = SharedLockManager::new();
     {
-        let lock3 = manager.try_lock(42, LockMode::Write);
+        let lock3 = manager.try_lock(LockKey::Cluster(42), LockMode::Write);
         assert!(lock3.is_some());
 
-        let lock1 = manager.try_lock(42, LockMode::Read);
+        let lock1 = manager.try_lock(LockKey::Cluster(42), LockMode::Read);
         assert!(lock1.is_none());
     }
 
-    let lock2 = manager.try_lock(42, LockMode::Read);
+    let lock2 = manager.try_lock(LockKey::Cluster(42), LockMode::Read);
     assert!(lock2.is_some());
 }
 
 #[test]
 fn test_basic3() {
     let manager = SharedLockManager::new();
-    let lock1 = manager.try_lock(42, LockMode::Read);
+    let lock1 = manager.try_lock(LockKey::Cluster(42), LockMode::Read);
     assert!(lock1.is_some());
 
-    let lock2 = manager.try_lock(42, LockMode::Read);
+    let lock2 = manager.try_lock(LockKey::Cluster(42), LockMode::Read);
     assert!(lock2.is_some());
 
-    let lock3 = manager.try_lock(43, LockMode::Write);
+    let lock3 = manager.try_lock(LockKey::Cluster(43), LockMode::Write);
     assert!(lock3.is_some());
 }
 
@@ -272,7 +551,7 @@ fn test_threaded1() {
 
     let manager_copy = manager.clone();
     thread::spawn(move|| {
-        let lock = manager_copy.try_lock(42, LockMode::Write);
+        let lock = manager_copy.try_lock(LockKey::Cluster(42), LockMode::Write);
         assert!(lock.is_some());
 
         thread::sleep(Duration::from_millis(200));
@@ -280,10 +559,10 @@ fn test_threaded1() {
 
     thread::sleep(Duration::from_millis(100));
 
-    let lock = manager.try_lock(42, LockMode::Read);
+    let lock = manager.try_lock(LockKey::Cluster(42), LockMode::Read);
     assert!(lock.is_none());
 
-    let _lock = manager.lock(42, LockMode::Read);
+    let _lock = manager.lock(LockKey::Cluster(42), LockMode::Read);
 }
 
 #[test]
@@ -294,7 +573,7 @@ fn test_threaded2() {
 
     let manager_copy = manager.clone();
     thread::spawn(move|| {
-        let lock = manager_copy.try_lock(42, LockMode::Write);
+        let lock = manager_copy.try_lock(LockKey::Cluster(42), LockMode::Write);
         assert!(lock.is_some());
 
         thread::sleep(Duration::from_millis(200));
@@ -302,19 +581,19 @@ fn test_threaded2() {
 
     thread::sleep(Duration::from_millis(100));
 
-    let lock = manager.try_lock(42, LockMode::Read);
+    let lock = manager.try_lock(LockKey::Cluster(42), LockMode::Read);
     assert!(lock.is_none());
 
     thread::sleep(Duration::from_millis(200));
 
-    let lock = manager.try_lock(42, LockMode::Read);
+    let lock = manager.try_lock(LockKey::Cluster(42), LockMode::Read);
     assert!(lock.is_some());
 }
 
 
 #[test]
 fn test_hash_map_cleanup1() {
-    let id = 42;
+    let id = LockKey::Cluster(42);
     let manager = SharedLockManager::new();
     let lock1 = manager.try_lock(id, LockMode::Read);
     assert!(lock1.is_some());
@@ -326,7 +605,7 @@ fn test_hash_map_cleanup1() {
 
 #[test]
 fn test_hash_map_cleanup2() {
-    let id = 42;
+    let id = LockKey::Cluster(42);
     let manager = SharedLockManager::new();
     let lock1 = manager.try_lock(id, LockMode::Read);
     assert!(lock1.is_some());
@@ -342,3 +621,170 @@ fn test_hash_map_cleanup2() {
     drop(lock2);
     assert!(!manager.0.lock().locks.contains_key(&id));
 }
+
+#[test]
+fn test_lock_timeout() {
+    use std::thread;
+
+    let manager = SharedLockManager::new();
+
+    let manager_copy = manager.clone();
+    let holder = thread::spawn(move|| {
+        let lock = manager_copy.try_lock(LockKey::Cluster(42), LockMode::Write);
+        assert!(lock.is_some());
+
+        thread::sleep(Duration::from_millis(150));
+    });
+
+    thread::sleep(Duration::from_millis(20));
+
+    let start = Instant::now();
+    let lock = manager.lock_timeout(LockKey::Cluster(42), LockMode::Read, Duration::from_millis(50));
+    let elapsed = start.elapsed();
+    assert!(lock.is_none());
+    assert!(elapsed >= Duration::from_millis(50));
+    assert!(elapsed < Duration::from_millis(130));
+
+    let start = Instant::now();
+    let lock = manager.lock_timeout(LockKey::Cluster(42), LockMode::Read, Duration::from_millis(300));
+    let elapsed = start.elapsed();
+    assert!(lock.is_some());
+    assert!(elapsed < Duration::from_millis(300));
+
+    holder.join().unwrap();
+}
+
+#[test]
+fn test_lock_timeout_free() {
+    let manager = SharedLockManager::new();
+    let start = Instant::now();
+    let lock = manager.lock_timeout(LockKey::Cluster(42), LockMode::Write, Duration::from_secs(10));
+    assert!(lock.is_some());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(manager.lock_timeout(LockKey::Cluster(42), LockMode::Write, Duration::from_millis(0)).is_none());
+}
+
+#[test]
+fn test_upgrade_alone() {
+    let manager = SharedLockManager::new();
+    let mut lock = manager.try_lock(LockKey::Cluster(42), LockMode::Read).unwrap();
+    lock.try_upgrade().unwrap();
+    assert_eq!(lock.mode(), Some(LockMode::Write));
+    assert!(manager.try_lock(LockKey::Cluster(42), LockMode::Read).is_none());
+    assert!(lock.try_upgrade().is_err());
+
+    lock.downgrade().unwrap();
+    assert_eq!(lock.mode(), Some(LockMode::Read));
+    lock.upgrade().unwrap();
+    assert_eq!(lock.mode(), Some(LockMode::Write));
+
+    drop(lock);
+    assert!(!manager.0.lock().locks.contains_key(&LockKey::Cluster(42)));
+}
+
+#[test]
+fn test_upgrade_with_other_reader() {
+    let manager = SharedLockManager::new();
+    let mut lock1 = manager.try_lock(LockKey::Cluster(42), LockMode::Read).unwrap();
+    let _ref = manager.try_lock(LockKey::Cluster(42), LockMode::Ref).unwrap();
+    let lock2 = manager.try_lock(LockKey::Cluster(42), LockMode::Read).unwrap();
+    assert_eq!(lock1.try_upgrade().unwrap_err().kind(), ::std::io::ErrorKind::WouldBlock);
+    assert_eq!(lock1.mode(), Some(LockMode::Read));
+
+    drop(lock2);
+    lock1.try_upgrade().unwrap();
+    assert_eq!(lock1.mode(), Some(LockMode::Write));
+}
+
+#[test]
+fn test_upgrade_waits() {
+    use std::thread;
+
+    let manager = SharedLockManager::new();
+    let mut lock1 = manager.try_lock(LockKey::Cluster(42), LockMode::Read).unwrap();
+    let mut lock2 = manager.try_lock(LockKey::Cluster(42), LockMode::Read).unwrap();
+
+    let reader = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        // The first upgrader is waiting, a second one would deadlock.
+        assert_eq!(lock2.upgrade().unwrap_err().kind(), ::std::io::ErrorKind::WouldBlock);
+        drop(lock2);
+    });
+
+    lock1.upgrade().unwrap();
+    assert_eq!(lock1.mode(), Some(LockMode::Write));
+    reader.join().unwrap();
+}
+
+#[test]
+fn test_downgrade_lets_readers_in() {
+    let manager = SharedLockManager::new();
+    let mut lock = manager.try_lock(LockKey::Cluster(42), LockMode::Write).unwrap();
+    assert!(manager.try_lock(LockKey::Cluster(42), LockMode::Read).is_none());
+    assert!(lock.upgrade().is_err());
+
+    lock.downgrade().unwrap();
+    let reader = manager.try_lock(LockKey::Cluster(42), LockMode::Read);
+    assert!(reader.is_some());
+    assert!(manager.try_lock(LockKey::Cluster(42), LockMode::Write).is_none());
+    assert!(lock.downgrade().is_err());
+
+    drop(reader);
+    drop(lock);
+    assert!(manager.try_lock(LockKey::Cluster(42), LockMode::Write).is_some());
+}
+
+#[test]
+fn test_snapshot() {
+    use std::thread;
+
+    let manager = SharedLockManager::new();
+    assert!(manager.snapshot().is_empty());
+
+    let _read1 = manager.try_lock(LockKey::Cluster(42), LockMode::Read).unwrap();
+    let _read2 = manager.try_lock(LockKey::Cluster(42), LockMode::Read).unwrap();
+    let _ref = manager.try_lock(LockKey::Cluster(42), LockMode::Ref).unwrap();
+    let write = manager.try_lock(LockKey::Cluster(7), LockMode::Write).unwrap();
+    let slot = LockKey::DirSlot { dir_cluster: 2, index: 5 };
+    let _delete = manager.try_lock(slot, LockMode::Delete).unwrap();
+
+    let manager_copy = manager.clone();
+    let waiter = thread::spawn(move || {
+        drop(manager_copy.lock(LockKey::Cluster(7), LockMode::Read));
+    });
+    while manager.snapshot()[0].waiters == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let snapshot = manager.snapshot();
+    assert_eq!(snapshot, vec![
+        LockSnapshot { key: LockKey::Cluster(7), read_locks: 0, ref_locks: 0, write_locked: true,
+                       delete_locked: false, waiters: 1 },
+        LockSnapshot { key: LockKey::Cluster(42), read_locks: 2, ref_locks: 1, write_locked: false,
+                       delete_locked: false, waiters: 0 },
+        LockSnapshot { key: slot, read_locks: 0, ref_locks: 0, write_locked: false,
+                       delete_locked: true, waiters: 0 },
+    ]);
+    let row = format!("{:?}", snapshot[1]);
+    assert!(row.starts_with("Cluster(42) "));
+    assert!(row.contains("read:   2  ref:   1  write: false"));
+    assert_eq!(row.len(), format!("{:?}", snapshot[0]).len());
+
+    drop(write);
+    waiter.join().unwrap();
+    assert_eq!(manager.snapshot().len(), 2);
+}
+
+#[test]
+fn test_rekey() {
+    let slot = LockKey::DirSlot { dir_cluster: 2, index: 3 };
+    let manager = SharedLockManager::new();
+    let mut lock = manager.try_lock(slot, LockMode::Write).unwrap();
+    lock.rekey(LockKey::Cluster(42)).unwrap();
+    assert!(manager.try_lock(LockKey::Cluster(42), LockMode::Read).is_none());
+    assert!(!manager.0.lock().locks.contains_key(&slot));
+
+    let _reader = manager.try_lock(LockKey::Cluster(43), LockMode::Read).unwrap();
+    assert!(lock.rekey(LockKey::Cluster(43)).is_err());
+    assert!(manager.try_lock(LockKey::Cluster(42), LockMode::Read).is_none());
+}
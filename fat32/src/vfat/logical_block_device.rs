@@ -1,6 +1,6 @@
 use traits::BlockDevice;
+use traits::check_range;
 use std::io;
-use std::cmp::min;
 use arc_mutex::ArcMutex;
 
 pub struct LogicalBlockDevice {
@@ -9,9 +9,13 @@ pub struct LogicalBlockDevice {
 }
 
 impl LogicalBlockDevice {
+    /// Returns a device with sectors of `logical_sector_size` bytes on top of
+    /// `source`. The source sectors may be larger than the logical ones, in
+    /// which case logical sector writes read, patch and write back a source
+    /// sector.
     pub fn new(source: Box<BlockDevice>, logical_sector_size: u64) -> Self {
-        assert!(logical_sector_size >= source.sector_size());
-        assert_eq!(logical_sector_size % source.sector_size(), 0);
+        let source_sector_size = source.sector_size();
+        assert!(logical_sector_size % source_sector_size == 0 || source_sector_size % logical_sector_size == 0);
 
         LogicalBlockDevice {
             source, logical_sector_size
@@ -24,25 +28,77 @@ impl BlockDevice for LogicalBlockDevice {
         self.logical_sector_size
     }
 
+    fn num_sectors(&self) -> u64 {
+        self.source.num_sectors().saturating_mul(self.source.sector_size()) / self.logical_sector_size
+    }
+
     fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), io::Error> {
-        let size = min(buf.len(), self.sector_size() as usize);
-        let buf2 = &mut buf[..size];
+        let size = self.sector_size() as usize;
+        if buf.len() < size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
+        }
         let source_offset = sector * self.sector_size();
-        self.source.read_by_offset(source_offset, buf2)?;
+        self.source.read_by_offset(source_offset, &mut buf[..size])?;
         Ok(())
     }
 
     fn write_sector(&mut self, sector: u64, buf: &[u8]) -> Result<(), io::Error> {
-        let size = min(buf.len(), self.sector_size() as usize);
-        let buf2 = &buf[..size];
+        let size = self.sector_size() as usize;
+        if buf.len() < size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is shorter than a sector"));
+        }
         let source_offset = sector * self.sector_size();
-        self.source.write_by_offset(source_offset, buf2)?;
+        self.source.write_by_offset(source_offset, &buf[..size])?;
         Ok(())
     }
 
+    fn read_sectors(&self, first: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        self.source.read_by_offset(first * self.sector_size(), buf)
+    }
+
+    fn write_sectors(&mut self, first: u64, buf: &[u8]) -> Result<(), io::Error> {
+        let source_offset = first * self.sector_size();
+        self.source.write_by_offset(source_offset, buf)
+    }
+
+    /// Leaves the bounce buffers to the source, whose sectors may be
+    /// smaller, so that a range aligned to them is read straight into `buf`.
+    fn read_by_offset(&self, offset_bytes: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self, offset_bytes, buf.len())?;
+        self.source.read_by_offset(offset_bytes, buf)
+    }
+
     fn sync(&mut self) -> io::Result<()> {
         self.source.sync()
     }
+
+    /// Syncs the source sectors that overlap the logical sectors.
+    fn sync_range(&mut self, sector: u64, count: u64) -> io::Result<()> {
+        let source_sector_size = self.source.sector_size();
+        let first = sector * self.logical_sector_size / source_sector_size;
+        let end = ((sector + count) * self.logical_sector_size + source_sector_size - 1) / source_sector_size;
+        self.source.sync_range(first, end - first)
+    }
+
+    fn barrier(&mut self) -> io::Result<()> {
+        self.source.barrier()
+    }
+
+    /// Discards the source sectors that lie entirely within the logical
+    /// sectors.
+    fn discard(&mut self, sector: u64, count: u64) -> io::Result<()> {
+        let source_sector_size = self.source.sector_size();
+        let first = (sector * self.logical_sector_size + source_sector_size - 1) / source_sector_size;
+        let end = (sector + count) * self.logical_sector_size / source_sector_size;
+        if end <= first {
+            return Ok(());
+        }
+        self.source.discard(first, end - first)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.source.is_read_only()
+    }
 }
 
 pub type SharedLogicalBlockDevice = ArcMutex<LogicalBlockDevice>;
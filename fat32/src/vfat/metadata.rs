@@ -1,46 +1,171 @@
+use std::fmt;
+
 use traits::{Date, DateTime, Metadata};
+use vfat::time::fat_epoch;
 
-/// File attributes as represented in FAT32 on-disk structures.
+/// File attributes as represented in FAT32 on-disk structures. They are
+/// built from the constants or with the `with_*` and `without_*` methods,
+/// e.g. `Attributes::HIDDEN.with_archive()`.
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
-pub(crate) struct Attributes(pub(crate) u8);
+pub struct Attributes(pub(crate) u8);
 
 impl Attributes {
+    pub const READ_ONLY: Attributes = Attributes(0x01);
+    pub const HIDDEN: Attributes = Attributes(0x02);
+    pub const SYSTEM: Attributes = Attributes(0x04);
+    pub const VOLUME_ID: Attributes = Attributes(0x08);
+    pub const DIRECTORY: Attributes = Attributes(0x10);
+    pub const ARCHIVE: Attributes = Attributes(0x20);
+
     pub fn new(is_dir: bool) -> Self {
         if is_dir {
-            Attributes(0x10)
+            Attributes::DIRECTORY
         } else {
             Attributes(0x00)
         }
     }
 
+    /// Returns `true` if all the bits set in `other` are set in `self`.
+    pub fn contains(&self, other: Attributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn with(self, other: Attributes) -> Self {
+        Attributes(self.0 | other.0)
+    }
+
+    fn without(self, other: Attributes) -> Self {
+        Attributes(self.0 & !other.0)
+    }
+
     pub fn is_read_only(&self) -> bool {
-        (self.0 & 0x01) != 0
+        self.contains(Attributes::READ_ONLY)
     }
 
     pub fn is_hidden(&self) -> bool {
-        (self.0 & 0x02) != 0
+        self.contains(Attributes::HIDDEN)
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.contains(Attributes::SYSTEM)
     }
 
     pub fn is_dir(&self) -> bool {
-        (self.0 & 0x10) != 0
+        self.contains(Attributes::DIRECTORY)
     }
 
     pub fn is_volume_id(&self) -> bool {
-        (self.0 & 0x08) != 0
+        self.contains(Attributes::VOLUME_ID)
+    }
+
+    pub fn is_archive(&self) -> bool {
+        self.contains(Attributes::ARCHIVE)
+    }
+
+    pub fn with_read_only(self) -> Self {
+        self.with(Attributes::READ_ONLY)
+    }
+
+    pub fn without_read_only(self) -> Self {
+        self.without(Attributes::READ_ONLY)
+    }
+
+    pub fn with_hidden(self) -> Self {
+        self.with(Attributes::HIDDEN)
+    }
+
+    pub fn without_hidden(self) -> Self {
+        self.without(Attributes::HIDDEN)
+    }
+
+    pub fn with_system(self) -> Self {
+        self.with(Attributes::SYSTEM)
+    }
+
+    pub fn without_system(self) -> Self {
+        self.without(Attributes::SYSTEM)
+    }
+
+    pub fn with_volume_id(self) -> Self {
+        self.with(Attributes::VOLUME_ID)
+    }
+
+    pub fn without_volume_id(self) -> Self {
+        self.without(Attributes::VOLUME_ID)
+    }
+
+    pub fn with_dir(self) -> Self {
+        self.with(Attributes::DIRECTORY)
+    }
+
+    pub fn without_dir(self) -> Self {
+        self.without(Attributes::DIRECTORY)
+    }
+
+    pub fn with_archive(self) -> Self {
+        self.with(Attributes::ARCHIVE)
+    }
+
+    pub fn without_archive(self) -> Self {
+        self.without(Attributes::ARCHIVE)
+    }
+}
+
+impl From<u8> for Attributes {
+    fn from(raw: u8) -> Attributes {
+        Attributes(raw)
+    }
+}
+
+impl From<Attributes> for u8 {
+    fn from(attributes: Attributes) -> u8 {
+        attributes.0
+    }
+}
+
+/// Formats the attributes that are set as letters in the order "RHSVDA",
+/// e.g. "HA" for a hidden file with the archive bit set. Bits without a
+/// letter, which FAT doesn't define, are left out.
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let letters = [(Attributes::READ_ONLY, 'R'), (Attributes::HIDDEN, 'H'), (Attributes::SYSTEM, 'S'),
+                       (Attributes::VOLUME_ID, 'V'), (Attributes::DIRECTORY, 'D'), (Attributes::ARCHIVE, 'A')];
+        for &(attribute, letter) in letters.iter() {
+            if self.contains(attribute) {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
     }
 }
 
-/// Metadata for a directory entry.
+/// Metadata for a directory entry. A timestamp is `None` when the stored
+/// value is zero, which some writers use to mean "unknown", or otherwise
+/// not a valid date and time. The `Metadata` accessors report such
+/// timestamps as `fat_epoch()`; the `*_opt` ones tell them apart.
 #[derive(Debug, Clone)]
 pub struct VFatMetadata {
     pub(crate) attributes: Attributes,
-    pub(crate) created: DateTime,
-    pub(crate) accessed: Date,
-    pub(crate) modified: DateTime,
+    pub(crate) created: Option<DateTime>,
+    pub(crate) accessed: Option<Date>,
+    pub(crate) modified: Option<DateTime>,
     pub(crate) first_cluster: u32,
     pub(crate) size: u32,
 }
 
+impl VFatMetadata {
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    /// The first cluster of the entry's cluster chain, 0 if it has none.
+    /// This is specific to FAT, for consumers such as boot loaders that
+    /// read the chain themselves.
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+}
+
 impl Metadata for VFatMetadata {
     fn is_dir(&self) -> bool {
         self.attributes.is_dir()
@@ -55,14 +180,31 @@ impl Metadata for VFatMetadata {
     }
 
     fn created(&self) -> DateTime {
-        self.created
+        self.created.unwrap_or_else(fat_epoch)
     }
 
-    fn accessed(&self) -> DateTime {
-        self.accessed.and_hms(0, 0, 0)
+    fn accessed(&self) -> Date {
+        self.accessed.unwrap_or_else(|| fat_epoch().date())
     }
 
     fn modified(&self) -> DateTime {
+        self.modified.unwrap_or_else(fat_epoch)
+    }
+
+    fn created_opt(&self) -> Option<DateTime> {
+        self.created
+    }
+
+    fn accessed_opt(&self) -> Option<Date> {
+        self.accessed
+    }
+
+    fn modified_opt(&self) -> Option<DateTime> {
         self.modified
     }
+
+    /// The size stored in the entry, which is 0 for directories.
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
 }
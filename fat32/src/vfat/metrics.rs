@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "trace")]
+use std::sync::Mutex;
+
+#[cfg(feature = "trace")]
+use arc_mutex::ignore_poison;
+
+/// A single low-level operation of a file system, as counted in `Metrics`
+/// and passed to the trace hook.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// `count` sectors of the data region were read, starting at `sector`.
+    SectorsRead { sector: u64, count: u64 },
+    /// `count` sectors of the data region were written, starting at `sector`.
+    SectorsWritten { sector: u64, count: u64 },
+    /// `len` bytes at byte `offset` of a FAT were read from the device.
+    FatRead { offset: u64, len: usize },
+    /// `len` bytes at byte `offset` of a FAT were written to the device.
+    FatWritten { offset: u64, len: usize },
+    /// `count` clusters were allocated, starting at `first_cluster`.
+    ClustersAllocated { first_cluster: u32, count: u32 },
+    /// `count` clusters were freed, the first of them `first_cluster`.
+    ClustersFreed { first_cluster: u32, count: u32 },
+}
+
+/// The number of operations a file system performed since it was mounted or
+/// the metrics were last reset, as returned by `VFatFileSystem::metrics`.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    /// Sectors of the data region read, i.e. of files and directories.
+    pub sectors_read: usize,
+    /// Sectors of the data region written.
+    pub sectors_written: usize,
+    /// FAT accesses that read the device, of single entries or whole
+    /// sectors. Lookups served by the FAT cache aren't counted.
+    pub fat_reads: usize,
+    /// FAT accesses that wrote the device; one for each copy of the FAT.
+    pub fat_writes: usize,
+    pub clusters_allocated: usize,
+    pub clusters_freed: usize,
+    /// Sector lookups served by the device cache, or `None` if the file
+    /// system was mounted with `MountOptions::disable_cache`.
+    pub cache_hits: Option<usize>,
+}
+
+/// The counters behind `Metrics`, shared by the parts of a file system.
+#[derive(Default)]
+pub(crate) struct Counters {
+    sectors_read: AtomicUsize,
+    sectors_written: AtomicUsize,
+    fat_reads: AtomicUsize,
+    fat_writes: AtomicUsize,
+    clusters_allocated: AtomicUsize,
+    clusters_freed: AtomicUsize,
+    /// The cache hits at the last reset, as the cache counts from the mount.
+    cache_hits_base: AtomicUsize,
+    #[cfg(feature = "trace")]
+    trace_hook: Mutex<Option<fn(Event)>>,
+}
+
+impl Counters {
+    /// Counts `event` and passes it to the trace hook, if there is one.
+    pub(crate) fn record(&self, event: Event) {
+        let (counter, amount) = match event {
+            Event::SectorsRead { count, .. } => (&self.sectors_read, count as usize),
+            Event::SectorsWritten { count, .. } => (&self.sectors_written, count as usize),
+            Event::FatRead { .. } => (&self.fat_reads, 1),
+            Event::FatWritten { .. } => (&self.fat_writes, 1),
+            Event::ClustersAllocated { count, .. } => (&self.clusters_allocated, count as usize),
+            Event::ClustersFreed { count, .. } => (&self.clusters_freed, count as usize),
+        };
+        counter.fetch_add(amount, Ordering::Relaxed);
+        #[cfg(feature = "trace")]
+        {
+            let hook = *ignore_poison(self.trace_hook.lock());
+            if let Some(hook) = hook {
+                hook(event);
+            }
+        }
+    }
+
+    /// Returns the current values, with `cache_hits` being the hits the
+    /// device cache counted since the mount.
+    pub(crate) fn snapshot(&self, cache_hits: Option<usize>) -> Metrics {
+        Metrics {
+            sectors_read: self.sectors_read.load(Ordering::Relaxed),
+            sectors_written: self.sectors_written.load(Ordering::Relaxed),
+            fat_reads: self.fat_reads.load(Ordering::Relaxed),
+            fat_writes: self.fat_writes.load(Ordering::Relaxed),
+            clusters_allocated: self.clusters_allocated.load(Ordering::Relaxed),
+            clusters_freed: self.clusters_freed.load(Ordering::Relaxed),
+            cache_hits: cache_hits.map(|hits| hits - self.cache_hits_base.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Sets all counters to zero. `cache_hits` are the hits the device cache
+    /// counted since the mount.
+    pub(crate) fn reset(&self, cache_hits: Option<usize>) {
+        for counter in &[&self.sectors_read, &self.sectors_written, &self.fat_reads, &self.fat_writes,
+                         &self.clusters_allocated, &self.clusters_freed] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.cache_hits_base.store(cache_hits.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "trace")]
+    pub(crate) fn set_trace_hook(&self, hook: Option<fn(Event)>) {
+        *ignore_poison(self.trace_hook.lock()) = hook;
+    }
+}
@@ -8,12 +8,40 @@ pub(crate) mod entry;
 pub(crate) mod metadata;
 pub(crate) mod logical_block_device;
 pub(crate) mod cluster_chain;
+pub(crate) mod data_region;
 pub(crate) mod lock_manager;
+pub(crate) mod short_name;
+pub(crate) mod fs_info;
+pub(crate) mod check;
+pub(crate) mod defrag;
+pub(crate) mod cluster_bitmap;
+pub(crate) mod time;
+pub(crate) mod node;
+pub mod resize;
+pub(crate) mod populate;
+pub(crate) mod metrics;
+pub(crate) mod layout;
 
-pub use self::ebpb::BiosParameterBlock;
+pub use self::ebpb::{BiosParameterBlock, BiosParameterBlockBuilder};
 pub use self::file::VFatFile;
-pub use self::dir::VFatDir;
-pub use self::error::Error;
-pub use self::vfat::VFatFileSystem;
-pub use self::entry::VFatEntry;
+pub use self::dir::{VFatDir, DirOptions};
+pub use self::error::{Error, ForeignFs, UnmountError, is_storage_full};
+pub use self::vfat::{VFatFileSystem, MountOptions, MountReport, RawWriteToken};
+pub use self::entry::{VFatEntry, NameSource};
+pub use self::metadata::{Attributes, VFatMetadata};
+pub use self::node::VFatNode;
+pub use self::lock_manager::{LockKey, LockSnapshot, LockWait};
+pub use self::short_name::ShortName;
+pub use self::fs_info::FsInfo;
+pub use self::fat::{FatCacheMode, FatKind};
+pub use self::cluster_bitmap::{ClusterBitmap, FreeRuns};
+pub use self::cluster_chain::BadRange;
+pub use self::check::{LostClusterReport, ReclaimMode, CrossLink, BrokenChain, ChainProblem};
+pub use self::check::{FsckReport, Finding, Severity, Location, Problem, RepairPolicy};
+pub use self::metrics::{Metrics, Event};
+pub use self::layout::VolumeLayout;
+pub use self::populate::{PopulateStats, populate_from_dir, extract_to_dir};
+pub use self::time::{TimeProvider, FixedTime, fat_epoch};
+#[cfg(feature = "clock")]
+pub use self::time::LocalTime;
 
@@ -0,0 +1,63 @@
+use std::io;
+use std::path::Path;
+
+use arc_mutex::ArcMutex;
+use traits::{Entry, FileOpenMode, FileSystem};
+use vfat::{VFatFile, VFatEntry, VFatFileSystem};
+use vfat::dir::SharedVFatDir;
+
+/// An open file or directory, for paths that may name either.
+pub enum VFatNode {
+    File(VFatFile),
+    Dir(SharedVFatDir),
+}
+
+impl VFatNode {
+    /// Opens the file or directory of `entry`. Files are opened with `mode`.
+    pub fn from_entry(entry: &VFatEntry, mode: FileOpenMode) -> io::Result<VFatNode> {
+        if entry.is_dir() {
+            entry.open_dir().map(VFatNode::Dir)
+        } else {
+            entry.open_file(mode).map(VFatNode::File)
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        match *self {
+            VFatNode::File(_) => true,
+            VFatNode::Dir(_) => false,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        !self.is_file()
+    }
+
+    /// The file, or `None` if this is a directory.
+    pub fn into_file(self) -> Option<VFatFile> {
+        match self {
+            VFatNode::File(file) => Some(file),
+            VFatNode::Dir(_) => None,
+        }
+    }
+
+    /// The directory, or `None` if this is a file.
+    pub fn into_dir(self) -> Option<SharedVFatDir> {
+        match self {
+            VFatNode::File(_) => None,
+            VFatNode::Dir(dir) => Some(dir),
+        }
+    }
+}
+
+impl ArcMutex<VFatFileSystem> {
+    /// Opens the file or directory at `path` with a single lookup. Files are
+    /// opened with `mode`.
+    pub fn open_node<P: AsRef<Path>>(&self, path: P, mode: FileOpenMode) -> io::Result<VFatNode> {
+        let path = path.as_ref();
+        if path == Path::new("/") {
+            return self.root().map(VFatNode::Dir);
+        }
+        VFatNode::from_entry(&self.get_entry(path)?, mode)
+    }
+}
@@ -0,0 +1,138 @@
+use std::cmp::max;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fallible_iterator::FallibleIterator;
+
+use arc_mutex::ArcMutex;
+use traits::{Dir, Entry, FileOpenMode, FileSystem, DateTime};
+use vfat::VFatFileSystem;
+use vfat::time::fat_epoch;
+
+/// What `populate_from_dir` or `extract_to_dir` copied.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PopulateStats {
+    /// The number of files copied.
+    pub files: usize,
+    /// The number of directories created, not counting the target itself.
+    pub dirs: usize,
+    /// The number of bytes of file contents copied.
+    pub bytes: u64,
+    /// The number of symbolic links that were skipped.
+    pub skipped_symlinks: usize,
+}
+
+/// Converts `time` to a `DateTime` in UTC. Times before 1980, which FAT
+/// can't represent, are clamped to `fat_epoch()`.
+pub(crate) fn date_time(time: SystemTime) -> DateTime {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    max(DateTime::from_timestamp(seconds as i64), fat_epoch())
+}
+
+/// Copies everything read from `source` to `target` in chunks of
+/// `chunk_size` bytes and returns the number of bytes copied.
+fn copy_chunked<R: Read, W: Write>(source: &mut R, target: &mut W, chunk_size: usize) -> io::Result<u64> {
+    let mut buf = vec![0; chunk_size];
+    let mut copied = 0;
+    loop {
+        let len = match source.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        target.write_all(&buf[..len])?;
+        copied += len as u64;
+    }
+}
+
+/// Copies the contents of the host directory `host_dir` into the directory
+/// `target` of `vfat`, which is created along with its parents if needed.
+///
+/// Subdirectories are mirrored and existing files are overwritten. The
+/// modification times of the host files and directories are kept. Symbolic
+/// links aren't followed but skipped and counted in the returned stats.
+pub fn populate_from_dir(vfat: &ArcMutex<VFatFileSystem>, host_dir: &Path, target: &Path)
+                         -> io::Result<PopulateStats> {
+    let mut stats = PopulateStats::default();
+    vfat.create_dir_all(target)?;
+    let chunk_size = vfat.lock().cluster_size_bytes() as usize;
+    populate_dir(vfat, host_dir, target, chunk_size, &mut stats)?;
+    Ok(stats)
+}
+
+fn populate_dir(vfat: &ArcMutex<VFatFileSystem>, host_dir: &Path, target: &Path, chunk_size: usize,
+                stats: &mut PopulateStats) -> io::Result<()> {
+    let mut host_entries = fs::read_dir(host_dir)?.collect::<io::Result<Vec<_>>>()?;
+    host_entries.sort_by_key(|entry| entry.file_name());
+    for host_entry in host_entries {
+        let host_path = host_entry.path();
+        let path = target.join(host_entry.file_name());
+        let metadata = fs::symlink_metadata(&host_path)?;
+        if metadata.file_type().is_symlink() {
+            stats.skipped_symlinks += 1;
+            continue;
+        } else if metadata.is_dir() {
+            vfat.create_dir_all(&path)?;
+            stats.dirs += 1;
+            populate_dir(vfat, &host_path, &path, chunk_size, stats)?;
+        } else {
+            let mut file = match vfat.create_file(&path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let mut file = vfat.open_file(&path, FileOpenMode::Write)?;
+                    file.set_len(0)?;
+                    file
+                }
+                Err(e) => return Err(e),
+            };
+            stats.bytes += copy_chunked(&mut fs::File::open(&host_path)?, &mut file, chunk_size)?;
+            // Dropping the file would swallow a failure to write its size.
+            file.flush()?;
+            stats.files += 1;
+        }
+        let modified = date_time(metadata.modified()?);
+        vfat.set_times(&path, modified.date(), modified)?;
+    }
+    Ok(())
+}
+
+/// Copies the contents of the directory `source` of `vfat` into the host
+/// directory `host_dir`, which is created along with its parents if needed.
+///
+/// Existing host files are overwritten. Hidden and system entries are
+/// copied as well; the volume label is not. Timestamps aren't carried over.
+pub fn extract_to_dir(vfat: &ArcMutex<VFatFileSystem>, source: &Path, host_dir: &Path)
+                      -> io::Result<PopulateStats> {
+    let mut stats = PopulateStats::default();
+    fs::create_dir_all(host_dir)?;
+    let chunk_size = vfat.lock().cluster_size_bytes() as usize;
+    extract_dir(vfat, source, host_dir, chunk_size, &mut stats)?;
+    Ok(stats)
+}
+
+fn extract_dir(vfat: &ArcMutex<VFatFileSystem>, source: &Path, host_dir: &Path, chunk_size: usize,
+               stats: &mut PopulateStats) -> io::Result<()> {
+    let entries = vfat.open_dir(source)?.entries()?.collect::<Vec<_>>()?;
+    for entry in entries {
+        let path = source.join(entry.name());
+        let host_path = host_dir.join(entry.name());
+        if entry.is_dir() {
+            if let Err(e) = fs::create_dir(&host_path) {
+                if e.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+            }
+            stats.dirs += 1;
+            drop(entry);
+            extract_dir(vfat, &path, &host_path, chunk_size, stats)?;
+        } else {
+            let mut file = entry.open_file(FileOpenMode::Read)?;
+            stats.bytes += copy_chunked(&mut file, &mut fs::File::create(&host_path)?, chunk_size)?;
+            stats.files += 1;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! Growing a FAT32 volume into space added at its end, e.g. after copying
+//! an image onto a larger card.
+
+use std::io;
+
+use byteorder::{LittleEndian, ByteOrder};
+
+use error::Error;
+use traits::BlockDevice;
+use vfat::BiosParameterBlock;
+use vfat::FsInfo;
+
+const CLEAN_SHUTDOWN_BIT: u32 = 0x08000000;
+
+/// Returns the smallest FAT size, in sectors, of at least `fat_size` that
+/// covers all the clusters of a volume of `total_sectors` sectors described
+/// by `ebpb`, along with the number of clusters.
+fn fit_fat_size(ebpb: &BiosParameterBlock, total_sectors: u64, mut fat_size: u64) -> (u64, u64) {
+    let bytes_per_sector = ebpb.bytes_per_logical_sector as u64;
+    let sectors_per_cluster = ebpb.logical_sectors_per_cluster as u64;
+    let reserved = ebpb.reserved_logical_sectors as u64;
+    let number_of_fats = ebpb.number_of_fats as u64;
+    loop {
+        let data_start = reserved + number_of_fats * fat_size;
+        let clusters = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+        let needed = ((clusters + 2) * 4 + bytes_per_sector - 1) / bytes_per_sector;
+        if needed <= fat_size {
+            return (fat_size, clusters);
+        }
+        fat_size = needed;
+    }
+}
+
+/// Grows the unmounted FAT32 volume on `device` to `new_total_sectors`
+/// logical sectors.
+///
+/// If the FATs have to grow to cover the new clusters, the data region moves
+/// up by the space they take: every allocated cluster is copied to its new
+/// location, so that cluster numbers and thus all chains and directory
+/// entries stay valid. The FATs are then extended with free entries and the
+/// EBPB, its backup and the FSInfo sector are updated. The operation must
+/// not be interrupted once it has started moving clusters.
+///
+/// # Errors
+///
+/// Refuses with `InvalidInput` if `new_total_sectors` is smaller than the
+/// volume or larger than the device, or if the volume would have too many
+/// clusters for FAT32. Refuses with `Corrupted` if the volume wasn't cleanly
+/// unmounted, since then the FAT can't be trusted to tell which clusters
+/// hold data. Nothing is changed in these cases.
+pub fn grow<T: BlockDevice>(device: &mut T, new_total_sectors: u32) -> io::Result<()> {
+    let mut ebpb = BiosParameterBlock::read_from(device).map_err(Error::from)?;
+    ebpb.validate().map_err(Error::from)?;
+    let bytes_per_sector = ebpb.bytes_per_logical_sector as u64;
+    let new_total_sectors = new_total_sectors as u64;
+    if new_total_sectors < ebpb.total_sectors() {
+        return Err(Error::InvalidInput { what: "a volume can only grow" }.into());
+    }
+    if new_total_sectors * bytes_per_sector > device.num_sectors() * device.sector_size() {
+        return Err(Error::InvalidInput { what: "the new size exceeds the device" }.into());
+    }
+    if new_total_sectors == ebpb.total_sectors() {
+        return Ok(());
+    }
+
+    let old_fat_size = ebpb.logical_sectors_per_fat as u64;
+    let fat_bytes = (old_fat_size * bytes_per_sector) as usize;
+    let old_clusters = ::std::cmp::min(ebpb.data_cluster_count(), (fat_bytes / 4) as u64 - 2);
+    let (new_fat_size, new_clusters) = fit_fat_size(&ebpb, new_total_sectors, old_fat_size);
+    if new_clusters > BiosParameterBlock::MAX_CLUSTER_COUNT {
+        return Err(Error::InvalidInput { what: "the volume would have too many clusters" }.into());
+    }
+    if new_clusters < old_clusters {
+        return Err(Error::InvalidInput { what: "the new size leaves fewer clusters" }.into());
+    }
+
+    let active_fat = if ebpb.mirroring_flags & 0x80 == 0 { 0 } else { (ebpb.mirroring_flags & 0xF) as u64 };
+    let reserved = ebpb.reserved_logical_sectors as u64;
+    let mut fat = vec![0u8; fat_bytes];
+    device.read_by_offset((reserved + active_fat * old_fat_size) * bytes_per_sector, &mut fat)?;
+    if LittleEndian::read_u32(&fat[4..8]) & CLEAN_SHUTDOWN_BIT == 0 {
+        return Err(Error::Corrupted { what: "the volume wasn't cleanly unmounted" }.into());
+    }
+
+    // Move the allocated clusters up, the last one first, as their new
+    // locations overlap the old ones of later clusters.
+    let old_data_start = ebpb.data_start_sector();
+    let new_data_start = reserved + ebpb.number_of_fats as u64 * new_fat_size;
+    let cluster_bytes = ebpb.logical_sectors_per_cluster as u64 * bytes_per_sector;
+    if new_data_start != old_data_start {
+        let mut buf = vec![0u8; cluster_bytes as usize];
+        for cluster in (2..old_clusters + 2).rev() {
+            let entry = LittleEndian::read_u32(&fat[cluster as usize * 4..]) & 0x0FFFFFFF;
+            // free and bad clusters hold no data
+            if entry == 0 || entry == 0x0FFFFFF7 {
+                continue;
+            }
+            let offset = (cluster - 2) * cluster_bytes;
+            device.read_by_offset(old_data_start * bytes_per_sector + offset, &mut buf)?;
+            device.write_by_offset(new_data_start * bytes_per_sector + offset, &buf)?;
+        }
+    }
+
+    // The entries past the old clusters are free, including the slack that
+    // didn't correspond to clusters before.
+    for byte in &mut fat[(old_clusters as usize + 2) * 4..] {
+        *byte = 0;
+    }
+    fat.resize((new_fat_size * bytes_per_sector) as usize, 0);
+    for index in 0..ebpb.number_of_fats as u64 {
+        device.write_by_offset((reserved + index * new_fat_size) * bytes_per_sector, &fat)?;
+    }
+
+    ebpb.total_logical_sectors = 0;
+    ebpb.large_total_logical_sectors = new_total_sectors as u32;
+    ebpb.logical_sectors_per_fat = new_fat_size as u32;
+    let added_clusters = (new_clusters - old_clusters) as u32;
+    let mut boot_sectors = vec![0];
+    if ebpb.backup_sector_location != 0 && ebpb.backup_sector_location != 0xFFFF {
+        boot_sectors.push(ebpb.backup_sector_location as u64);
+    }
+    let fs_info_location = match ebpb.fs_information_sector_location {
+        0 | 0xFFFF => None,
+        location => Some(location as u64),
+    };
+    for &boot_sector in &boot_sectors {
+        device.write_by_offset(boot_sector * bytes_per_sector, &ebpb.to_bytes())?;
+        let fs_info_sector = match fs_info_location {
+            Some(location) => boot_sector + location,
+            None => continue,
+        };
+        let mut sector = vec![0u8; bytes_per_sector as usize];
+        device.read_by_offset(fs_info_sector * bytes_per_sector, &mut sector)?;
+        let mut fs_info = FsInfo::parse(&sector);
+        if fs_info.free_count != FsInfo::UNKNOWN {
+            fs_info.free_count += added_clusters;
+            fs_info.write_into(&mut sector);
+            device.write_by_offset(fs_info_sector * bytes_per_sector, &sector)?;
+        }
+    }
+    device.sync()
+}
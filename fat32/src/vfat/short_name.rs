@@ -0,0 +1,130 @@
+use std::fmt;
+use std::io;
+
+use error::Error;
+
+/// An 8.3 short file name as stored in a regular directory entry.
+///
+/// The base name and the extension are stored upper case and padded with
+/// spaces, exactly as they appear on disk.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShortName {
+    name: [u8; 8],
+    ext: [u8; 3],
+}
+
+fn is_valid_short_name_char(c: u8) -> bool {
+    match c {
+        b'A'..=b'Z' | b'0'..=b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-' |
+        b'@' | b'^' | b'_' | b'`' | b'{' | b'}' | b'~' => true,
+        _ => false,
+    }
+}
+
+/// Returns `true` if `name` and `ext` as stored in a directory entry form a
+/// valid short name: a non-empty base name and an extension of valid
+/// characters, each padded at the end only. Characters outside of ASCII are
+/// accepted as they belong to the OEM code page, and so is the 0x05 that
+/// stands for a leading 0xE5. Padding with zeroes instead of spaces is
+/// tolerated.
+pub(crate) fn is_valid_stored(name: &[u8; 8], ext: &[u8; 3]) -> bool {
+    fn is_valid_part(part: &[u8]) -> bool {
+        let len = part.iter().position(|&c| c == b' ' || c == 0).unwrap_or(part.len());
+        part[..len].iter().all(|&c| c >= 0x80 || is_valid_short_name_char(c)) &&
+            part[len..].iter().all(|&c| c == b' ' || c == 0)
+    }
+    let mut name = *name;
+    if name[0] == 0x05 {
+        name[0] = 0xE5;
+    }
+    name[0] != b' ' && name[0] != 0 && is_valid_part(&name) && is_valid_part(ext)
+}
+
+fn invalid_short_name(reason: &'static str) -> io::Error {
+    Error::NameInvalid { reason }.into()
+}
+
+impl ShortName {
+    /// Creates a short name from a base name of 1 to 8 characters and an
+    /// extension of up to 3 characters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if either part is too long, the base
+    /// name is empty, or a character is not an upper case letter, a digit or
+    /// one of the special characters allowed in 8.3 names.
+    pub fn new(name: &str, ext: &str) -> io::Result<ShortName> {
+        if name.len() == 0 || name.len() > 8 {
+            return Err(invalid_short_name("short name must have 1 to 8 characters"));
+        }
+        if ext.len() > 3 {
+            return Err(invalid_short_name("short name extension must have at most 3 characters"));
+        }
+        if !name.bytes().chain(ext.bytes()).all(is_valid_short_name_char) {
+            return Err(invalid_short_name("short name contains an invalid character"));
+        }
+
+        let mut short_name = ShortName {
+            name: [b' '; 8],
+            ext: [b' '; 3],
+        };
+        short_name.name[..name.len()].copy_from_slice(name.as_bytes());
+        short_name.ext[..ext.len()].copy_from_slice(ext.as_bytes());
+        Ok(short_name)
+    }
+
+    /// Parses a short name of the form `NAME.EXT` or `NAME`.
+    pub fn parse(s: &str) -> io::Result<ShortName> {
+        match s.rfind('.') {
+            Some(index) => ShortName::new(&s[..index], &s[index + 1..]),
+            None => ShortName::new(s, ""),
+        }
+    }
+
+    /// The space padded base name.
+    pub fn name_bytes(&self) -> &[u8; 8] {
+        &self.name
+    }
+
+    /// The space padded extension.
+    pub fn ext_bytes(&self) -> &[u8; 3] {
+        &self.ext
+    }
+}
+
+impl fmt::Display for ShortName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn trimmed(bytes: &[u8]) -> &str {
+            let end = bytes.iter().position(|&c| c == b' ').unwrap_or(bytes.len());
+            ::std::str::from_utf8(&bytes[..end]).unwrap()
+        }
+
+        let ext = trimmed(&self.ext);
+        if ext.len() > 0 {
+            write!(f, "{}.{}", trimmed(&self.name), ext)
+        } else {
+            write!(f, "{}", trimmed(&self.name))
+        }
+    }
+}
+
+#[test]
+fn test_short_name_parse() {
+    let short_name = ShortName::parse("KERNEL8.IMG").unwrap();
+    assert_eq!(short_name.name_bytes(), b"KERNEL8 ");
+    assert_eq!(short_name.ext_bytes(), b"IMG");
+    assert_eq!(short_name.to_string(), "KERNEL8.IMG");
+
+    let short_name = ShortName::parse("README").unwrap();
+    assert_eq!(short_name.name_bytes(), b"README  ");
+    assert_eq!(short_name.ext_bytes(), b"   ");
+    assert_eq!(short_name.to_string(), "README");
+
+    assert!(ShortName::parse("kernel8.img").is_err());
+    assert!(ShortName::parse("KERNEL888.IMG").is_err());
+    assert!(ShortName::parse("KERNEL8.IMAG").is_err());
+    assert!(ShortName::parse(".IMG").is_err());
+    assert!(ShortName::parse("A B.TXT").is_err());
+    assert!(ShortName::parse("A.B.TXT").is_err());
+}
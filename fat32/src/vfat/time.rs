@@ -0,0 +1,60 @@
+use traits::{Date, DateTime};
+
+/// A source of the current time for the timestamps of new entries.
+pub trait TimeProvider: Send + Sync {
+    /// The current local time.
+    fn now(&self) -> DateTime;
+}
+
+/// The earliest time FAT timestamps can represent, 1980-01-01 00:00:00. New
+/// entries get this timestamp when there is no clock.
+pub fn fat_epoch() -> DateTime {
+    Date::from_ymd(1980, 1, 1).and_hms(0, 0, 0)
+}
+
+/// Always returns the same time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FixedTime(pub DateTime);
+
+impl FixedTime {
+    /// Returns a provider of `fat_epoch()`.
+    pub fn epoch() -> FixedTime {
+        FixedTime(fat_epoch())
+    }
+}
+
+impl TimeProvider for FixedTime {
+    fn now(&self) -> DateTime {
+        self.0
+    }
+}
+
+impl<F: Fn() -> DateTime + Send + Sync> TimeProvider for F {
+    fn now(&self) -> DateTime {
+        self()
+    }
+}
+
+/// The local time of the host according to `chrono`.
+#[cfg(feature = "clock")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct LocalTime;
+
+#[cfg(feature = "clock")]
+impl TimeProvider for LocalTime {
+    fn now(&self) -> DateTime {
+        ::chrono::offset::Local::now().naive_local().into()
+    }
+}
+
+/// `LocalTime` if the `clock` feature is enabled, `fat_epoch()` otherwise.
+pub(crate) fn default_time_provider() -> Box<TimeProvider> {
+    #[cfg(feature = "clock")]
+    {
+        Box::new(LocalTime)
+    }
+    #[cfg(not(feature = "clock"))]
+    {
+        Box::new(FixedTime::epoch())
+    }
+}
@@ -1,126 +1,870 @@
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use vfat::{VFatFile, VFatDir, Error};
+use vfat::{VFatFile, VFatDir, Error, FsInfo, ClusterBitmap};
+use error::Error as FsError;
 use vfat::BiosParameterBlock;
 use traits::{FileSystem, BlockDevice, Entry, Dir};
 use vfat::logical_block_device::LogicalBlockDevice;
 use std::path::Component;
-use vfat::VFatEntry;
+use vfat::{VFatEntry, NameSource, ShortName};
+use vfat::error::is_busy;
+use vfat::error::UnmountError;
 use vfat::logical_block_device::SharedLogicalBlockDevice;
-use vfat::fat::SharedFat;
-use vfat::lock_manager::SharedLockManager;
+use vfat::fat::{SharedFat, FatCacheMode, FatKind, Status};
+use vfat::lock_manager::{SharedLockManager, LockSnapshot};
 use arc_mutex::Weak;
 use std::collections::HashMap;
-use vfat::dir::SharedVFatDir;
-use vfat::lock_manager::LockMode;
-use fallible_iterator::FallibleIterator;
+use vfat::dir::{SharedVFatDir, empty_dir_cluster};
+use vfat::lock_manager::{LockMode, LockWait};
 use vfat::metadata::VFatMetadata;
 use vfat::metadata::Attributes;
 use traits::FileOpenMode;
 use vfat::lock_manager::FSObjectGuard;
 use arc_mutex::ArcMutex;
 use std::sync::Mutex;
+use vfat::time::{TimeProvider, default_time_provider};
+use traits::{Date, DateTime};
+use arc_mutex::Arc;
+use vfat::metrics::{Counters, Metrics};
+#[cfg(feature = "trace")]
+use vfat::metrics::Event;
+use vfat::data_region::DataRegion;
+use vfat::layout::VolumeLayout;
+use cache::{CachedDevice, CacheStats, DEFAULT_CACHE_BYTES, DEFAULT_READ_AHEAD_SECTORS};
+use fallible_iterator::FallibleIterator;
+
+/// Options controlling how a file system is mounted.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MountOptions {
+    /// Compare the copies of the FAT at mount and count the entries that
+    /// differ. The copies are not repaired.
+    pub verify_fat_mirrors: bool,
+    /// How much of the FAT to keep in memory. Cached FAT updates reach the
+    /// device only on `sync` or unmount.
+    pub fat_cache: FatCacheMode,
+    /// Don't call `BlockDevice::discard` for the data sectors of freed
+    /// clusters.
+    pub disable_discard: bool,
+    /// Access the device directly instead of through a `CachedDevice` of
+    /// `DEFAULT_CACHE_BYTES` bytes.
+    pub disable_cache: bool,
+    /// The number of sectors the cache reads ahead during sequential reads,
+    /// `DEFAULT_READ_AHEAD_SECTORS` if `None`. `Some(0)` disables read-ahead.
+    pub read_ahead_sectors: Option<u64>,
+    /// Let `remove` and `rename` remove files that are open. The entry is
+    /// removed right away, but the clusters of the file are only freed when
+    /// the last handle to it is closed.
+    pub deferred_delete: bool,
+    /// Count the free clusters with one pass over the FAT at mount and
+    /// report the result in `VFatFileSystem::mount_report`.
+    pub verify_free_count: bool,
+    /// Keep reading files past clusters the device fails to read. The
+    /// unreadable part of such a cluster reads as zeros and is reported by
+    /// `VFatFile::read_errors` instead of failing the read.
+    pub read_recovery: bool,
+}
+
+/// Permission to write clusters with `write_cluster_raw`, obtained from
+/// `raw_write_token`.
+#[derive(Debug)]
+pub struct RawWriteToken(());
+
+/// What was found about the state of the volume at mount, as returned by
+/// `VFatFileSystem::mount_report`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MountReport {
+    /// The free cluster count recorded in the FSInfo sector, `None` if it
+    /// is unknown.
+    pub fsinfo_free: Option<u32>,
+    /// The number of free clusters counted in the FAT. `None` unless
+    /// `MountOptions::verify_free_count` was set.
+    pub counted_free: Option<u32>,
+    /// Whether the volume was not cleanly unmounted.
+    pub dirty_bit_was_set: bool,
+    /// Whether the boot sector was unusable and the backup boot sector was
+    /// used instead.
+    pub backup_boot_used: bool,
+}
+
+impl MountReport {
+    /// Returns `true` if the free cluster count was both recorded and
+    /// counted, and the two differ. This hints at corruption, so running
+    /// the consistency checks is advisable.
+    pub fn free_count_mismatch(&self) -> bool {
+        match (self.fsinfo_free, self.counted_free) {
+            (Some(recorded), Some(counted)) => recorded != counted,
+            _ => false,
+        }
+    }
+}
 
 pub struct VFatFileSystem {
     pub(crate) device: SharedLogicalBlockDevice,
-    pub(crate) bytes_per_sector: u16,
-    pub(crate) sectors_per_cluster: u8,
-    pub(crate) data_start_sector: u64,
-    pub(crate) root_dir_cluster: u32,
+    /// The cache between `device` and the mounted device, which `device`
+    /// holds another handle to.
+    cache: Option<ArcMutex<CachedDevice<Box<BlockDevice>>>>,
+    /// The EBPB the volume was mounted with.
+    params: BiosParameterBlock,
+    pub(crate) cluster_count: u32,
     fat: SharedFat,
     lock_manager: SharedLockManager,
     dirs: HashMap<u32, Weak<Mutex<VFatDir>>>,
+    fat_mirror_mismatches: usize,
+    mount_report: MountReport,
+    boot_sector_damaged: bool,
+    read_only: bool,
+    deferred_delete: bool,
+    read_recovery: bool,
+    time_provider: Box<TimeProvider>,
+    counters: Arc<Counters>,
+    data: DataRegion,
 }
 
 impl VFatFileSystem {
-    pub fn from(mut device: Box<BlockDevice>) -> Result<ArcMutex<VFatFileSystem>, Error>
+    pub fn from(device: Box<BlockDevice>) -> Result<ArcMutex<VFatFileSystem>, Error> {
+        VFatFileSystem::from_with_options(device, MountOptions::default())
+    }
+
+    pub fn from_with_options(device: Box<BlockDevice>, options: MountOptions) -> Result<ArcMutex<VFatFileSystem>, Error>
     {
-        let ebpb = BiosParameterBlock::read_from(&mut device)?;
+        let (ebpb, boot_sector_damaged) = BiosParameterBlock::read_valid_from(&device)?;
+        // FAT12 and FAT16 volumes can only be read.
+        let read_only = device.is_read_only() || ebpb.fat_kind() != FatKind::Fat32;
+        let (device, cache): (Box<BlockDevice>, _) = if options.disable_cache {
+            (device, None)
+        } else {
+            let read_ahead = options.read_ahead_sectors.unwrap_or(DEFAULT_READ_AHEAD_SECTORS);
+            let cache = ArcMutex::new(CachedDevice::with_read_ahead(device, DEFAULT_CACHE_BYTES, read_ahead));
+            (Box::new(cache.clone()), Some(cache))
+        };
         let logical_block_device = LogicalBlockDevice::new(device, ebpb.bytes_per_logical_sector as u64);
         let device = ArcMutex::new(logical_block_device);
+        let counters = Arc::new(Counters::default());
+        let fat = SharedFat::new(&device, &ebpb, &options, counters.clone())?;
+        let fat_mirror_mismatches = if options.verify_fat_mirrors {
+            fat.verify_mirrors()?.len()
+        } else {
+            0
+        };
+        let fsinfo_free = Some(fat.fs_info().free_count).filter(|&free| free != FsInfo::UNKNOWN);
+        let counted_free = if options.verify_free_count {
+            let bitmap = fat.usage_bitmap()?;
+            Some(bitmap.cluster_count() - bitmap.allocated_count())
+        } else {
+            None
+        };
+        let mount_report = MountReport {
+            fsinfo_free,
+            counted_free,
+            dirty_bit_was_set: fat.was_mounted_dirty(),
+            backup_boot_used: boot_sector_damaged,
+        };
+        let data = DataRegion::new(device.clone(), fat.clone(), counters.clone(), ebpb.bytes_per_logical_sector,
+                                   ebpb.logical_sectors_per_cluster, ebpb.data_start_sector(), ebpb.root_dir_sectors(),
+                                   fat.cluster_count(), read_only);
         let vfat = VFatFileSystem {
-            fat: SharedFat::new(&device, &ebpb),
+            cluster_count: fat.cluster_count(),
+            fat,
             device,
-            bytes_per_sector: ebpb.bytes_per_logical_sector,
-            sectors_per_cluster: ebpb.logical_sectors_per_cluster,
-            data_start_sector: (ebpb.reserved_logical_sectors as u64) +
-                (ebpb.number_of_fats as u64 * ebpb.logical_sectors_per_fat as u64),
-            root_dir_cluster: ebpb.root_directory_cluster,
+            cache,
+            params: ebpb,
             lock_manager: SharedLockManager::new(),
             dirs: HashMap::new(),
+            fat_mirror_mismatches,
+            mount_report,
+            boot_sector_damaged,
+            read_only,
+            deferred_delete: options.deferred_delete,
+            read_recovery: options.read_recovery,
+            time_provider: default_time_provider(),
+            counters,
+            data,
         };
         Ok(ArcMutex::new(vfat))
     }
 
+    /// Returns the EBPB the volume was mounted with, which is the backup if
+    /// `boot_sector_damaged` is `true`. Changes to the boot sector after the
+    /// mount are not reflected.
+    pub fn params(&self) -> &BiosParameterBlock {
+        &self.params
+    }
+
     pub(crate) fn cluster_size_bytes(&self) -> u32 {
-        self.sectors_per_cluster as u32 * self.bytes_per_sector as u32
+        self.params.logical_sectors_per_cluster as u32 * self.params.bytes_per_logical_sector as u32
     }
 
-    fn get_full_offset(&self, cluster: u32, offset: u32, buf_len: usize) -> io::Result<u64> {
-        if cluster < 2 {
-            return Err(io::Error::from(io::ErrorKind::InvalidInput));
-        }
-        if (offset + buf_len as u32) > self.cluster_size_bytes() {
-            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    /// Returns the FAT type of the volume.
+    pub fn fat_kind(&self) -> FatKind {
+        self.params.fat_kind()
+    }
+
+    /// Returns the first cluster of the root directory, or 0 for the fixed
+    /// root directory region of FAT12 and FAT16 volumes.
+    pub(crate) fn root_dir_cluster(&self) -> u32 {
+        match self.fat_kind() {
+            FatKind::Fat32 => self.params.root_directory_cluster,
+            FatKind::Fat12 | FatKind::Fat16 => 0,
         }
+    }
+
+    /// Returns the byte offset of `cluster` from the start of the volume's
+    /// device. The cluster isn't checked to exist.
+    pub fn cluster_to_device_offset(&self, cluster: u32) -> u64 {
+        self.data.cluster_offset(cluster)
+    }
 
-        let cluster_sector = self.data_start_sector + (cluster as u64 - 2) * self.sectors_per_cluster as u64;
-        Ok(cluster_sector * self.bytes_per_sector as u64 + offset as u64)
+    /// Returns the data region, for cluster I/O without locking the file
+    /// system. The device is still locked for each access.
+    pub(crate) fn data_region(&self) -> DataRegion {
+        self.data.clone()
+    }
+
+    /// Returns where the reserved region, the FATs and the data region of
+    /// the volume are.
+    pub fn layout(&self) -> VolumeLayout {
+        let params = &self.params;
+        let fat_start_sectors = (0..params.number_of_fats as u64)
+            .map(|index| params.reserved_logical_sectors as u64 + index * params.fat_size_sectors() as u64)
+            .collect();
+        VolumeLayout {
+            bytes_per_sector: params.bytes_per_logical_sector,
+            sectors_per_cluster: params.logical_sectors_per_cluster,
+            reserved_sectors: params.reserved_logical_sectors,
+            fat_count: params.number_of_fats,
+            fat_start_sectors,
+            fat_sectors: params.fat_size_sectors(),
+            data_start_sector: params.data_start_sector(),
+            cluster_count: self.cluster_count,
+            root_dir_cluster: self.root_dir_cluster(),
+        }
     }
 
     //
     //  * A method to read from an offset of a cluster into a buffer.
     //
     pub(crate) fn read_cluster(&mut self, cluster: u32, offset: u32, buf: &mut [u8]) -> io::Result<()> {
-        let full_offset = self.get_full_offset(cluster, offset, buf.len())?;
-        self.device.read_by_offset(full_offset, buf)
+        self.data.read_cluster(cluster, offset, buf)
     }
 
     pub(crate) fn write_cluster(&mut self, cluster: u32, offset: u32, buf: &[u8]) -> io::Result<()> {
-        let full_offset = self.get_full_offset(cluster, offset, buf.len())?;
-        self.device.write_by_offset(full_offset, buf)
+        self.data.write_cluster(cluster, offset, buf)
+    }
+
+    /// Returns the operations performed since the mount or the last
+    /// `reset_metrics`.
+    pub fn metrics(&self) -> Metrics {
+        self.counters.snapshot(self.cache_stats().map(|stats| stats.hits))
+    }
+
+    pub fn reset_metrics(&self) {
+        self.counters.reset(self.cache_stats().map(|stats| stats.hits));
+    }
+
+    /// Calls `hook` for every operation counted in `metrics`, or stops calling
+    /// the previous hook if `hook` is `None`. The hook runs while parts of
+    /// the file system are locked, so it must not use the file system.
+    #[cfg(feature = "trace")]
+    pub fn set_trace_hook(&self, hook: Option<fn(Event)>) {
+        self.counters.set_trace_hook(hook);
+    }
+
+    /// Returns `true` if files are read with `MountOptions::read_recovery`.
+    pub(crate) fn read_recovery(&self) -> bool {
+        self.read_recovery
     }
 
     pub(crate) fn fat(&self) -> SharedFat {
         self.fat.clone()
     }
 
+    /// Returns the number of FAT entries found to differ between the copies of
+    /// the FAT at mount. Always 0 unless `MountOptions::verify_fat_mirrors`
+    /// was set.
+    pub fn fat_mirror_mismatches(&self) -> usize {
+        self.fat_mirror_mismatches
+    }
+
+    /// Returns the index of the FAT that is read from. Unless mirroring is
+    /// disabled, this is always 0.
+    pub fn active_fat(&self) -> u8 {
+        self.fat.active_fat()
+    }
+
+    /// Returns `true` if updates are written to every copy of the FAT.
+    pub fn is_fat_mirrored(&self) -> bool {
+        self.fat.is_mirrored()
+    }
+
+    /// Returns the number of entries in a single FAT, including the two
+    /// reserved entries.
+    pub fn fat_entries(&self) -> u32 {
+        self.fat.size()
+    }
+
+    /// Returns what was found about the state of the volume at mount.
+    pub fn mount_report(&self) -> MountReport {
+        self.mount_report
+    }
+
+    /// Returns `true` if the underlying device rejects writes or the volume
+    /// is a FAT12 or FAT16 volume. All operations that would modify the
+    /// volume then fail with `PermissionDenied`, or on FAT12 and FAT16 with
+    /// `InvalidData` (`UnsupportedFs`).
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Sets the clock for the timestamps of new entries. The default is the
+    /// local time with the `clock` feature and `fat_epoch()` without it.
+    pub fn set_time_provider(&mut self, time_provider: Box<TimeProvider>) {
+        self.time_provider = time_provider;
+    }
+
+    /// The current time according to the time provider.
+    pub fn now(&self) -> DateTime {
+        self.time_provider.now()
+    }
+
+    pub(crate) fn check_writable(&self) -> io::Result<()> {
+        if self.fat_kind() != FatKind::Fat32 {
+            return Err(FsError::UnsupportedFs.into());
+        }
+        if self.read_only {
+            return Err(FsError::ReadOnly.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the statistics of the device cache, or `None` if the file
+    /// system was mounted with `MountOptions::disable_cache`.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.lock().stats())
+    }
+
+    /// Returns `true` if the boot sector was unusable and the volume was
+    /// mounted from the backup boot sector instead.
+    pub fn boot_sector_damaged(&self) -> bool {
+        self.boot_sector_damaged
+    }
+
+    /// Copies the backup boot sector over the damaged primary boot sector.
+    /// Does nothing if the primary boot sector is intact.
+    pub fn repair_boot_sector(&mut self) -> io::Result<()> {
+        if !self.boot_sector_damaged {
+            return Ok(());
+        }
+        let mut buf = vec![0; self.params.bytes_per_logical_sector as usize];
+        self.device.read_sector(BiosParameterBlock::BACKUP_SECTOR, &mut buf)?;
+        self.device.write_sector(0, &buf)?;
+        self.boot_sector_damaged = false;
+        Ok(())
+    }
+
+    /// Returns the free cluster count and next free cluster hint as currently
+    /// tracked in memory.
+    pub fn fs_info(&self) -> FsInfo {
+        self.fat.fs_info()
+    }
+
+    /// Returns `true` if the volume was not cleanly unmounted before this
+    /// mount, in which case running the consistency checks is advisable.
+    pub fn was_mounted_dirty(&self) -> bool {
+        self.fat.was_mounted_dirty()
+    }
+
+    /// Returns a bitmap of the allocated data clusters, e.g. for imaging only
+    /// the used parts of a volume.
+    pub fn usage_bitmap(&self) -> io::Result<ClusterBitmap> {
+        self.fat.usage_bitmap()
+    }
+
+    /// Returns the number of clusters marked as bad in the FAT.
+    pub fn bad_cluster_count(&self) -> io::Result<u32> {
+        self.fat.bad_cluster_count()
+    }
+
     pub(crate) fn lock_manager(&self) -> SharedLockManager {
         self.lock_manager.clone()
     }
 
+    #[cfg(test)]
+    pub(crate) fn is_dir_cached(&self, first_cluster: u32) -> bool {
+        self.dirs.contains_key(&first_cluster)
+    }
+
+    /// Returns the locks currently held or waited for on files and
+    /// directories, for debugging hangs.
+    pub fn debug_locks(&self) -> Vec<LockSnapshot> {
+        self.lock_manager.snapshot()
+    }
+
+}
+
+
+/// Returns the name in the path component `component`. Fails with
+/// `PathInvalid` unless it is a normal component that is valid UTF-8 and has
+/// no NUL characters.
+fn component_name(component: Component) -> io::Result<&str> {
+    let invalid = |reason| -> io::Error {
+        let component = component.as_os_str().to_string_lossy().into_owned();
+        FsError::PathInvalid { component, reason }.into()
+    };
+    match component {
+        Component::Normal(name) => {
+            let name = name.to_str().ok_or_else(|| invalid("not valid UTF-8"))?;
+            if name.contains('\0') {
+                return Err(invalid("contains a NUL character"));
+            }
+            Ok(name)
+        }
+        Component::CurDir | Component::ParentDir => Err(invalid("`.` and `..` aren't supported")),
+        Component::RootDir | Component::Prefix(_) => Err(invalid("not a name")),
+    }
+}
+
+/// Returns `true` if `path` ends with a separator or a "." component, which
+/// `Path::components` drops. Like with `std::fs`, the entry at `path` must
+/// then be a directory.
+fn has_trailing_separator(path: &Path) -> bool {
+    let path = path.as_os_str().to_string_lossy();
+    path.ends_with('/') || path.ends_with("/.")
+}
+
+/// Returns `true` if `dir` is the directory starting at `first_cluster` or
+/// lies below it, found by walking up from `dir` to the root.
+fn is_within(dir: &SharedVFatDir, first_cluster: u32) -> bool {
+    let mut entry = dir.entry();
+    while let Some(current) = entry {
+        if current.metadata.first_cluster == first_cluster {
+            return true;
+        }
+        entry = current.parent().entry();
+    }
+    false
+}
+
+/// Splits the absolute `path` into its parent and the name of its last
+/// component. Fails with `AlreadyExists` for the root directory.
+fn split_path(path: &Path) -> io::Result<(&Path, &str)> {
+    if !path.is_absolute() {
+        return Err(FsError::InvalidInput { what: "relative paths are not supported" }.into());
+    }
+    match path.components().next_back() {
+        Some(Component::RootDir) | None => Err(FsError::AlreadyExists.into()),
+        Some(component) => {
+            let name = component_name(component)?;
+            Ok((path.parent().unwrap_or_else(|| Path::new("/")), name))
+        }
+    }
 }
 
+/// The most entries `remove_dir_recursively` locks before it removes any of
+/// them. Larger trees have to be removed in parts.
+const MAX_TREE_REMOVAL_ENTRIES: usize = 1 << 16;
+
+/// An entry of a tree that `remove_dir_recursively` locked for removal.
+struct LockedEntry {
+    entry: VFatEntry,
+    /// The deletion lock of a file, `None` if its removal is deferred.
+    lock: Option<FSObjectGuard>,
+    /// The handle of a directory, through which its children are removed
+    /// and whose lock becomes its deletion lock afterwards.
+    dir: Option<SharedVFatDir>,
+}
 
+/// Operations on a file or directory that is in use fail with `Busy`, except
+/// for the `_blocking` variants, which wait for it to be released: opening a
+/// file waits for conflicting handles of the file to be closed, removing a
+/// file waits for all its handles and entries to be dropped, and removing a
+/// directory waits for its handles to be dropped. They block forever if the
+/// calling thread holds one of those itself.
 impl ArcMutex<VFatFileSystem> {
-    fn lock_entry_for_deletion(&self, entry: &mut VFatEntry) -> io::Result<FSObjectGuard> {
+    /// Creates the entry `file_name` for the new chain of `metadata`, or
+    /// frees the chain if that fails, e.g. because the entry exists.
+    fn create_entry_or_free(&self, dir: &SharedVFatDir, file_name: &str, metadata: &VFatMetadata)
+                            -> io::Result<VFatEntry> {
+        dir.create_entry(file_name, metadata).map_err(|e| {
+            let mut fat = self.lock().fat();
+            let _ = fat.free_chain(metadata.first_cluster);
+            e
+        })
+    }
+
+    fn lock_entry_for_deletion(&self, entry: &mut VFatEntry, wait: LockWait) -> io::Result<FSObjectGuard> {
         if entry.is_file() {
             entry.ref_guard.take();
-            let mut lock = self.lock().lock_manager().try_lock(entry.metadata.first_cluster, LockMode::Delete)
-                .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "can't get delete lock for file"))?;
+            let lock_manager = self.lock().lock_manager();
+            let mut lock = lock_manager.lock_with(entry.lock_key, LockMode::Delete, wait)
+                .ok_or(FsError::Busy)?;
             Ok(lock.take())
         } else {
-            let dir = VFatDir::open(self.clone(), entry.metadata.first_cluster, Some(entry.clone()))
-                .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "failed to lock dir before deleting it"))?;
-            if dir.entries()?.next()?.is_some() {
-                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "can't remove non-empty dir"));
+            let dir = VFatDir::open_with(self.clone(), entry.metadata.first_cluster, Some(entry.clone()), wait)
+                .ok_or(FsError::Busy)?;
+            // This scans the raw entries, so the "." and ".." entries, which
+            // point to this directory and its parent, aren't locked.
+            if !dir.is_empty()? {
+                return Err(FsError::DirectoryNotEmpty.into());
             }
             let mut dir = dir.0.lock();
             Ok(dir.chain.guard.take())
         }
     }
 
-    pub fn into_block_device(self) -> Box<BlockDevice> {
-        let vfat = self.unwrap();
-        // TODO: unwrap fat, lock manager
-        vfat.fat.unwrap().unwrap();
-        vfat.device.unwrap().source
+    /// Like `lock_entry_for_deletion`, but with `MountOptions::deferred_delete`
+    /// returns `None` for a file that is in use instead of waiting.
+    fn lock_entry_for_deletion_or_defer(&self, entry: &mut VFatEntry, wait: LockWait)
+                                        -> io::Result<Option<FSObjectGuard>> {
+        if !(entry.is_file() && self.lock().deferred_delete) {
+            return self.lock_entry_for_deletion(entry, wait).map(Some);
+        }
+        match self.lock_entry_for_deletion(entry, LockWait::NoWait) {
+            Ok(lock) => Ok(Some(lock)),
+            Err(ref e) if is_busy(e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Frees the clusters of the removed `entry` now, or if `lock` is `None`,
+    /// when the file isn't used anymore.
+    fn free_removed_entry(&self, entry: &VFatEntry, lock: Option<FSObjectGuard>) -> io::Result<()> {
+        self.write_barrier()?;
+        let (mut fat, lock_manager) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.lock_manager())
+        };
+        let first_cluster = entry.metadata.first_cluster;
+        if first_cluster == 0 {
+            // The handles of an open file without clusters learn from their
+            // locks that the entry is gone, as its key is its place, which a
+            // new entry may take.
+            if lock.is_none() {
+                lock_manager.detach_removed(entry.lock_key);
+            }
+            return Ok(());
+        }
+        if lock.is_none() && lock_manager.free_when_released(fat.clone(), first_cluster) {
+            return Ok(());
+        }
+        fat.free_chain(first_cluster)
+    }
+
+    fn remove_entry_with(&self, mut entry: VFatEntry, wait: LockWait) -> io::Result<()> {
+        self.lock().check_writable()?;
+        let lock = self.lock_entry_for_deletion_or_defer(&mut entry, wait)?;
+        entry.dir.0.lock().remove_entry(&entry)?;
+        if entry.is_dir() {
+            self.forget_dir(entry.metadata.first_cluster);
+        }
+        self.free_removed_entry(&entry, lock)
+    }
+
+    /// Locks `entry`, at `path`, and everything below it for removal, and
+    /// appends them to `locked` with the children of a directory before it.
+    /// Directories are opened bypassing the cache, so that this fails for
+    /// those that are open elsewhere.
+    fn lock_tree_for_deletion(&self, entry: VFatEntry, path: String, locked: &mut Vec<LockedEntry>)
+                              -> io::Result<()> {
+        if locked.len() >= MAX_TREE_REMOVAL_ENTRIES {
+            return Err(FsError::InvalidInput { what: "the tree has too many entries to remove at once" }.into());
+        }
+        if entry.is_file() {
+            let mut entry = entry;
+            let lock = match self.lock_entry_for_deletion_or_defer(&mut entry, LockWait::NoWait) {
+                Ok(lock) => lock,
+                Err(ref e) if is_busy(e) => return Err(FsError::BusyEntry { path }.into()),
+                Err(e) => return Err(e),
+            };
+            locked.push(LockedEntry { entry, lock, dir: None });
+            return Ok(());
+        }
+        let dir = VFatDir::open_with(self.clone(), entry.metadata.first_cluster, Some(entry.clone()), LockWait::NoWait)
+            .ok_or_else(|| FsError::BusyEntry { path: path.clone() })?;
+        {
+            let mut iterator = dir.entries()?;
+            while let Some(child) = iterator.next()? {
+                let child_path = format!("{}/{}", path, child.name());
+                self.lock_tree_for_deletion(child, child_path, locked)?;
+            }
+        }
+        locked.push(LockedEntry { entry, lock: None, dir: Some(dir) });
+        Ok(())
+    }
+
+    /// Drops the cached handle of the directory starting at `first_cluster`
+    /// after its entry was removed or moved, as the cluster may be reused.
+    fn forget_dir(&self, first_cluster: u32) {
+        self.lock().dirs.remove(&first_cluster);
+    }
+
+    /// Like `remove`, but first overwrites the clusters of the entry with
+    /// zeroes, so that its data can't be recovered from the device. The
+    /// directory entries are cleared by every removal, leaving only the
+    /// deletion marker. Removal of a file in use is never deferred; it fails
+    /// with `Busy` instead.
+    pub fn remove_secure<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.lock().check_writable()?;
+        let mut entry = self.get_entry(path)?;
+        let lock = self.lock_entry_for_deletion(&mut entry, LockWait::NoWait)?;
+        self.zero_chain(entry.metadata.first_cluster)?;
+        entry.dir.0.lock().remove_entry(&entry)?;
+        if entry.is_dir() {
+            self.forget_dir(entry.metadata.first_cluster);
+        }
+        self.free_removed_entry(&entry, Some(lock))
+    }
+
+    /// Overwrites the clusters of the chain starting at `first_cluster` with
+    /// zeroes, up to a bad cluster or where the chain is broken.
+    fn zero_chain(&self, first_cluster: u32) -> io::Result<()> {
+        if first_cluster == 0 {
+            return Ok(());
+        }
+        let (fat, cluster_size) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.cluster_size_bytes())
+        };
+        let zeroes = vec![0; cluster_size as usize];
+        let mut cluster = first_cluster;
+        for _ in 0..fat.cluster_count() {
+            match fat.get(cluster)?.status() {
+                Status::Data(next) => {
+                    self.lock().write_cluster(cluster, 0, &zeroes)?;
+                    cluster = next;
+                }
+                Status::Eoc(_) => {
+                    self.lock().write_cluster(cluster, 0, &zeroes)?;
+                    break;
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `open_file`, but if the file is open in a conflicting mode, blocks
+    /// until it is closed.
+    pub fn open_file_blocking<P: AsRef<Path>>(&self, path: P, mode: FileOpenMode) -> io::Result<VFatFile> {
+        let entry = self.get_entry(path)?;
+        if entry.is_dir() {
+            return Err(FsError::IsADirectory.into());
+        }
+        VFatFile::from_entry_blocking(&entry, mode)
+    }
+
+    /// Like `remove`, but if the entry is in use, blocks until it is released.
+    /// With `MountOptions::deferred_delete`, files are removed without
+    /// blocking.
+    pub fn remove_blocking<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let entry = self.get_entry(path)?;
+        self.remove_entry_with(entry, LockWait::Forever)
+    }
+
+    /// Creates the directory at `path` along with any missing parents and
+    /// returns it. Directories that already exist are kept; if a component is
+    /// a file, fails with `NotADirectory`.
+    pub fn create_dir_all<P: AsRef<Path>>(&self, path: P) -> io::Result<SharedVFatDir> {
+        let path = path.as_ref();
+        if !path.is_absolute() {
+            return Err(FsError::InvalidInput { what: "path must be absolute" }.into());
+        }
+        let mut current = PathBuf::from("/");
+        let mut dir = self.root()?;
+        for component in path.components() {
+            if component == Component::RootDir {
+                continue;
+            }
+            current.push(component_name(component)?);
+            dir = match self.open_dir(&current) {
+                Ok(dir) => dir,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => self.create_dir(&current)?,
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Sets the last access date and the modification time of the entry at
+    /// `path`. The modification time is stored with a resolution of two
+    /// seconds.
+    pub fn set_times<P: AsRef<Path>>(&self, path: P, accessed: Date, modified: DateTime) -> io::Result<()> {
+        self.lock().check_writable()?;
+        let entry = self.get_entry(path)?;
+        let mut dir = entry.dir.0.lock();
+        dir.set_times(entry.dir_entry_index_range.end, accessed, modified)
+    }
+
+    /// Fails with `InvalidInput` unless `cluster` is a data cluster of the
+    /// volume.
+    fn check_raw_cluster(&self, cluster: u32) -> io::Result<()> {
+        if cluster < 2 || cluster as u64 >= self.lock().cluster_count as u64 + 2 {
+            return Err(FsError::InvalidInput { what: "no such cluster" }.into());
+        }
+        Ok(())
+    }
+
+    /// Reads the first `buf.len()` bytes of `cluster`, whether or not it is
+    /// allocated, without going through a file or directory. Fails with
+    /// `InvalidInput` if there is no such cluster or `buf` is larger than a
+    /// cluster.
+    pub fn read_cluster_raw(&self, cluster: u32, buf: &mut [u8]) -> io::Result<()> {
+        self.check_raw_cluster(cluster)?;
+        let data = self.lock().data_region();
+        data.read_cluster(cluster, 0, buf)
+    }
+
+    /// Returns the token that `write_cluster_raw` requires.
+    ///
+    /// Raw writes bypass the file system: they can overwrite directories and
+    /// the data of open files, and nothing but the caller keeps them
+    /// consistent with the FAT and the directory entries. A wrong raw write
+    /// corrupts the volume.
+    pub fn raw_write_token(&self) -> RawWriteToken {
+        RawWriteToken(())
+    }
+
+    /// Writes `buf` to the start of `cluster`, whether or not it is
+    /// allocated, without going through a file or directory. Fails like
+    /// `read_cluster_raw`. Later reads through the file system see the new
+    /// data; see `raw_write_token` for the risks.
+    pub fn write_cluster_raw(&self, cluster: u32, buf: &[u8], _token: &RawWriteToken) -> io::Result<()> {
+        self.lock().check_writable()?;
+        self.check_raw_cluster(cluster)?;
+        let mut data = self.lock().data_region();
+        data.write_cluster(cluster, 0, buf)?;
+        // Open directories keep the cluster they read last.
+        let dirs: Vec<_> = self.lock().dirs.values().filter_map(|dir| dir.upgrade()).collect();
+        for dir in dirs {
+            ArcMutex::from_arc(dir).lock().discard_read_buffer();
+        }
+        Ok(())
+    }
+
+    /// Makes every copy of the FAT identical to FAT number `source_index`,
+    /// e.g. after `fat_mirror_mismatches` reported differences.
+    ///
+    /// # Errors
+    ///
+    /// If `source_index` isn't the index of a FAT, an error of
+    /// `InvalidInput` is returned.
+    pub fn repair_fat_mirrors(&self, source_index: u8) -> io::Result<()> {
+        self.lock().check_writable()?;
+        let mut fat = self.lock().fat();
+        fat.repair_mirrors(source_index)?;
+        self.lock().fat_mirror_mismatches = 0;
+        Ok(())
+    }
+
+    /// Writes back the cached FAT and the FSInfo sector, flushes the
+    /// underlying device and then marks the volume as cleanly unmounted.
+    pub fn sync(&self) -> io::Result<()> {
+        self.write_barrier()?;
+        let (mut fat, mut device) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.device.clone())
+        };
+        fat.mark_volume_clean()?;
+        device.sync()
+    }
+
+    /// Writes back the cached FAT and issues a device barrier, so that
+    /// everything written so far is on the device before anything written
+    /// later.
+    ///
+    /// Updates are ordered with barriers, or in `VFatFile::flush` by
+    /// flushing the sectors involved one after the other, such that a crash
+    /// at any point leaves at most lost clusters behind, never a directory
+    /// entry referring to free clusters or a file larger than its chain:
+    ///
+    ///  * the clusters of a new entry are allocated before the entry is
+    ///    written,
+    ///  * the data and the FAT of a file that grew are written before its new
+    ///    size, and a file is shrunk before its clusters are freed,
+    ///  * a removed entry is cleared before its clusters are freed.
+    pub(crate) fn write_barrier(&self) -> io::Result<()> {
+        let (mut fat, mut device) = {
+            let vfat = self.lock();
+            (vfat.fat(), vfat.device.clone())
+        };
+        fat.sync()?;
+        device.barrier()
+    }
+
+    /// Syncs the file system and returns the underlying block device.
+    ///
+    /// # Errors
+    ///
+    /// If another handle of the file system is alive, including those held
+    /// by open files, directories and entries, an error of `Busy` is
+    /// returned. The error hands back the file system, as it does if the
+    /// sync fails.
+    pub fn try_unmount(self) -> Result<Box<BlockDevice>, UnmountError> {
+        if let Err(error) = self.sync() {
+            return Err(UnmountError::new(error, Some(self)));
+        }
+        let vfat = match ArcMutex::try_unwrap(self) {
+            Ok(vfat) => vfat,
+            Err(file_system) => return Err(UnmountError::new(FsError::Busy.into(), Some(file_system))),
+        };
+        // Every lock is held through a handle of the file system, so none is
+        // left, and the FAT and the data region were the only other holders
+        // of the device.
+        drop(vfat.lock_manager);
+        drop(vfat.data);
+        drop(vfat.fat);
+        let source = vfat.device.unwrap().source;
+        match vfat.cache {
+            Some(cache) => {
+                drop(source);
+                cache.unwrap().into_inner().map_err(|error| UnmountError::new(error, None))
+            }
+            None => Ok(source),
+        }
+    }
+
+    /// Returns the entry at the absolute `path`, or `None` for the root
+    /// directory. "." stays in a directory and ".." goes to its parent, or
+    /// stays in the root directory.
+    fn resolve(&self, path: &Path) -> io::Result<Option<VFatEntry>> {
+        if !path.is_absolute() {
+            return Err(FsError::InvalidInput { what: "relative paths are not supported" }.into());
+        }
+        let mut dir = self.root()?;
+        let mut iterator = path.components().filter(|&component| component != Component::RootDir).peekable();
+        while let Some(component) = iterator.next() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if let Some(entry) = dir.entry() {
+                        dir = entry.parent();
+                    }
+                }
+                component => {
+                    let entry = dir.find(component_name(component)?)?;
+                    if iterator.peek().is_none() { // last iteration
+                        if entry.is_file() && has_trailing_separator(path) {
+                            return Err(FsError::NotADirectory.into());
+                        }
+                        return Ok(Some(entry));
+                    } else { // not last iteration
+                        dir = entry.open_dir()?;
+                    }
+                }
+            }
+        }
+        Ok(dir.entry())
     }
 
     pub(crate) fn get_dir(&self, first_cluster: u32, entry: Option<VFatEntry>) -> Option<SharedVFatDir> {
-        if let Some(r) = self.lock().dirs.get(&first_cluster).and_then(|w| w.upgrade()) {
-            return Some(SharedVFatDir(ArcMutex::from_rc(r)));
+        let cached = self.lock().dirs.get(&first_cluster).and_then(|w| w.upgrade());
+        if let Some(r) = cached {
+            let dir = SharedVFatDir(ArcMutex::from_arc(r));
+            let is_current = entry.as_ref().map_or(true, |entry| dir.0.lock().is_opened_from(entry));
+            debug_assert!(is_current, "cached directory at cluster {} belongs to another entry", first_cluster);
+            if is_current {
+                return Some(dir);
+            }
         }
         if let Some(dir) = VFatDir::open(self.clone(), first_cluster, entry) {
             self.lock().dirs.insert(first_cluster, ArcMutex::downgrade(&dir.0));
@@ -137,105 +881,183 @@ impl FileSystem for ArcMutex<VFatFileSystem> {
     type Entry = VFatEntry;
 
     fn get_entry<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::Entry> {
-        let path = path.as_ref();
-        if !path.is_absolute() {
-            return Err(io::Error::new(io::ErrorKind::Other, "relative paths are not supported"));
-        }
-        let mut parent = self.root().unwrap();
-        let mut iterator = path.components().peekable();
-        while let Some(component) = iterator.next() {
-            if component == Component::RootDir {
-                continue;
-            }
-            let entry = parent.find(component)?;
-            if iterator.peek().is_none() { // last iteration
-                return Ok(entry);
-            } else { // not last iteration
-                parent = entry.open_dir()?;
-            }
+        self.resolve(path.as_ref())?
+            .ok_or_else(|| FsError::InvalidInput { what: "the root directory has no entry" }.into())
+    }
+
+    /// Like the default, but also opens the root directory for paths like
+    /// "/dir/.." that lead back to it.
+    fn open_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::Dir> {
+        match self.resolve(path.as_ref())? {
+            Some(entry) => entry.open_dir(),
+            None => self.root(),
         }
-        unreachable!()
     }
 
     fn root(&self) -> io::Result<SharedVFatDir> {
-        let first_cluster = self.lock().root_dir_cluster;
-        Self::get_dir(self, first_cluster, None).ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "can't get root dir"))
+        let first_cluster = self.lock().root_dir_cluster();
+        Self::get_dir(self, first_cluster, None).ok_or_else(|| FsError::Busy.into())
     }
 
     fn create_file<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::File> {
+        self.lock().check_writable()?;
         let path = path.as_ref();
-        if let Some(parent_dir) = path.parent() {
-            let dir = self.open_dir(parent_dir)?;
-            let file_name = path.file_name().unwrap().to_str().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
-            let current_time = ::chrono::offset::Local::now().naive_local();
-            let first_cluster = self.lock().fat.new_chain()?;
-            let metadata = VFatMetadata {
-                attributes: Attributes::new(false),
-                created: current_time,
-                accessed: current_time.date(),
-                modified: current_time,
-                first_cluster,
-                size: 0,
-            };
-            let entry = dir.create_entry(file_name, &metadata)?;
-            entry.open_file(FileOpenMode::Write)
-        } else {
-            Err(io::Error::new(io::ErrorKind::AlreadyExists, "invalid file path"))
+        let (parent_dir, file_name) = split_path(path)?;
+        if has_trailing_separator(path) {
+            return Err(FsError::IsADirectory.into());
         }
+        let dir = self.open_dir(parent_dir)?;
+        let current_time = self.lock().now();
+        let first_cluster = self.lock().fat.new_chain()?;
+        self.write_barrier()?;
+        let metadata = VFatMetadata {
+            attributes: Attributes::new(false),
+            created: Some(current_time),
+            accessed: Some(current_time.date()),
+            modified: Some(current_time),
+            first_cluster,
+            size: 0,
+        };
+        let entry = self.create_entry_or_free(&dir, file_name, &metadata)?;
+        self.write_barrier()?;
+        entry.open_file(FileOpenMode::Write)
     }
 
     fn create_dir<P>(&self, path: P) -> io::Result<Self::Dir>
         where P: AsRef<Path>
     {
-        let path = path.as_ref();
-        if let Some(parent_dir) = path.parent() {
-            let dir = self.open_dir(parent_dir)?;
-            let file_name = path.file_name().unwrap().to_str().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
-            let current_time = ::chrono::offset::Local::now().naive_local();
-            let first_cluster = self.lock().fat.new_chain()?;
-            let metadata = VFatMetadata {
-                attributes: Attributes::new(true),
-                created: current_time,
-                accessed: current_time.date(),
-                modified: current_time,
-                first_cluster,
-                size: 0,
-            };
-            let entry = dir.create_entry(file_name, &metadata)?;
-            let dir = entry.open_dir()?;
-            dir.0.lock().init_empty(current_time)?;
-            Ok(dir)
-        } else {
-            Err(io::Error::new(io::ErrorKind::AlreadyExists, "invalid directory path"))
+        self.lock().check_writable()?;
+        let (parent_dir, file_name) = split_path(path.as_ref())?;
+        let dir = self.open_dir(parent_dir)?;
+        let current_time = self.lock().now();
+        let first_cluster = self.lock().fat.new_chain()?;
+        let metadata = VFatMetadata {
+            attributes: Attributes::new(true),
+            created: Some(current_time),
+            accessed: Some(current_time.date()),
+            modified: Some(current_time),
+            first_cluster,
+            size: 0,
+        };
+        // The cluster is allocated and filled before the entry points to
+        // it, so that a crash never leaves the entry pointing to stale data.
+        let parent_first_cluster = dir.entry().map_or(0, |entry| entry.metadata.first_cluster);
+        let (mut data, cluster_size) = {
+            let vfat = self.lock();
+            (vfat.data_region(), vfat.cluster_size_bytes())
+        };
+        let written = data.write_cluster(first_cluster, 0, &empty_dir_cluster(cluster_size, &metadata, parent_first_cluster))
+            .and_then(|_| self.write_barrier());
+        if let Err(e) = written {
+            let _ = self.lock().fat().free_chain(first_cluster);
+            return Err(e);
         }
+        let entry = self.create_entry_or_free(&dir, file_name, &metadata)?;
+        self.write_barrier()?;
+        entry.open_dir()
     }
 
     fn rename<P, Q>(&self, from: P, to: Q) -> io::Result<()>
         where P: AsRef<Path>, Q: AsRef<Path>
     {
+        self.lock().check_writable()?;
         let from = from.as_ref();
         let to = to.as_ref();
 
         let mut entry = self.get_entry(from)?;
-        let _lock = self.lock_entry_for_deletion(&mut entry)?;
+        let (new_parent_path, file_name) = split_path(to)?;
+        if entry.is_file() && has_trailing_separator(to) {
+            return Err(FsError::NotADirectory.into());
+        }
 
-        let new_parent_path = if let Some(p) = to.parent() {
-            p
+        let new_parent = self.open_dir(new_parent_path)?;
+        // With deferred deletion, a file replaces an existing file, which may
+        // be open. The target has to be looked up before `entry` is locked
+        // for deletion, as finding `entry` again would wait for that lock.
+        let target = if self.lock().deferred_delete && entry.is_file() {
+            match new_parent.find(file_name) {
+                Ok(ref target) if target.lock_key == entry.lock_key => return Ok(()),
+                Ok(ref target) if target.is_dir() => return Err(FsError::AlreadyExists.into()),
+                Ok(target) => Some(target),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            }
         } else {
-            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "invalid path"));
+            None
         };
 
-        let new_parent = self.open_dir(new_parent_path)?;
-        let file_name = to.file_name().unwrap().to_str().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
-        new_parent.0.lock().create_entry(file_name, &entry.metadata)?;
+        // A directory is moved along with its contents, so it has to be
+        // unused, but not empty. Its handle is kept to rewrite "..".
+        let (_lock, moved_dir) = if entry.is_dir() {
+            if is_within(&new_parent, entry.metadata.first_cluster) {
+                return Err(FsError::InvalidInput { what: "a directory can't be moved into itself" }.into());
+            }
+            let dir = VFatDir::open_with(self.clone(), entry.metadata.first_cluster, Some(entry.clone()),
+                                         LockWait::NoWait).ok_or(FsError::Busy)?;
+            (None, Some(dir))
+        } else {
+            (Some(self.lock_entry_for_deletion(&mut entry, LockWait::NoWait)?), None)
+        };
+        let target = match target {
+            Some(mut target) => {
+                let lock = self.lock_entry_for_deletion_or_defer(&mut target, LockWait::NoWait)?;
+                Some((target, lock))
+            }
+            None => None,
+        };
+        // An entry without a long name keeps going without one if the new
+        // name is a valid short name.
+        let short_name = match entry.name_source() {
+            NameSource::Short => ShortName::parse(file_name).ok().filter(|short_name| short_name.to_string() == file_name),
+            NameSource::Long => None,
+        };
+        // The target is only removed once the new entry is there, so that a
+        // failed create leaves it untouched.
+        let replaced = target.as_ref().map(|&(ref target, _)| target.dir_entry_index_range.end);
+        new_parent.0.lock().create_entry_replacing(file_name, short_name.as_ref(), &entry.metadata, replaced)?;
+        // A crash in between leaves two entries sharing the chain rather
+        // than none.
+        self.write_barrier()?;
+        if let Some((target, lock)) = target {
+            target.dir.0.lock().remove_entry(&target)?;
+            self.free_removed_entry(&target, lock)?;
+        }
         entry.dir.0.lock().remove_entry(&entry)?;
+        if let Some(moved_dir) = moved_dir {
+            let parent_first_cluster = new_parent.entry().map_or(0, |entry| entry.metadata.first_cluster);
+            moved_dir.0.lock().set_parent_cluster(parent_first_cluster)?;
+            self.forget_dir(entry.metadata.first_cluster);
+        }
         Ok(())
     }
 
-    fn remove_entry(&self, mut entry: VFatEntry) -> io::Result<()> {
-        let _lock = self.lock_entry_for_deletion(&mut entry)?;
-        entry.dir.0.lock().remove_entry(&entry)?;
-        self.lock().fat.free_chain(entry.metadata.first_cluster)
+    fn remove_entry(&self, entry: VFatEntry) -> io::Result<()> {
+        self.remove_entry_with(entry, LockWait::NoWait)
+    }
+
+    /// Locks every entry of the tree first and fails with `BusyEntry` naming
+    /// the first one in use before anything is removed. Open files don't
+    /// count as in use with `MountOptions::deferred_delete`.
+    fn remove_dir_recursively(&self, dir: SharedVFatDir) -> io::Result<()> {
+        self.lock().check_writable()?;
+        let entry = dir.entry().ok_or(FsError::InvalidInput { what: "the root directory can't be removed" })?;
+        drop(dir);
+        let path = entry.path();
+        let mut locked = Vec::new();
+        self.lock_tree_for_deletion(entry, path, &mut locked)?;
+        for LockedEntry { entry, lock, dir } in locked {
+            // The children of a directory are gone by now.
+            let lock = match dir {
+                Some(dir) => Some(dir.0.lock().chain.guard.take()),
+                None => lock,
+            };
+            entry.dir.0.lock().remove_entry(&entry)?;
+            if entry.is_dir() {
+                self.forget_dir(entry.metadata.first_cluster);
+            }
+            self.free_removed_entry(&entry, lock)?;
+        }
+        Ok(())
     }
 }
 